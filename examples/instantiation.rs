@@ -1,5 +1,7 @@
+#[cfg(any(feature = "keychain", feature = "protected"))]
 use std::collections::HashMap;
 
+#[cfg(any(feature = "keychain", feature = "protected"))]
 use keyring_core::{Entry, Result};
 
 fn main() {
@@ -101,6 +103,7 @@ fn cloud_keychain_sample() -> Result<()> {
     Ok(())
 }
 
+#[cfg(any(feature = "keychain", feature = "protected"))]
 fn report_error(example: &str, result: Result<()>) {
     if let Err(e) = result {
         println!("{example} failed with error: {e:?}");
@@ -13,15 +13,44 @@ use std::backtrace;
 use std::collections::HashMap;
 use std::ffi::{CString, c_char};
 use std::io::Write;
-use std::panic::catch_unwind;
+use std::ops::ControlFlow;
+use std::panic::{AssertUnwindSafe, catch_unwind};
 use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime};
 
 use linkme::distributed_slice;
+use objc2_local_authentication::LAContext;
 
-use keyring_core::{CredentialStore, Entry, Error, api::CredentialPersistence, get_default_store};
+use keyring_core::{
+    CredentialStore, Entry, Error,
+    api::{CredentialApi, CredentialPersistence, CredentialStoreApi},
+    get_default_store,
+};
 
+use apple_native_keyring_store::error::{ErrorKind, error_kind};
+use apple_native_keyring_store::ext::apple_store;
+use apple_native_keyring_store::ext::{Availability, Backend};
+use apple_native_keyring_store::protected::AccessPolicy;
+use apple_native_keyring_store::protected::AuthenticationUi;
+use apple_native_keyring_store::protected::Biometry;
 use apple_native_keyring_store::protected::Cred;
+use apple_native_keyring_store::protected::Duplicates;
+use apple_native_keyring_store::protected::EnvelopeCred;
+use apple_native_keyring_store::protected::HealthScope;
+use apple_native_keyring_store::protected::KeyKind;
+use apple_native_keyring_store::protected::PolicyUnavailable;
+use apple_native_keyring_store::protected::Query;
+use apple_native_keyring_store::protected::SpecifierHasher;
 use apple_native_keyring_store::protected::Store;
+use apple_native_keyring_store::protected::Tombstone;
+use apple_native_keyring_store::protected::biometry;
+use apple_native_keyring_store::protected::diagnostics;
+use apple_native_keyring_store::protected::entitled_access_groups;
+use apple_native_keyring_store::protected::entitlements::TOKEN_GROUP;
+use apple_native_keyring_store::protected::envelope::CHUNK_SIZE;
+use apple_native_keyring_store::testkit::ScopedNamespace;
+use apple_native_keyring_store::timeout;
+use apple_native_keyring_store::validate::{Severity, validate_configuration};
 
 static OP_STRINGS: &str = "
     run tests
@@ -412,6 +441,26 @@ fn test_simultaneous_independent_create_set() {
     }
 }
 
+#[distributed_slice(TESTS)]
+fn test_simultaneous_set_same_entry() {
+    let name = generate_random_string();
+    let mut handles = vec![];
+    for i in 0..10 {
+        let name = name.clone();
+        let test = move || {
+            let entry = entry_new(&name, &name);
+            entry.set_password(&format!("writer{i}")).unwrap();
+        };
+        handles.push(std::thread::spawn(test))
+    }
+    for handle in handles {
+        handle.join().unwrap()
+    }
+    let entry = entry_new(&name, &name);
+    assert!(entry.get_password().unwrap().starts_with("writer"));
+    entry.delete_credential().unwrap();
+}
+
 #[distributed_slice(TESTS)]
 fn test_multiple_create_delete_single_thread() {
     let name = generate_random_string();
@@ -496,6 +545,99 @@ fn test_shared_access_groups() {
     standard_entry.delete_credential().unwrap();
 }
 
+#[distributed_slice(TESTS)]
+fn test_resolve_duplicates() {
+    let shared_group = "group.com.brotsky.test-harness";
+    let name = generate_random_string();
+    let standard_entry = entry_new(&name, &name);
+    standard_entry.set_password("app group").unwrap();
+    let mods = HashMap::from([("access-group", shared_group)]);
+    let store: Arc<CredentialStore> = Store::new_with_configuration(&mods).unwrap();
+    let shared_entry = store.build(&name, &name, None).unwrap();
+    shared_entry.set_password("shared group").unwrap();
+    let err = standard_entry.get_credential().unwrap_err();
+    let duplicates = Duplicates::of_error(&err).unwrap();
+    assert_eq!(duplicates.creds.len(), 2);
+    assert!(duplicates.access_groups().contains(&shared_group));
+    let narrowed = duplicates.in_access_group(shared_group).unwrap();
+    assert_eq!(narrowed.get_password().unwrap(), "shared group");
+    assert!(duplicates.in_access_group("no such group").is_none());
+    assert!(Duplicates::of_error(&Error::NoEntry).is_none());
+    assert_eq!(duplicates.delete_all_except(Some(shared_group)).unwrap(), 1);
+    // what's left is unambiguous
+    assert_eq!(standard_entry.get_password().unwrap(), "shared group");
+    assert_eq!(duplicates.delete_all_except(None).unwrap(), 1);
+    assert!(matches!(shared_entry.get_password(), Err(Error::NoEntry)));
+}
+
+#[distributed_slice(TESTS)]
+fn test_find_duplicates() {
+    let name = generate_random_string();
+    let single = entry_new(&name, "single");
+    single.set_password("only one").unwrap();
+    let local_entry = entry_new(&name, &name);
+    local_entry.set_password("local").unwrap();
+    let mods = HashMap::from([("cloud-sync", "true")]);
+    let sync_store: Arc<CredentialStore> = Store::new_with_configuration(&mods).unwrap();
+    std::thread::sleep(Duration::from_secs(2));
+    let sync_entry = sync_store.build(&name, &name, None).unwrap();
+    sync_entry.set_password("synced").unwrap();
+    let store = Store::new().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let found = store.find_duplicates(&spec).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].creds.len(), 2);
+    assert!(found[0].creds.iter().all(|cred| cred.account == name));
+    // the cloud copy was written last, so it's the one kept
+    assert_eq!(found[0].keep_newest().unwrap(), 1);
+    assert!(matches!(local_entry.get_password(), Err(Error::NoEntry)));
+    assert_eq!(sync_entry.get_password().unwrap(), "synced");
+    assert!(store.find_duplicates(&spec).unwrap().is_empty());
+    sync_entry.delete_credential().unwrap();
+    single.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_access_group_modifier() {
+    let name = generate_random_string();
+    let store = get_default_store().unwrap();
+    let mods = HashMap::from([("access-group", "group.com.brotsky.test-harness")]);
+    let shared_entry = store.build(&name, &name, Some(&mods)).unwrap();
+    let cred: &Cred = shared_entry.as_any().downcast_ref().unwrap();
+    assert_eq!(
+        cred.access_group.as_deref(),
+        Some("group.com.brotsky.test-harness")
+    );
+    shared_entry.set_password("shared group").unwrap();
+    let private_entry = store.build(&name, &name, None).unwrap();
+    let cred: &Cred = private_entry.as_any().downcast_ref().unwrap();
+    assert!(cred.access_group.is_none());
+    let spec = HashMap::from([
+        ("service", name.as_str()),
+        ("access-group", "group.com.brotsky.test-harness"),
+    ]);
+    assert_eq!(store.search(&spec).unwrap().len(), 1);
+    assert!(matches!(
+        store.build(&name, &name, Some(&HashMap::from([("access-group", "")]))),
+        Err(Error::Invalid(_, _))
+    ));
+    // the app isn't entitled to this group
+    let mods = HashMap::from([("access-group", "group.com.example.not-entitled")]);
+    assert!(matches!(
+        store.build(&name, &name, Some(&mods)),
+        Err(Error::Invalid(key, _)) if key == "access-group"
+    ));
+    // nor can an entry leave the App ID group in a store that declares it
+    let app_id_store =
+        Store::new_with_configuration(&HashMap::from([("default-group", "app-id")])).unwrap();
+    let mods = HashMap::from([("access-group", "group.com.brotsky.test-harness")]);
+    assert!(matches!(
+        app_id_store.build(&name, &name, Some(&mods)),
+        Err(Error::Invalid(key, _)) if key == "access-group"
+    ));
+    shared_entry.delete_credential().unwrap();
+}
+
 #[distributed_slice(TESTS)]
 fn test_separate_sync_store() {
     let name = generate_random_string();
@@ -526,6 +668,189 @@ fn test_separate_sync_store() {
     sync_entry.delete_credential().unwrap();
 }
 
+#[distributed_slice(TESTS)]
+fn test_search_any_sync_scope() {
+    let name = generate_random_string();
+    let local_entry = entry_new(&name, &name);
+    local_entry.set_password("local").unwrap();
+    let mods = HashMap::from([("cloud-sync", "true")]);
+    let sync_store: Arc<CredentialStore> = Store::new_with_configuration(&mods).unwrap();
+    let sync_entry = sync_store.build(&name, &name, None).unwrap();
+    sync_entry.set_password("synced").unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert_eq!(Entry::search(&spec).unwrap().len(), 1);
+    let spec = HashMap::from([("service", name.as_str()), ("sync-scope", "any")]);
+    let found = Entry::search(&spec).unwrap();
+    let mut synced: Vec<bool> = found
+        .iter()
+        .map(|entry| {
+            let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+            cred.cloud_synchronize
+        })
+        .collect();
+    synced.sort();
+    assert_eq!(synced, vec![false, true]);
+    // the same search from the cloud-synchronized store finds both, too
+    assert_eq!(sync_store.search(&spec).unwrap().len(), 2);
+    let spec = HashMap::from([("sync-scope", "everywhere")]);
+    assert!(matches!(
+        Entry::search(&spec),
+        Err(Error::Invalid(key, _)) if key == "sync-scope"
+    ));
+    local_entry.delete_credential().unwrap();
+    sync_entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_with_attributes() {
+    let store = Store::new().unwrap();
+    let name = generate_random_string();
+    let entry = store.build(&name, &name, None).unwrap();
+    entry.set_password("attributed").unwrap();
+    entry
+        .update_attributes(&HashMap::from([("label", "Picker label")]))
+        .unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let found = store.search_with_attributes(&spec).unwrap();
+    assert_eq!(found.len(), 1);
+    let (found_entry, attrs) = &found[0];
+    assert_eq!(found_entry.get_specifiers().unwrap().1, name);
+    assert_eq!(attrs, &entry.get_attributes().unwrap());
+    assert_eq!(attrs.get("label").unwrap(), "Picker label");
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_insensitive() {
+    let name = generate_random_string();
+    let user = format!("{name}-Zoë");
+    let entry = entry_new(&name, &user);
+    entry.set_password("loose").unwrap();
+    let plain = format!("{name}-zoe").to_lowercase();
+    let spec = HashMap::from([("account", plain.as_str())]);
+    assert!(Entry::search(&spec).unwrap().is_empty());
+    let spec = HashMap::from([
+        ("account", plain.as_str()),
+        ("case-sensitive", "false"),
+        ("diacritic-sensitive", "false"),
+    ]);
+    let found = Entry::search(&spec).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].get_specifiers().unwrap().1, user);
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_prefix_and_contains() {
+    let name = generate_random_string();
+    let entries = [
+        entry_new(&format!("{name}-mail"), "alice"),
+        entry_new(&format!("{name}-mail"), "bob"),
+        entry_new(&format!("{name}-chat"), "alice"),
+    ];
+    for entry in entries.iter() {
+        entry.set_password("typed").unwrap();
+    }
+    let prefix = format!("{name}-m");
+    let spec = HashMap::from([("service-prefix", prefix.as_str())]);
+    assert_eq!(Entry::search(&spec).unwrap().len(), 2);
+    let spec = HashMap::from([("service-prefix", name.as_str()), ("account-prefix", "b")]);
+    assert_eq!(Entry::search(&spec).unwrap().len(), 1);
+    let spec = HashMap::from([("service-prefix", name.as_str()), ("contains", "li")]);
+    assert_eq!(Entry::search(&spec).unwrap().len(), 2);
+    for entry in entries {
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_pages() {
+    let name = generate_random_string();
+    let entries: Vec<Entry> = ["c", "a", "b"]
+        .iter()
+        .map(|user| entry_new(&name, user))
+        .collect();
+    for entry in entries.iter() {
+        entry.set_password("paged").unwrap();
+    }
+    let spec = HashMap::from([("service", name.as_str()), ("limit", "2")]);
+    let first: Vec<String> = Entry::search(&spec)
+        .unwrap()
+        .iter()
+        .map(|entry| entry.get_specifiers().unwrap().1)
+        .collect();
+    assert_eq!(first, ["a", "b"]);
+    let spec = HashMap::from([("service", name.as_str()), ("offset", "2")]);
+    let rest = Entry::search(&spec).unwrap();
+    assert_eq!(rest.len(), 1);
+    assert_eq!(rest[0].get_specifiers().unwrap().1, "c");
+    for entry in entries {
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_each() {
+    let name = generate_random_string();
+    let entries: Vec<Entry> = ["a", "b", "c"]
+        .iter()
+        .map(|user| entry_new(&name, user))
+        .collect();
+    for entry in entries.iter() {
+        entry.set_password("streamed").unwrap();
+    }
+    let store = Store::new().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let mut count = 0;
+    store
+        .search_each(&spec, |entry| {
+            assert_eq!(entry.get_specifiers().unwrap().0, name);
+            count += 1;
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert_eq!(count, 3);
+    let mut first = None;
+    store
+        .search_each(&spec, |entry| {
+            first = entry.get_specifiers();
+            ControlFlow::Break(())
+        })
+        .unwrap();
+    assert!(first.is_some());
+    for entry in entries {
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_sorted() {
+    let name = generate_random_string();
+    let older = entry_new(&name, "older");
+    let newer = entry_new(&name, "newer");
+    newer.set_password("first").unwrap();
+    older.set_password("second").unwrap();
+    std::thread::sleep(Duration::from_secs(2));
+    newer.set_password("third").unwrap();
+    let users = |sort: &str| -> Vec<String> {
+        let spec = HashMap::from([("service", name.as_str()), ("sort", sort)]);
+        Entry::search(&spec)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.get_specifiers().unwrap().1)
+            .collect()
+    };
+    assert_eq!(users("modified-desc"), ["newer", "older"]);
+    assert_eq!(users("created-desc"), ["older", "newer"]);
+    let spec = HashMap::from([("service", name.as_str()), ("sort", "by-date")]);
+    assert!(matches!(
+        Entry::search(&spec),
+        Err(Error::Invalid(key, _)) if key == "sort"
+    ));
+    older.delete_credential().unwrap();
+    newer.delete_credential().unwrap();
+}
+
 #[distributed_slice(TESTS)]
 fn test_search_with_ui() {
     let base_count = Entry::search(&HashMap::new()).unwrap().len();
@@ -548,3 +873,1793 @@ fn test_search_with_ui() {
     let count = Entry::search(&spec).unwrap().len();
     assert_eq!(count, base_count);
 }
+
+#[distributed_slice(TESTS)]
+fn test_found_protection() {
+    let name = generate_random_string();
+    let mods = HashMap::from([("access-policy", "after-first-unlock-this-device-only")]);
+    let device_only = Entry::new_with_modifiers(&name, "device", &mods).unwrap();
+    device_only.set_password("device only").unwrap();
+    let mods = HashMap::from([("access-policy", "require-user-presence")]);
+    let protected = Entry::new_with_modifiers(&name, "presence", &mods).unwrap();
+    protected.set_password("protected").unwrap();
+    let spec = HashMap::from([
+        ("service", name.as_str()),
+        ("show-authentication-ui", "true"),
+    ]);
+    for entry in Entry::search(&spec).unwrap() {
+        let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+        let protection = cred.protection.as_ref().unwrap();
+        match cred.account.as_str() {
+            "device" => {
+                assert!(protection.device_only());
+                assert!(!protection.requires_user_presence);
+            }
+            _ => assert!(protection.requires_user_presence),
+        }
+    }
+    let found = device_only.get_credential().unwrap();
+    let cred = found.as_any().downcast_ref::<Cred>().unwrap();
+    assert_eq!(
+        cred.protection.as_ref().unwrap().accessibility,
+        "kSecAttrAccessibleAfterFirstUnlockThisDeviceOnly"
+    );
+    // a credential that wasn't found has no protection to report
+    assert!(
+        entry_new(&name, "device")
+            .as_any()
+            .downcast_ref::<Cred>()
+            .unwrap()
+            .protection
+            .is_none()
+    );
+    device_only.delete_credential().unwrap();
+    protected.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_verify_access_control() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(cred.verify_access_control(), Err(Error::NoEntry)));
+    entry.set_password("default policy").unwrap();
+    assert!(cred.verify_access_control().unwrap().is_empty());
+    entry.delete_credential().unwrap();
+    let mods = HashMap::from([("access-policy", "after-first-unlock")]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    entry.set_password("after first unlock").unwrap();
+    assert!(cred.verify_access_control().unwrap().is_empty());
+    // a default-policy wrapper for the same item sees the mismatch
+    let wrapper = entry_new(&name, &name);
+    let mismatches = wrapper
+        .as_any()
+        .downcast_ref::<Cred>()
+        .unwrap()
+        .verify_access_control()
+        .unwrap();
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].attribute, "protection-class");
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_biometry_policies() {
+    let name = generate_random_string();
+    for (policy, expected) in [
+        ("biometry-any", AccessPolicy::BiometryAny),
+        ("BiometryCurrentSet", AccessPolicy::BiometryCurrentSet),
+    ] {
+        let mods = HashMap::from([("access-policy", policy)]);
+        let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+        let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+        assert_eq!(cred.access_policy, expected);
+        // no item is written, since the test machine may have no biometrics
+        assert!(matches!(cred.verify_access_control(), Err(Error::NoEntry)));
+        let mods = HashMap::from([
+            ("access-policy", policy),
+            ("require-user-presence-within", "60"),
+        ]);
+        assert!(matches!(
+            Entry::new_with_modifiers(&name, &name, &mods),
+            Err(Error::Invalid(key, _)) if key == "require-user-presence-within"
+        ));
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_application_password() {
+    let name = generate_random_string();
+    let mods = HashMap::from([("access-policy", "application-password")]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert_eq!(cred.access_policy, AccessPolicy::ApplicationPassword);
+    assert!(matches!(
+        cred.set_secret_with_application_password(b"secret", b""),
+        Err(Error::Invalid(_, _))
+    ));
+    cred.set_secret_with_application_password(b"secret", b"open sesame")
+        .unwrap();
+    assert_eq!(
+        cred.get_secret_with_application_password(b"open sesame")
+            .unwrap(),
+        b"secret"
+    );
+    assert!(
+        cred.get_secret_with_application_password(b"wrong password")
+            .is_err()
+    );
+    cred.set_secret_with_application_password(b"updated", b"open sesame")
+        .unwrap();
+    assert_eq!(
+        cred.get_secret_with_application_password(b"open sesame")
+            .unwrap(),
+        b"updated"
+    );
+    assert!(cred.verify_access_control().unwrap().is_empty());
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_this_device_only_policies() {
+    let name = generate_random_string();
+    for (policy, expected) in [
+        (
+            "after-first-unlock-this-device-only",
+            AccessPolicy::AfterFirstUnlockThisDeviceOnly,
+        ),
+        (
+            "when-unlocked-this-device-only",
+            AccessPolicy::WhenUnlockedThisDeviceOnly,
+        ),
+    ] {
+        let mods = HashMap::from([("access-policy", policy)]);
+        let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+        let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+        assert_eq!(cred.access_policy, expected);
+        entry.set_password(policy).unwrap();
+        assert_eq!(entry.get_password().unwrap(), policy);
+        assert!(cred.verify_access_control().unwrap().is_empty());
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_get_secret_with_prompt() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(
+        cred.get_secret_with_prompt("unlock to sign in"),
+        Err(Error::NoEntry)
+    ));
+    entry.set_password("prompted").unwrap();
+    assert_eq!(
+        cred.get_secret_with_prompt("unlock to sign in").unwrap(),
+        b"prompted"
+    );
+    assert!(matches!(
+        cred.get_secret_with_prompt(""),
+        Err(Error::Invalid(key, _)) if key == "prompt"
+    ));
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_scoped_namespace() {
+    let store = get_default_store().unwrap();
+    let kept = generate_random_string();
+    let outside = entry_new(&kept, &kept);
+    outside.set_password("outside").unwrap();
+    let namespace = ScopedNamespace::new(&store, "keyring-test").unwrap();
+    assert!(namespace.service("a").starts_with(namespace.prefix()));
+    let entry = namespace.entry("a", "user").unwrap();
+    entry.set_password("inside").unwrap();
+    namespace
+        .entry("b/c", "user")
+        .unwrap()
+        .set_password("inside")
+        .unwrap();
+    // a second namespace doesn't share the first one's prefix
+    let other = ScopedNamespace::new(&store, "keyring-test").unwrap();
+    assert_ne!(other.prefix(), namespace.prefix());
+    drop(other);
+    assert_eq!(entry.get_password().unwrap(), "inside");
+    // items are cleaned up even when the test panics
+    let prefix = namespace.prefix().to_string();
+    let result = catch_unwind(AssertUnwindSafe(move || {
+        let _namespace = namespace;
+        panic!("test failure");
+    }));
+    assert!(result.is_err());
+    assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+    let spec = HashMap::from([("service-under", prefix.as_str())]);
+    assert_eq!(apple_store(&store).unwrap().count(&spec).unwrap(), 0);
+    assert_eq!(outside.get_password().unwrap(), "outside");
+    outside.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_authentication_ui() {
+    assert_eq!(
+        "Skip".parse::<AuthenticationUi>().unwrap(),
+        AuthenticationUi::Skip
+    );
+    assert!(matches!(
+        "never".parse::<AuthenticationUi>(),
+        Err(Error::Invalid(key, _)) if key == "authentication-ui"
+    ));
+    let name = generate_random_string();
+    let mods = HashMap::from([("authentication-ui", "fail")]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert_eq!(cred.authentication_ui, AuthenticationUi::Fail);
+    // an item that needs no authentication reads the same under every policy
+    entry.set_password("no prompt").unwrap();
+    for ui in [
+        AuthenticationUi::Allow,
+        AuthenticationUi::Skip,
+        AuthenticationUi::Fail,
+    ] {
+        assert_eq!(
+            cred.with_authentication_ui(ui).get_secret().unwrap(),
+            b"no prompt"
+        );
+    }
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_get_attributes() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    assert!(matches!(entry.get_attributes(), Err(Error::NoEntry)));
+    entry.set_password("attributes").unwrap();
+    let attrs = entry.get_attributes().unwrap();
+    assert_eq!(attrs["accessibility"], "kSecAttrAccessibleWhenUnlocked");
+    assert_eq!(attrs["synchronizable"], "false");
+    assert!(!attrs["access-group"].is_empty());
+    let created: u64 = attrs["creation-date"].parse().unwrap();
+    let modified: u64 = attrs["modification-date"].parse().unwrap();
+    assert!(created > 0 && modified >= created, "{attrs:?}");
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_update_attributes() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let attrs = HashMap::from([("label", "Readable name"), ("comment", "a note")]);
+    assert!(matches!(
+        entry.update_attributes(&attrs),
+        Err(Error::NoEntry)
+    ));
+    entry.set_password("described").unwrap();
+    entry.update_attributes(&attrs).unwrap();
+    entry
+        .update_attributes(&HashMap::from([("description", "a kind")]))
+        .unwrap();
+    let found = entry.get_attributes().unwrap();
+    assert_eq!(found["label"], "Readable name");
+    assert_eq!(found["comment"], "a note");
+    assert_eq!(found["description"], "a kind");
+    assert_eq!(entry.get_password().unwrap(), "described");
+    assert!(matches!(
+        entry.update_attributes(&HashMap::from([("service", "other")])),
+        Err(Error::Invalid(_, _))
+    ));
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_set_secret_updates_in_place() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("first").unwrap();
+    let before = entry.get_attributes().unwrap();
+    entry
+        .update_attributes(&HashMap::from([("label", "set elsewhere")]))
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    entry.set_password("second").unwrap();
+    let after = entry.get_attributes().unwrap();
+    assert_eq!(after["label"], "set elsewhere");
+    assert_eq!(after["creation-date"], before["creation-date"]);
+    assert_eq!(after["accessibility"], before["accessibility"]);
+    assert_eq!(entry.get_password().unwrap(), "second");
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_create_only() {
+    let name = generate_random_string();
+    let mods = HashMap::from([("create-only", "true")]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    entry.set_password("first").unwrap();
+    let err = entry.set_password("second").unwrap_err();
+    assert_eq!(error_kind(&err), Some(ErrorKind::DuplicateItem));
+    assert_eq!(entry.get_password().unwrap(), "first");
+    let mods = HashMap::from([("create-only", "true"), ("skip-unchanged", "true")]);
+    assert!(matches!(
+        Entry::new_with_modifiers(&name, &name, &mods),
+        Err(Error::Invalid(key, _)) if key == "create-only"
+    ));
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_move_to_sync_store() {
+    let name = generate_random_string();
+    let store = Store::new().unwrap();
+    let sync_store =
+        Store::new_with_configuration(&HashMap::from([("cloud-sync", "true")])).unwrap();
+    let entry = store.build(&name, &name, None).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(cred.copy_to(&sync_store), Err(Error::NoEntry)));
+    entry.set_password("synced").unwrap();
+    cred.set_metadata(b"v1").unwrap();
+    let synced = cred.move_to(&sync_store).unwrap();
+    assert!(synced.cloud_synchronize);
+    assert_eq!(synced.get_password().unwrap(), "synced");
+    assert_eq!(synced.get_metadata().unwrap().unwrap(), b"v1");
+    assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+    // and back again, twice
+    let local = synced.copy_to(&store).unwrap();
+    assert_eq!(local.get_password().unwrap(), "synced");
+    let err = synced.copy_to(&store).unwrap_err();
+    assert_eq!(error_kind(&err), Some(ErrorKind::DuplicateItem));
+    local.delete_credential().unwrap();
+    synced.delete_credential().unwrap();
+    // items kept to this device don't move
+    let mods = HashMap::from([("access-policy", "when-unlocked-this-device-only")]);
+    let entry = store.build(&name, &name, Some(&mods)).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(
+        cred.move_to(&sync_store),
+        Err(Error::Invalid(key, _)) if key == "access-policy"
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_metadata() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(cred.set_metadata(b"v2"), Err(Error::NoEntry)));
+    entry.set_password("versioned").unwrap();
+    assert_eq!(cred.get_metadata().unwrap(), None);
+    cred.set_metadata(b"schema\x00v2").unwrap();
+    assert_eq!(cred.get_metadata().unwrap().unwrap(), b"schema\x00v2");
+    // setting the secret leaves the metadata alone
+    entry.set_password("still versioned").unwrap();
+    assert_eq!(cred.get_metadata().unwrap().unwrap(), b"schema\x00v2");
+    assert!(matches!(
+        cred.set_metadata(b"keyring-tag:theirs"),
+        Err(Error::Invalid(key, _)) if key == "metadata"
+    ));
+    let mods = HashMap::from([("tag", "tagged")]);
+    let tagged = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    let tagged = tagged.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(
+        tagged.set_metadata(b"v2"),
+        Err(Error::Invalid(_, _))
+    ));
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_persistent_ref() {
+    let store = Store::new().unwrap();
+    let name = generate_random_string();
+    let entry = store.build(&name, &name, None).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(cred.persistent_ref(), Err(Error::NoEntry)));
+    entry.set_password("persistent").unwrap();
+    let reference = cred.persistent_ref().unwrap();
+    assert!(!reference.is_empty());
+    let reopened = Cred::from_persistent_ref(&store, &reference).unwrap();
+    assert_eq!(reopened.service, name);
+    assert_eq!(reopened.account, name);
+    assert!(reopened.access_group.is_some());
+    assert_eq!(reopened.get_password().unwrap(), "persistent");
+    assert!(matches!(
+        Cred::from_persistent_ref(&store, b""),
+        Err(Error::Invalid(_, _))
+    ));
+    entry.delete_credential().unwrap();
+    assert!(matches!(
+        Cred::from_persistent_ref(&store, &reference),
+        Err(Error::NoEntry)
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_envelope() {
+    let key_tag = format!("test.envelope.{}", generate_random_string());
+    let config = HashMap::from([("envelope-key", key_tag.as_str())]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    assert_eq!(store.configuration()["envelope-enclave"], "false");
+    let name = generate_random_string();
+    let mods = HashMap::from([("envelope", "true")]);
+    let entry = store.build(&name, &name, Some(&mods)).unwrap();
+    assert!(entry.as_any().downcast_ref::<EnvelopeCred>().is_some());
+    // a small secret is kept in the entry's item
+    entry.set_secret(b"small").unwrap();
+    assert_eq!(entry.get_secret().unwrap(), b"small");
+    // a large one is split over chunk items, which searches leave out
+    let large: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+    entry.set_secret(&large).unwrap();
+    assert_eq!(entry.get_secret().unwrap(), large);
+    let found = store
+        .search(&HashMap::from([("account", name.as_str())]))
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    let cred = found[0].as_any().downcast_ref::<Cred>().unwrap();
+    let wrapped = store.envelope_entry(cred).unwrap();
+    assert_eq!(wrapped.get_secret().unwrap(), large);
+    // the plain entry sees the header, not the secret
+    let plain = store.build(&name, &name, None).unwrap();
+    assert_ne!(plain.get_secret().unwrap(), large);
+    entry.set_secret(b"small again").unwrap();
+    assert_eq!(wrapped.get_secret().unwrap(), b"small again");
+    entry.delete_credential().unwrap();
+    assert!(matches!(entry.get_secret(), Err(Error::NoEntry)));
+    store
+        .key_item(key_tag.as_bytes(), None)
+        .unwrap()
+        .delete()
+        .unwrap();
+    // the chunks of a secret that requires user presence require it too, so
+    // the ciphertext can't be read (and decrypted with the key) without a prompt
+    let mods = HashMap::from([
+        ("envelope", "true"),
+        ("access-policy", "require-user-presence"),
+    ]);
+    let protected = store.build(&name, &name, Some(&mods)).unwrap();
+    protected.set_secret(&large).unwrap();
+    let skip = HashMap::from([("authentication-ui", "skip")]);
+    let chunk_service = format!("keyring-envelope:{name}");
+    for index in 0..3 {
+        let account = format!("{name}:{index}");
+        let chunk = store.build(&chunk_service, &account, Some(&skip)).unwrap();
+        assert!(chunk.get_attributes().is_ok(), "chunk {index} is missing");
+        assert!(matches!(chunk.get_secret(), Err(Error::NoEntry)));
+    }
+    let header = store.build(&name, &name, Some(&skip)).unwrap();
+    assert!(matches!(header.get_secret(), Err(Error::NoEntry)));
+    protected.delete_credential().unwrap();
+    store
+        .key_item(key_tag.as_bytes(), None)
+        .unwrap()
+        .delete()
+        .unwrap();
+    // envelope entries need a key, and can't skip unchanged writes
+    let plain_store = Store::new().unwrap();
+    assert!(matches!(
+        plain_store.build(&name, &name, Some(&mods)),
+        Err(Error::Invalid(key, _)) if key == "envelope"
+    ));
+    let mods = HashMap::from([("envelope", "true"), ("skip-unchanged", "true")]);
+    assert!(matches!(
+        store.build(&name, &name, Some(&mods)),
+        Err(Error::Invalid(key, _)) if key == "envelope"
+    ));
+    let config = HashMap::from([
+        ("envelope-key", key_tag.as_str()),
+        ("envelope-enclave", "true"),
+        ("cloud-sync", "true"),
+    ]);
+    assert!(matches!(
+        Store::new_with_configuration(&config),
+        Err(Error::Invalid(key, _)) if key == "envelope-enclave"
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_key_items() {
+    use security_framework::key::{GenerateKeyOptions, KeyType, SecKey};
+    let store = Store::new().unwrap();
+    assert!(matches!(
+        store.key_item(b"", None),
+        Err(Error::Invalid(_, _))
+    ));
+    let tag = format!("test.key.{}", generate_random_string());
+    let item = store.key_item(tag.as_bytes(), None).unwrap();
+    assert!(matches!(item.get(), Err(Error::NoEntry)));
+    let mut options = GenerateKeyOptions::default();
+    options
+        .set_key_type(KeyType::ec_sec_prime_random())
+        .set_size_in_bits(256);
+    let key = SecKey::new(&options).unwrap();
+    let exported = key.external_representation().unwrap().bytes().to_vec();
+    item.import(KeyKind::EcPrivate, &exported).unwrap();
+    assert_eq!(item.export().unwrap(), exported);
+    // storing another key replaces the first
+    let other = SecKey::new(&options).unwrap();
+    item.store(&other).unwrap();
+    assert_eq!(
+        item.export().unwrap(),
+        other.external_representation().unwrap().bytes().to_vec()
+    );
+    assert!(matches!(
+        item.import(KeyKind::RsaPublic, b"not a key"),
+        Err(Error::Invalid(_, _))
+    ));
+    item.delete().unwrap();
+    assert!(matches!(item.get(), Err(Error::NoEntry)));
+}
+
+#[distributed_slice(TESTS)]
+fn test_label_template() {
+    let mods = HashMap::from([("label-template", "no placeholder")]);
+    assert!(matches!(
+        Entry::new_with_modifiers("service", "user", &mods),
+        Err(Error::Invalid(key, _)) if key == "label-template"
+    ));
+    let name = generate_random_string();
+    let mods = HashMap::from([
+        ("access-policy", "when-unlocked"),
+        ("label-template", "test item {policy}"),
+    ]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    entry.set_password("labeled").unwrap();
+    let label = &entry.get_attributes().unwrap()["label"];
+    assert_eq!(label, "test item \u{1F512} when-unlocked");
+    assert_eq!(
+        AccessPolicy::from_label(label),
+        Some(AccessPolicy::WhenUnlocked)
+    );
+    assert_eq!(AccessPolicy::from_label("test item"), None);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    let updated = cred
+        .update_access_policy(AccessPolicy::AfterFirstUnlock)
+        .unwrap();
+    assert_eq!(updated.access_policy, AccessPolicy::AfterFirstUnlock);
+    let attrs = updated.get_attributes().unwrap();
+    assert_eq!(attrs["accessibility"], "kSecAttrAccessibleAfterFirstUnlock");
+    assert_eq!(
+        AccessPolicy::from_label(&attrs["label"]),
+        Some(AccessPolicy::AfterFirstUnlock)
+    );
+    assert_eq!(updated.get_secret().unwrap(), b"labeled");
+    assert!(matches!(
+        cred.update_access_policy(AccessPolicy::ApplicationPassword),
+        Err(Error::Invalid(_, _))
+    ));
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_get_password_lossy() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(cred.get_password_lossy(), Err(Error::NoEntry)));
+    let secret = b"binary\xff\xfedata".to_vec();
+    entry.set_secret(&secret).unwrap();
+    match entry.get_password() {
+        Err(Error::BadEncoding(bytes)) => assert_eq!(bytes, secret),
+        other => panic!("Expected a bad encoding error, got {other:?}"),
+    }
+    assert_eq!(
+        cred.get_password_lossy().unwrap(),
+        "binary\u{FFFD}\u{FFFD}data"
+    );
+    entry.delete_credential().unwrap();
+}
+
+#[cfg(feature = "secrecy")]
+#[distributed_slice(TESTS)]
+fn test_secure_accessors() {
+    use secrecy::ExposeSecret;
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(cred.get_secret_secure(), Err(Error::NoEntry)));
+    entry.set_password("wrapped").unwrap();
+    assert_eq!(
+        cred.get_password_secure().unwrap().expose_secret(),
+        "wrapped"
+    );
+    let secret = cred.get_secret_secure().unwrap();
+    assert_eq!(secret.expose_secret(), b"wrapped");
+    assert!(!format!("{secret:?}").contains("wrapped"));
+    entry.set_secret(b"\xffbinary").unwrap();
+    assert!(matches!(
+        cred.get_password_secure(),
+        Err(Error::BadEncoding(bytes)) if bytes == b"\xffbinary"
+    ));
+    entry.delete_credential().unwrap();
+}
+
+#[cfg(feature = "async")]
+#[distributed_slice(TESTS)]
+fn test_async_operations() {
+    use apple_native_keyring_store::protected::aio::{self, AsyncEntry};
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct Unparker(std::thread::Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(Unparker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    let store = Store::new().unwrap();
+    let name = generate_random_string();
+    let entry = AsyncEntry::new(store.build(&name, &name, None).unwrap());
+    assert!(matches!(block_on(entry.get_secret()), Err(Error::NoEntry)));
+    block_on(entry.set_password("test async operations")).unwrap();
+    assert_eq!(
+        block_on(entry.get_password()).unwrap(),
+        "test async operations"
+    );
+    let found = block_on(aio::search(
+        &store,
+        &HashMap::from([("service", name.as_str())]),
+    ))
+    .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(
+        block_on(found[0].get_secret()).unwrap(),
+        b"test async operations"
+    );
+    block_on(entry.delete_credential()).unwrap();
+    assert!(matches!(
+        block_on(entry.delete_credential()),
+        Err(Error::NoEntry)
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_retry_when_available() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("available").unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let retried = entry_new(&name, &name);
+    let pending = store
+        .retry_when_available(
+            move || retried.get_password(),
+            move |result| sender.send(result).unwrap(),
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap();
+    let result = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(result.unwrap(), "available");
+    // the device is unlocked, so the retry isn't waiting to be canceled
+    assert!(!pending.cancel());
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_and_get() {
+    let name = generate_random_string();
+    let entry1 = entry_new(&name, &name);
+    entry1.set_password("first").unwrap();
+    let entry2 = entry_new(&name, &format!("{name}-2"));
+    entry2.set_password("second").unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let mut results: Vec<(String, Vec<u8>)> = store
+        .search_and_get(&spec)
+        .unwrap()
+        .into_iter()
+        .map(|(entry, secret)| (entry.get_specifiers().unwrap().1, secret))
+        .collect();
+    results.sort();
+    assert_eq!(
+        results,
+        vec![
+            (name.clone(), b"first".to_vec()),
+            (format!("{name}-2"), b"second".to_vec()),
+        ]
+    );
+    entry1.delete_credential().unwrap();
+    entry2.delete_credential().unwrap();
+    assert!(store.search_and_get(&spec).unwrap().is_empty());
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_in_context() {
+    let name = generate_random_string();
+    let entry1 = entry_new(&name, &name);
+    entry1.set_password("unprotected").unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    // SAFETY: creating a context and setting its properties have no preconditions.
+    let context = unsafe {
+        let context = LAContext::new();
+        context.setInteractionNotAllowed(true);
+        context
+    };
+    let spec = HashMap::from([("service", name.as_str())]);
+    let results = store.search_in_context(&spec, &context).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].1, b"unprotected");
+    // a protected item is included, and read in the context, which can't prompt
+    let mods = HashMap::from([("access-policy", "require-user-presence")]);
+    let entry2 = Entry::new_with_modifiers(&name, &format!("{name}-2"), &mods).unwrap();
+    entry2.set_password("protected").unwrap();
+    let err = store.search_in_context(&spec, &context).unwrap_err();
+    assert_eq!(error_kind(&err), Some(ErrorKind::InteractionNotAllowed));
+    entry1.delete_credential().unwrap();
+    entry2.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_detailed() {
+    let name = generate_random_string();
+    let entry1 = entry_new(&name, &name);
+    entry1.set_password("unprotected").unwrap();
+    let mods = HashMap::from([("access-policy", "require-user-presence")]);
+    let entry2 = Entry::new_with_modifiers(&name, &format!("{name}-2"), &mods).unwrap();
+    entry2.set_password("protected").unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let outcome = store.search_detailed(&spec).unwrap();
+    assert_eq!(outcome.entries.len(), 1);
+    assert_eq!(outcome.skipped, 1);
+    let spec = HashMap::from([
+        ("service", name.as_str()),
+        ("show-authentication-ui", "true"),
+    ]);
+    let outcome = store.search_detailed(&spec).unwrap();
+    assert_eq!(outcome.entries.len(), 2);
+    assert_eq!(outcome.skipped, 0);
+    entry1.delete_credential().unwrap();
+    entry2.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_display_user() {
+    let name = generate_random_string();
+    let display = format!("{name} (display)");
+    let mods = HashMap::from([("display-user", display.as_str())]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+    test_round_trip_no_delete("display user", &entry, "first password");
+    test_round_trip_no_delete("display user update", &entry, "second password");
+    // a plain entry for the same service and user doesn't see the item
+    assert!(matches!(
+        entry_new(&name, &name).get_password(),
+        Err(Error::NoEntry)
+    ));
+    // search finds the item and reports both users
+    let found = Entry::search(&HashMap::from([("service", name.as_str())])).unwrap();
+    assert_eq!(found.len(), 1);
+    let cred = found[0].as_any().downcast_ref::<Cred>().unwrap();
+    assert_eq!(cred.account, name);
+    assert_eq!(cred.display_user.as_deref(), Some(display.as_str()));
+    assert_eq!(found[0].get_password().unwrap(), "second password");
+    entry.get_credential().unwrap();
+    entry.delete_credential().unwrap();
+    assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+    let mods = HashMap::from([("display-user", "")]);
+    Entry::new_with_modifiers(&name, &name, &mods).unwrap_err();
+}
+
+#[distributed_slice(TESTS)]
+fn test_update_attributes_matching() {
+    let name = generate_random_string();
+    let entry1 = entry_new(&name, &format!("{name}-1"));
+    entry1.set_password("first").unwrap();
+    let entry2 = entry_new(&name, &format!("{name}-2"));
+    entry2.set_password("second").unwrap();
+    let mods = HashMap::from([("access-policy", "require-user-presence")]);
+    let protected = Entry::new_with_modifiers(&name, &format!("{name}-3"), &mods).unwrap();
+    protected.set_password("protected").unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let attrs = HashMap::from([("label", "relabeled"), ("comment", "rebranded")]);
+    assert_eq!(store.update_attributes_matching(&spec, &attrs).unwrap(), 2);
+    // secrets are untouched
+    assert_eq!(entry1.get_password().unwrap(), "first");
+    assert_eq!(entry2.get_password().unwrap(), "second");
+    assert_eq!(entry1.get_attributes().unwrap()["label"], "relabeled");
+    // the item that requires user presence is left alone
+    assert_ne!(
+        protected
+            .get_attributes()
+            .unwrap()
+            .get("label")
+            .map(String::as_str),
+        Some("relabeled")
+    );
+    protected.delete_credential().unwrap();
+    let spec = HashMap::from([("service", "no such service")]);
+    assert_eq!(store.update_attributes_matching(&spec, &attrs).unwrap(), 0);
+    assert!(matches!(
+        store.update_attributes_matching(&spec, &HashMap::new()),
+        Err(Error::Invalid(_, _))
+    ));
+    entry1.delete_credential().unwrap();
+    entry2.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_set_and_get_many() {
+    let name = generate_random_string();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let users: Vec<String> = (0..3).map(|i| format!("{name}-{i}")).collect();
+    let results = store
+        .set_many(&[
+            (&name, &users[0], b"zero"),
+            (&name, &users[1], b"one"),
+            (&name, "", b"empty"),
+        ])
+        .unwrap();
+    assert!(results[0].is_ok() && results[1].is_ok());
+    assert!(matches!(&results[2], Err(Error::Invalid(key, _)) if key == "user"));
+    let secrets = store
+        .get_many(&[(&name, &users[0]), (&name, &users[1]), (&name, &users[2])])
+        .unwrap();
+    assert_eq!(secrets[0].as_ref().unwrap(), b"zero");
+    assert_eq!(secrets[1].as_ref().unwrap(), b"one");
+    assert!(matches!(secrets[2], Err(Error::NoEntry)));
+    assert_eq!(entry_new(&name, &users[1]).get_password().unwrap(), "one");
+    for user in &users[..2] {
+        entry_new(&name, user).delete_credential().unwrap();
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_delete_prefixed() {
+    let name = generate_random_string();
+    let registration = entry_new(&format!("{name}/registration"), &name);
+    registration.set_password("device").unwrap();
+    let session = entry_new(&format!("{name}/session"), &name);
+    session.set_password("user").unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let keep = format!("{name}/registration");
+    assert_eq!(
+        store
+            .delete_prefixed(&format!("{name}/"), &[&keep])
+            .unwrap(),
+        1
+    );
+    assert!(matches!(session.get_password(), Err(Error::NoEntry)));
+    assert_eq!(registration.get_password().unwrap(), "device");
+    registration.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_delete_matching() {
+    let name = generate_random_string();
+    let entry1 = entry_new(&name, &format!("{name}-1"));
+    entry1.set_password("first").unwrap();
+    let entry2 = entry_new(&name, &format!("{name}-2"));
+    entry2.set_password("second").unwrap();
+    let mods = HashMap::from([("access-policy", "require-user-presence")]);
+    let protected = Entry::new_with_modifiers(&name, &format!("{name}-3"), &mods).unwrap();
+    protected.set_password("protected").unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let spec = HashMap::from([("service", name.as_str()), ("account", "nobody")]);
+    assert_eq!(store.delete_matching(&spec).unwrap(), 0);
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert_eq!(store.delete_matching(&spec).unwrap(), 2);
+    assert!(matches!(entry1.get_password(), Err(Error::NoEntry)));
+    assert!(matches!(entry2.get_password(), Err(Error::NoEntry)));
+    // the item that requires user presence is left alone
+    assert!(protected.get_attributes().is_ok());
+    protected.delete_credential().unwrap();
+    let config = HashMap::from([("tombstones", "true")]);
+    let tombstone_store = Store::new_with_configuration(&config).unwrap();
+    assert!(matches!(
+        tombstone_store.delete_matching(&spec),
+        Err(Error::NotSupportedByStore(_))
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_shared_stores() {
+    let config = HashMap::from([("cloud-sync", "false")]);
+    let store1 = Store::shared(&config).unwrap();
+    let store2 = Store::shared(&config).unwrap();
+    assert!(Arc::ptr_eq(&store1, &store2));
+    let evicted = Store::evict_shared(&config).unwrap().unwrap();
+    assert!(Arc::ptr_eq(&store1, &evicted));
+    let store3 = Store::shared(&config).unwrap();
+    assert!(!Arc::ptr_eq(&store1, &store3));
+    // the defaults needn't be spelled out
+    assert!(Arc::ptr_eq(
+        &store3,
+        &Store::shared(&HashMap::new()).unwrap()
+    ));
+    // what's prefetched isn't part of the configuration
+    let prefetching = HashMap::from([("prefetch", "com.example.prefetched")]);
+    assert!(Arc::ptr_eq(&store3, &Store::shared(&prefetching).unwrap()));
+    Store::evict_all_shared();
+    assert!(Store::evict_shared(&config).unwrap().is_none());
+    let bad = HashMap::from([("default-group", "explicit")]);
+    assert!(matches!(
+        Store::evict_shared(&bad),
+        Err(Error::Invalid(_, _))
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_service_list() {
+    let name1 = generate_random_string();
+    let name2 = generate_random_string();
+    let entry1 = entry_new(&name1, &name1);
+    entry1.set_password("first").unwrap();
+    let entry2 = entry_new(&name2, &name2);
+    entry2.set_password("second").unwrap();
+    let services = format!("{name1}, {name2}");
+    let found = Entry::search(&HashMap::from([("service", services.as_str())])).unwrap();
+    assert_eq!(found.len(), 2);
+    let services = format!("{name1},no such service");
+    let found = Entry::search(&HashMap::from([("service", services.as_str())])).unwrap();
+    assert_eq!(found.len(), 1);
+    let spec = HashMap::from([("service", services.as_str()), ("account", name2.as_str())]);
+    assert!(Entry::search(&spec).unwrap().is_empty());
+    // a service listed twice is found once
+    let services = format!("{name1},{name1}");
+    let found = Entry::search(&HashMap::from([("service", services.as_str())])).unwrap();
+    assert_eq!(found.len(), 1);
+    for services in [
+        ",".to_string(),
+        format!("{name1},,{name2}"),
+        format!("{name1}, "),
+    ] {
+        assert!(matches!(
+            Entry::search(&HashMap::from([("service", services.as_str())])),
+            Err(Error::Invalid(key, _)) if key == "service"
+        ));
+    }
+    entry1.delete_credential().unwrap();
+    entry2.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_entry_for() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("from search").unwrap();
+    let found = Entry::search(&HashMap::from([("service", name.as_str())])).unwrap();
+    assert_eq!(found.len(), 1);
+    let cred: &Cred = found[0].as_any().downcast_ref().unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let rebuilt = store.entry_for(cred.clone()).unwrap();
+    assert_eq!(rebuilt.get_password().unwrap(), "from search");
+    let mut empty = cred.clone();
+    empty.display_user = Some(String::new());
+    assert!(matches!(store.entry_for(empty), Err(Error::Invalid(_, _))));
+    // the store's settings are passed on
+    let config = HashMap::from([
+        ("prompt-interval", "30"),
+        ("prompt-timeout", "20"),
+        ("fail-on-main-thread", "true"),
+    ]);
+    let configured = Store::new_with_configuration(&config).unwrap();
+    let rebuilt = configured.entry_for(cred.clone()).unwrap();
+    let rebuilt: &Cred = rebuilt.as_any().downcast_ref().unwrap();
+    assert_eq!(rebuilt.prompt_interval, Some(30));
+    assert_eq!(rebuilt.prompt_timeout, Some(20));
+    assert!(rebuilt.fail_on_main_thread);
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_skip_unchanged() {
+    let name = generate_random_string();
+    let mods = HashMap::from([("skip-unchanged", "true")]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    test_round_trip_no_delete("skip unchanged create", &entry, "same");
+    test_round_trip_no_delete("skip unchanged repeat", &entry, "same");
+    test_round_trip_no_delete("skip unchanged change", &entry, "different");
+    entry.delete_credential().unwrap();
+    let mods = HashMap::from([("skip-unchanged", "sometimes")]);
+    assert!(matches!(
+        Entry::new_with_modifiers(&name, &name, &mods),
+        Err(Error::Invalid(_, _))
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_search_app_id_only() {
+    let name = generate_random_string();
+    let app_id_store =
+        Store::new_with_configuration(&HashMap::from([("default-group", "app-id")])).unwrap();
+    let app_id_group = app_id_store.configuration()["access-group"].clone();
+    let own_entry = app_id_store.build(&name, "own", None).unwrap();
+    own_entry.set_password("app-id group").unwrap();
+    let config = HashMap::from([("access-group", "group.com.brotsky.test-harness")]);
+    let shared_store = Store::new_with_configuration(&config).unwrap();
+    let shared_entry = shared_store.build(&name, "shared", None).unwrap();
+    shared_entry.set_password("shared group").unwrap();
+    let all = Entry::search(&HashMap::from([("service", name.as_str())])).unwrap();
+    assert_eq!(all.len(), 2);
+    let spec = HashMap::from([("service", name.as_str()), ("app-id-only", "true")]);
+    let own = Entry::search(&spec).unwrap();
+    // only the item in the App ID group is found
+    assert_eq!(own.len(), 1);
+    let cred: &Cred = own[0].as_any().downcast_ref().unwrap();
+    assert_eq!(cred.account, "own");
+    assert_eq!(cred.access_group.as_deref(), Some(app_id_group.as_str()));
+    own_entry.delete_credential().unwrap();
+    shared_entry.delete_credential().unwrap();
+}
+
+struct PrefixHasher;
+
+impl SpecifierHasher for PrefixHasher {
+    fn hash_service(&self, service: &str) -> String {
+        format!("hashed-{service}")
+    }
+
+    fn hash_account(&self, account: &str) -> String {
+        format!("hashed-{account}")
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_hashed_specifiers() {
+    let name = generate_random_string();
+    let hashed_name = format!("hashed-{name}");
+    let store: Arc<CredentialStore> =
+        Store::new_with_hasher(&HashMap::new(), Arc::new(PrefixHasher)).unwrap();
+    let entry = store.build(&name, &name, None).unwrap();
+    test_round_trip_no_delete("hashed", &entry, "hashed secret");
+    assert_eq!(
+        entry.get_specifiers().unwrap(),
+        (name.clone(), name.clone())
+    );
+    // the item is stored under the hashed specifiers
+    assert!(matches!(
+        entry_new(&name, &name).get_password(),
+        Err(Error::NoEntry)
+    ));
+    assert_eq!(
+        entry_new(&hashed_name, &hashed_name)
+            .get_password()
+            .unwrap(),
+        "hashed secret"
+    );
+    // searches report the plain specifiers they were given
+    let found = store
+        .search(&HashMap::from([("service", name.as_str())]))
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    let cred: &Cred = found[0].as_any().downcast_ref().unwrap();
+    assert_eq!(cred.service, name);
+    assert_eq!(cred.account, hashed_name);
+    assert_eq!(
+        cred.stored_specifiers,
+        Some((hashed_name.clone(), hashed_name.clone()))
+    );
+    assert_eq!(found[0].get_password().unwrap(), "hashed secret");
+    // its plain account isn't known, so it can't be hashed again for a copy
+    let hashing = store.as_any().downcast_ref::<Store>().unwrap();
+    assert!(matches!(cred.copy_to(hashing), Err(Error::Invalid(_, _))));
+    entry.delete_credential().unwrap();
+    // plain items can be migrated
+    let plain = entry_new(&name, &name);
+    plain.set_password("plain secret").unwrap();
+    assert!(hashing.migrate_to_hashed(&name, &name).unwrap());
+    assert!(!hashing.migrate_to_hashed(&name, &name).unwrap());
+    assert!(matches!(plain.get_password(), Err(Error::NoEntry)));
+    assert_eq!(entry.get_password().unwrap(), "plain secret");
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_dry_run() {
+    let name = generate_random_string();
+    let config = HashMap::from([("dry-run", "true")]);
+    let store: Arc<CredentialStore> = Store::new_with_configuration(&config).unwrap();
+    let dry = store.build(&name, &name, None).unwrap();
+    // nothing is created
+    dry.set_password("dry").unwrap();
+    assert!(matches!(dry.get_password(), Err(Error::NoEntry)));
+    assert!(matches!(dry.delete_credential(), Err(Error::NoEntry)));
+    // nothing is changed or deleted
+    let real = entry_new(&name, &name);
+    real.set_password("real").unwrap();
+    dry.set_password("dry").unwrap();
+    assert_eq!(dry.get_password().unwrap(), "real");
+    dry.delete_credential().unwrap();
+    assert_eq!(real.get_password().unwrap(), "real");
+    let dry_store = store.as_any().downcast_ref::<Store>().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let attrs = HashMap::from([("label", "dry")]);
+    assert_eq!(
+        dry_store.update_attributes_matching(&spec, &attrs).unwrap(),
+        1
+    );
+    assert_eq!(dry_store.delete_matching(&spec).unwrap(), 1);
+    assert!(dry_store.delete_all().unwrap() >= 1);
+    assert_eq!(real.get_password().unwrap(), "real");
+    real.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_audit_sync() {
+    let name = generate_random_string();
+    let local = entry_new(&name, &name);
+    local.set_password("local").unwrap();
+    let store = get_default_store().unwrap();
+    let store = store.as_any().downcast_ref::<Store>().unwrap();
+    let items = store
+        .audit_sync(&HashMap::from([("service", name.as_str())]))
+        .unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].account, name);
+    assert!(!items[0].synchronized);
+    assert!(!items[0].device_only);
+    assert_eq!(items[0].protection_class, "kSecAttrAccessibleWhenUnlocked");
+    local.delete_credential().unwrap();
+    assert!(
+        store
+            .audit_sync(&HashMap::from([("service", name.as_str())]))
+            .unwrap()
+            .is_empty()
+    );
+    let mods = HashMap::from([("access-policy", "after-first-unlock-this-device-only")]);
+    let device_only = store.build(&name, &name, Some(&mods)).unwrap();
+    device_only.set_password("device only").unwrap();
+    let items = store
+        .audit_sync(&HashMap::from([("service", name.as_str())]))
+        .unwrap();
+    assert!(items[0].device_only);
+    device_only.delete_credential().unwrap();
+    // an unbounded audit is a search like any other
+    let config = HashMap::from([("allow-unbounded-search", "false")]);
+    let bounded = Store::new_with_configuration(&config).unwrap();
+    assert!(matches!(
+        bounded.audit_sync(&HashMap::new()),
+        Err(Error::Invalid(_, _))
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_validate_configuration() {
+    let config = HashMap::from([("cloud-sync", "false"), ("tombstones", "true")]);
+    assert!(validate_configuration(Backend::Protected, &config).is_empty());
+    let config = HashMap::from([("default-group", "explicit"), ("dry-run", "yes")]);
+    let findings = validate_configuration(Backend::Protected, &config);
+    assert_eq!(findings.len(), 2, "{findings:?}");
+    assert!(findings.iter().all(|f| f.severity == Severity::Error));
+    let config = HashMap::from([("access-group", "group.example"), ("cloud-sync", "true")]);
+    let findings = validate_configuration(Backend::Protected, &config);
+    assert_eq!(findings.len(), 2, "{findings:?}");
+    assert!(findings.iter().all(|f| f.severity == Severity::Warning));
+    // validating never creates a store, so the access group needn't exist
+    let config = HashMap::from([
+        ("access-group", "group.example"),
+        ("default-group", "app-id"),
+    ]);
+    let findings = validate_configuration(Backend::Protected, &config);
+    assert!(
+        findings
+            .iter()
+            .any(|f| f.severity == Severity::Error && f.key.as_deref() == Some("access-group"))
+    );
+}
+
+#[distributed_slice(TESTS)]
+fn test_entitlement_diagnostics() {
+    // the test harness is signed with the shared group and iCloud
+    let config = HashMap::from([
+        ("access-group", "group.com.brotsky.test-harness"),
+        ("cloud-sync", "true"),
+    ]);
+    let report = diagnostics(&config);
+    assert!(report.code_signed);
+    assert!(report.signing_identifier.is_some());
+    assert!(report.icloud);
+    assert!(report.findings.is_empty(), "{:?}", report.findings);
+    let config = HashMap::from([("access-group", "group.example.not-entitled")]);
+    let report = diagnostics(&config);
+    assert!(!report.allows_access_group("group.example.not-entitled"));
+    assert!(
+        report
+            .findings
+            .iter()
+            .any(|f| f.severity == Severity::Error && f.key.as_deref() == Some("access-group"))
+    );
+    // configuration problems are reported too
+    let report = diagnostics(&HashMap::from([("dry-run", "yes")]));
+    assert!(
+        report
+            .findings
+            .iter()
+            .any(|f| f.key.as_deref() == Some("dry-run"))
+    );
+}
+
+#[distributed_slice(TESTS)]
+fn test_entitled_access_groups() {
+    let groups = entitled_access_groups();
+    assert!(groups.iter().any(|g| g == "group.com.brotsky.test-harness"));
+    assert_eq!(groups.last().map(String::as_str), Some(TOKEN_GROUP));
+    let config = HashMap::from([("access-group", "group.com.brotsky.test-harness")]);
+    Store::new_with_configuration(&config).unwrap();
+    let config = HashMap::from([("access-group", "group.example.not-entitled")]);
+    match Store::new_with_configuration(&config) {
+        Err(Error::Invalid(key, message)) => {
+            assert_eq!(key, "access-group");
+            assert!(
+                message.contains("group.com.brotsky.test-harness"),
+                "{message}"
+            );
+        }
+        other => panic!("Unexpected result: {other:?}"),
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_is_available() {
+    let store = Store::new().unwrap();
+    assert_eq!(store.is_available(), Availability::Available);
+    let config = HashMap::from([("access-group", "group.com.brotsky.test-harness")]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    assert!(store.is_available().is_available());
+    let err = Error::NotSupportedByStore("the protected data store".to_string());
+    assert_eq!(
+        Availability::of_error(&err),
+        Availability::Unavailable("the protected data store".to_string())
+    );
+}
+
+#[distributed_slice(TESTS)]
+fn test_biometry() {
+    let available = biometry();
+    // the test device has a passcode, or the user-presence tests couldn't pass
+    assert_ne!(available, Biometry::Unavailable);
+    assert_eq!(
+        available.strongest_policy(false),
+        AccessPolicy::RequireUserPresence
+    );
+    let expected = if available.is_biometric() {
+        AccessPolicy::BiometryAny
+    } else {
+        AccessPolicy::RequireUserPresence
+    };
+    assert_eq!(available.strongest_policy(true), expected);
+    assert_eq!(
+        Biometry::Unavailable.strongest_policy(true),
+        AccessPolicy::default()
+    );
+}
+
+#[distributed_slice(TESTS)]
+fn test_policy_preflight() {
+    AccessPolicy::WhenUnlocked.preflight().unwrap();
+    AccessPolicy::ApplicationPassword.preflight().unwrap();
+    AccessPolicy::RequireUserPresence.preflight().unwrap();
+    let biometric = AccessPolicy::BiometryAny.preflight();
+    assert_eq!(
+        biometric.is_ok(),
+        biometry().is_biometric(),
+        "{biometric:?}"
+    );
+    let err: Error = PolicyUnavailable::BiometryNotEnrolled.into();
+    assert!(matches!(err, Error::NotSupportedByStore(_)));
+}
+
+#[distributed_slice(TESTS)]
+fn test_health_check() {
+    let report = Store::new().unwrap().health_check().unwrap();
+    let scopes: Vec<_> = report.iter().map(|health| health.scope).collect();
+    assert_eq!(scopes, [HealthScope::Local, HealthScope::UserPresence]);
+    assert!(report.iter().all(|health| health.healthy()), "{report:?}");
+    // probes are written even in a dry-run store, and cleaned up
+    let config = HashMap::from([("cloud-sync", "true"), ("dry-run", "true")]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    let report = store.health_check().unwrap();
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0].scope, HealthScope::Cloud);
+    assert!(report[0].healthy(), "{report:?}");
+    let spec = HashMap::from([("service", "keyring-health-check"), ("sync-scope", "any")]);
+    assert!(store.search(&spec).unwrap().is_empty());
+}
+
+#[distributed_slice(TESTS)]
+fn test_apple_store_ext() {
+    let store = get_default_store().unwrap();
+    let ext = apple_store(&store).unwrap();
+    assert!(ext.capabilities().modifier_keys.contains(&"access-policy"));
+    let info = ext.provider_info();
+    assert_eq!(info.backend, Backend::Protected);
+    assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+    assert!(
+        ext.diagnostics()
+            .iter()
+            .any(|(k, v)| k == "cloud-sync" && v == "false")
+    );
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("wiped").unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert_eq!(ext.count(&spec).unwrap(), 1);
+    assert_eq!(ext.wipe(&spec).unwrap(), 1);
+    assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+}
+
+#[distributed_slice(TESTS)]
+fn test_require_user_presence_within() {
+    let name = generate_random_string();
+    let mods = HashMap::from([("require-user-presence-within", "300")]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert_eq!(cred.access_policy, AccessPolicy::RequireUserPresence);
+    assert_eq!(cred.user_presence_within, Some(300));
+    assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+    for (policy, seconds) in [
+        ("require-user-presence", "0"),
+        ("require-user-presence", "301"),
+        ("require-user-presence", "soon"),
+        ("when-unlocked", "60"),
+    ] {
+        let mods = HashMap::from([
+            ("access-policy", policy),
+            ("require-user-presence-within", seconds),
+        ]);
+        assert!(matches!(
+            Entry::new_with_modifiers(&name, &name, &mods),
+            Err(Error::Invalid(_, _))
+        ));
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_user_presence_reuse_within() {
+    let config = HashMap::from([("user-presence-reuse-within", "30")]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    assert_eq!(store.configuration()["user-presence-reuse-within"], "30");
+    let name = generate_random_string();
+    let built = |mods: &[(&str, &str)]| {
+        let mods: HashMap<&str, &str> = mods.iter().copied().collect();
+        let entry = store.build(&name, &name, Some(&mods)).unwrap();
+        entry
+            .as_any()
+            .downcast_ref::<Cred>()
+            .unwrap()
+            .user_presence_within
+    };
+    assert_eq!(
+        built(&[("access-policy", "require-user-presence")]),
+        Some(30)
+    );
+    assert_eq!(built(&[("require-user-presence-within", "10")]), Some(10));
+    assert_eq!(built(&[]), None);
+    for config in [
+        HashMap::from([("user-presence-reuse-within", "0")]),
+        HashMap::from([("user-presence-reuse-within", "a while")]),
+        HashMap::from([("user-presence-reuse-within", "30"), ("cloud-sync", "true")]),
+    ] {
+        assert!(matches!(
+            Store::new_with_configuration(&config),
+            Err(Error::Invalid(key, _)) if key == "user-presence-reuse-within"
+        ));
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_prompt_interval() {
+    let config = HashMap::from([("prompt-interval", "60")]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    assert_eq!(store.configuration()["prompt-interval"], "60");
+    let name = generate_random_string();
+    let entry = store.build(&name, &name, None).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert_eq!(cred.prompt_interval, Some(60));
+    // an item that doesn't require user presence is never rate-limited
+    entry.set_password("test prompt interval").unwrap();
+    for _ in 0..3 {
+        assert_eq!(entry.get_password().unwrap(), "test prompt interval");
+    }
+    entry.delete_credential().unwrap();
+    for config in [
+        HashMap::from([("prompt-interval", "0")]),
+        HashMap::from([("prompt-interval", "4000")]),
+        HashMap::from([("prompt-interval", "60"), ("cloud-sync", "true")]),
+    ] {
+        assert!(matches!(
+            Store::new_with_configuration(&config),
+            Err(Error::Invalid(key, _)) if key == "prompt-interval"
+        ));
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_shared_presence_read() {
+    // with a prompt interval, a second read that wasn't shared would be rate-limited
+    let config = HashMap::from([("prompt-interval", "60")]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    let name = generate_random_string();
+    let mods = HashMap::from([("access-policy", "require-user-presence")]);
+    let entry = Arc::new(store.build(&name, &name, Some(&mods)).unwrap());
+    entry.set_password("test shared presence read").unwrap();
+    let start = Arc::new(std::sync::Barrier::new(2));
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let (entry, start) = (entry.clone(), start.clone());
+            std::thread::spawn(move || {
+                start.wait();
+                entry.get_password()
+            })
+        })
+        .collect();
+    let results: Vec<_> = readers.into_iter().map(|r| r.join().unwrap()).collect();
+    // the user authenticates once, and both threads get what that read got
+    assert_eq!(results[0].as_ref().unwrap(), "test shared presence read");
+    assert_eq!(results[1].as_ref().unwrap(), "test shared presence read");
+    // that was the one read the interval allows
+    assert!(
+        apple_native_keyring_store::error::prompt_rate_limited(&entry.get_password().unwrap_err())
+            .is_some()
+    );
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_prompt_timeout() {
+    let config = HashMap::from([("prompt-timeout", "30")]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    assert_eq!(store.configuration()["prompt-timeout"], "30");
+    let name = generate_random_string();
+    let built = |mods: &[(&str, &str)]| {
+        let mods: HashMap<&str, &str> = mods.iter().copied().collect();
+        let entry = store.build(&name, &name, Some(&mods)).unwrap();
+        entry
+            .as_any()
+            .downcast_ref::<Cred>()
+            .unwrap()
+            .prompt_timeout
+    };
+    assert_eq!(built(&[]), Some(30));
+    assert_eq!(built(&[("prompt-timeout", "5")]), Some(5));
+    // a read that doesn't prompt finishes well within the deadline
+    let entry = store
+        .build(
+            &name,
+            &name,
+            Some(&HashMap::from([("prompt-timeout", "1")])),
+        )
+        .unwrap();
+    entry.set_password("test prompt timeout").unwrap();
+    assert_eq!(entry.get_password().unwrap(), "test prompt timeout");
+    entry.delete_credential().unwrap();
+    for config in [
+        HashMap::from([("prompt-timeout", "0")]),
+        HashMap::from([("prompt-timeout", "601")]),
+        HashMap::from([("prompt-timeout", "30"), ("cloud-sync", "true")]),
+    ] {
+        assert!(matches!(
+            Store::new_with_configuration(&config),
+            Err(Error::Invalid(key, _)) if key == "prompt-timeout"
+        ));
+    }
+    let sync_store =
+        Store::new_with_configuration(&HashMap::from([("cloud-sync", "true")])).unwrap();
+    assert!(matches!(
+        sync_store.build(&name, &name, Some(&HashMap::from([("prompt-timeout", "5")]))),
+        Err(Error::Invalid(key, _)) if key == "prompt-timeout"
+    ));
+}
+
+#[distributed_slice(TESTS)]
+fn test_fail_on_main_thread() {
+    let store = Store::new().unwrap();
+    assert_eq!(store.configuration()["fail-on-main-thread"], "false");
+    let config = HashMap::from([("fail-on-main-thread", "true")]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    assert_eq!(store.configuration()["fail-on-main-thread"], "true");
+    let name = generate_random_string();
+    let entry = store.build(&name, &name, None).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(cred.fail_on_main_thread);
+    // an item that doesn't require user presence can be read on any thread
+    entry.set_password("test fail on main thread").unwrap();
+    assert_eq!(entry.get_password().unwrap(), "test fail on main thread");
+    let found = store
+        .search(&HashMap::from([("service", name.as_str())]))
+        .unwrap();
+    let found = found[0].as_any().downcast_ref::<Cred>().unwrap();
+    assert!(found.fail_on_main_thread);
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_default_group() {
+    let store = Store::new().unwrap();
+    let config = store.configuration();
+    assert_eq!(config["default-group"], "first-entitlement");
+    assert!(!config.contains_key("access-group"));
+    let store =
+        Store::new_with_configuration(&HashMap::from([("default-group", "app-id")])).unwrap();
+    let config = store.configuration();
+    assert_eq!(config["default-group"], "app-id");
+    let group = config["access-group"].clone();
+    // a dry-run store finds the same group without writing a probe item
+    let dry_run = Store::new_with_configuration(&HashMap::from([
+        ("default-group", "app-id"),
+        ("dry-run", "true"),
+    ]))
+    .unwrap();
+    assert_eq!(dry_run.configuration()["access-group"], group);
+    let store = Store::new_with_configuration(&HashMap::from([
+        ("default-group", "explicit"),
+        ("access-group", group.as_str()),
+    ]))
+    .unwrap();
+    assert_eq!(store.configuration()["access-group"], group);
+    for config in [
+        HashMap::from([("default-group", "explicit")]),
+        HashMap::from([
+            ("default-group", "app-id"),
+            ("access-group", group.as_str()),
+        ]),
+        HashMap::from([("default-group", "somewhere")]),
+    ] {
+        assert!(matches!(
+            Store::new_with_configuration(&config),
+            Err(Error::Invalid(_, _))
+        ));
+    }
+}
+
+#[distributed_slice(TESTS)]
+fn test_low_level_query() {
+    let name = generate_random_string();
+    let entry1 = entry_new(&name, &name);
+    entry1.set_password("first").unwrap();
+    let entry2 = entry_new(&name, &format!("{name}-2"));
+    entry2.set_password("second").unwrap();
+    let mut query = Query::new();
+    query.service(&name).synchronized(false);
+    let items = query.find().unwrap();
+    assert_eq!(items.len(), 2);
+    assert!(
+        items
+            .iter()
+            .all(|item| item.service == name && !item.synchronized)
+    );
+    assert_eq!(query.entries().unwrap().len(), 2);
+    assert!(matches!(query.secret(), Err(Error::Ambiguous(_))));
+    assert_eq!(query.clone().limit(1).find().unwrap().len(), 1);
+    assert!(matches!(
+        query.clone().limit(0).find(),
+        Err(Error::Invalid(_, _))
+    ));
+    query.account(&name);
+    assert_eq!(query.secret().unwrap(), b"first");
+    entry1.delete_credential().unwrap();
+    entry2.delete_credential().unwrap();
+    assert!(query.find().unwrap().is_empty());
+    assert!(matches!(query.secret(), Err(Error::NoEntry)));
+}
+
+#[distributed_slice(TESTS)]
+fn test_operation_timeout() {
+    timeout::set_operation_timeout(Some(Duration::from_secs(60)));
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    test_round_trip("timed", &entry, "on a worker");
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert!(Entry::search(&spec).unwrap().is_empty());
+    timeout::set_operation_timeout(None);
+    assert_eq!(timeout::operation_timeout(), None);
+}
+
+#[distributed_slice(TESTS)]
+fn test_tombstones() {
+    let store = Store::new_with_configuration(&HashMap::from([("tombstones", "true")])).unwrap();
+    assert_eq!(store.configuration()["tombstones"], "true");
+    let name = generate_random_string();
+    let before = SystemTime::now() - Duration::from_secs(1);
+    let entry = store.build(&name, &name, None).unwrap();
+    entry.set_password("doomed").unwrap();
+    entry.delete_credential().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert!(store.search(&spec).unwrap().is_empty());
+    let tombstones: Vec<Tombstone> = store
+        .deleted_since(before)
+        .unwrap()
+        .into_iter()
+        .filter(|t| t.service == name)
+        .collect();
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(tombstones[0].account, name);
+    assert!(tombstones[0].deleted_at >= before);
+    // recreating the credential doesn't revive the tombstone as an entry
+    entry.set_password("revived").unwrap();
+    assert_eq!(store.search(&spec).unwrap().len(), 1);
+    entry.delete_credential().unwrap();
+    let after = SystemTime::now() + Duration::from_secs(1);
+    assert!(store.purge_tombstones(after).unwrap() >= 1);
+    assert!(
+        store
+            .deleted_since(before)
+            .unwrap()
+            .iter()
+            .all(|t| t.service != name)
+    );
+}
+
+#[distributed_slice(TESTS)]
+fn test_tag_search() {
+    let store = get_default_store().unwrap();
+    let name = generate_random_string();
+    let tag = "order-0c6f";
+    let entry = store
+        .build(&name, &name, Some(&HashMap::from([("tag", tag)])))
+        .unwrap();
+    entry.set_password("tagged").unwrap();
+    let by_tag = store
+        .search(&HashMap::from([("service", name.as_str()), ("tag", tag)]))
+        .unwrap();
+    assert_eq!(by_tag.len(), 1);
+    let cred: &Cred = by_tag[0].as_any().downcast_ref().unwrap();
+    assert_eq!(cred.tag.as_deref(), Some(tag));
+    // the base64 of "keyring-tag:order-0c6f"
+    let generic = "a2V5cmluZy10YWc6b3JkZXItMGM2Zg==";
+    let by_generic = store
+        .search(&HashMap::from([
+            ("service", name.as_str()),
+            ("generic", generic),
+        ]))
+        .unwrap();
+    assert_eq!(by_generic.len(), 1);
+    assert!(
+        store
+            .search(&HashMap::from([
+                ("service", name.as_str()),
+                ("tag", "another")
+            ]))
+            .unwrap()
+            .is_empty()
+    );
+    assert!(matches!(
+        store.search(&HashMap::from([("generic", "not base64!")])),
+        Err(Error::Invalid(_, _))
+    ));
+    assert!(matches!(
+        store.build(
+            &name,
+            &name,
+            Some(&HashMap::from([("tag", tag), ("display-user", "shown")]))
+        ),
+        Err(Error::Invalid(_, _))
+    ));
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_update_if() {
+    let store = Store::new().unwrap();
+    let name = generate_random_string();
+    let entry = store.build(&name, &name, None).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(
+        cred.update_if(b"old", b"new"),
+        Err(Error::NoEntry)
+    ));
+    entry.set_secret(b"old").unwrap();
+    assert!(!cred.update_if(b"stale", b"new").unwrap());
+    assert!(cred.update_if(b"old", b"new").unwrap());
+    assert_eq!(entry.get_secret().unwrap(), b"new");
+    let ttl = Duration::from_secs(60);
+    let lease = store.lock_credential(&name, &name, ttl).unwrap().unwrap();
+    assert!(matches!(
+        cred.update_if(b"new", b"newer"),
+        Err(Error::NoStorageAccess(_))
+    ));
+    lease.release().unwrap();
+    assert!(cred.update_if(b"new", b"newer").unwrap());
+    entry.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_rename() {
+    let store = Store::new().unwrap();
+    let name = generate_random_string();
+    let new_name = generate_random_string();
+    let entry = store.build(&name, &name, None).unwrap();
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(
+        cred.rename(&new_name, &new_name),
+        Err(Error::NoEntry)
+    ));
+    assert!(matches!(
+        cred.rename(&new_name, ""),
+        Err(Error::Invalid(_, _))
+    ));
+    entry.set_secret(b"kept").unwrap();
+    let reference = cred.persistent_ref().unwrap();
+    let renamed = cred.rename(&new_name, &new_name).unwrap();
+    assert_eq!(renamed.account, new_name);
+    assert!(matches!(entry.get_secret(), Err(Error::NoEntry)));
+    assert_eq!(renamed.persistent_ref().unwrap(), reference);
+    let moved = store.build(&new_name, &new_name, None).unwrap();
+    assert_eq!(moved.get_secret().unwrap(), b"kept");
+    entry.set_secret(b"other").unwrap();
+    let err = cred.rename(&new_name, &new_name).unwrap_err();
+    assert_eq!(error_kind(&err), Some(ErrorKind::DuplicateItem));
+    entry.delete_credential().unwrap();
+    moved.delete_credential().unwrap();
+}
+
+#[distributed_slice(TESTS)]
+fn test_lock_credential() {
+    let store = Store::new().unwrap();
+    let name = generate_random_string();
+    let ttl = Duration::from_secs(60);
+    let lease = store.lock_credential(&name, &name, ttl).unwrap().unwrap();
+    assert!(store.lock_credential(&name, &name, ttl).unwrap().is_none());
+    let spec = HashMap::from([("account", name.as_str())]);
+    assert!(store.search(&spec).unwrap().is_empty());
+    lease.release().unwrap();
+    let again = store.lock_credential(&name, &name, ttl).unwrap();
+    assert!(again.is_some());
+    drop(again);
+    assert!(store.lock_credential(&name, &name, ttl).unwrap().is_some());
+}
+
+#[distributed_slice(TESTS)]
+fn test_debug_redaction() {
+    let name = generate_random_string();
+    let redacted = entry_new(&name, &name);
+    let output = format!("{redacted:?}");
+    assert!(
+        !output.contains(&name),
+        "Specifiers in debug output: {output}"
+    );
+    let cred: &Cred = redacted.as_any().downcast_ref().unwrap();
+    assert!(format!("{:?}", cred.reveal_specifiers_only()).contains(&name));
+    let config = HashMap::from([("debug-redaction", "reveal-specifiers")]);
+    let store: Arc<CredentialStore> = Store::new_with_configuration(&config).unwrap();
+    let revealed = store.build(&name, &name, None).unwrap();
+    assert!(format!("{revealed:?}").contains(&name));
+}
+
+#[distributed_slice(TESTS)]
+fn test_unbounded_search() {
+    let config = HashMap::from([("allow-unbounded-search", "false")]);
+    let store: Arc<CredentialStore> = Store::new_with_configuration(&config).unwrap();
+    assert!(matches!(
+        store.search(&HashMap::new()),
+        Err(Error::Invalid(_, _))
+    ));
+    assert!(matches!(
+        store.search(&HashMap::from([("show-authentication-ui", "false")])),
+        Err(Error::Invalid(_, _))
+    ));
+    assert!(store.search(&HashMap::from([("all", "true")])).is_ok());
+    let name = generate_random_string();
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert!(store.search(&spec).unwrap().is_empty());
+    let ext = apple_store(&store).unwrap();
+    assert!(matches!(
+        ext.wipe(&HashMap::new()),
+        Err(Error::Invalid(_, _))
+    ));
+}
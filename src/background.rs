@@ -0,0 +1,83 @@
+/*!
+
+# Background operations
+
+A credential operation can block for as long as the user takes to answer an
+authentication prompt, and on an app's main thread that freezes its UI (and,
+on macOS, can keep the prompt itself from appearing). [submit] runs any
+operation on a background thread instead, and hands back a channel that
+receives its result; [submit_then] calls a callback with the result.
+
+The operations all run, one at a time and in the order they were submitted,
+on a single thread this module starts when it's first used. So prompts don't
+pile up on top of each other, and an operation submitted after a write sees
+it. An operation that panics fails with a `PlatformFailure` error, and the
+thread goes on to the next one.
+
+The callback runs on the background thread, not the thread that submitted
+the operation: in a Tauri command that's fine, but an AppKit or UIKit app has
+to dispatch back to the main queue before touching its views.
+
+ */
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+
+use keyring_core::{Error as ErrorCode, Result};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The queue of the background thread, once it's started.
+static QUEUE: Mutex<Option<Sender<Job>>> = Mutex::new(None);
+
+/// Run an operation on the background thread.
+///
+/// The returned channel receives the operation's result when it finishes. An
+/// app with an event loop can poll it with `try_recv` rather than wait.
+pub fn submit<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Receiver<Result<T>> {
+    let (sender, receiver) = mpsc::channel();
+    submit_then(f, move |result| {
+        // the caller may have dropped the receiver, in which case nobody wants the result
+        let _ = sender.send(result);
+    });
+    receiver
+}
+
+/// Run an operation on the background thread, and call the callback with its
+/// result (on that thread) when it finishes.
+pub fn submit_then<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+    callback: impl FnOnce(Result<T>) + Send + 'static,
+) {
+    let mut queue = QUEUE.lock().unwrap_or_else(PoisonError::into_inner);
+    if queue.is_none() {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let spawned = thread::Builder::new()
+            .name("keyring-background".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    // a panicking callback mustn't take the queue down with it
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                }
+            });
+        if let Err(err) = spawned {
+            drop(queue);
+            return callback(Err(ErrorCode::PlatformFailure(Box::new(err))));
+        }
+        *queue = Some(sender);
+    }
+    let job: Job = Box::new(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+            Err(ErrorCode::PlatformFailure(
+                "the background operation panicked".into(),
+            ))
+        });
+        callback(result);
+    });
+    // the thread never stops listening, so this can't fail
+    let _ = queue.as_ref().unwrap().send(job);
+}
@@ -0,0 +1,113 @@
+/*!
+
+# Chunked secrets
+
+Keychain items are meant for small secrets. A secret too large for one item
+is split into chunks of [CHUNK_SIZE] bytes, each kept in an item of its own,
+and the entry's own item holds an _index_: a magic string followed by the
+number of chunks (big-endian). A secret that fits in one item is kept in its
+index, after a count of zero.
+
+Chunk items have the entry's service behind a prefix, so searches can leave
+them out, and the entry's account followed by the chunk's number. The
+protected store keeps the ciphertexts of its envelope entries this way, and
+the keychain store its large secrets. Each store reads, writes, and deletes
+the items its own way; this module has what they share: the index format, the
+chunk items' specifiers, and splitting and joining secrets.
+
+ */
+
+use keyring_core::{Error as ErrorCode, Result};
+
+/// The largest piece of a secret kept in one chunk item.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How one store lays out its chunked secrets.
+pub(crate) struct Layout {
+    /// The start of every index.
+    pub magic: &'static [u8],
+    /// The prefix on the service of chunk items.
+    pub tag: &'static str,
+}
+
+impl Layout {
+    /// The index for a secret, and the chunks it lists (none, if the secret
+    /// is kept in the index).
+    pub fn split<'a>(&self, secret: &'a [u8]) -> (Vec<u8>, Vec<&'a [u8]>) {
+        let chunks: Vec<&[u8]> = if secret.len() > CHUNK_SIZE {
+            secret.chunks(CHUNK_SIZE).collect()
+        } else {
+            Vec::new()
+        };
+        let mut index = self.magic.to_vec();
+        index.extend_from_slice(&(chunks.len() as u32).to_be_bytes());
+        if chunks.is_empty() {
+            index.extend_from_slice(secret);
+        }
+        (index, chunks)
+    }
+
+    /// The number of chunks an index lists, or `None` if it isn't an index.
+    pub fn chunk_count(&self, index: &[u8]) -> Option<u32> {
+        index
+            .strip_prefix(self.magic)
+            .and_then(|rest| rest.get(..4))
+            .map(|count| u32::from_be_bytes(count.try_into().unwrap()))
+    }
+
+    /// The secret an index stands for, reading the chunks it lists with `read`.
+    ///
+    /// A missing chunk (or an index that isn't one) is a `BadDataFormat` error
+    /// holding the index.
+    pub fn join(
+        &self,
+        index: Vec<u8>,
+        mut read: impl FnMut(u32) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let Some(count) = self.chunk_count(&index) else {
+            return Err(ErrorCode::BadDataFormat(
+                index,
+                "the item doesn't hold a chunk index".into(),
+            ));
+        };
+        if count == 0 {
+            return Ok(index[self.magic.len() + 4..].to_vec());
+        }
+        let mut secret = Vec::new();
+        for number in 0..count {
+            match read(number) {
+                Ok(chunk) => secret.extend_from_slice(&chunk),
+                Err(ErrorCode::NoEntry) => {
+                    return Err(ErrorCode::BadDataFormat(
+                        index,
+                        format!("chunk {number} of {count} is missing").into(),
+                    ));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(secret)
+    }
+
+    /// The service and account of the chunk item with the given number, for
+    /// an entry stored under the given service and account.
+    pub fn chunk_specifiers(&self, service: &str, account: &str, number: u32) -> (String, String) {
+        (
+            format!("{}{service}", self.tag),
+            format!("{account}:{number}"),
+        )
+    }
+}
+
+/// Delete chunk items, with `delete`, from the given number on, stopping at
+/// the first that's missing.
+pub(crate) fn delete_from(first: u32, mut delete: impl FnMut(u32) -> Result<()>) -> Result<()> {
+    for number in first.. {
+        match delete(number) {
+            Ok(()) => {}
+            Err(ErrorCode::NoEntry) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
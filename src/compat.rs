@@ -0,0 +1,119 @@
+/*!
+
+# keyring 3.x compatibility
+
+Code written against keyring 3.x builds entries with `Entry::new` and
+`Entry::new_with_target`. This module provides an [Entry] with those
+constructors, so such code can move to this crate one call site at a time:
+change its `use keyring::Entry` to `use apple_native_keyring_store::compat::Entry`
+and it will keep making the same credentials it made before.
+
+The entries behave as keyring 3.x entries did on macOS:
+
+- They are generic passwords in the legacy keychain (see the
+  [keychain](crate::keychain) module), whatever the default store is.
+- The target, if given, names the keychain: `User`, `System`, `Common`, or
+  `Dynamic`, in any case. Without one, the User (login) keychain is used.
+- Neither the service nor the user may be empty.
+
+The errors are keyring-core's, which have the same variants as keyring
+3.x's. Once a call site no longer needs the old constructors, it can use the
+wrapped keyring-core entry directly; see [into_inner](Entry::into_inner).
+
+ */
+
+use std::collections::HashMap;
+
+use keyring_core::{Error as ErrorCode, Result};
+
+use crate::keychain::{Cred, MacKeychainDomain};
+
+/// An entry built the way keyring 3.x built them. See the [module docs](self).
+#[derive(Debug)]
+pub struct Entry {
+    inner: keyring_core::Entry,
+}
+
+impl Entry {
+    /// An entry for the given service and user in the User keychain.
+    pub fn new(service: &str, user: &str) -> Result<Entry> {
+        Self::build(MacKeychainDomain::User, service, user)
+    }
+
+    /// An entry for the given service and user in the keychain named by the target.
+    ///
+    /// An unknown target is an `Invalid` error for the `target` parameter, as it
+    /// was in keyring 3.x.
+    pub fn new_with_target(target: &str, service: &str, user: &str) -> Result<Entry> {
+        let domain = target.parse().map_err(|err| match err {
+            ErrorCode::Invalid(_, reason) => ErrorCode::Invalid("target".to_string(), reason),
+            other => other,
+        })?;
+        Self::build(domain, service, user)
+    }
+
+    fn build(domain: MacKeychainDomain, service: &str, user: &str) -> Result<Entry> {
+        Ok(Entry {
+            inner: Cred::build(domain, service, user)?,
+        })
+    }
+
+    /// Set the password of the entry's credential.
+    pub fn set_password(&self, password: &str) -> Result<()> {
+        self.inner.set_password(password)
+    }
+
+    /// Set the secret of the entry's credential.
+    pub fn set_secret(&self, secret: &[u8]) -> Result<()> {
+        self.inner.set_secret(secret)
+    }
+
+    /// The password of the entry's credential.
+    pub fn get_password(&self) -> Result<String> {
+        self.inner.get_password()
+    }
+
+    /// The secret of the entry's credential.
+    pub fn get_secret(&self) -> Result<Vec<u8>> {
+        self.inner.get_secret()
+    }
+
+    /// The attributes of the entry's credential.
+    pub fn get_attributes(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_attributes()
+    }
+
+    /// Update the attributes of the entry's credential.
+    pub fn update_attributes(&self, attributes: &HashMap<&str, &str>) -> Result<()> {
+        self.inner.update_attributes(attributes)
+    }
+
+    /// Delete the entry's credential.
+    pub fn delete_credential(&self) -> Result<()> {
+        self.inner.delete_credential()
+    }
+
+    /// The entry's credential, which can be downcast to a [Cred].
+    ///
+    /// In keyring 3.x this was a `MacCredential`; [Cred] has the same fields
+    /// of interest (the domain, service, and account).
+    pub fn get_credential(&self) -> &dyn std::any::Any {
+        self.inner.as_any()
+    }
+
+    /// The wrapped keyring-core entry.
+    pub fn inner(&self) -> &keyring_core::Entry {
+        &self.inner
+    }
+
+    /// Unwrap the keyring-core entry, for code that's done migrating.
+    pub fn into_inner(self) -> keyring_core::Entry {
+        self.inner
+    }
+}
+
+impl From<Entry> for keyring_core::Entry {
+    fn from(entry: Entry) -> Self {
+        entry.into_inner()
+    }
+}
@@ -0,0 +1,486 @@
+/*!
+
+# Security framework status codes
+
+Both stores map the status codes they expect from the Security framework
+to specific keyring errors, and wrap everything else in a
+[PlatformFailure](keyring_core::Error::PlatformFailure). This module lets
+an application explain those other codes: [decode_osstatus] classifies any
+`OSStatus` value, and [ErrorKind::description] gives the name of the Apple
+constant for it along with a short explanation.
+
+If you have a keyring error rather than a raw status code,
+[error_kind] will dig the status code out of it for you.
+
+For the common conditions an app needs to explain to its users,
+[ErrorKind::user_message] provides localized messages.
+
+A user who cancels an authentication prompt hasn't hit an error, and an app
+usually just goes back to where it was. So both stores report a canceled
+prompt as a [NoStorageAccess](keyring_core::Error::NoStorageAccess) error
+wrapping an [AuthenticationCanceled], which [canceled] finds. Similarly, a
+protected item that can't be read because biometry is locked out (after too
+many failed attempts) is a `NoStorageAccess` error wrapping a
+[BiometryLockedOut], which [biometry_locked_out] recognizes, so the app can
+ask the user to enter their passcode. And a protected item whose access
+policy needs a device passcode, on a device without one, can't be created:
+that's a `NoStorageAccess` error wrapping a [PasscodeNotSet], which
+[passcode_not_set] recognizes.
+
+An item protected until the device is unlocked can't be read or written while
+it's locked. The protected store reports that as a `NoStorageAccess` error
+wrapping a [KeychainLocked], which [keychain_locked] recognizes; see there for
+how to retry.
+
+A protected store configured with a `prompt-interval` won't prompt twice for
+the same item within that interval: the read that would have prompted fails
+with a `NoStorageAccess` error wrapping a [PromptRateLimited], which
+[prompt_rate_limited] recognizes.
+
+Reading such an item on the main thread blocks it until the user answers the
+prompt, and an app that drives the authentication UI from its main run loop
+can deadlock. A protected store configured with `fail-on-main-thread` refuses
+those reads with a `NoStorageAccess` error wrapping a [MainThreadPrompt],
+which [main_thread_prompt] recognizes.
+
+ */
+
+use std::time::Duration;
+
+use keyring_core::Error as ErrorCode;
+use security_framework::base::Error;
+
+macro_rules! error_kinds {
+    ($($kind:ident = $code:literal, $name:literal, $text:literal;)*) => {
+        /// The kinds of failure reported by Security framework status codes.
+        ///
+        /// Each kind except [Other](ErrorKind::Other) corresponds to one of
+        /// the `errSec*` constants in Apple's `SecBase.h`.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum ErrorKind {
+            $(
+                #[doc = concat!("`", $name, "` (", stringify!($code), "): ", $text)]
+                $kind,
+            )*
+            /// A status code this crate doesn't know about.
+            Other(i32),
+        }
+
+        impl ErrorKind {
+            /// The status code for this kind.
+            pub fn code(&self) -> i32 {
+                match self {
+                    $(ErrorKind::$kind => $code,)*
+                    ErrorKind::Other(code) => *code,
+                }
+            }
+
+            /// The name of the Apple constant for this kind, if it has one.
+            pub fn name(&self) -> Option<&'static str> {
+                match self {
+                    $(ErrorKind::$kind => Some($name),)*
+                    ErrorKind::Other(_) => None,
+                }
+            }
+
+            fn text(&self) -> &'static str {
+                match self {
+                    $(ErrorKind::$kind => $text,)*
+                    ErrorKind::Other(_) => "Unknown status code.",
+                }
+            }
+        }
+
+        /// Classify a Security framework status code.
+        pub fn decode_osstatus(code: i32) -> ErrorKind {
+            match code {
+                $($code => ErrorKind::$kind,)*
+                other => ErrorKind::Other(other),
+            }
+        }
+    };
+}
+
+error_kinds! {
+    Success = 0, "errSecSuccess", "No error.";
+    Unimplemented = -4, "errSecUnimplemented", "Function or operation not implemented.";
+    DiskFull = -34, "errSecDiskFull", "The disk is full.";
+    Io = -36, "errSecIO", "I/O error.";
+    OpenForWrite = -49, "errSecOpWr", "The file is already open with write permission.";
+    Param = -50, "errSecParam", "One or more parameters passed to a function were not valid.";
+    WritePermission = -61, "errSecWrPerm", "Write permissions error.";
+    Allocate = -108, "errSecAllocate", "Failed to allocate memory.";
+    UserCanceled = -128, "errSecUserCanceled", "User canceled the operation.";
+    BadRequest = -909, "errSecBadReq", "Bad parameter or invalid state for operation.";
+    InternalComponent = -2070, "errSecInternalComponent", "An internal component failed.";
+    CoreFoundationUnknown = -4960, "errSecCoreFoundationUnknown", "An unknown Core Foundation error occurred.";
+    NotAvailable = -25291, "errSecNotAvailable", "No keychain is available.";
+    ReadOnly = -25292, "errSecReadOnly", "Read-only error.";
+    AuthFailed = -25293, "errSecAuthFailed", "Authorization and/or authentication failed.";
+    NoSuchKeychain = -25294, "errSecNoSuchKeychain", "The keychain does not exist.";
+    InvalidKeychain = -25295, "errSecInvalidKeychain", "The keychain is not valid.";
+    DuplicateKeychain = -25296, "errSecDuplicateKeychain", "A keychain with the same name already exists.";
+    DuplicateItem = -25299, "errSecDuplicateItem", "The item already exists.";
+    ItemNotFound = -25300, "errSecItemNotFound", "The item cannot be found.";
+    BufferTooSmall = -25301, "errSecBufferTooSmall", "The buffer is too small.";
+    DataTooLarge = -25302, "errSecDataTooLarge", "The data is too large for the supplied buffer.";
+    NoSuchAttribute = -25303, "errSecNoSuchAttr", "The attribute does not exist.";
+    InvalidItemRef = -25304, "errSecInvalidItemRef", "The item reference is invalid.";
+    InvalidSearchRef = -25305, "errSecInvalidSearchRef", "The search reference is invalid.";
+    NoSuchClass = -25306, "errSecNoSuchClass", "The keychain item class does not exist.";
+    NoDefaultKeychain = -25307, "errSecNoDefaultKeychain", "A default keychain does not exist.";
+    InteractionNotAllowed = -25308, "errSecInteractionNotAllowed", "User interaction is not allowed.";
+    ReadOnlyAttribute = -25309, "errSecReadOnlyAttr", "The attribute is read-only.";
+    InteractionRequired = -25315, "errSecInteractionRequired", "User interaction is required.";
+    DataNotAvailable = -25316, "errSecDataNotAvailable", "The data is not available.";
+    DataNotModifiable = -25317, "errSecDataNotModifiable", "The data cannot be modified.";
+    InvalidPrefsDomain = -25319, "errSecInvalidPrefsDomain", "The preference domain is not valid.";
+    InDarkWake = -25320, "errSecInDarkWake", "The operation is not allowed while the system is in dark wake.";
+    NoAccessForItem = -25243, "errSecNoAccessForItem", "The item has no access control.";
+    Decode = -26275, "errSecDecode", "Unable to decode the provided data.";
+    MissingEntitlement = -34018, "errSecMissingEntitlement", "A required entitlement is missing.";
+}
+
+impl ErrorKind {
+    /// A description of this kind, starting with the Apple constant name.
+    ///
+    /// For example, `errSecItemNotFound (-25300): The item cannot be found.`
+    pub fn description(&self) -> String {
+        let name = self.name().unwrap_or("OSStatus");
+        format!("{name} ({}): {}", self.code(), self.text())
+    }
+}
+
+impl ErrorKind {
+    /// A message about this kind that's suitable for showing to users.
+    ///
+    /// Messages are only provided for the common conditions: the device needs
+    /// to be unlocked, the user canceled, the app is missing an entitlement, or
+    /// the item wasn't found. Other kinds return `None`.
+    ///
+    /// The locale is a language tag such as `fr` or `fr-CA` (or `fr_CA`); only
+    /// its language is used. English, French, German, Spanish, and Japanese are
+    /// provided, and any other language gets the English message.
+    pub fn user_message(&self, locale: &str) -> Option<&'static str> {
+        let language = locale
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let messages = match self {
+            ErrorKind::InteractionNotAllowed => [
+                ("en", "Unlock your device to access this password."),
+                (
+                    "fr",
+                    "Déverrouillez votre appareil pour accéder à ce mot de passe.",
+                ),
+                (
+                    "de",
+                    "Entsperren Sie Ihr Gerät, um auf dieses Passwort zuzugreifen.",
+                ),
+                (
+                    "es",
+                    "Desbloquea tu dispositivo para acceder a esta contraseña.",
+                ),
+                (
+                    "ja",
+                    "このパスワードにアクセスするには、デバイスのロックを解除してください。",
+                ),
+            ],
+            ErrorKind::UserCanceled => [
+                ("en", "The request was canceled."),
+                ("fr", "La demande a été annulée."),
+                ("de", "Die Anfrage wurde abgebrochen."),
+                ("es", "Se canceló la solicitud."),
+                ("ja", "リクエストはキャンセルされました。"),
+            ],
+            ErrorKind::MissingEntitlement => [
+                (
+                    "en",
+                    "This app isn't set up to store passwords. Please contact its developer.",
+                ),
+                (
+                    "fr",
+                    "Cette app n'est pas configurée pour enregistrer des mots de passe. Veuillez contacter son développeur.",
+                ),
+                (
+                    "de",
+                    "Diese App ist nicht für das Speichern von Passwörtern eingerichtet. Bitte wenden Sie sich an den Entwickler.",
+                ),
+                (
+                    "es",
+                    "Esta app no está configurada para guardar contraseñas. Ponte en contacto con su desarrollador.",
+                ),
+                (
+                    "ja",
+                    "このアプリはパスワードを保存するように設定されていません。開発元にお問い合わせください。",
+                ),
+            ],
+            ErrorKind::ItemNotFound => [
+                ("en", "No saved password was found."),
+                ("fr", "Aucun mot de passe enregistré n'a été trouvé."),
+                ("de", "Es wurde kein gespeichertes Passwort gefunden."),
+                ("es", "No se encontró ninguna contraseña guardada."),
+                ("ja", "保存されたパスワードが見つかりませんでした。"),
+            ],
+            _ => return None,
+        };
+        messages
+            .iter()
+            .find(|(lang, _)| *lang == language)
+            .or(messages.first())
+            .map(|(_, message)| *message)
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.description().fmt(f)
+    }
+}
+
+/// Who canceled an authentication prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CanceledBy {
+    /// The user dismissed the prompt.
+    User,
+    /// The system dismissed it, say because another app came to the front.
+    System,
+    /// The app invalidated its authentication context.
+    App,
+}
+
+/// The platform error inside a keyring error for a canceled authentication prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthenticationCanceled {
+    pub by: CanceledBy,
+}
+
+impl AuthenticationCanceled {
+    /// Classify an `LAError` code (from a LocalAuthentication policy evaluation
+    /// of the app's own), if it's one of the cancel codes.
+    pub fn of_la_error(code: isize) -> Option<Self> {
+        let by = match code {
+            -2 => CanceledBy::User,   // LAErrorUserCancel
+            -4 => CanceledBy::System, // LAErrorSystemCancel
+            -9 => CanceledBy::App,    // LAErrorAppCancel
+            _ => return None,
+        };
+        Some(AuthenticationCanceled { by })
+    }
+
+    /// The keyring error for a canceled prompt.
+    pub(crate) fn error(by: CanceledBy) -> ErrorCode {
+        ErrorCode::NoStorageAccess(Box::new(AuthenticationCanceled { by }))
+    }
+}
+
+impl std::fmt::Display for AuthenticationCanceled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.by {
+            CanceledBy::User => write!(f, "The user canceled authentication"),
+            CanceledBy::System => write!(f, "The system canceled authentication"),
+            CanceledBy::App => write!(f, "The app canceled authentication"),
+        }
+    }
+}
+
+impl std::error::Error for AuthenticationCanceled {}
+
+/// The canceled authentication prompt behind a keyring error, if that's
+/// what it is.
+pub fn canceled(err: &ErrorCode) -> Option<AuthenticationCanceled> {
+    match err {
+        ErrorCode::NoStorageAccess(inner) => {
+            inner.downcast_ref::<AuthenticationCanceled>().copied()
+        }
+        _ => None,
+    }
+}
+
+/// The platform error inside a keyring error for an authentication that
+/// failed because biometry is locked out.
+///
+/// Biometry stays locked out until the user enters the device passcode
+/// (by unlocking the device, say).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BiometryLockedOut;
+
+impl std::fmt::Display for BiometryLockedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Biometry is locked out after too many failed attempts; enter the passcode to unlock it"
+        )
+    }
+}
+
+impl std::error::Error for BiometryLockedOut {}
+
+/// Whether a keyring error is for an authentication that failed because
+/// biometry is locked out.
+pub fn biometry_locked_out(err: &ErrorCode) -> bool {
+    match err {
+        ErrorCode::NoStorageAccess(inner) => inner.is::<BiometryLockedOut>(),
+        _ => false,
+    }
+}
+
+/// The platform error inside a keyring error for an item that couldn't be
+/// created because its access policy needs a device passcode and the device
+/// doesn't have one.
+///
+/// An onboarding flow can ask the user to set a passcode, or fall back to an
+/// access policy (such as the default) that doesn't need one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasscodeNotSet;
+
+impl std::fmt::Display for PasscodeNotSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The item's access policy needs a device passcode, and none is set; \
+             set one in Settings, or use a policy that doesn't need one"
+        )
+    }
+}
+
+impl std::error::Error for PasscodeNotSet {}
+
+/// Whether a keyring error is for an item that couldn't be created because
+/// the device has no passcode.
+pub fn passcode_not_set(err: &ErrorCode) -> bool {
+    match err {
+        ErrorCode::NoStorageAccess(inner) => inner.is::<PasscodeNotSet>(),
+        _ => false,
+    }
+}
+
+/// The platform error inside a keyring error for an operation that failed
+/// because the device is locked (`errSecInteractionNotAllowed` while
+/// protected data is unavailable).
+///
+/// Nothing was read or changed, and the same operation will work once the
+/// device is unlocked, so a daemon or background fetch should queue it for
+/// then rather than retry it in a loop or report a failure. The protected
+/// store's `retry_when_available` does the queueing. Trying again before the
+/// device is unlocked fails the same way, and doesn't prompt the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeychainLocked;
+
+impl std::fmt::Display for KeychainLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The device is locked; the operation can be retried once it's unlocked"
+        )
+    }
+}
+
+impl std::error::Error for KeychainLocked {}
+
+/// Whether a keyring error is for an operation that failed because the
+/// device is locked.
+pub fn keychain_locked(err: &ErrorCode) -> bool {
+    match err {
+        ErrorCode::NoStorageAccess(inner) => inner.is::<KeychainLocked>(),
+        _ => false,
+    }
+}
+
+/// The platform error inside a keyring error for a read that wasn't allowed
+/// to prompt, because the store's `prompt-interval` hadn't passed since it
+/// last prompted for the same item.
+///
+/// Nothing was read and the user wasn't asked anything. The same read can be
+/// retried once `retry_after` has passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptRateLimited {
+    /// How long until the item can be read again.
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for PromptRateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The item was read too recently to prompt again; retry in {:.1} seconds",
+            self.retry_after.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for PromptRateLimited {}
+
+/// How long to wait before retrying, if a keyring error is for a read that
+/// wasn't allowed to prompt because of the store's `prompt-interval`.
+pub fn prompt_rate_limited(err: &ErrorCode) -> Option<Duration> {
+    match err {
+        ErrorCode::NoStorageAccess(inner) => inner
+            .downcast_ref::<PromptRateLimited>()
+            .map(|limited| limited.retry_after),
+        _ => None,
+    }
+}
+
+/// The platform error inside a keyring error for a read of an item that
+/// requires user presence, refused because it was made on the main thread.
+///
+/// Nothing was read and the user wasn't asked anything. The read works on
+/// any other thread: the [background](crate::background) module runs
+/// operations on one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MainThreadPrompt;
+
+impl std::fmt::Display for MainThreadPrompt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Reading this item may prompt the user, which can deadlock on the main thread; \
+             read it on a background thread"
+        )
+    }
+}
+
+impl std::error::Error for MainThreadPrompt {}
+
+/// Whether a keyring error is for a read refused because it would have
+/// prompted on the main thread.
+pub fn main_thread_prompt(err: &ErrorCode) -> bool {
+    match err {
+        ErrorCode::NoStorageAccess(inner) => inner.is::<MainThreadPrompt>(),
+        _ => false,
+    }
+}
+
+/// The kind of Security framework failure behind a keyring error, if any.
+///
+/// This looks through the errors that wrap a platform error
+/// ([PlatformFailure](keyring_core::Error::PlatformFailure) and
+/// [NoStorageAccess](keyring_core::Error::NoStorageAccess)). A
+/// [NoEntry](keyring_core::Error::NoEntry) error is reported as
+/// [ItemNotFound](ErrorKind::ItemNotFound), since that's the only
+/// status code the stores turn into it, and a canceled authentication
+/// prompt is reported as [UserCanceled](ErrorKind::UserCanceled) and a
+/// biometry lockout as [AuthFailed](ErrorKind::AuthFailed), and a locked
+/// device as [InteractionNotAllowed](ErrorKind::InteractionNotAllowed).
+pub fn error_kind(err: &ErrorCode) -> Option<ErrorKind> {
+    if keychain_locked(err) {
+        return Some(ErrorKind::InteractionNotAllowed);
+    }
+    if canceled(err).is_some() {
+        return Some(ErrorKind::UserCanceled);
+    }
+    if biometry_locked_out(err) {
+        return Some(ErrorKind::AuthFailed);
+    }
+    match err {
+        ErrorCode::PlatformFailure(inner) | ErrorCode::NoStorageAccess(inner) => inner
+            .downcast_ref::<Error>()
+            .map(|err| decode_osstatus(err.code())),
+        ErrorCode::NoEntry => Some(ErrorKind::ItemNotFound),
+        _ => None,
+    }
+}
@@ -0,0 +1,472 @@
+/*!
+
+# Apple-specific store operations
+
+Both stores in this crate offer operations beyond the keyring-core API. The
+[AppleStoreExt] trait collects the ones they have in common, so code that
+holds a `CredentialStore` can get at them with a single call to [apple_store]
+rather than downcasting to each concrete store type in turn.
+
+Each store also describes itself with a [ProviderInfo], so code that has to
+know which backend it's talking to (and which version of this crate provides
+it) doesn't have to parse the store's vendor string. The vendor string is still
+what keyring-core reports.
+
+Before its first credential access, an app can ask a store whether it
+[is available](AppleStoreExt::is_available), and get back an [Availability]
+saying why not (a missing entitlement, a locked keychain, an OS that doesn't
+implement what the store needs) so it can degrade gracefully.
+
+There is no export operation, since neither store can export its items
+(see the keychain module docs).
+
+ */
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+use keyring_core::{CredentialStore, Result};
+
+use crate::error::{ErrorKind, error_kind};
+use crate::query::Attr;
+
+/// The keys a store understands, as reported by [capabilities](AppleStoreExt::capabilities).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The keys allowed in the store's configuration.
+    pub configuration_keys: Vec<&'static str>,
+    /// The keys allowed in entry modifiers.
+    pub modifier_keys: Vec<&'static str>,
+    /// The keys allowed in search specs.
+    pub search_keys: Vec<&'static str>,
+}
+
+impl Capabilities {
+    /// Capabilities from the key lists a store passes to `parse_attributes`,
+    /// with their `*` and `+` value markers removed.
+    pub(crate) fn from_keys(
+        configuration: &[&'static str],
+        modifiers: &[&'static str],
+        search: &[&'static str],
+    ) -> Self {
+        let strip = |keys: &[&'static str]| {
+            keys.iter()
+                .map(|k| k.trim_start_matches(['*', '+']))
+                .collect()
+        };
+        Capabilities {
+            configuration_keys: strip(configuration),
+            modifier_keys: strip(modifiers),
+            search_keys: strip(search),
+        }
+    }
+}
+
+/// Which of this crate's stores a [ProviderInfo] describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Backend {
+    /// The legacy keychain store, [keychain::Store](crate::keychain).
+    Keychain,
+    /// The data protection keychain store, [protected::Store](crate::protected).
+    Protected,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Keychain => f.write_str("keychain"),
+            Backend::Protected => f.write_str("protected"),
+        }
+    }
+}
+
+/// A structured description of a store, as reported by
+/// [provider_info](AppleStoreExt::provider_info).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderInfo {
+    /// The name of the crate providing the store.
+    pub name: &'static str,
+    /// Which of the crate's stores this is.
+    pub backend: Backend,
+    /// The version of the crate providing the store.
+    pub crate_version: &'static str,
+    /// The keys the store understands.
+    pub capabilities: Capabilities,
+}
+
+impl ProviderInfo {
+    pub(crate) fn new(backend: Backend, capabilities: Capabilities) -> Self {
+        ProviderInfo {
+            name: env!("CARGO_PKG_NAME"),
+            backend,
+            crate_version: env!("CARGO_PKG_VERSION"),
+            capabilities,
+        }
+    }
+}
+
+/// Whether a store can be used right now, as reported by
+/// [is_available](AppleStoreExt::is_available).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Availability {
+    /// The store can read and write items.
+    Available,
+    /// The app is missing an entitlement the store needs, such as one for its
+    /// access group.
+    MissingEntitlement,
+    /// The keychain (or the device) is locked.
+    Locked,
+    /// The OS doesn't implement an operation the store needs
+    /// (`errSecUnimplemented`); the message is the error's.
+    UnsupportedOs(String),
+    /// The store can't be used for some other reason, described by the message.
+    Unavailable(String),
+}
+
+impl Availability {
+    /// Whether the store can be used.
+    pub fn is_available(&self) -> bool {
+        *self == Availability::Available
+    }
+
+    /// Classify an error from creating or using a store.
+    ///
+    /// A missing item doesn't make a store unavailable, so `NoEntry` is
+    /// [Available](Availability::Available). An operation the store doesn't
+    /// support (`NotSupportedByStore`, such as a configuration that needs an app
+    /// bundle in a process without one) makes it
+    /// [Unavailable](Availability::Unavailable), with the error's message.
+    pub fn of_error(err: &keyring_core::Error) -> Self {
+        if let keyring_core::Error::NotSupportedByStore(message) = err {
+            return Availability::Unavailable(message.clone());
+        }
+        match error_kind(err) {
+            Some(ErrorKind::ItemNotFound) => Availability::Available,
+            Some(ErrorKind::MissingEntitlement) => Availability::MissingEntitlement,
+            Some(ErrorKind::InteractionNotAllowed) => Availability::Locked,
+            Some(ErrorKind::Unimplemented) => Availability::UnsupportedOs(err.to_string()),
+            _ => Availability::Unavailable(err.to_string()),
+        }
+    }
+}
+
+/// The Apple-specific operations shared by both stores.
+///
+/// The search specs taken here are the same as those taken by each store's
+/// `search`, so the keys allowed differ between the stores.
+pub trait AppleStoreExt {
+    /// The number of items matching a search spec.
+    fn count(&self, spec: &HashMap<&str, &str>) -> Result<usize>;
+
+    /// Delete every item matching a search spec, returning how many were deleted.
+    ///
+    /// An empty spec matches (and deletes) every item the store can see, unless
+    /// the store was configured to reject unbounded searches.
+    fn wipe(&self, spec: &HashMap<&str, &str>) -> Result<usize>;
+
+    /// Change the attributes of every item matching a search spec.
+    ///
+    /// See each store's `update_attributes_matching` for details.
+    fn update_attributes_matching(
+        &self,
+        spec: &HashMap<&str, &str>,
+        attrs: &HashMap<&str, &str>,
+    ) -> Result<usize>;
+
+    /// Delete every item matching a search spec with a single query,
+    /// returning how many were deleted.
+    ///
+    /// See each store's `delete_matching` for the spec keys it takes.
+    fn delete_matching(&self, spec: &HashMap<&str, &str>) -> Result<usize>;
+
+    /// The configuration, modifier, and search keys the store understands.
+    fn capabilities(&self) -> Capabilities;
+
+    /// Which store this is, which crate version provides it, and its capabilities.
+    fn provider_info(&self) -> ProviderInfo;
+
+    /// A description of the store and the OS it's running on, for logs and bug reports.
+    fn diagnostics(&self) -> Vec<(String, String)>;
+
+    /// Probe whether the store can be used, without touching any credential.
+    ///
+    /// See each store's `is_available` for the probe it makes.
+    fn is_available(&self) -> Availability;
+}
+
+/// The Apple-specific operations of a store, if it's one of this crate's stores.
+pub fn apple_store(store: &Arc<CredentialStore>) -> Option<&dyn AppleStoreExt> {
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    if let Some(store) = store.as_any().downcast_ref::<crate::keychain::Store>() {
+        return Some(store);
+    }
+    #[cfg(feature = "protected")]
+    if let Some(store) = store.as_any().downcast_ref::<crate::protected::Store>() {
+        return Some(store);
+    }
+    None
+}
+
+/// Reject a search spec that doesn't restrict the search, unless the store
+/// allows that or the spec asks for every item with `all=true`.
+///
+/// The restricting keys are the ones that narrow a search; the others (such as
+/// `show-authentication-ui`) don't count.
+pub(crate) fn check_bounded(
+    spec: &HashMap<String, String>,
+    restricting: &[&str],
+    allow_unbounded: bool,
+) -> Result<()> {
+    if allow_unbounded
+        || spec.get("all").is_some_and(|s| s.eq("true"))
+        || restricting.iter().any(|key| spec.contains_key(*key))
+    {
+        return Ok(());
+    }
+    Err(keyring_core::Error::Invalid(
+        "spec".to_string(),
+        format!(
+            "must have one of {} (or all=true) in this store",
+            restricting.join(", ")
+        ),
+    ))
+}
+
+/// The page of a search's results asked for by its spec's `offset` and `limit`
+/// keys (both numbers of entries).
+///
+/// When either is given the results are first sorted by service and user (as
+/// given by `specifiers`), unless the spec asks for another order with `sort`,
+/// so that successive pages of an unchanging store neither repeat nor skip one.
+pub(crate) fn page<T>(
+    mut entries: Vec<T>,
+    spec: &HashMap<String, String>,
+    specifiers: impl Fn(&T) -> Option<(String, String)>,
+) -> Result<Vec<T>> {
+    let offset = count_key(spec, "offset")?;
+    let limit = count_key(spec, "limit")?;
+    if offset.is_none() && limit.is_none() {
+        return Ok(entries);
+    }
+    if !spec.contains_key("sort") {
+        entries.sort_by_cached_key(specifiers);
+    }
+    Ok(entries
+        .into_iter()
+        .skip(offset.unwrap_or(0))
+        .take(limit.unwrap_or(usize::MAX))
+        .collect())
+}
+
+/// Whether a parsed search spec sorts or pages its results, in which case
+/// none of them can be handed over until all of them have been found.
+pub(crate) fn needs_all_results(spec: &HashMap<String, String>) -> bool {
+    ["sort", "limit", "offset"]
+        .iter()
+        .any(|key| spec.contains_key(*key))
+}
+
+/// Sort search results as asked for by a parsed search spec's `sort` key:
+/// `modified-desc` (most recently modified first), `modified-asc`,
+/// `created-desc`, or `created-asc`. `date` gives a result's modification or
+/// creation date; results without one go last when newest come first.
+pub(crate) fn sort_by_date<T>(
+    results: &mut [T],
+    spec: &HashMap<String, String>,
+    date: impl Fn(&T, Attr) -> Option<SystemTime>,
+) -> Result<()> {
+    let Some(order) = spec.get("sort") else {
+        return Ok(());
+    };
+    let (attr, newest_first) = match order.as_str() {
+        "modified-desc" => (Attr::ModificationDate, true),
+        "modified-asc" => (Attr::ModificationDate, false),
+        "created-desc" => (Attr::CreationDate, true),
+        "created-asc" => (Attr::CreationDate, false),
+        other => {
+            return Err(keyring_core::Error::Invalid(
+                "sort".to_string(),
+                format!(
+                    "must be modified-desc, modified-asc, created-desc, or created-asc, not {other}"
+                ),
+            ));
+        }
+    };
+    if newest_first {
+        results.sort_by_cached_key(|result| Reverse(date(result, attr)));
+    } else {
+        results.sort_by_cached_key(|result| date(result, attr));
+    }
+    Ok(())
+}
+
+/// The type-ahead filters in a parsed search spec: `service-prefix`, a prefix
+/// of the user (whose key differs between the stores), and `contains`, which
+/// matches a substring of either the service or the user.
+///
+/// They're applied to the results of an attribute-only search, ignoring case
+/// if the spec's `case-sensitive` is false.
+pub(crate) struct TextFilters {
+    service_prefix: Option<String>,
+    user_prefix: Option<String>,
+    contains: Option<String>,
+    ignore_case: bool,
+}
+
+impl TextFilters {
+    /// The filters in the spec, or `None` if it has none of them.
+    pub fn of_spec(spec: &HashMap<String, String>, user_prefix_key: &str) -> Option<Self> {
+        let ignore_case = spec.get("case-sensitive").is_some_and(|s| s.eq("false"));
+        let fold = |key: &str| {
+            spec.get(key).map(|value| match ignore_case {
+                true => value.to_lowercase(),
+                false => value.clone(),
+            })
+        };
+        let filters = TextFilters {
+            service_prefix: fold("service-prefix"),
+            user_prefix: fold(user_prefix_key),
+            contains: fold("contains"),
+            ignore_case,
+        };
+        let any = filters.service_prefix.is_some()
+            || filters.user_prefix.is_some()
+            || filters.contains.is_some();
+        any.then_some(filters)
+    }
+
+    /// Whether an item with the given service and user passes the filters.
+    pub fn matches(&self, service: &str, user: &str) -> bool {
+        let (service, user) = match self.ignore_case {
+            true => (service.to_lowercase(), user.to_lowercase()),
+            false => (service.to_string(), user.to_string()),
+        };
+        self.service_prefix
+            .as_ref()
+            .is_none_or(|prefix| service.starts_with(prefix.as_str()))
+            && self
+                .user_prefix
+                .as_ref()
+                .is_none_or(|prefix| user.starts_with(prefix.as_str()))
+            && self
+                .contains
+                .as_ref()
+                .is_none_or(|text| service.contains(text.as_str()) || user.contains(text.as_str()))
+    }
+}
+
+/// The value of a search spec key that holds a number of entries.
+fn count_key(spec: &HashMap<String, String>, key: &str) -> Result<Option<usize>> {
+    spec.get(key)
+        .map(|value| {
+            value.parse::<usize>().map_err(|_| {
+                keyring_core::Error::Invalid(
+                    key.to_string(),
+                    format!("not a number of entries: {value}"),
+                )
+            })
+        })
+        .transpose()
+}
+
+/// Whether a service is at or below a path, treating services as `/`-separated paths.
+pub(crate) fn service_is_under(service: &str, path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    path.is_empty()
+        || service == path
+        || service
+            .strip_prefix(path)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// The diagnostics common to both stores.
+pub(crate) fn common_diagnostics(vendor: String, id: String) -> Vec<(String, String)> {
+    let os_version = match crate::os_version::current() {
+        Some(version) => crate::os_version::format_version(version),
+        None => "unknown".to_string(),
+    };
+    vec![
+        ("vendor".to_string(), vendor),
+        ("id".to_string(), id),
+        ("os".to_string(), std::env::consts::OS.to_string()),
+        ("os-version".to_string(), os_version),
+    ]
+}
+
+/// A store configuration as sorted key/value pairs.
+type SharedKey = Vec<(String, String)>;
+
+/// A store's function for finding the configuration a store created with the
+/// given one would report.
+type Normalize = fn(&HashMap<&str, &str>) -> Result<HashMap<String, String>>;
+
+fn shared_key<K: AsRef<str>, V: AsRef<str>>(config: impl Iterator<Item = (K, V)>) -> SharedKey {
+    let mut key: SharedKey = config
+        .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
+        .collect();
+    key.sort();
+    key
+}
+
+/// The process-wide shared stores of one type, as handed out by each store's
+/// `shared`.
+///
+/// Stores are keyed by their normalized configuration: the one a store created
+/// with it would report, with every default filled in, so `{}` and
+/// `{"cloud-sync": "false"}` share a store. Normalizing a configuration doesn't
+/// create a store. A store's configuration leaves out its `prefetch`, so
+/// configurations that differ only in what they prefetch share a store (and
+/// only the first one's prefetch is done).
+pub(crate) struct SharedStores<S> {
+    create: fn(&HashMap<&str, &str>) -> Result<Arc<S>>,
+    normalize: Normalize,
+    stores: LazyLock<Mutex<HashMap<SharedKey, Arc<S>>>>,
+}
+
+impl<S> SharedStores<S> {
+    pub(crate) const fn new(
+        create: fn(&HashMap<&str, &str>) -> Result<Arc<S>>,
+        normalize: Normalize,
+    ) -> Self {
+        SharedStores {
+            create,
+            normalize,
+            stores: LazyLock::new(Default::default),
+        }
+    }
+
+    /// The shared store for a configuration, created if there isn't one.
+    ///
+    /// The store is created without holding the registry's lock, so creating
+    /// one doesn't hold up callers asking for others. If two callers create a
+    /// store for the same configuration at once, the first one shared is the
+    /// one both get.
+    pub(crate) fn get(&self, config: &HashMap<&str, &str>) -> Result<Arc<S>> {
+        let key = shared_key((self.normalize)(config)?.iter());
+        if let Some(store) = self.lock().get(&key) {
+            return Ok(store.clone());
+        }
+        let created = (self.create)(config)?;
+        Ok(self.lock().entry(key).or_insert(created).clone())
+    }
+
+    /// Stop sharing the store for a configuration, returning it if there was
+    /// one, or an error if the configuration isn't valid.
+    pub(crate) fn evict(&self, config: &HashMap<&str, &str>) -> Result<Option<Arc<S>>> {
+        let key = shared_key((self.normalize)(config)?.iter());
+        Ok(self.lock().remove(&key))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SharedKey, Arc<S>>> {
+        self.stores.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stop sharing all the stores.
+    pub(crate) fn clear(&self) {
+        self.lock().clear();
+    }
+}
@@ -9,6 +9,12 @@ configuration key specified when instantiating a [Store] determines
 which keychain this store uses for its credentials. By default,
 the 'User' (aka login) keychain is used.
 
+The user's _default_ keychain (the one Keychain Access adds new items to) is
+usually the login keychain, but the user can make another keychain the default.
+Specify `Default` as the `keychain` to use whichever keychain that is. A
+store's [resolved_keychain_path](Store::resolved_keychain_path) tells you which
+keychain file it ended up with.
+
 For a given service/user pair, this module creates/searches for a generic
 credential in the store's keychain whose _account_ attribute holds the user
 and whose _service_ attribute holds the service. Because generic credentials are
@@ -28,24 +34,148 @@ _account_ attribute (which is not displayed by _Keychain Access_).
 ## Attributes
 
 Credentials on macOS have some fixed _key/value_ attributes, but this
-module ignores all of them.
+module ignores almost all of them. The exceptions are
+[update_attributes_matching](Store::update_attributes_matching), which
+sets the label, comment, or description of many items at once, and the
+partition IDs described below.
+
+## Partition IDs
+
+Since macOS 10.12, each item has a list of _partition IDs_ naming the code
+that may read it without prompting: `teamid:` followed by a team ID for apps
+signed by that team, `apple:` and `apple-tool:` for Apple's software, and
+`unsigned:` for unsigned code. An entry's `get_attributes` reports them as
+a comma-separated `partition-ids` attribute (without reading the secret), and
+[add_partition_id](Cred::add_partition_id) adds one, so an app that moves to
+a new signing team can keep silent access to the items it created before.
+Changing the list takes the keychain's password, as it does with
+`security set-generic-password-partition-list`.
+
+An app whose signing certificate changed may also no longer be one of the
+trusted applications in its items' access lists.
+[normalize_acls](Store::normalize_acls) gives every matching item a fresh
+access that trusts just the app (and any other applications you name),
+reporting the items it couldn't change.
+
+## App gating
+
+An app that keeps its own lock screen can put credentials behind it
+rather than the system's authentication: register a callback with
+[set_app_gate] and build entries with the `app-gated` modifier. See
+[build](Store::build) for details.
+
+## Large secrets
+
+A secret larger than [CHUNK_SIZE] is split
+across linked items, the way the protected store keeps its `envelope`
+entries: each chunk goes in an item whose service is the credential's
+behind a `keyring-chunk:` prefix, and the credential's own item holds an
+index listing them. (A small secret that happens to start like an index is
+kept in an index too, so it reads back unchanged.) Reading a large secret
+takes a lookup per chunk; writing or deleting any secret takes one more
+lookup, to clean up chunks an earlier, larger secret left. Chunk items
+never show up in searches, and renaming or deleting a credential (by any of
+the store's bulk deletes, too) carries its chunks along. Tools outside this
+crate see the index as the item's data, so a large secret is only readable
+through this crate.
+
+## Export
+
+There is no way to export a credential from this store. The Security
+framework's export API (`SecItemExport`) only handles keys, certificates,
+and identities; it can't export password items, wrapped or otherwise. To
+move a credential to another machine, read its secret and store it there
+over a channel your application already trusts.
+
+## Smart cards
+
+Smart cards and other tokens (such as PIV tokens) don't appear as keychains
+this store can open. The OS makes their contents available through CryptoTokenKit,
+and those contents are keys, certificates, and identities rather than generic
+passwords, so a credential store over a token would have no entries. To read
+the certificates on a token, search for certificate items in the token access
+group (`kSecAttrAccessGroupToken`) with the security-framework crate's
+`ItemSearchOptions`; to be told when tokens come and go, use CryptoTokenKit's
+`TKTokenWatcher`.
+
+## Low-level queries
+
+A [Query] can match items on more than `search` does (their label, or
+whether they're invisible) and limit the number of results. It reports
+each item's attributes, and can wrap the items in entries.
+
+## Snapshots
+
+A backup agent (or anything else that needs to notice credential changes
+between runs) can take a [snapshot](Store::snapshot) of a keychain, which
+describes its items without their secrets, and [diff] it with a later one.
+
+## Daemons
+
+A daemon or login item that needs a credential before any user has logged in
+can read it from the System keychain with [Bootstrap], which never shows UI,
+retries while the keychain is locked or not yet available, and reports
+failures as a [BootstrapError]. The item is usually put there by an installer
+(for example with `security add-generic-password` on
+`/Library/Keychains/System.keychain`, using `-T` to let the daemon's binary
+read it); reading the System keychain usually requires running as root.
 
 ## Search
 
 You can search the credentials in a given store (keychain) by `service`
-and `user`. The search is case-sensitive, and a wrapper around each
+and `user`. The search is case-sensitive (unless you specify `case-sensitive`
+as `false`), and a wrapper around each
 matching credential is returned. Specifying neither `service` nor `user`
-returns wrappers around all the credentials in the store.
+returns wrappers around all the credentials in the store. Specify
+`include-invisible` as `true` to also find items that other tools
+have marked invisible. Specify `service-under` with a `/`-separated path
+to find the items whose services are at or below that path. Specify `class`
+as `internet` or `any` to find internet passwords, too; they are returned as
+[InternetCred] wrappers. For a keychain with many items,
+[Store::search_each] hands the wrappers over one at a time.
 
  */
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsString, c_char, c_void};
+use std::ops::ControlFlow;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use core_foundation::array::{CFArray, CFArrayRef};
+use core_foundation::base::{CFType, CFTypeRef, TCFType};
+use core_foundation::data::CFData;
+use core_foundation::dictionary::CFDictionary;
+use core_foundation::propertylist::{
+    create_data, create_with_data, kCFPropertyListImmutable, kCFPropertyListXMLFormat_v1_0,
+};
+use core_foundation::string::{CFString, CFStringRef};
+use log::debug;
 use security_framework::base::Error;
 use security_framework::item;
+use security_framework::os::macos::access::SecAccess;
 use security_framework::os::macos::keychain::{SecKeychain, SecPreferencesDomain};
+use security_framework::os::macos::keychain_item::SecKeychainItem;
 use security_framework::os::macos::passwords::find_generic_password;
+use security_framework_sys::base::{
+    SecAccessRef, SecKeychainItemRef, SecKeychainRef, errSecDuplicateItem, errSecItemNotFound,
+};
+
+use crate::chunks::{self, Layout};
+use crate::error::{AuthenticationCanceled, CanceledBy};
+use crate::ext::{
+    AppleStoreExt, Availability, Backend, Capabilities, ProviderInfo, SharedStores, TextFilters,
+    check_bounded, common_diagnostics, needs_all_results, page, service_is_under, sort_by_date,
+};
+use crate::lease::{self, LEASE_TAG, Lease, Marker};
+use crate::query::{Attr, Attributes, Query as RawQuery, StringMatching};
+use crate::redact::{Masked, Redaction, Specifiers};
+use crate::timeout;
+use crate::validate::{Finding, check_keys};
+
+pub use crate::chunks::CHUNK_SIZE;
+pub use crate::query::ItemAttributes;
 
 use keyring_core::{
     Entry,
@@ -59,37 +189,62 @@ use keyring_core::{
 /// The actual credentials can have lots of attributes
 /// not represented here.  There's no way to use this
 /// module to get at those attributes.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Cred {
     pub domain: MacKeychainDomain,
     pub service: String,
     pub account: String,
+    /// If set, every operation on the credential must first be approved by
+    /// the app gate. See [set_app_gate].
+    pub app_gated: bool,
+    /// If set, setting the secret fails rather than replace the secret of an
+    /// existing item. See [build](Store::build).
+    pub create_only: bool,
+    /// How much of the credential its `Debug` output shows. See the
+    /// [redact](crate::redact) module.
+    pub redaction: Redaction,
+}
+
+impl std::fmt::Debug for Cred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cred")
+            .field("domain", &self.domain)
+            .field("service", &Masked(&self.service, self.redaction))
+            .field("account", &Masked(&self.account, self.redaction))
+            .field("app_gated", &self.app_gated)
+            .field("create_only", &self.create_only)
+            .field("redaction", &self.redaction)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for Cred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "generic password in the {} keychain", self.domain)
+    }
 }
 
 impl CredentialApi for Cred {
     /// See the keychain-core API docs.
+    ///
+    /// An existing item is changed in place: only its secret is replaced, so
+    /// its label, creation date, access list, and any other attributes set by
+    /// other tools are kept.
     fn set_secret(&self, secret: &[u8]) -> Result<()> {
-        self.get_keychain()?
-            .set_generic_password(&self.service, &self.account, secret)
-            .map_err(decode_error)?;
-        Ok(())
+        let secret = secret.to_vec();
+        timeout::run("set_secret", self.clone(), move |cred| {
+            cred.write_secret(&secret)
+        })
     }
 
     /// See the keychain-core API docs.
     fn get_secret(&self) -> Result<Vec<u8>> {
-        let (password_bytes, _) =
-            find_generic_password(Some(&[self.get_keychain()?]), &self.service, &self.account)
-                .map_err(decode_error)?;
-        Ok(password_bytes.to_owned())
+        timeout::run("get_secret", self.clone(), Cred::read_secret)
     }
 
     /// See the keychain-core API docs.
     fn delete_credential(&self) -> Result<()> {
-        let (_, item) =
-            find_generic_password(Some(&[self.get_keychain()?]), &self.service, &self.account)
-                .map_err(decode_error)?;
-        item.delete();
-        Ok(())
+        timeout::run("delete_credential", self.clone(), Cred::delete_item)
     }
 
     /// See the keychain-core API docs.
@@ -97,9 +252,15 @@ impl CredentialApi for Cred {
     /// Since every specifier is also a wrapper, this is just a check
     /// to see whether the underlying credential exists.
     fn get_credential(&self) -> Result<Option<Arc<Credential>>> {
-        find_generic_password(Some(&[self.get_keychain()?]), &self.service, &self.account)
-            .map_err(decode_error)?;
-        Ok(None)
+        timeout::run("get_credential", self.clone(), Cred::find_item)
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// The only attribute is `partition-ids` (see the [module docs](self#partition-ids)),
+    /// and the secret isn't read.
+    fn get_attributes(&self) -> Result<HashMap<String, String>> {
+        timeout::run("get_attributes", self.clone(), Cred::read_attributes)
     }
 
     /// See the keychain-core API docs.
@@ -132,6 +293,11 @@ impl Cred {
     /// because empty attribute values act as wildcards in the
     /// Keychain Services API.
     pub fn build(keychain: MacKeychainDomain, service: &str, user: &str) -> Result<Entry> {
+        let cred = Self::new(keychain, service, user)?;
+        Ok(Entry::new_with_credential(Arc::new(cred)))
+    }
+
+    fn new(keychain: MacKeychainDomain, service: &str, user: &str) -> Result<Self> {
         if service.is_empty() {
             return Err(ErrorCode::Invalid(
                 "service".to_string(),
@@ -144,203 +310,2366 @@ impl Cred {
                 "cannot be empty".to_string(),
             ));
         }
-        let cred = Cred {
+        Ok(Cred {
             domain: keychain,
             service: service.to_string(),
             account: user.to_string(),
-        };
-        Ok(Entry::new_with_credential(Arc::new(cred)))
+            app_gated: false,
+            create_only: false,
+            redaction: Redaction::Full,
+        })
     }
 
-    fn get_keychain(&self) -> Result<SecKeychain> {
-        get_keychain(&self.domain)
+    /// The secret of the credential, wrapped so that it stays out of logs
+    /// and is zeroized when dropped.
+    #[cfg(feature = "secrecy")]
+    pub fn get_secret_secure(&self) -> Result<secrecy::SecretSlice<u8>> {
+        self.get_secret().map(crate::secure::secure_secret)
     }
-}
-
-/// The store for Mac keychain credentials
-pub struct Store {
-    id: String,
-    keychain: MacKeychainDomain,
-}
 
-impl std::fmt::Debug for Store {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Store")
-            .field("vendor", &self.vendor())
-            .field("id", &self.id())
-            .field("domain", &self.keychain)
-            .finish()
+    /// The password of the credential, wrapped so that it stays out of logs
+    /// and is zeroized when dropped. A secret that isn't UTF-8 is a
+    /// [BadEncoding](ErrorCode::BadEncoding) error.
+    #[cfg(feature = "secrecy")]
+    pub fn get_password_secure(&self) -> Result<secrecy::SecretString> {
+        crate::secure::secure_password(self.get_secret()?)
     }
-}
 
-impl Store {
-    /// Create a default store, which uses the User (aka login) keychain.
-    pub fn new() -> Result<Arc<Self>> {
-        Ok(Self::new_internal(MacKeychainDomain::User))
+    /// The work of [set_secret](CredentialApi::set_secret), which may run on a worker.
+    ///
+    /// A secret larger than [CHUNK_SIZE], or one that starts like a chunk
+    /// index, is written as chunks and then the index that lists them (a
+    /// create-only credential writes the index first, so the add that claims
+    /// the entry comes before anything that could clobber another writer's
+    /// chunks). Chunks left by an earlier, larger secret are deleted after.
+    fn write_secret(&self, secret: &[u8]) -> Result<()> {
+        self.check_gate(GateOperation::SetSecret)?;
+        if secret.len() <= CHUNK_SIZE && LAYOUT.chunk_count(secret).is_none() {
+            self.write_item(secret)?;
+            return self.delete_chunks_from(0);
+        }
+        let (index, chunks) = LAYOUT.split(secret);
+        if self.create_only {
+            self.write_item(&index)?;
+        }
+        for (number, chunk) in chunks.iter().enumerate() {
+            self.chunk(number as u32)?.write_item(chunk)?;
+        }
+        if !self.create_only {
+            self.write_item(&index)?;
+        }
+        self.delete_chunks_from(chunks.len() as u32)
     }
 
-    /// Create a store configured to use a specific keychain.
+    /// Write the secret to the credential's own item.
     ///
-    /// The keychain used can be overridden by a modifier on a specific entry.
-    pub fn new_with_configuration(configuration: &HashMap<&str, &str>) -> Result<Arc<Self>> {
-        let config = parse_attributes(&["keychain"], Some(configuration))?;
-        let mut keychain = MacKeychainDomain::User;
-        if let Some(option) = config.get("keychain") {
-            keychain = option.parse()?;
+    /// If another writer adds the item between the update finding none and
+    /// the add, the add is turned back into an update (up to [WRITE_ATTEMPTS]
+    /// times), so concurrent writers never see the duplicate-item error.
+    fn write_item(&self, secret: &[u8]) -> Result<()> {
+        let mut changes = RawQuery::new();
+        changes.value(secret);
+        let mut attempt = 1;
+        loop {
+            // a create-only credential goes straight to adding the item
+            let updated = if self.create_only {
+                Err(Error::from_code(errSecItemNotFound))
+            } else {
+                self.item_query()?.update(&changes)
+            };
+            match updated {
+                Ok(()) => break,
+                Err(err) if err.code() == errSecItemNotFound => {}
+                Err(err) => return Err(decode_error(err)),
+            }
+            match self
+                .get_keychain()?
+                .add_generic_password(&self.service, &self.account, secret)
+            {
+                Ok(()) => break,
+                Err(err)
+                    if err.code() == errSecDuplicateItem
+                        && !self.create_only
+                        && attempt < WRITE_ATTEMPTS =>
+                {
+                    debug!("Item was added concurrently; updating it (attempt {attempt})");
+                    attempt += 1;
+                }
+                Err(err) => return Err(decode_error(err)),
+            }
+        }
+        if self.app_gated {
+            let mut changes = RawQuery::new();
+            changes.data(Attr::Generic, APP_GATED_TAG.as_bytes());
+            self.item_query()?.update(&changes).map_err(decode_error)?;
         }
-        Ok(Self::new_internal(keychain))
+        Ok(())
     }
 
-    fn new_internal(keychain: MacKeychainDomain) -> Arc<Self> {
-        let now = SystemTime::now();
-        let elapsed = if now.lt(&UNIX_EPOCH) {
-            UNIX_EPOCH.duration_since(now).unwrap()
-        } else {
-            now.duration_since(UNIX_EPOCH).unwrap()
-        };
-        Arc::new(Store {
-            id: format!(
-                "Keychain Storage, Crate version {}, Instantiated at {}",
-                env!("CARGO_PKG_VERSION"),
-                elapsed.as_secs_f64()
-            ),
-            keychain,
+    /// The work of [get_secret](CredentialApi::get_secret).
+    fn read_secret(&self) -> Result<Vec<u8>> {
+        self.check_gate(GateOperation::GetSecret)?;
+        let secret = self.read_item()?;
+        if LAYOUT.chunk_count(&secret).is_none() {
+            return Ok(secret);
+        }
+        LAYOUT.join(secret, |number| self.chunk(number)?.read_item())
+    }
+
+    /// The data of the credential's own item.
+    fn read_item(&self) -> Result<Vec<u8>> {
+        let (password_bytes, _) =
+            find_generic_password(Some(&[self.get_keychain()?]), &self.service, &self.account)
+                .map_err(decode_error)?;
+        Ok(password_bytes.to_owned())
+    }
+
+    /// The work of [delete_credential](CredentialApi::delete_credential).
+    fn delete_item(&self) -> Result<()> {
+        self.check_gate(GateOperation::DeleteCredential)?;
+        self.remove_item()?;
+        self.delete_chunks_from(0)
+    }
+
+    /// Delete the credential's own item.
+    fn remove_item(&self) -> Result<()> {
+        let (_, item) =
+            find_generic_password(Some(&[self.get_keychain()?]), &self.service, &self.account)
+                .map_err(decode_error)?;
+        item.delete();
+        Ok(())
+    }
+
+    /// The credential for one of this credential's chunk items.
+    fn chunk(&self, number: u32) -> Result<Cred> {
+        let (service, account) = LAYOUT.chunk_specifiers(&self.service, &self.account, number);
+        let chunk = Self::new(self.domain.clone(), &service, &account)?;
+        Ok(Cred {
+            redaction: self.redaction,
+            ..chunk
         })
     }
-}
 
-impl CredentialStoreApi for Store {
-    /// See the keychain-core API docs.
-    fn vendor(&self) -> String {
-        "macOS Keychain Store, https://crates.io/crates/apple-native-keyring-store".to_string()
+    /// Delete this credential's chunk items from the given number on.
+    fn delete_chunks_from(&self, first: u32) -> Result<()> {
+        chunks::delete_from(first, |number| self.chunk(number)?.remove_item())
     }
 
-    /// See the keychain-core API docs.
-    fn id(&self) -> String {
-        self.id.to_string()
+    /// The work of [get_credential](CredentialApi::get_credential).
+    fn find_item(&self) -> Result<Option<Arc<Credential>>> {
+        self.check_gate(GateOperation::GetCredential)?;
+        find_generic_password(Some(&[self.get_keychain()?]), &self.service, &self.account)
+            .map_err(decode_error)?;
+        Ok(None)
     }
 
-    /// See the keychain-core API docs.
+    /// The work of [get_attributes](CredentialApi::get_attributes).
+    fn read_attributes(&self) -> Result<HashMap<String, String>> {
+        self.check_gate(GateOperation::GetCredential)?;
+        let ids = match PartitionAcl::of(&self.item_ref()?)? {
+            Some(acl) => acl.partition_ids(),
+            None => Vec::new(),
+        };
+        Ok(HashMap::from([(
+            "partition-ids".to_string(),
+            ids.join(","),
+        )]))
+    }
+
+    /// Let code with the given partition ID (such as `teamid:ABCDE12345`) read the
+    /// credential's item without prompting, returning whether it was added.
     ///
-    /// The only option you can specify is `keychain`, and the value
-    /// must name a keychain (User, System, Common, or Dynamic)
-    /// you want to use to hold the credential when it's created.
-    /// The default is the User (aka login) keychain.
-    fn build(
-        &self,
-        service: &str,
-        user: &str,
-        modifiers: Option<&HashMap<&str, &str>>,
-    ) -> Result<Entry> {
-        let mods = parse_attributes(&["keychain"], modifiers)?;
-        let mut keychain = self.keychain.clone();
-        if let Some(option) = mods.get("keychain") {
-            keychain = option.parse()?;
+    /// This takes the password of the item's keychain, and fails with
+    /// `NotSupportedByStore` if the item has no partition ID list (as items made
+    /// before macOS 10.12 don't). Adding a partition ID to an app-gated
+    /// credential has to be approved by the app gate as a
+    /// [SetSecret](GateOperation::SetSecret) operation.
+    pub fn add_partition_id(&self, partition_id: &str, keychain_password: &str) -> Result<bool> {
+        self.check_gate(GateOperation::SetSecret)?;
+        let password_len = u32::try_from(keychain_password.len())
+            .map_err(|_| ErrorCode::TooLong("keychain password".to_string(), u32::MAX))?;
+        let item = self.item_ref()?;
+        let Some(acl) = PartitionAcl::of(&item)? else {
+            return Err(ErrorCode::NotSupportedByStore(
+                "the item has no partition ID list".to_string(),
+            ));
+        };
+        let mut ids = acl.partition_ids();
+        if ids.iter().any(|id| id == partition_id) {
+            return Ok(false);
         }
-        Cred::build(keychain, service, user)
+        ids.push(partition_id.to_string());
+        acl.set_partition_ids(&ids)?;
+        // SAFETY: the item and access are valid, and the password pointer is
+        // valid for its length.
+        let status = unsafe {
+            SecKeychainItemSetAccessWithPassword(
+                item.as_concrete_TypeRef(),
+                acl.access.as_concrete_TypeRef(),
+                password_len,
+                keychain_password.as_ptr().cast(),
+            )
+        };
+        check_status(status)?;
+        Ok(true)
     }
 
-    /// See the keychain-core API docs.
+    /// Replace the access of the credential's item with one for the given policy.
     ///
-    /// The (optional) search spec keys allowed are `service` and `user`. They
-    /// are matched case-sensitively against the service and account attributes
-    /// of the generic passwords in the store's configured keychain. A wrapper
-    /// for each matching credential is returned. If no `service` or `user` is
-    /// specified, all credentials in the store's configured keychain are
-    /// returned.
-    fn search(&self, spec: &HashMap<&str, &str>) -> Result<Vec<Entry>> {
-        let spec = parse_attributes(&["service", "user"], Some(spec))?;
-        let keychains = [get_keychain(&self.keychain)?];
+    /// This is the work of [normalize_acls](Store::normalize_acls) for one item.
+    fn replace_access(&self, policy: &AclPolicy, keychain_password: &str) -> Result<()> {
+        self.check_gate(GateOperation::SetSecret)?;
+        let password_len = u32::try_from(keychain_password.len())
+            .map_err(|_| ErrorCode::TooLong("keychain password".to_string(), u32::MAX))?;
+        let item = self.item_ref()?;
+        let access = policy.access(&self.service)?;
+        // SAFETY: the item and access are valid, and the password pointer is
+        // valid for its length.
+        check_status(unsafe {
+            SecKeychainItemSetAccessWithPassword(
+                item.as_concrete_TypeRef(),
+                access.as_concrete_TypeRef(),
+                password_len,
+                keychain_password.as_ptr().cast(),
+            )
+        })
+    }
+
+    /// Replace the secret only if it's currently the expected one, returning
+    /// whether it was replaced.
+    ///
+    /// This is a compare-and-swap for processes that rotate a shared token:
+    /// the one whose `update_if` sees the old token replaces it, and the others
+    /// get `false` (and can read the new token). The read and the write happen
+    /// under the credential's [lease](crate::lease), so this is atomic with
+    /// respect to other `update_if` calls and `lock_credential` holders, though
+    /// not to plain `set_secret` calls. It fails with `NoEntry` if there's no
+    /// item, and with `NoStorageAccess` if another process holds the lease for
+    /// longer than [SWAP_WAIT](crate::lease::SWAP_WAIT).
+    pub fn update_if(&self, expected: &[u8], new: &[u8]) -> Result<bool> {
+        let (expected, new) = (expected.to_vec(), new.to_vec());
+        timeout::run("update_if", self.clone(), move |cred| {
+            lease::compare_and_swap(
+                || cred.lease_marker(),
+                || cred.read_secret(),
+                || cred.write_secret(&new),
+                &expected,
+            )
+        })
+    }
+
+    /// Move the credential's item to a new service and user, returning the
+    /// credential for its new name.
+    ///
+    /// The item is changed in place, so its secret isn't read and its access
+    /// control list and partition IDs are kept. This fails with `NoEntry` if
+    /// there's no item, and with a duplicate-item `PlatformFailure` if there's
+    /// already one with the new name. Renaming an app-gated credential has to
+    /// be approved by the app gate as a [SetSecret](GateOperation::SetSecret)
+    /// operation. The items holding a large secret's chunks are moved after
+    /// the credential's own item.
+    pub fn rename(&self, new_service: &str, new_user: &str) -> Result<Cred> {
+        let renamed = Self::new(self.domain.clone(), new_service, new_user)?;
+        let renamed = Cred {
+            app_gated: self.app_gated,
+            create_only: self.create_only,
+            redaction: self.redaction,
+            ..renamed
+        };
+        timeout::run("rename", self.clone(), move |cred| {
+            cred.check_gate(GateOperation::SetSecret)?;
+            let mut changes = RawQuery::new();
+            changes
+                .string(Attr::Service, &renamed.service)
+                .string(Attr::Account, &renamed.account);
+            cred.item_query()?.update(&changes).map_err(decode_error)?;
+            for number in 0.. {
+                let (service, account) =
+                    LAYOUT.chunk_specifiers(&renamed.service, &renamed.account, number);
+                let mut changes = RawQuery::new();
+                changes
+                    .string(Attr::Service, &service)
+                    .string(Attr::Account, &account);
+                match cred.chunk(number)?.item_query()?.update(&changes) {
+                    Ok(()) => {}
+                    Err(err) if err.code() == errSecItemNotFound => break,
+                    Err(err) => return Err(decode_error(err)),
+                }
+            }
+            Ok(renamed)
+        })
+    }
+
+    /// The marker for a lease on this credential.
+    fn lease_marker(&self) -> Marker {
+        let cred = self.clone();
+        Marker {
+            query: Box::new(move |adding| {
+                let keychain = cred.get_keychain()?;
+                let mut query = RawQuery::generic_password();
+                query
+                    .string(Attr::Service, &format!("{LEASE_TAG}{}", cred.service))
+                    .string(Attr::Account, &cred.account);
+                if adding {
+                    query.use_keychain(&keychain);
+                } else {
+                    query.keychains(&[keychain]);
+                }
+                Ok(query)
+            }),
+            decode: decode_error,
+        }
+    }
+
+    /// The keychain item for this credential, found without reading its secret.
+    fn item_ref(&self) -> Result<SecKeychainItem> {
+        let keychains = [self.get_keychain()?];
         let mut options = item::ItemSearchOptions::new();
         options
             .keychains(&keychains)
             .class(item::ItemClass::generic_password())
-            .limit(item::Limit::All)
-            .load_attributes(true);
-        if let Some(service) = spec.get("service") {
-            options.service(service);
+            .service(&self.service)
+            .account(&self.account)
+            .load_refs(true);
+        for result in options.search().map_err(decode_error)? {
+            if let item::SearchResult::Ref(item::Reference::KeychainItem(item)) = result {
+                return Ok(item);
+            }
         }
-        if let Some(user) = spec.get("user") {
-            options.account(user);
+        Err(ErrorCode::NoEntry)
+    }
+
+    /// A query for this credential's item.
+    fn item_query(&self) -> Result<RawQuery> {
+        let mut query = RawQuery::generic_password();
+        query
+            .keychains(&[self.get_keychain()?])
+            .string(Attr::Service, &self.service)
+            .string(Attr::Account, &self.account);
+        Ok(query)
+    }
+
+    /// Ask the app gate to approve an operation, if the credential or its item is gated.
+    ///
+    /// Operations on app-gated credentials are denied if no gate has been set.
+    /// The item is only looked up to see whether it's gated while a gate is
+    /// set, so apps that don't use gating don't pay for a lookup per operation.
+    fn check_gate(&self, operation: GateOperation) -> Result<()> {
+        let gate = APP_GATE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let gated = self.app_gated
+            || (gate.is_some() && {
+                let mut query = self.item_query()?;
+                query.return_attributes();
+                query.find().map_err(decode_error)?.iter().any(|attrs| {
+                    attrs.data(Attr::Generic).as_deref() == Some(APP_GATED_TAG.as_bytes())
+                })
+            });
+        if !gated {
+            return Ok(());
         }
-        let items = match options.search().map_err(decode_error) {
-            Ok(items) => items,
-            Err(ErrorCode::NoEntry) => return Ok(Vec::new()),
-            Err(e) => return Err(e),
+        let request = GateRequest {
+            operation,
+            service: self.service.clone(),
+            account: self.account.clone(),
         };
-        let mut result = Vec::new();
-        for item in items {
-            if let Some(map) = item.simplify_dict() {
-                if let Some(service) = map.get("svce") {
-                    if let Some(account) = map.get("acct") {
-                        let cred = Cred {
-                            domain: self.keychain.clone(),
-                            service: service.to_string(),
-                            account: account.to_string(),
-                        };
-                        result.push(Entry::new_with_credential(Arc::new(cred)))
-                    }
-                }
-            }
+        match gate {
+            Some(gate) if gate(&request) => Ok(()),
+            Some(_) => Err(ErrorCode::NoStorageAccess(
+                format!("the app gate denied {operation:?}").into(),
+            )),
+            None => Err(ErrorCode::NoStorageAccess(
+                "the credential is app-gated but no app gate is set".into(),
+            )),
         }
-        Ok(result)
     }
 
-    /// Return the underlying builder object with an `Any` type so that it can
-    /// be downgraded to a [Store] for platform-specific processing.
-    fn as_any(&self) -> &dyn std::any::Any {
-        self
+    fn get_keychain(&self) -> Result<SecKeychain> {
+        get_keychain(&self.domain)
     }
 
-    //// See the keychain-core API docs.
-    fn persistence(&self) -> CredentialPersistence {
-        CredentialPersistence::UntilDelete
+    /// A formatter that shows the credential's service and user, and nothing else.
+    pub fn reveal_specifiers_only(&self) -> Specifiers<'_> {
+        Specifiers {
+            service: &self.service,
+            user: &self.account,
+        }
     }
+}
 
-    /// See the keychain-core API docs.
-    fn debug_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
-    }
+/// A wrapper around an internet password item, as found by a search with
+/// `class` set to `internet` or `any`.
+///
+/// Internet passwords are identified by more than their server and account,
+/// so a wrapper records every identifying attribute its item had, and matches
+/// the item by all of them. Setting the secret of a wrapper whose item has
+/// been deleted re-creates the item with those attributes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct InternetCred {
+    pub domain: MacKeychainDomain,
+    pub server: String,
+    pub account: String,
+    /// The item's protocol, as a four-character code such as `htps`.
+    pub protocol: Option<String>,
+    pub port: Option<u16>,
+    pub path: Option<String>,
+    pub security_domain: Option<String>,
+    /// The item's authentication type, as a four-character code such as `dflt`.
+    pub authentication_type: Option<String>,
+    /// How much of the credential its `Debug` output shows. See the
+    /// [redact](crate::redact) module.
+    pub redaction: Redaction,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-/// The four pre-defined Mac keychains.
-pub enum MacKeychainDomain {
-    User,
-    System,
-    Common,
-    Dynamic,
+impl std::fmt::Debug for InternetCred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InternetCred")
+            .field("domain", &self.domain)
+            .field("server", &Masked(&self.server, self.redaction))
+            .field("account", &Masked(&self.account, self.redaction))
+            .field("protocol", &self.protocol)
+            .field("port", &self.port)
+            .field("path", &self.path)
+            .field("security_domain", &self.security_domain)
+            .field("authentication_type", &self.authentication_type)
+            .field("redaction", &self.redaction)
+            .finish()
+    }
 }
 
-impl std::fmt::Display for MacKeychainDomain {
+impl std::fmt::Display for InternetCred {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            MacKeychainDomain::User => "User".fmt(f),
-            MacKeychainDomain::System => "System".fmt(f),
-            MacKeychainDomain::Common => "Common".fmt(f),
-            MacKeychainDomain::Dynamic => "Dynamic".fmt(f),
-        }
+        write!(f, "internet password in the {} keychain", self.domain)
     }
 }
 
-impl std::str::FromStr for MacKeychainDomain {
-    type Err = ErrorCode;
+impl CredentialApi for InternetCred {
+    /// See the keychain-core API docs.
+    fn set_secret(&self, secret: &[u8]) -> Result<()> {
+        let secret = secret.to_vec();
+        timeout::run("set_secret", self.clone(), move |cred| {
+            cred.write_secret(&secret)
+        })
+    }
 
-    /// Convert a keychain specification string to a keychain domain.
+    /// See the keychain-core API docs.
+    fn get_secret(&self) -> Result<Vec<u8>> {
+        timeout::run("get_secret", self.clone(), |cred| {
+            cred.item_query()?.find_data().map_err(decode_error)
+        })
+    }
+
+    /// See the keychain-core API docs.
+    fn delete_credential(&self) -> Result<()> {
+        timeout::run("delete_credential", self.clone(), |cred| {
+            cred.item_query()?.delete().map_err(decode_error)
+        })
+    }
+
+    /// See the keychain-core API docs.
     ///
-    /// We accept any case in the string,
-    /// but the value has to match a known keychain domain name.
-    fn from_str(s: &str) -> Result<Self> {
-        match s.to_ascii_lowercase().as_str() {
-            "user" => Ok(MacKeychainDomain::User),
-            "system" => Ok(MacKeychainDomain::System),
-            "common" => Ok(MacKeychainDomain::Common),
-            "dynamic" => Ok(MacKeychainDomain::Dynamic),
-            _ => Err(ErrorCode::Invalid(
-                "keychain".to_string(),
-                format!("'{s}' is not User, System, Common, or Dynamic"),
-            )),
+    /// Since every internet credential is a wrapper, this is just a check
+    /// to see whether the underlying item exists.
+    fn get_credential(&self) -> Result<Option<Arc<Credential>>> {
+        timeout::run("get_credential", self.clone(), |cred| {
+            let mut query = cred.item_query()?;
+            query.return_attributes();
+            match query.find().map_err(decode_error)?.is_empty() {
+                true => Err(ErrorCode::NoEntry),
+                false => Ok(None),
+            }
+        })
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// The attributes are the identifying attributes other than the server and
+    /// account: `protocol`, `port`, `path`, `security-domain`, and
+    /// `authentication-type`, each only if the item has it. The secret isn't read.
+    fn get_attributes(&self) -> Result<HashMap<String, String>> {
+        let mut attrs = HashMap::new();
+        for (key, value) in [
+            ("protocol", self.protocol.clone()),
+            ("port", self.port.map(|p| p.to_string())),
+            ("path", self.path.clone()),
+            ("security-domain", self.security_domain.clone()),
+            ("authentication-type", self.authentication_type.clone()),
+        ] {
+            if let Some(value) = value {
+                attrs.insert(key.to_string(), value);
+            }
         }
+        Ok(attrs)
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// The specifiers are the item's server and account.
+    fn get_specifiers(&self) -> Option<(String, String)> {
+        Some((self.server.clone(), self.account.clone()))
+    }
+
+    /// See the keychain-core API docs.
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    /// See the keychain-core API docs.
+    fn debug_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl InternetCred {
+    /// The wrapper for an internet password item's attributes, if it has
+    /// a server and account.
+    fn from_attributes(
+        domain: &MacKeychainDomain,
+        attrs: &crate::query::Attributes,
+        redaction: Redaction,
+    ) -> Option<Self> {
+        Some(InternetCred {
+            domain: domain.clone(),
+            server: attrs.string(Attr::Server)?,
+            account: attrs.string(Attr::Account)?,
+            protocol: attrs.string(Attr::Protocol),
+            port: attrs.number(Attr::Port).and_then(|p| u16::try_from(p).ok()),
+            path: attrs.string(Attr::Path).filter(|p| !p.is_empty()),
+            security_domain: attrs.string(Attr::SecurityDomain).filter(|d| !d.is_empty()),
+            authentication_type: attrs.string(Attr::AuthenticationType),
+            redaction,
+        })
+    }
+
+    /// A query matching the underlying item by all its identifying attributes.
+    fn item_query(&self) -> Result<RawQuery> {
+        let mut query = self.identifying_attributes();
+        query.keychains(&[get_keychain(&self.domain)?]);
+        Ok(query)
+    }
+
+    /// The underlying item's identifying attributes, as a query or a new item.
+    fn identifying_attributes(&self) -> RawQuery {
+        let mut query = RawQuery::internet_password();
+        query
+            .string(Attr::Server, &self.server)
+            .string(Attr::Account, &self.account);
+        for (attr, value) in [
+            (Attr::Protocol, &self.protocol),
+            (Attr::Path, &self.path),
+            (Attr::SecurityDomain, &self.security_domain),
+            (Attr::AuthenticationType, &self.authentication_type),
+        ] {
+            if let Some(value) = value {
+                query.string(attr, value);
+            }
+        }
+        if let Some(port) = self.port {
+            query.number(Attr::Port, port.into());
+        }
+        query
+    }
+
+    /// The work of [set_secret](CredentialApi::set_secret), which may run on a worker.
+    fn write_secret(&self, secret: &[u8]) -> Result<()> {
+        let query = self.item_query()?;
+        let mut changes = RawQuery::new();
+        changes.value(secret);
+        match query.update(&changes) {
+            Ok(()) => Ok(()),
+            Err(err) if err.code() == errSecItemNotFound => {
+                let mut item = self.identifying_attributes();
+                item.use_keychain(&get_keychain(&self.domain)?)
+                    .value(secret);
+                item.add().map_err(decode_error)
+            }
+            Err(err) => Err(decode_error(err)),
+        }
+    }
+}
+
+/// Which applications may read an item without prompting, as set by
+/// [normalize_acls](Store::normalize_acls).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclPolicy {
+    /// Only the calling application.
+    SelfOnly,
+    /// The calling application and the applications (or tools) at these paths.
+    TrustedApplications(Vec<PathBuf>),
+}
+
+impl AclPolicy {
+    /// A new access for an item with this policy, described by the given name
+    /// in any prompts the OS shows.
+    fn access(&self, name: &str) -> Result<SecAccess> {
+        let mut applications = vec![trusted_application(None)?];
+        if let AclPolicy::TrustedApplications(paths) = self {
+            for path in paths {
+                let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+                    ErrorCode::Invalid(
+                        "trusted application".to_string(),
+                        format!("not a valid path: {}", path.display()),
+                    )
+                })?;
+                applications.push(trusted_application(Some(&path))?);
+            }
+        }
+        let applications = CFArray::from_CFTypes(&applications);
+        let name = CFString::new(name);
+        let mut access: SecAccessRef = std::ptr::null_mut();
+        // SAFETY: the name and application list are valid, and the access is a
+        // valid place to write.
+        check_status(unsafe {
+            SecAccessCreate(
+                name.as_concrete_TypeRef(),
+                applications.as_concrete_TypeRef(),
+                &mut access,
+            )
+        })?;
+        // SAFETY: the access was just created, so we own it.
+        Ok(unsafe { SecAccess::wrap_under_create_rule(access) })
+    }
+}
+
+/// The trusted application for the code at a path, or for the calling app.
+fn trusted_application(path: Option<&CString>) -> Result<CFType> {
+    let mut application: CFTypeRef = std::ptr::null();
+    let path = path.map_or(std::ptr::null(), |path| path.as_ptr());
+    // SAFETY: the path is null or a valid C string, and the application is a
+    // valid place to write.
+    check_status(unsafe { SecTrustedApplicationCreateFromPath(path, &mut application) })?;
+    // SAFETY: the application was just created, so we own it.
+    Ok(unsafe { CFType::wrap_under_create_rule(application) })
+}
+
+/// An item that [normalize_acls](Store::normalize_acls) couldn't change.
+#[derive(Debug)]
+pub struct AclFailure {
+    pub service: String,
+    pub account: String,
+    pub error: ErrorCode,
+}
+
+/// What [normalize_acls](Store::normalize_acls) did.
+#[derive(Debug, Default)]
+pub struct AclReport {
+    /// How many items were given the new access.
+    pub changed: usize,
+    /// The items that kept their old access, and why.
+    pub failures: Vec<AclFailure>,
+}
+
+/// The ACL in an item's access that lists its partition IDs.
+struct PartitionAcl {
+    access: SecAccess,
+    acl: CFType,
+    applications: Option<CFArray<CFType>>,
+    description: CFString,
+    prompt_selector: u16,
+}
+
+impl PartitionAcl {
+    /// The partition ID ACL of an item, if it has one.
+    fn of(item: &SecKeychainItem) -> Result<Option<Self>> {
+        let mut access: SecAccessRef = std::ptr::null_mut();
+        // SAFETY: the item is valid, and the access is a valid place to write.
+        check_status(unsafe {
+            SecKeychainItemCopyAccess(item.as_concrete_TypeRef(), &mut access)
+        })?;
+        // SAFETY: the access was just copied, so we own it.
+        let access = unsafe { SecAccess::wrap_under_create_rule(access) };
+        // SAFETY: the access is valid, and the tag is an immutable constant
+        // exported by the Security framework.
+        let list = unsafe {
+            SecAccessCopyMatchingACLList(
+                access.as_concrete_TypeRef(),
+                kSecACLAuthorizationPartitionID.cast(),
+            )
+        };
+        if list.is_null() {
+            return Ok(None);
+        }
+        // SAFETY: the list was just copied, so we own it.
+        let list: CFArray<CFType> = unsafe { CFArray::wrap_under_create_rule(list) };
+        let Some(acl) = list.get(0).map(|acl| acl.clone()) else {
+            return Ok(None);
+        };
+        let mut applications: CFArrayRef = std::ptr::null();
+        let mut description: CFStringRef = std::ptr::null();
+        let mut prompt_selector = 0u16;
+        // SAFETY: the ACL is valid, and the other pointers are valid places to write.
+        check_status(unsafe {
+            SecACLCopyContents(
+                acl.as_CFTypeRef(),
+                &mut applications,
+                &mut description,
+                &mut prompt_selector,
+            )
+        })?;
+        // SAFETY: the contents were just copied, so we own them; the
+        // application list is null if any application is allowed.
+        let applications = (!applications.is_null())
+            .then(|| unsafe { CFArray::wrap_under_create_rule(applications) });
+        let description = if description.is_null() {
+            CFString::new("")
+        } else {
+            // SAFETY: as above.
+            unsafe { CFString::wrap_under_create_rule(description) }
+        };
+        Ok(Some(PartitionAcl {
+            access,
+            acl,
+            applications,
+            description,
+            prompt_selector,
+        }))
+    }
+
+    /// The partition IDs, from the hex-encoded property list in the description.
+    fn partition_ids(&self) -> Vec<String> {
+        let hex = self.description.to_string();
+        let Some(bytes) = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+        else {
+            return Vec::new();
+        };
+        let Ok((plist, _)) =
+            create_with_data(CFData::from_buffer(&bytes), kCFPropertyListImmutable)
+        else {
+            return Vec::new();
+        };
+        // SAFETY: the property list was just created, so we own it.
+        let plist = unsafe { CFType::wrap_under_create_rule(plist) };
+        let key = CFString::new("Partitions");
+        let Some(partitions) = plist.downcast::<CFDictionary>().and_then(|dict| {
+            // SAFETY: values in a property list dictionary are CF objects.
+            dict.find(key.as_CFTypeRef())
+                .map(|value| unsafe { CFType::wrap_under_get_rule(*value) })
+        }) else {
+            return Vec::new();
+        };
+        let Some(partitions) = partitions.downcast::<CFArray>() else {
+            return Vec::new();
+        };
+        partitions
+            .iter()
+            // SAFETY: the elements of a property list array are CF objects.
+            .filter_map(|id| unsafe { CFType::wrap_under_get_rule(*id) }.downcast::<CFString>())
+            .map(|id| id.to_string())
+            .collect()
+    }
+
+    /// Replace the partition IDs in the ACL (but not yet in the item).
+    fn set_partition_ids(&self, ids: &[String]) -> Result<()> {
+        let ids: Vec<CFString> = ids.iter().map(|id| CFString::new(id)).collect();
+        let plist = CFDictionary::from_CFType_pairs(&[(
+            CFString::new("Partitions").into_CFType(),
+            CFArray::from_CFTypes(&ids).into_CFType(),
+        )]);
+        let data = create_data(plist.as_CFTypeRef(), kCFPropertyListXMLFormat_v1_0)
+            .map_err(|err| ErrorCode::PlatformFailure(err.description().to_string().into()))?;
+        let hex: String = data.bytes().iter().map(|b| format!("{b:02X}")).collect();
+        let description = CFString::new(&hex);
+        let applications = self
+            .applications
+            .as_ref()
+            .map_or(std::ptr::null(), |list| list.as_concrete_TypeRef());
+        // SAFETY: the ACL and description are valid, and the application list
+        // is either valid or null.
+        check_status(unsafe {
+            SecACLSetContents(
+                self.acl.as_CFTypeRef(),
+                applications,
+                description.as_concrete_TypeRef(),
+                self.prompt_selector,
+            )
+        })
+    }
+}
+
+fn check_status(status: i32) -> Result<()> {
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(decode_error(Error::from_code(status)))
+    }
+}
+
+/// The generic metadata that marks an item as app-gated.
+const APP_GATED_TAG: &str = "keyring-app-gated";
+
+/// The prefix on the service of the items holding a large secret's chunks.
+const CHUNK_TAG: &str = "keyring-chunk:";
+
+/// How large secrets are split across items. See the [chunks] module.
+const LAYOUT: Layout = Layout {
+    magic: b"keyring-chunked/1",
+    tag: CHUNK_TAG,
+};
+
+/// How many times setting a secret tries to update or add its item, when
+/// concurrent writers keep adding or deleting it in between.
+pub const WRITE_ATTEMPTS: u32 = 3;
+
+/// The service and account of the (never present) item looked up by
+/// [is_available](Store::is_available).
+const AVAILABILITY_PROBE: &str = "keyring-availability-probe";
+
+/// The operations that an app gate is asked to approve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateOperation {
+    SetSecret,
+    GetSecret,
+    DeleteCredential,
+    GetCredential,
+}
+
+/// What an app gate is asked to approve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GateRequest {
+    pub operation: GateOperation,
+    pub service: String,
+    pub account: String,
+}
+
+/// A callback that approves (true) or denies (false) an operation on an app-gated credential.
+pub type AppGate = Arc<dyn Fn(&GateRequest) -> bool + Send + Sync>;
+
+static APP_GATE: Mutex<Option<AppGate>> = Mutex::new(None);
+
+/// Set (or, with `None`, clear) the process-wide app gate.
+///
+/// The gate lets an app put credentials behind its own authentication, such as
+/// an app-level lock screen, rather than the system's. It's called before every
+/// operation on an app-gated credential, and the operation fails with a
+/// [NoStorageAccess](ErrorCode::NoStorageAccess) error unless it returns true.
+/// The gate is called on the thread doing the operation, so it shouldn't block
+/// on that thread's UI.
+///
+/// A credential is app-gated if it was built with the `app-gated` modifier or,
+/// while a gate is set, if its item was created by such a credential; see
+/// [build](Store::build). So with no gate set, operations on credentials built
+/// with the modifier fail, but other credentials for their items aren't
+/// checked: an app that gates credentials should set its gate at startup.
+pub fn set_app_gate(gate: Option<AppGate>) {
+    *APP_GATE.lock().unwrap_or_else(PoisonError::into_inner) = gate;
+}
+
+const CONFIGURATION_KEYS: &[&str] = &[
+    "keychain",
+    "prefetch",
+    "*allow-unbounded-search",
+    "debug-redaction",
+];
+const MODIFIER_KEYS: &[&str] = &["keychain", "*app-gated", "*create-only"];
+const SEARCH_KEYS: &[&str] = &[
+    "service",
+    "user",
+    "*include-invisible",
+    "service-under",
+    "*all",
+    "class",
+    "limit",
+    "offset",
+    "*case-sensitive",
+    "*diacritic-sensitive",
+    "service-prefix",
+    "user-prefix",
+    "contains",
+    "sort",
+];
+/// The search keys that narrow a search, for stores that reject unbounded searches.
+const RESTRICTING_KEYS: &[&str] = &[
+    "service",
+    "user",
+    "service-under",
+    "service-prefix",
+    "user-prefix",
+    "contains",
+];
+
+/// The store for Mac keychain credentials
+#[derive(Clone)]
+pub struct Store {
+    id: String,
+    keychain: MacKeychainDomain,
+    allow_unbounded_search: bool,
+    redaction: Redaction,
+}
+
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store")
+            .field("vendor", &self.vendor())
+            .field("id", &self.id())
+            .field("domain", &self.keychain)
+            .field("allow_unbounded_search", &self.allow_unbounded_search)
+            .field("redaction", &self.redaction)
+            .finish()
+    }
+}
+
+impl Store {
+    /// Create a default store, which uses the User (aka login) keychain.
+    pub fn new() -> Result<Arc<Self>> {
+        Ok(Self::new_internal(
+            MacKeychainDomain::User,
+            true,
+            Redaction::Full,
+        ))
+    }
+
+    /// Create a store configured to use a specific keychain.
+    ///
+    /// The keychain used can be overridden by a modifier on a specific entry.
+    ///
+    /// There is also a `prefetch` key, whose value is a comma-separated list of
+    /// services. When it's given, the store looks up the attributes (never the secrets)
+    /// of the items for those services on a background thread, so the keychain is
+    /// open and warm by the time the app first asks for them. Any failures are only logged.
+    ///
+    /// There is also an `allow-unbounded-search` key (`true` or `false`), default true.
+    /// When it's false, a search spec (or an
+    /// [update_attributes_matching](Store::update_attributes_matching) spec) that
+    /// doesn't have a `service`, `user`, `service-under`, `service-prefix`,
+    /// `user-prefix`, or `contains` is an error unless it has `all` set to true,
+    /// so a bug that passes an empty spec along can't reach every item in the
+    /// keychain.
+    ///
+    /// There is also a `debug-redaction` key (`full` or `reveal-specifiers`),
+    /// default `full`, which sets how much of its credentials' `Debug` output
+    /// shows. See the [redact](crate::redact) module.
+    pub fn new_with_configuration(configuration: &HashMap<&str, &str>) -> Result<Arc<Self>> {
+        let config = parse_attributes(CONFIGURATION_KEYS, Some(configuration))?;
+        let (keychain, allow_unbounded_search, redaction) = parse_configuration(&config)?;
+        if let Some(services) = config.get("prefetch") {
+            prefetch(services, keychain.clone());
+        }
+        Ok(Self::new_internal(
+            keychain,
+            allow_unbounded_search,
+            redaction,
+        ))
+    }
+
+    /// The [configuration](Store::configuration) a store created with the given
+    /// one would have, found without creating it.
+    pub(crate) fn normalized_configuration(
+        configuration: &HashMap<&str, &str>,
+    ) -> Result<HashMap<String, String>> {
+        let config = parse_attributes(CONFIGURATION_KEYS, Some(configuration))?;
+        let (keychain, allow_unbounded_search, redaction) = parse_configuration(&config)?;
+        Ok(configuration_of(
+            &keychain,
+            allow_unbounded_search,
+            redaction,
+        ))
+    }
+
+    /// The work of [validate_configuration](crate::validate::validate_configuration)
+    /// for this store.
+    pub(crate) fn validate_configuration(config: &HashMap<&str, &str>) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let config = check_keys(CONFIGURATION_KEYS, config, &mut findings);
+        match config
+            .get("keychain")
+            .map(|k| k.parse::<MacKeychainDomain>())
+        {
+            Some(Ok(MacKeychainDomain::System)) => findings.push(Finding::warning(
+                Some("keychain"),
+                "writing to the System keychain needs administrator rights",
+            )),
+            Some(Err(err)) => findings.push(Finding::from_error(err)),
+            _ => {}
+        }
+        if let Some(Err(err)) = config
+            .get("debug-redaction")
+            .map(|p| p.parse::<Redaction>())
+        {
+            findings.push(Finding::from_error(err));
+        }
+        findings
+    }
+
+    /// Wrap a credential in a new entry.
+    ///
+    /// This is for callers who take the credential from a search result
+    /// (or any other entry), change some of its fields, and want an entry for the
+    /// changed credential without rebuilding it from its service and user.
+    /// The credential is checked just as it would be if it were built, so this
+    /// fails if its service or account is empty.
+    pub fn entry_for(&self, cred: Cred) -> Result<Entry> {
+        let mut checked = Cred::new(cred.domain, &cred.service, &cred.account)?;
+        checked.app_gated = cred.app_gated;
+        checked.create_only = cred.create_only;
+        checked.redaction = cred.redaction;
+        Ok(Entry::new_with_credential(Arc::new(checked)))
+    }
+
+    /// Change the password of the store's keychain file.
+    ///
+    /// This is what `security set-keychain-password` does: the keychain is
+    /// re-encrypted under the new password, and it stays unlocked afterward.
+    /// If the old password is wrong, this fails with a platform error
+    /// (`errSecAuthFailed`) and the keychain is unchanged.
+    ///
+    /// This needs the `unstable-change-password` feature, because there's no
+    /// public API for it: it calls `SecKeychainChangePassword`, which the
+    /// Security framework exports but doesn't declare in its headers. Apple
+    /// could change or remove it in any macOS release, in which case apps
+    /// using this fail to load rather than fail to build.
+    #[cfg(feature = "unstable-change-password")]
+    pub fn change_password(&self, old: &str, new: &str) -> Result<()> {
+        change_keychain_password(&get_keychain(&self.keychain)?, old, new)
+    }
+
+    /// Take an advisory lease on a credential for the given time, so that
+    /// processes rotating it can agree on which of them does.
+    ///
+    /// This returns `None` if another unexpired lease on the credential is
+    /// held, by this process or any other. See the [lease](crate::lease) module.
+    pub fn lock_credential(
+        &self,
+        service: &str,
+        user: &str,
+        ttl: Duration,
+    ) -> Result<Option<Lease>> {
+        let cred = Cred::new(self.keychain.clone(), service, user)?;
+        lease::acquire(cred.lease_marker(), ttl)
+    }
+
+    /// The store's configuration.
+    ///
+    /// This has the `keychain`, `allow-unbounded-search`, and `debug-redaction`
+    /// in effect, including the values of any that weren't specified when the
+    /// store was created. (A `prefetch` is done when the store is created, so it
+    /// isn't part of the configuration.)
+    pub fn configuration(&self) -> HashMap<String, String> {
+        configuration_of(&self.keychain, self.allow_unbounded_search, self.redaction)
+    }
+
+    /// Get the process-wide shared store for a configuration.
+    ///
+    /// The first call with a given configuration creates the store, as
+    /// [new_with_configuration](Store::new_with_configuration) would, and later
+    /// calls with the same configuration (in any order, and whether or not
+    /// defaults are spelled out) return that same store. This lets independent
+    /// crates in one binary share a store rather than each creating their own.
+    /// Configurations that differ only in their `prefetch` share a store, since
+    /// it isn't part of the [configuration](Store::configuration). Shared stores
+    /// live until they are evicted with [evict_shared](Store::evict_shared) or
+    /// [evict_all_shared](Store::evict_all_shared).
+    pub fn shared(configuration: &HashMap<&str, &str>) -> Result<Arc<Self>> {
+        SHARED.get(configuration)
+    }
+
+    /// Stop sharing the store for a configuration, returning it if there was one.
+    ///
+    /// Anyone still holding the store can keep using it, but the next call
+    /// to [shared](Store::shared) with this configuration creates a new one.
+    /// The configuration is checked (but no store is created for it), so this
+    /// fails if it isn't valid.
+    pub fn evict_shared(configuration: &HashMap<&str, &str>) -> Result<Option<Arc<Self>>> {
+        SHARED.evict(configuration)
+    }
+
+    /// Stop sharing all the shared stores.
+    pub fn evict_all_shared() {
+        SHARED.clear();
+    }
+
+    /// Search the keychain, handing each matching entry to `f` as its item is
+    /// decoded instead of collecting them all.
+    ///
+    /// This takes the same spec as [search](CredentialStoreApi::search) and
+    /// finds the same entries, but `f` sees each one as soon as it's made, so
+    /// a keychain with thousands of items never has thousands of entries in
+    /// memory at once. The search stops when `f` returns `ControlFlow::Break`.
+    ///
+    /// A spec with `sort`, `limit`, or `offset` needs every result before it
+    /// can hand over the first, as does a search under an operation timeout
+    /// (which runs on a worker thread); those searches are done in full and
+    /// their entries handed over afterwards.
+    pub fn search_each(
+        &self,
+        spec: &HashMap<&str, &str>,
+        mut f: impl FnMut(Entry) -> ControlFlow<()>,
+    ) -> Result<()> {
+        let spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        check_bounded(&spec, RESTRICTING_KEYS, self.allow_unbounded_search)?;
+        if needs_all_results(&spec) || timeout::operation_timeout().is_some() {
+            let entries = timeout::run("search", self.clone(), move |store| {
+                store.find_entries(&spec)
+            })?;
+            for entry in entries {
+                if f(entry).is_break() {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        self.each_entry(&spec, &mut |entry, _| f(entry))
+    }
+
+    /// The work of [search](CredentialStoreApi::search), given the parsed spec.
+    fn find_entries(&self, spec: &HashMap<String, String>) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        self.each_entry(spec, &mut |entry, attrs| {
+            entries.push((entry, attrs));
+            ControlFlow::Continue(())
+        })?;
+        sort_by_date(&mut entries, spec, |(_, attrs), attr| attrs.date(attr))?;
+        let entries = page(entries, spec, |(entry, _)| entry.get_specifiers())?;
+        Ok(entries.into_iter().map(|(entry, _)| entry).collect())
+    }
+
+    /// Hand each item matching a parsed search spec, as an entry with its
+    /// attributes, to `f`, until it breaks.
+    fn each_entry(
+        &self,
+        spec: &HashMap<String, String>,
+        f: &mut dyn FnMut(Entry, Attributes) -> ControlFlow<()>,
+    ) -> Result<()> {
+        let (generic, internet) = match spec.get("class").map(String::as_str) {
+            None | Some("generic") => (true, false),
+            Some("internet") => (false, true),
+            Some("any") => (true, true),
+            Some(other) => {
+                return Err(ErrorCode::Invalid(
+                    "class".to_string(),
+                    format!("must be generic, internet, or any, not {other}"),
+                ));
+            }
+        };
+        if generic && self.each_generic_entry(spec, f)?.is_break() {
+            return Ok(());
+        }
+        if internet {
+            self.each_internet_entry(spec, f)?;
+        }
+        Ok(())
+    }
+
+    /// Hand each internet password item matching a parsed search spec to `f`.
+    fn each_internet_entry(
+        &self,
+        spec: &HashMap<String, String>,
+        f: &mut dyn FnMut(Entry, Attributes) -> ControlFlow<()>,
+    ) -> Result<()> {
+        let mut query = RawQuery::internet_password();
+        query
+            .keychains(&[get_keychain(&self.keychain)?])
+            .limit_all()
+            .return_attributes()
+            .matching(StringMatching::of_spec(spec));
+        if let Some(service) = spec.get("service") {
+            query.string(Attr::Server, service);
+        }
+        if let Some(user) = spec.get("user") {
+            query.string(Attr::Account, user);
+        }
+        let include_invisible = spec.get("include-invisible").is_some_and(|s| s.eq("true"));
+        let service_under = spec.get("service-under");
+        let filters = TextFilters::of_spec(spec, "user-prefix");
+        for attrs in query.find().map_err(decode_error)? {
+            if !include_invisible && attrs.boolean(Attr::IsInvisible).unwrap_or_default() {
+                continue;
+            }
+            let Some(cred) = InternetCred::from_attributes(&self.keychain, &attrs, self.redaction)
+            else {
+                continue;
+            };
+            if service_under.is_some_and(|path| !service_is_under(&cred.server, path))
+                || filters
+                    .as_ref()
+                    .is_some_and(|filters| !filters.matches(&cred.server, &cred.account))
+            {
+                continue;
+            }
+            if f(Entry::new_with_credential(Arc::new(cred)), attrs).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand each generic password item matching a parsed search spec to `f`.
+    fn each_generic_entry(
+        &self,
+        spec: &HashMap<String, String>,
+        f: &mut dyn FnMut(Entry, Attributes) -> ControlFlow<()>,
+    ) -> Result<ControlFlow<()>> {
+        let keychains = [get_keychain(&self.keychain)?];
+        let mut query = RawQuery::generic_password();
+        query
+            .keychains(&keychains)
+            .limit_all()
+            .return_attributes()
+            .matching(StringMatching::of_spec(spec));
+        if let Some(service) = spec.get("service") {
+            query.string(Attr::Service, service);
+        }
+        if let Some(user) = spec.get("user") {
+            query.string(Attr::Account, user);
+        }
+        let mut found = query.find().map_err(decode_error)?;
+        if spec.get("include-invisible").is_some_and(|s| s.eq("true")) {
+            query.boolean(Attr::IsInvisible, true);
+            found.extend(query.find().map_err(decode_error)?);
+        }
+        let service_under = spec.get("service-under");
+        let filters = TextFilters::of_spec(spec, "user-prefix");
+        // the specifiers (and gating) of the items handed over, to skip repeats
+        let mut seen = HashSet::new();
+        for attrs in found {
+            let (Some(service), Some(account)) =
+                (attrs.string(Attr::Service), attrs.string(Attr::Account))
+            else {
+                continue;
+            };
+            if service.starts_with(LEASE_TAG)
+                || service.starts_with(CHUNK_TAG)
+                || service_under.is_some_and(|path| !service_is_under(&service, path))
+                || filters
+                    .as_ref()
+                    .is_some_and(|filters| !filters.matches(&service, &account))
+            {
+                continue;
+            }
+            let app_gated = attrs.data(Attr::Generic).as_deref() == Some(APP_GATED_TAG.as_bytes());
+            if !seen.insert((service.clone(), account.clone(), app_gated)) {
+                continue;
+            }
+            let cred = Cred {
+                domain: self.keychain.clone(),
+                service,
+                account,
+                app_gated,
+                create_only: false,
+                redaction: self.redaction,
+            };
+            if f(Entry::new_with_credential(Arc::new(cred)), attrs).is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn new_internal(
+        keychain: MacKeychainDomain,
+        allow_unbounded_search: bool,
+        redaction: Redaction,
+    ) -> Arc<Self> {
+        let now = SystemTime::now();
+        let elapsed = if now.lt(&UNIX_EPOCH) {
+            UNIX_EPOCH.duration_since(now).unwrap()
+        } else {
+            now.duration_since(UNIX_EPOCH).unwrap()
+        };
+        Arc::new(Store {
+            id: format!(
+                "Keychain Storage, Crate version {}, Instantiated at {}",
+                env!("CARGO_PKG_VERSION"),
+                elapsed.as_secs_f64()
+            ),
+            keychain,
+            allow_unbounded_search,
+            redaction,
+        })
+    }
+
+    /// The path of the keychain file the store's keychain resolves to.
+    ///
+    /// The keychain is looked up each time, so for a `Default` store this
+    /// follows the user changing their default keychain.
+    pub fn resolved_keychain_path(&self) -> Result<PathBuf> {
+        keychain_path(&get_keychain(&self.keychain)?)
+    }
+
+    /// An inventory of the items in the store's keychain, for comparing with a later one.
+    ///
+    /// Each item is described by its service, account, label, creation and
+    /// modification dates, and a SHA-256 checksum of its data, so [diff] can tell
+    /// when an item's secret changed without the snapshot holding any secrets.
+    /// Checksums are left out for app-gated items (reading them would go around
+    /// the app gate) and for items whose data can't be read without prompting.
+    /// To find those without prompting, the snapshot turns off keychain user
+    /// interaction while it reads, so items whose access control list doesn't
+    /// trust this app fail to read rather than show the "wants to use your
+    /// confidential information" dialog. The setting is per process, so other
+    /// threads' keychain reads can't prompt while a snapshot is being taken.
+    ///
+    /// A checksum of a short or guessable secret can be matched by trying
+    /// candidate secrets, so keep snapshots as private as the keychain itself.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let keychains = [get_keychain(&self.keychain)?];
+        let mut query = RawQuery::generic_password();
+        query.keychains(&keychains).limit_all().return_attributes();
+        // restored when dropped, unless interaction was already off
+        let _no_ui = if SecKeychain::user_interaction_allowed().unwrap_or(true) {
+            Some(SecKeychain::disable_user_interaction().map_err(decode_error)?)
+        } else {
+            None
+        };
+        let mut items = Vec::new();
+        for attrs in query.find().map_err(decode_error)? {
+            let service = attrs.string(Attr::Service).unwrap_or_default();
+            let account = attrs.string(Attr::Account).unwrap_or_default();
+            let app_gated = attrs.data(Attr::Generic).as_deref() == Some(APP_GATED_TAG.as_bytes());
+            // empty specifiers would act as wildcards when reading the data
+            let checksum = if app_gated || service.is_empty() || account.is_empty() {
+                None
+            } else {
+                let mut item = RawQuery::generic_password();
+                item.keychains(&keychains)
+                    .string(Attr::Service, &service)
+                    .string(Attr::Account, &account);
+                item.find_data().ok().map(|data| sha256_hex(&data))
+            };
+            items.push(SnapshotItem {
+                service,
+                account,
+                label: attrs.string(Attr::Label),
+                created: attrs.date(Attr::CreationDate),
+                modified: attrs.date(Attr::ModificationDate),
+                checksum,
+            });
+        }
+        items.sort_by(|a, b| (&a.service, &a.account).cmp(&(&b.service, &b.account)));
+        Ok(Snapshot {
+            keychain: self.keychain.to_string(),
+            taken_at: SystemTime::now(),
+            items,
+        })
+    }
+
+    /// Change the attributes of every item matching a search spec, in one pass.
+    ///
+    /// The spec keys are `service` and `user`, as for [search](Store::search),
+    /// and only the store's configured keychain is changed. The attribute keys
+    /// are `label`, `comment`, and `description`, and each one given replaces
+    /// that attribute's value on every matching item.
+    ///
+    /// Returns the number of items changed. Secrets are never read or changed.
+    pub fn update_attributes_matching(
+        &self,
+        spec: &HashMap<&str, &str>,
+        attrs: &HashMap<&str, &str>,
+    ) -> Result<usize> {
+        let spec = parse_attributes(&["service", "user", "*all"], Some(spec))?;
+        check_bounded(&spec, &["service", "user"], self.allow_unbounded_search)?;
+        let changes = RawQuery::attribute_changes(attrs)?;
+        let keychains = [get_keychain(&self.keychain)?];
+        let mut query = RawQuery::generic_password();
+        query.keychains(&keychains);
+        if let Some(service) = spec.get("service") {
+            query.string(Attr::Service, service);
+        }
+        if let Some(user) = spec.get("user") {
+            query.string(Attr::Account, user);
+        }
+        let mut count_query = query.clone();
+        count_query.limit_all().return_attributes();
+        let count = count_query.find().map_err(decode_error)?.len();
+        if count == 0 {
+            return Ok(0);
+        }
+        query.update(&changes).map_err(decode_error)?;
+        Ok(count)
+    }
+
+    /// Set the secrets of many credentials in one operation, such as when
+    /// provisioning an app.
+    ///
+    /// Each `(service, user, secret)` is a credential in the store's keychain,
+    /// as [build](CredentialStoreApi::build) makes it without modifiers. The
+    /// list returned has each credential's result in its place, and one that
+    /// fails doesn't keep the others from being set. An operation timeout
+    /// covers the whole batch.
+    pub fn set_many(&self, items: &[(&str, &str, &[u8])]) -> Result<Vec<Result<()>>> {
+        let items: Vec<(String, String, Vec<u8>)> = items
+            .iter()
+            .map(|(service, user, secret)| (service.to_string(), user.to_string(), secret.to_vec()))
+            .collect();
+        timeout::run("set_many", self.clone(), move |store| {
+            Ok(items
+                .iter()
+                .map(|(service, user, secret)| {
+                    store.plain_cred(service, user)?.write_secret(secret)
+                })
+                .collect())
+        })
+    }
+
+    /// Get the secrets of many credentials in one operation.
+    ///
+    /// Each `(service, user)` is a credential as for [set_many](Store::set_many),
+    /// and each result is in its place in the list returned (a missing item
+    /// gives `NoEntry` there).
+    pub fn get_many(&self, items: &[(&str, &str)]) -> Result<Vec<Result<Vec<u8>>>> {
+        let items: Vec<(String, String)> = items
+            .iter()
+            .map(|(service, user)| (service.to_string(), user.to_string()))
+            .collect();
+        timeout::run("get_many", self.clone(), move |store| {
+            Ok(items
+                .iter()
+                .map(|(service, user)| store.plain_cred(service, user)?.read_secret())
+                .collect())
+        })
+    }
+
+    /// Probe whether the store's keychain can be used, so an app can degrade
+    /// gracefully before its first credential access fails.
+    ///
+    /// This opens the keychain, checks whether it's locked, and looks up an
+    /// item that isn't there. It never shows UI or changes anything.
+    pub fn is_available(&self) -> Availability {
+        let probe = timeout::run("is_available", self.clone(), |store| {
+            let keychain = get_keychain(&store.keychain)?;
+            if is_locked(&keychain) {
+                return Ok(false);
+            }
+            match find_generic_password(Some(&[keychain]), AVAILABILITY_PROBE, AVAILABILITY_PROBE) {
+                Ok(_) => Ok(true),
+                Err(err) if err.code() == errSecItemNotFound => Ok(true),
+                Err(err) => Err(decode_error(err)),
+            }
+        });
+        match probe {
+            Ok(true) => Availability::Available,
+            Ok(false) => Availability::Locked,
+            Err(err) => Availability::of_error(&err),
+        }
+    }
+
+    /// A credential in the store's keychain built without modifiers.
+    fn plain_cred(&self, service: &str, user: &str) -> Result<Cred> {
+        let mut cred = Cred::new(self.keychain.clone(), service, user)?;
+        cred.redaction = self.redaction;
+        Ok(cred)
+    }
+
+    /// Delete every credential for the given services in the store's
+    /// keychain, and return how many were deleted, for "sign out and erase"
+    /// flows.
+    ///
+    /// The keychain is shared by every app the user runs, so there's no
+    /// "all of this app's credentials" to delete: the app has to name its
+    /// services (or, with [delete_prefixed](Store::delete_prefixed), their
+    /// common prefix). An empty list, or an empty service, is an error.
+    ///
+    /// This searches for each service's generic passwords (the invisible ones
+    /// included) and deletes each one found. App-gated credentials are
+    /// deleted only if the app gate approves; a denial, like any failure other
+    /// than the item having gone already, ends the pass with that error.
+    pub fn delete_all(&self, services: &[&str]) -> Result<usize> {
+        if services.is_empty() || services.iter().any(|service| service.is_empty()) {
+            return Err(ErrorCode::Invalid(
+                "services".to_string(),
+                "must list at least one service, and no empty ones".to_string(),
+            ));
+        }
+        let mut count = 0;
+        for service in services {
+            let spec = HashMap::from([
+                ("service".to_string(), service.to_string()),
+                ("include-invisible".to_string(), "true".to_string()),
+            ]);
+            count += self.delete_found("delete_all", spec, &[])?;
+        }
+        Ok(count)
+    }
+
+    /// Delete the generic passwords whose service starts with a prefix, except
+    /// those whose service is on a keep-list, and return how many were deleted.
+    ///
+    /// A logout flow can use this to clear the user's credentials (all under,
+    /// say, `com.example.app.`) while keeping the device-registration secrets
+    /// it lists in `keep`. Otherwise it works like [delete_all](Store::delete_all).
+    pub fn delete_prefixed(&self, service_prefix: &str, keep: &[&str]) -> Result<usize> {
+        if service_prefix.is_empty() {
+            return Err(ErrorCode::Invalid(
+                "service_prefix".to_string(),
+                "cannot be empty".to_string(),
+            ));
+        }
+        let spec = HashMap::from([
+            ("service-prefix".to_string(), service_prefix.to_string()),
+            ("include-invisible".to_string(), "true".to_string()),
+        ]);
+        self.delete_found("delete_prefixed", spec, keep)
+    }
+
+    /// Delete the credentials a parsed search spec finds, except those whose
+    /// service is in `keep`, and return how many were deleted.
+    fn delete_found(
+        &self,
+        operation: &'static str,
+        spec: HashMap<String, String>,
+        keep: &[&str],
+    ) -> Result<usize> {
+        let keep: Vec<String> = keep.iter().map(|service| service.to_string()).collect();
+        timeout::run(operation, self.clone(), move |store| {
+            let mut count = 0;
+            for entry in store.find_entries(&spec)? {
+                if entry
+                    .get_specifiers()
+                    .is_some_and(|(service, _)| keep.contains(&service))
+                {
+                    continue;
+                }
+                let deleted = match entry.as_any().downcast_ref::<Cred>() {
+                    Some(cred) => cred.delete_item(),
+                    None => entry.delete_credential(),
+                };
+                match deleted {
+                    Ok(()) => count += 1,
+                    Err(ErrorCode::NoEntry) => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(count)
+        })
+    }
+
+    /// Delete every item matching a search spec, with one delete call.
+    ///
+    /// The spec keys are `service` and `user`, as for
+    /// [update_attributes_matching](Store::update_attributes_matching). Unlike
+    /// searching and then deleting each entry, nothing can be added to (or
+    /// deleted from) the matching items in between. If any of them is app-gated,
+    /// the app gate must approve deleting every app-gated one before anything
+    /// is deleted. The items holding large secrets' chunks are deleted after
+    /// the matching items, which takes a lookup for each one.
+    ///
+    /// Returns the number of items deleted.
+    pub fn delete_matching(&self, spec: &HashMap<&str, &str>) -> Result<usize> {
+        let spec = parse_attributes(&["service", "user", "*all"], Some(spec))?;
+        check_bounded(&spec, &["service", "user"], self.allow_unbounded_search)?;
+        let keychains = [get_keychain(&self.keychain)?];
+        let mut query = RawQuery::generic_password();
+        query.keychains(&keychains);
+        if let Some(service) = spec.get("service") {
+            query.string(Attr::Service, service);
+        }
+        if let Some(user) = spec.get("user") {
+            query.string(Attr::Account, user);
+        }
+        let mut count_query = query.clone();
+        count_query.limit_all().return_attributes();
+        let found = count_query.find().map_err(decode_error)?;
+        for attrs in found.iter() {
+            if attrs.data(Attr::Generic).as_deref() == Some(APP_GATED_TAG.as_bytes()) {
+                let mut cred = Cred::new(
+                    self.keychain.clone(),
+                    &attrs.string(Attr::Service).unwrap_or_default(),
+                    &attrs.string(Attr::Account).unwrap_or_default(),
+                )?;
+                cred.app_gated = true;
+                cred.check_gate(GateOperation::DeleteCredential)?;
+            }
+        }
+        if found.is_empty() {
+            return Ok(0);
+        }
+        query.delete().map_err(decode_error)?;
+        for attrs in found.iter() {
+            let service = attrs.string(Attr::Service).unwrap_or_default();
+            let account = attrs.string(Attr::Account).unwrap_or_default();
+            // items with empty specifiers can't have chunks
+            if let Ok(cred) = Cred::new(self.keychain.clone(), &service, &account) {
+                cred.delete_chunks_from(0)?;
+            }
+        }
+        Ok(found.len())
+    }
+
+    /// Give every item matching a search spec a new access, so that just the
+    /// applications the policy names can read it without prompting.
+    ///
+    /// This is for repairing items after an app's code signature changes (when
+    /// its signing certificate is rotated, say) and the OS no longer recognizes
+    /// it as one of the applications trusted by its old items. The spec keys are
+    /// `service` and `user`, as for
+    /// [update_attributes_matching](Store::update_attributes_matching). Setting
+    /// an item's access takes the keychain's password, and replaces the item's
+    /// whole access, partition IDs included; use
+    /// [add_partition_id](Cred::add_partition_id) afterward if other code needs
+    /// silent access. App-gated items are changed only if the app gate approves
+    /// a [SetSecret](GateOperation::SetSecret) operation on them.
+    ///
+    /// An item that can't be changed doesn't stop the others from being
+    /// changed; it's reported as a failure. Secrets are never read or changed.
+    pub fn normalize_acls(
+        &self,
+        spec: &HashMap<&str, &str>,
+        policy: &AclPolicy,
+        keychain_password: &str,
+    ) -> Result<AclReport> {
+        let spec = parse_attributes(&["service", "user", "*all"], Some(spec))?;
+        check_bounded(&spec, &["service", "user"], self.allow_unbounded_search)?;
+        let keychains = [get_keychain(&self.keychain)?];
+        let mut query = RawQuery::generic_password();
+        query.keychains(&keychains).limit_all().return_attributes();
+        if let Some(service) = spec.get("service") {
+            query.string(Attr::Service, service);
+        }
+        if let Some(user) = spec.get("user") {
+            query.string(Attr::Account, user);
+        }
+        let mut report = AclReport::default();
+        for attrs in query.find().map_err(decode_error)? {
+            let service = attrs.string(Attr::Service).unwrap_or_default();
+            let account = attrs.string(Attr::Account).unwrap_or_default();
+            let result = Cred::new(self.keychain.clone(), &service, &account)
+                .and_then(|cred| cred.replace_access(policy, keychain_password));
+            match result {
+                Ok(()) => report.changed += 1,
+                Err(error) => report.failures.push(AclFailure {
+                    service,
+                    account,
+                    error,
+                }),
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// The shared stores.
+static SHARED: SharedStores<Store> = SharedStores::new(
+    Store::new_with_configuration,
+    Store::normalized_configuration,
+);
+
+/// The keychain, `allow-unbounded-search`, and `debug-redaction` of a parsed
+/// configuration, with their defaults filled in.
+fn parse_configuration(
+    config: &HashMap<String, String>,
+) -> Result<(MacKeychainDomain, bool, Redaction)> {
+    let keychain = match config.get("keychain") {
+        Some(option) => option.parse()?,
+        None => MacKeychainDomain::User,
+    };
+    let allow_unbounded_search = !config
+        .get("allow-unbounded-search")
+        .is_some_and(|s| s.eq("false"));
+    let redaction = match config.get("debug-redaction") {
+        Some(policy) => policy.parse()?,
+        None => Redaction::Full,
+    };
+    Ok((keychain, allow_unbounded_search, redaction))
+}
+
+/// The configuration reported by a store with these settings.
+fn configuration_of(
+    keychain: &MacKeychainDomain,
+    allow_unbounded_search: bool,
+    redaction: Redaction,
+) -> HashMap<String, String> {
+    HashMap::from([
+        ("keychain".to_string(), keychain.to_string()),
+        (
+            "allow-unbounded-search".to_string(),
+            allow_unbounded_search.to_string(),
+        ),
+        ("debug-redaction".to_string(), redaction.to_string()),
+    ])
+}
+
+impl CredentialStoreApi for Store {
+    /// See the keychain-core API docs.
+    fn vendor(&self) -> String {
+        "macOS Keychain Store, https://crates.io/crates/apple-native-keyring-store".to_string()
+    }
+
+    /// See the keychain-core API docs.
+    fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// The `keychain` modifier must name a keychain (User, System, Common, Dynamic,
+    /// or the user's Default keychain) you want to use to hold the credential when it's created.
+    /// The default is the User (aka login) keychain.
+    ///
+    /// The `app-gated` modifier (value true or false, default false) puts the
+    /// credential behind the app's own authentication: every operation on it must
+    /// first be approved by the gate set with [set_app_gate]. When such a credential
+    /// sets its secret, the item is marked (in its generic metadata) so that every
+    /// other credential for it, including those returned from search, is gated too
+    /// (while a gate is set; see [set_app_gate]).
+    ///
+    /// The `create-only` modifier (value true or false, default false) makes
+    /// setting the secret fail if the item already exists, rather than replace
+    /// its secret, for workflows in which overwriting someone else's credential
+    /// would be destructive. The failure is a `PlatformFailure` whose
+    /// [error_kind](crate::error::error_kind) is
+    /// [DuplicateItem](crate::error::ErrorKind::DuplicateItem).
+    fn build(
+        &self,
+        service: &str,
+        user: &str,
+        modifiers: Option<&HashMap<&str, &str>>,
+    ) -> Result<Entry> {
+        let mods = parse_attributes(MODIFIER_KEYS, modifiers)?;
+        let mut keychain = self.keychain.clone();
+        if let Some(option) = mods.get("keychain") {
+            keychain = option.parse()?;
+        }
+        let mut cred = Cred::new(keychain, service, user)?;
+        cred.app_gated = mods.get("app-gated").is_some_and(|s| s.eq("true"));
+        cred.create_only = mods.get("create-only").is_some_and(|s| s.eq("true"));
+        cred.redaction = self.redaction;
+        Ok(Entry::new_with_credential(Arc::new(cred)))
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// All the search spec keys are optional. The main ones are `service` and
+    /// `user`, which are matched case-sensitively against the service and
+    /// account attributes of the generic passwords in the store's configured
+    /// keychain; the others, described below, narrow, loosen, order, or page the
+    /// search. A wrapper for each matching credential is returned. If the spec
+    /// has none of the keys that narrow the search (`service`, `user`,
+    /// `service-under`, `service-prefix`, `user-prefix`, and `contains`), all
+    /// credentials in the store's configured keychain are returned, unless the
+    /// store doesn't allow unbounded searches, in which case the spec must have
+    /// `all` set to true (see [new_with_configuration](Store::new_with_configuration)).
+    ///
+    /// Items that other tools have marked invisible (with `kSecAttrIsInvisible`)
+    /// may be left out of the results. Specify `include-invisible` as `true`
+    /// to make sure they are found.
+    ///
+    /// If your services are `/`-separated paths, the `service-under` key
+    /// restricts the results to items whose service is the given path or is below
+    /// it: `org/app` matches `org/app` and `org/app/component` but not `org/apps`.
+    ///
+    /// The `class` key says which kind of password items to search: `generic`
+    /// (the default), `internet`, or `any`. Internet passwords (such as those
+    /// saved by browsers and other network clients) have a server rather than a service,
+    /// and `service`, `user`, and `service-under` match their server and account.
+    /// Each is returned as an [InternetCred] wrapper, so code that needs to
+    /// tell the kinds apart can downcast each entry's credential.
+    ///
+    /// The `case-sensitive` and `diacritic-sensitive` keys (value true or false,
+    /// default true) can loosen how `service` and `user` are matched, so that
+    /// `Café` finds `cafe`, say. (`service-under` still matches exactly.)
+    ///
+    /// For type-ahead pickers, the `service-prefix` and `user-prefix` keys
+    /// restrict the results to items whose service or user starts with the
+    /// given text, and the `contains` key to items whose service or user
+    /// contains it. These are matched after the search, ignoring case if
+    /// `case-sensitive` is false.
+    ///
+    /// The `limit` and `offset` keys (numbers of entries) ask for one page of
+    /// the results: the first `limit` of them after skipping `offset`. When
+    /// either is given, the results are sorted by service and user, so paging
+    /// through an unchanging keychain neither repeats nor skips an entry.
+    ///
+    /// The `sort` key orders the results by date instead: `modified-desc`
+    /// puts the most recently changed items first, and `modified-asc`,
+    /// `created-desc`, and `created-asc` work likewise. Sorting happens
+    /// before paging.
+    fn search(&self, spec: &HashMap<&str, &str>) -> Result<Vec<Entry>> {
+        let spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        check_bounded(&spec, RESTRICTING_KEYS, self.allow_unbounded_search)?;
+        timeout::run("search", self.clone(), move |store| {
+            store.find_entries(&spec)
+        })
+    }
+
+    /// Return the underlying builder object with an `Any` type so that it can
+    /// be downgraded to a [Store] for platform-specific processing.
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    //// See the keychain-core API docs.
+    fn persistence(&self) -> CredentialPersistence {
+        CredentialPersistence::UntilDelete
+    }
+
+    /// See the keychain-core API docs.
+    fn debug_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl AppleStoreExt for Store {
+    fn count(&self, spec: &HashMap<&str, &str>) -> Result<usize> {
+        Ok(self.search(spec)?.len())
+    }
+
+    fn wipe(&self, spec: &HashMap<&str, &str>) -> Result<usize> {
+        let entries = self.search(spec)?;
+        for entry in entries.iter() {
+            entry.delete_credential()?;
+        }
+        Ok(entries.len())
+    }
+
+    fn update_attributes_matching(
+        &self,
+        spec: &HashMap<&str, &str>,
+        attrs: &HashMap<&str, &str>,
+    ) -> Result<usize> {
+        Store::update_attributes_matching(self, spec, attrs)
+    }
+
+    fn delete_matching(&self, spec: &HashMap<&str, &str>) -> Result<usize> {
+        Store::delete_matching(self, spec)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::from_keys(CONFIGURATION_KEYS, MODIFIER_KEYS, SEARCH_KEYS)
+    }
+
+    fn provider_info(&self) -> ProviderInfo {
+        ProviderInfo::new(Backend::Keychain, self.capabilities())
+    }
+
+    fn diagnostics(&self) -> Vec<(String, String)> {
+        let mut diagnostics = common_diagnostics(self.vendor(), self.id());
+        diagnostics.push(("keychain".to_string(), self.keychain.to_string()));
+        diagnostics
+    }
+
+    fn is_available(&self) -> Availability {
+        Store::is_available(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The four pre-defined Mac keychains, and the user's default keychain.
+pub enum MacKeychainDomain {
+    User,
+    System,
+    Common,
+    Dynamic,
+    /// The keychain the user has chosen as their default, which
+    /// needn't be the User (login) keychain.
+    Default,
+}
+
+impl std::fmt::Display for MacKeychainDomain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MacKeychainDomain::User => "User".fmt(f),
+            MacKeychainDomain::System => "System".fmt(f),
+            MacKeychainDomain::Common => "Common".fmt(f),
+            MacKeychainDomain::Dynamic => "Dynamic".fmt(f),
+            MacKeychainDomain::Default => "Default".fmt(f),
+        }
+    }
+}
+
+impl std::str::FromStr for MacKeychainDomain {
+    type Err = ErrorCode;
+
+    /// Convert a keychain specification string to a keychain domain.
+    ///
+    /// We accept any case in the string,
+    /// but the value has to match a known keychain domain name.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "user" => Ok(MacKeychainDomain::User),
+            "system" => Ok(MacKeychainDomain::System),
+            "common" => Ok(MacKeychainDomain::Common),
+            "dynamic" => Ok(MacKeychainDomain::Dynamic),
+            "default" => Ok(MacKeychainDomain::Default),
+            _ => Err(ErrorCode::Invalid(
+                "keychain".to_string(),
+                format!("'{s}' is not User, System, Common, Dynamic, or Default"),
+            )),
+        }
+    }
+}
+
+/// A low-level query over the generic password items in a keychain.
+///
+/// Start with every item in a keychain and narrow the match with the setters,
+/// then run the query with [find](Query::find), [entries](Query::entries), or
+/// [secret](Query::secret). Failures are mapped to keyring errors the same way
+/// the store maps them.
+#[derive(Debug, Clone)]
+pub struct Query {
+    keychain: MacKeychainDomain,
+    service: Option<String>,
+    account: Option<String>,
+    label: Option<String>,
+    invisible: Option<bool>,
+    limit: Option<u32>,
+}
+
+impl Query {
+    /// A query that matches every item in the given keychain.
+    pub fn new(keychain: MacKeychainDomain) -> Self {
+        Query {
+            keychain,
+            service: None,
+            account: None,
+            label: None,
+            invisible: None,
+            limit: None,
+        }
+    }
+
+    /// Match items with this service.
+    pub fn service(&mut self, service: &str) -> &mut Self {
+        self.service = Some(service.to_string());
+        self
+    }
+
+    /// Match items with this account.
+    pub fn account(&mut self, account: &str) -> &mut Self {
+        self.account = Some(account.to_string());
+        self
+    }
+
+    /// Match items with this label.
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Match only items that other tools have (or haven't) marked invisible.
+    pub fn invisible(&mut self, invisible: bool) -> &mut Self {
+        self.invisible = Some(invisible);
+        self
+    }
+
+    /// Match at most this many items, which must be at least 1.
+    pub fn limit(&mut self, limit: u32) -> &mut Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// The attributes of the matching items.
+    ///
+    /// Finding no items is not an error; it returns an empty list.
+    pub fn find(&self) -> Result<Vec<ItemAttributes>> {
+        let mut query = RawQuery::generic_password();
+        query
+            .keychains(&[get_keychain(&self.keychain)?])
+            .return_attributes();
+        match self.limit {
+            Some(0) => {
+                return Err(ErrorCode::Invalid(
+                    "limit".to_string(),
+                    "must be at least 1".to_string(),
+                ));
+            }
+            Some(limit) => query.limit(limit),
+            None => query.limit_all(),
+        };
+        for (attr, value) in [
+            (Attr::Service, &self.service),
+            (Attr::Account, &self.account),
+            (Attr::Label, &self.label),
+        ] {
+            if let Some(value) = value {
+                query.string(attr, value);
+            }
+        }
+        if let Some(invisible) = self.invisible {
+            query.boolean(Attr::IsInvisible, invisible);
+        }
+        let found = query.find().map_err(decode_error)?;
+        Ok(found.iter().map(|attrs| attrs.item()).collect())
+    }
+
+    /// Wrappers for the matching items, as [search](Store::search) would return.
+    ///
+    /// Items with an empty service or account are left out, since they
+    /// can't be wrapped.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for item in self.find()? {
+            if let Ok(mut cred) = Cred::new(self.keychain.clone(), &item.service, &item.account) {
+                cred.app_gated = item.generic.as_deref() == Some(APP_GATED_TAG.as_bytes());
+                entries.push(Entry::new_with_credential(Arc::new(cred)));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The secret of the one matching item.
+    ///
+    /// This fails with `NoEntry` if no item matches, and with `Ambiguous`
+    /// if more than one does. Reading the secret of an app-gated item
+    /// has to be approved by the app gate, as usual.
+    pub fn secret(&self) -> Result<Vec<u8>> {
+        let mut entries = self.entries()?;
+        match entries.len() {
+            0 => Err(ErrorCode::NoEntry),
+            1 => entries.remove(0).get_secret(),
+            _ => Err(ErrorCode::Ambiguous(entries)),
+        }
+    }
+}
+
+/// A read of a bootstrap credential from the System keychain.
+///
+/// See the [module docs](self#daemons). While the read is in progress, keychain
+/// UI is disabled for the whole process, so any other thread's keychain call that
+/// would prompt fails instead.
+#[derive(Debug, Clone)]
+pub struct Bootstrap {
+    service: String,
+    account: String,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Bootstrap {
+    /// A read of the item with this service and account, which makes 10
+    /// attempts, 3 seconds apart.
+    pub fn new(service: &str, account: &str) -> Self {
+        Bootstrap {
+            service: service.to_string(),
+            account: account.to_string(),
+            attempts: 10,
+            retry_delay: Duration::from_secs(3),
+        }
+    }
+
+    /// Make at most this many attempts (at least 1) while the keychain is locked.
+    pub fn attempts(&mut self, attempts: u32) -> &mut Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait this long between attempts.
+    pub fn retry_delay(&mut self, delay: Duration) -> &mut Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Read the secret.
+    ///
+    /// An attempt that finds the System keychain locked (or not yet
+    /// available, as early in boot) is retried; any other failure ends the read.
+    pub fn read(&self) -> std::result::Result<Vec<u8>, BootstrapError> {
+        let cred = Cred::new(MacKeychainDomain::System, &self.service, &self.account)
+            .map_err(BootstrapError::Failed)?;
+        let _no_ui = if SecKeychain::user_interaction_allowed().unwrap_or(true) {
+            Some(
+                SecKeychain::disable_user_interaction()
+                    .map_err(|err| BootstrapError::Failed(decode_error(err)))?,
+            )
+        } else {
+            None
+        };
+        for attempt in 1..=self.attempts {
+            if attempt > 1 {
+                std::thread::sleep(self.retry_delay);
+            }
+            let keychain = match cred.get_keychain() {
+                Ok(keychain) => keychain,
+                Err(err) if is_unavailable(&err) => continue,
+                Err(err) => return Err(BootstrapError::Failed(err)),
+            };
+            if is_locked(&keychain) {
+                debug!("The System keychain is locked (attempt {attempt})");
+                continue;
+            }
+            match timeout::run("get_secret", cred.clone(), Cred::read_secret) {
+                Ok(secret) => return Ok(secret),
+                Err(ErrorCode::NoEntry) => return Err(BootstrapError::NotFound),
+                Err(err) if is_unavailable(&err) => continue,
+                Err(err) => {
+                    return Err(match crate::error::error_kind(&err) {
+                        // the keychain is unlocked, so it's the item that wants to prompt
+                        Some(crate::error::ErrorKind::InteractionNotAllowed) => {
+                            BootstrapError::NeedsApproval
+                        }
+                        _ => BootstrapError::Failed(err),
+                    });
+                }
+            }
+        }
+        Err(BootstrapError::Locked {
+            attempts: self.attempts,
+        })
+    }
+}
+
+/// Why a [Bootstrap] read failed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BootstrapError {
+    /// The System keychain has no such item.
+    NotFound,
+    /// The System keychain was locked or unavailable on every attempt.
+    Locked { attempts: u32 },
+    /// The item's access control doesn't let this process read it without a prompt.
+    NeedsApproval,
+    /// Any other failure, such as not being allowed to open the System keychain.
+    Failed(ErrorCode),
+}
+
+impl std::fmt::Display for BootstrapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BootstrapError::NotFound => write!(f, "The bootstrap credential doesn't exist"),
+            BootstrapError::Locked { attempts } => write!(
+                f,
+                "The System keychain was still locked after {attempts} attempts"
+            ),
+            BootstrapError::NeedsApproval => write!(
+                f,
+                "Reading the bootstrap credential needs approval from a user"
+            ),
+            BootstrapError::Failed(err) => {
+                write!(f, "Couldn't read the bootstrap credential: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BootstrapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BootstrapError::Failed(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Whether an error means the keychain isn't available yet.
+fn is_unavailable(err: &ErrorCode) -> bool {
+    crate::error::error_kind(err) == Some(crate::error::ErrorKind::NotAvailable)
+}
+
+/// The path of a keychain's file.
+fn keychain_path(keychain: &SecKeychain) -> Result<PathBuf> {
+    let mut path = vec![0u8; 1024];
+    let mut length = path.len() as u32;
+    // SAFETY: the keychain is valid, and the length is that of the path buffer.
+    let status = unsafe {
+        SecKeychainGetPath(
+            keychain.as_concrete_TypeRef(),
+            &mut length,
+            path.as_mut_ptr().cast(),
+        )
+    };
+    if status != 0 {
+        return Err(decode_error(Error::from_code(status)));
+    }
+    path.truncate(length as usize);
+    Ok(PathBuf::from(OsString::from_vec(path)))
+}
+
+/// Whether a keychain is locked, treating an unknown status as unlocked.
+fn is_locked(keychain: &SecKeychain) -> bool {
+    const UNLOCKED: u32 = 1; // kSecUnlockStateStatus
+    let mut status = 0u32;
+    // SAFETY: the keychain is valid, and the status is a valid place to write.
+    let result = unsafe { SecKeychainGetStatus(keychain.as_concrete_TypeRef(), &mut status) };
+    result == 0 && status & UNLOCKED == 0
+}
+
+/// An inventory of a keychain's items, as taken by [snapshot](Store::snapshot).
+///
+/// With the `serde` feature, snapshots can be serialized, so they can be
+/// saved and compared with a snapshot taken in a later run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    /// The keychain the snapshot is of.
+    pub keychain: String,
+    pub taken_at: SystemTime,
+    /// The items, sorted by service and then account.
+    pub items: Vec<SnapshotItem>,
+}
+
+/// One item in a [Snapshot].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SnapshotItem {
+    pub service: String,
+    pub account: String,
+    pub label: Option<String>,
+    pub created: Option<SystemTime>,
+    pub modified: Option<SystemTime>,
+    /// The hex-encoded SHA-256 of the item's data, if it could be read.
+    pub checksum: Option<String>,
+}
+
+/// The differences between two snapshots, as found by [diff].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    /// Items only in the later snapshot.
+    pub added: Vec<SnapshotItem>,
+    /// Items only in the earlier snapshot.
+    pub removed: Vec<SnapshotItem>,
+    /// Items in both snapshots whose label, dates, or checksum differ,
+    /// as (earlier, later) pairs.
+    pub changed: Vec<(SnapshotItem, SnapshotItem)>,
+}
+
+/// Compare an earlier snapshot with a later one.
+///
+/// Items are matched by their service and account.
+pub fn diff(before: &Snapshot, after: &Snapshot) -> SnapshotDiff {
+    let key = |item: &SnapshotItem| (item.service.clone(), item.account.clone());
+    let earlier: HashMap<_, _> = before.items.iter().map(|item| (key(item), item)).collect();
+    let later: HashMap<_, _> = after.items.iter().map(|item| (key(item), item)).collect();
+    let mut result = SnapshotDiff::default();
+    for item in after.items.iter() {
+        match earlier.get(&key(item)) {
+            None => result.added.push(item.clone()),
+            Some(old) if *old != item => result.changed.push(((*old).clone(), item.clone())),
+            Some(_) => {}
+        }
+    }
+    for item in before.items.iter() {
+        if !later.contains_key(&key(item)) {
+            result.removed.push(item.clone());
+        }
+    }
+    result
+}
+
+/// The hex-encoded SHA-256 digest of some data.
+fn sha256_hex(data: &[u8]) -> String {
+    let mut digest = [0u8; 32];
+    // SAFETY: the data pointer and length describe a valid buffer, and the
+    // digest buffer has room for the 32 bytes of a SHA-256 digest.
+    unsafe { CC_SHA256(data.as_ptr().cast(), data.len() as u32, digest.as_mut_ptr()) };
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// CommonCrypto is part of libSystem, so there's nothing extra to link.
+unsafe extern "C" {
+    fn CC_SHA256(data: *const c_void, len: u32, md: *mut u8) -> *mut u8;
+}
+
+// This isn't in the public headers, but it's what `security set-keychain-password` uses.
+#[cfg(feature = "unstable-change-password")]
+#[link(name = "Security", kind = "framework")]
+unsafe extern "C" {
+    fn SecKeychainChangePassword(
+        keychain: SecKeychainRef,
+        old_password_length: u32,
+        old_password: *const c_void,
+        new_password_length: u32,
+        new_password: *const c_void,
+    ) -> i32;
+}
+
+#[link(name = "Security", kind = "framework")]
+unsafe extern "C" {
+    fn SecKeychainGetStatus(keychain: SecKeychainRef, status: *mut u32) -> i32;
+    fn SecKeychainGetPath(keychain: SecKeychainRef, length: *mut u32, path: *mut c_char) -> i32;
+}
+
+// The SecAccess and SecACL calls are deprecated, but they are still the only
+// way to get at an item's partition IDs.
+#[link(name = "Security", kind = "framework")]
+unsafe extern "C" {
+    static kSecACLAuthorizationPartitionID: CFStringRef;
+    fn SecKeychainItemCopyAccess(item: SecKeychainItemRef, access: *mut SecAccessRef) -> i32;
+    fn SecAccessCopyMatchingACLList(
+        access: SecAccessRef,
+        authorization_tag: CFTypeRef,
+    ) -> CFArrayRef;
+    fn SecACLCopyContents(
+        acl: CFTypeRef,
+        application_list: *mut CFArrayRef,
+        description: *mut CFStringRef,
+        prompt_selector: *mut u16,
+    ) -> i32;
+    fn SecACLSetContents(
+        acl: CFTypeRef,
+        application_list: CFArrayRef,
+        description: CFStringRef,
+        prompt_selector: u16,
+    ) -> i32;
+    fn SecAccessCreate(
+        descriptor: CFStringRef,
+        trusted_list: CFArrayRef,
+        access: *mut SecAccessRef,
+    ) -> i32;
+    fn SecTrustedApplicationCreateFromPath(path: *const c_char, app: *mut CFTypeRef) -> i32;
+    fn SecKeychainItemSetAccessWithPassword(
+        item: SecKeychainItemRef,
+        access: SecAccessRef,
+        password_length: u32,
+        password: *const c_void,
+    ) -> i32;
+}
+
+/// Look up the attributes of the given services' items on a background thread.
+fn prefetch(services: &str, domain: MacKeychainDomain) {
+    let services: Vec<String> = services
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+    if services.is_empty() {
+        return;
+    }
+    let spawned = std::thread::Builder::new()
+        .name("keyring-prefetch".to_string())
+        .spawn(move || {
+            let keychains = match get_keychain(&domain) {
+                Ok(keychain) => [keychain],
+                Err(err) => {
+                    debug!("Couldn't open the {domain} keychain to prefetch: {err:?}");
+                    return;
+                }
+            };
+            for service in services {
+                let mut query = RawQuery::generic_password();
+                query
+                    .keychains(&keychains)
+                    .limit_all()
+                    .return_attributes()
+                    .string(Attr::Service, &service);
+                match query.find() {
+                    Ok(items) => debug!("Prefetched {} items for {service}", items.len()),
+                    Err(err) => debug!("Couldn't prefetch items for {service}: {err:?}"),
+                }
+            }
+        });
+    if let Err(err) = spawned {
+        debug!("Couldn't start the prefetch thread: {err:?}");
+    }
+}
+
+/// Change the password of a keychain file. See [Store::change_password].
+#[cfg(feature = "unstable-change-password")]
+pub(crate) fn change_keychain_password(keychain: &SecKeychain, old: &str, new: &str) -> Result<()> {
+    let old_len = u32::try_from(old.len())
+        .map_err(|_| ErrorCode::TooLong("old password".to_string(), u32::MAX))?;
+    let new_len = u32::try_from(new.len())
+        .map_err(|_| ErrorCode::TooLong("new password".to_string(), u32::MAX))?;
+    // SAFETY: the keychain is valid, and each pointer is valid for the given length.
+    let status = unsafe {
+        SecKeychainChangePassword(
+            keychain.as_concrete_TypeRef(),
+            old_len,
+            old.as_ptr().cast(),
+            new_len,
+            new.as_ptr().cast(),
+        )
+    };
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(decode_error(Error::from_code(status)))
     }
 }
 
@@ -350,6 +2679,7 @@ fn get_keychain(domain: &MacKeychainDomain) -> Result<SecKeychain> {
         MacKeychainDomain::System => SecPreferencesDomain::System,
         MacKeychainDomain::Common => SecPreferencesDomain::Common,
         MacKeychainDomain::Dynamic => SecPreferencesDomain::Dynamic,
+        MacKeychainDomain::Default => return SecKeychain::default().map_err(decode_error),
     };
     match SecKeychain::default_for_domain(domain) {
         Ok(keychain) => Ok(keychain),
@@ -364,6 +2694,7 @@ fn get_keychain(domain: &MacKeychainDomain) -> Result<SecKeychain> {
 pub fn decode_error(err: Error) -> ErrorCode {
     match err.code() {
         -61 => ErrorCode::NoStorageAccess(Box::new(err)), // Write permissions error
+        -128 => AuthenticationCanceled::error(CanceledBy::User), // errSecUserCanceled
         -25291 => ErrorCode::NoStorageAccess(Box::new(err)), // errSecNotAvailable
         -25292 => ErrorCode::NoStorageAccess(Box::new(err)), // errSecReadOnly
         -25294 => ErrorCode::NoStorageAccess(Box::new(err)), // errSecNoSuchKeychain
@@ -1,11 +1,28 @@
 use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::panic::{AssertUnwindSafe, catch_unwind};
 use std::sync::{Arc, Once};
+use std::time::Duration;
 
 use log::debug;
 
 use keyring_core::{CredentialStore, Entry, Error, api::CredentialPersistence, get_default_store};
 
-use super::keychain::{Cred, Store};
+use super::background;
+use super::error::{
+    AuthenticationCanceled, BiometryLockedOut, CanceledBy, ErrorKind, KeychainLocked,
+    MainThreadPrompt, PasscodeNotSet, PromptRateLimited, biometry_locked_out, canceled,
+    decode_osstatus, error_kind, keychain_locked, main_thread_prompt, passcode_not_set,
+    prompt_rate_limited,
+};
+use super::ext::{Availability, Backend, apple_store};
+use super::keychain::{self, Cred, GateOperation, Store, set_app_gate};
+use super::os_version;
+use super::query::{Attr, Query};
+use super::refresh::Refresher;
+use super::testkit::ScopedNamespace;
+use super::timeout;
+use super::validate::{Finding, Severity, validate_configuration};
 
 static SET_STORE: Once = Once::new();
 
@@ -301,6 +318,26 @@ fn test_simultaneous_independent_create_set() {
     }
 }
 
+#[test]
+fn test_simultaneous_set_same_entry() {
+    let name = generate_random_string();
+    let mut handles = vec![];
+    for i in 0..10 {
+        let name = name.clone();
+        let test = move || {
+            let entry = entry_new(&name, &name);
+            entry.set_password(&format!("writer{i}")).unwrap();
+        };
+        handles.push(std::thread::spawn(test))
+    }
+    for handle in handles {
+        handle.join().unwrap()
+    }
+    let entry = entry_new(&name, &name);
+    assert!(entry.get_password().unwrap().starts_with("writer"));
+    entry.delete_credential().unwrap();
+}
+
 #[test]
 fn test_multiple_create_delete_single_thread() {
     let name = generate_random_string();
@@ -375,6 +412,1362 @@ fn test_search() {
     e2.delete_credential().unwrap();
 }
 
+#[test]
+fn test_search_include_invisible() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("visible item").unwrap();
+    // other tools mark some of their items invisible
+    let mut invisible = Query::generic_password();
+    invisible
+        .string(Attr::Service, &name)
+        .string(Attr::Account, "hidden")
+        .boolean(Attr::IsInvisible, true);
+    let mut adding = Query::generic_password();
+    adding
+        .string(Attr::Service, &name)
+        .string(Attr::Account, "hidden")
+        .boolean(Attr::IsInvisible, true)
+        .value(b"invisible item");
+    adding.add().unwrap();
+    let users = |spec: &HashMap<&str, &str>| {
+        let mut users: Vec<String> = Entry::search(spec)
+            .unwrap()
+            .iter()
+            .map(|e| e.get_specifiers().unwrap().1)
+            .collect();
+        users.sort();
+        users
+    };
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert_eq!(users(&spec), vec![name.clone()]);
+    let spec = HashMap::from([("service", name.as_str()), ("include-invisible", "true")]);
+    // the visible item is found exactly once, even though both searches are run
+    let mut both = vec![name.clone(), "hidden".to_string()];
+    both.sort();
+    assert_eq!(users(&spec), both);
+    let spec = HashMap::from([("include-invisible", "maybe")]);
+    assert!(matches!(Entry::search(&spec), Err(Error::Invalid(_, _))));
+    invisible.delete().unwrap();
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_scoped_namespace() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let store = get_default_store().unwrap();
+    let kept = generate_random_string();
+    let outside = entry_new(&kept, &kept);
+    outside.set_password("outside").unwrap();
+    let namespace = ScopedNamespace::new(&store, "keyring-test").unwrap();
+    assert!(namespace.service("a").starts_with(namespace.prefix()));
+    let entry = namespace.entry("a", "user").unwrap();
+    entry.set_password("inside").unwrap();
+    namespace
+        .entry("b/c", "user")
+        .unwrap()
+        .set_password("inside")
+        .unwrap();
+    // a second namespace doesn't share the first one's prefix
+    let other = ScopedNamespace::new(&store, "keyring-test").unwrap();
+    assert_ne!(other.prefix(), namespace.prefix());
+    drop(other);
+    assert_eq!(entry.get_password().unwrap(), "inside");
+    // items are cleaned up even when the test panics
+    let prefix = namespace.prefix().to_string();
+    let result = catch_unwind(AssertUnwindSafe(move || {
+        let _namespace = namespace;
+        panic!("test failure");
+    }));
+    assert!(result.is_err());
+    assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+    let spec = HashMap::from([("service-under", prefix.as_str())]);
+    assert_eq!(apple_store(&store).unwrap().count(&spec).unwrap(), 0);
+    assert_eq!(outside.get_password().unwrap(), "outside");
+    outside.delete_credential().unwrap();
+}
+
+#[test]
+fn test_search_internet_passwords() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let internet = keychain::InternetCred {
+        domain: keychain::MacKeychainDomain::User,
+        server: name.clone(),
+        account: name.clone(),
+        protocol: Some("htps".to_string()),
+        port: Some(443),
+        path: None,
+        security_domain: None,
+        authentication_type: None,
+        redaction: Default::default(),
+    };
+    let internet = Entry::new_with_credential(Arc::new(internet));
+    internet.set_password("internet").unwrap();
+    let generic = entry_new(&name, &name);
+    generic.set_password("generic").unwrap();
+    let count = |class: &str| {
+        let spec = HashMap::from([("service", name.as_str()), ("class", class)]);
+        Entry::search(&spec).unwrap().len()
+    };
+    assert_eq!(count("generic"), 1);
+    assert_eq!(count("any"), 2);
+    let spec = HashMap::from([("service", name.as_str()), ("class", "internet")]);
+    let found = Entry::search(&spec).unwrap();
+    assert_eq!(found.len(), 1);
+    let cred = found[0]
+        .as_any()
+        .downcast_ref::<keychain::InternetCred>()
+        .unwrap();
+    assert_eq!(cred.protocol.as_deref(), Some("htps"));
+    assert_eq!(cred.port, Some(443));
+    assert_eq!(found[0].get_password().unwrap(), "internet");
+    let attrs = found[0].get_attributes().unwrap();
+    assert_eq!(attrs.get("port").map(String::as_str), Some("443"));
+    found[0].set_password("updated").unwrap();
+    assert_eq!(internet.get_password().unwrap(), "updated");
+    let spec = HashMap::from([("service", name.as_str()), ("class", "certificate")]);
+    assert!(matches!(Entry::search(&spec), Err(Error::Invalid(key, _)) if key == "class"));
+    found[0].delete_credential().unwrap();
+    assert!(matches!(internet.get_password(), Err(Error::NoEntry)));
+    assert_eq!(count("any"), 1);
+    generic.delete_credential().unwrap();
+}
+
+#[test]
+fn test_update_attributes_matching() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let e1 = entry_new(&name, "user1");
+    e1.set_password("e1").unwrap();
+    let e2 = entry_new(&name, "user2");
+    e2.set_password("e2").unwrap();
+    let store = Store::new().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let attrs = HashMap::from([("label", "relabeled"), ("comment", "rebranded")]);
+    assert_eq!(store.update_attributes_matching(&spec, &attrs).unwrap(), 2);
+    let mut query = Query::generic_password();
+    query
+        .limit_all()
+        .return_attributes()
+        .string(Attr::Service, &name);
+    let found = query.find().unwrap();
+    assert_eq!(found.len(), 2);
+    for attrs in found {
+        assert_eq!(attrs.string(Attr::Label).as_deref(), Some("relabeled"));
+        assert_eq!(attrs.string(Attr::Comment).as_deref(), Some("rebranded"));
+    }
+    // secrets are untouched
+    assert_eq!(e1.get_password().unwrap(), "e1");
+    let spec = HashMap::from([("service", "no such service")]);
+    assert_eq!(store.update_attributes_matching(&spec, &attrs).unwrap(), 0);
+    let empty = HashMap::new();
+    assert!(matches!(
+        store.update_attributes_matching(&spec, &empty),
+        Err(Error::Invalid(_, _))
+    ));
+    let bad = HashMap::from([("account", "x")]);
+    assert!(matches!(
+        store.update_attributes_matching(&spec, &bad),
+        Err(Error::Invalid(_, _))
+    ));
+    e1.delete_credential().unwrap();
+    e2.delete_credential().unwrap();
+}
+
+#[test]
+fn test_set_and_get_many() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let store = Store::new().unwrap();
+    let results = store
+        .set_many(&[
+            (&name, "user1", b"one"),
+            ("", "user2", b"two"),
+            (&name, "user3", b"three"),
+        ])
+        .unwrap();
+    assert!(results[0].is_ok());
+    assert!(matches!(&results[1], Err(Error::Invalid(key, _)) if key == "service"));
+    assert!(results[2].is_ok());
+    let secrets = store
+        .get_many(&[(&name, "user1"), (&name, "user2"), (&name, "user3")])
+        .unwrap();
+    assert_eq!(secrets[0].as_ref().unwrap(), b"one");
+    assert!(matches!(secrets[1], Err(Error::NoEntry)));
+    assert_eq!(secrets[2].as_ref().unwrap(), b"three");
+    entry_new(&name, "user1").delete_credential().unwrap();
+    entry_new(&name, "user3").delete_credential().unwrap();
+}
+
+#[test]
+fn test_delete_prefixed() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let device = format!("{name}.device");
+    let entries: Vec<Entry> = [
+        format!("{name}.mail"),
+        format!("{name}.chat"),
+        device.clone(),
+    ]
+    .iter()
+    .map(|service| entry_new(service, "user"))
+    .collect();
+    for entry in entries.iter() {
+        entry.set_password("signed in").unwrap();
+    }
+    let outside = entry_new(&generate_random_string(), "user");
+    outside.set_password("outside").unwrap();
+    let store = Store::new().unwrap();
+    let prefix = format!("{name}.");
+    assert_eq!(store.delete_prefixed(&prefix, &[&device]).unwrap(), 2);
+    assert!(matches!(entries[0].get_password(), Err(Error::NoEntry)));
+    assert!(matches!(entries[1].get_password(), Err(Error::NoEntry)));
+    assert_eq!(entries[2].get_password().unwrap(), "signed in");
+    assert_eq!(outside.get_password().unwrap(), "outside");
+    assert!(matches!(
+        store.delete_prefixed("", &[]),
+        Err(Error::Invalid(key, _)) if key == "service_prefix"
+    ));
+    entries[2].delete_credential().unwrap();
+    outside.delete_credential().unwrap();
+}
+
+#[test]
+fn test_delete_all() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let mail = generate_random_string();
+    let chat = generate_random_string();
+    let erased = [
+        entry_new(&mail, "user1"),
+        entry_new(&mail, "user2"),
+        entry_new(&chat, "user1"),
+    ];
+    for entry in erased.iter() {
+        entry.set_password("signed in").unwrap();
+    }
+    let outside = entry_new(&generate_random_string(), "user1");
+    outside.set_password("outside").unwrap();
+    let store = Store::new().unwrap();
+    assert_eq!(store.delete_all(&[&mail, &chat]).unwrap(), 3);
+    for entry in erased.iter() {
+        assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+    }
+    assert_eq!(outside.get_password().unwrap(), "outside");
+    // there's no deleting everything in the keychain
+    assert!(matches!(
+        store.delete_all(&[]),
+        Err(Error::Invalid(key, _)) if key == "services"
+    ));
+    assert!(matches!(
+        store.delete_all(&[&mail, ""]),
+        Err(Error::Invalid(key, _)) if key == "services"
+    ));
+    outside.delete_credential().unwrap();
+}
+
+#[test]
+fn test_delete_matching() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let other = generate_random_string();
+    let e1 = entry_new(&name, "user1");
+    e1.set_password("e1").unwrap();
+    let e2 = entry_new(&name, "user2");
+    e2.set_password("e2").unwrap();
+    let kept = entry_new(&other, "user1");
+    kept.set_password("kept").unwrap();
+    let store = Store::new().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert_eq!(store.delete_matching(&spec).unwrap(), 2);
+    assert!(matches!(e1.get_password(), Err(Error::NoEntry)));
+    assert!(matches!(e2.get_password(), Err(Error::NoEntry)));
+    assert_eq!(kept.get_password().unwrap(), "kept");
+    assert_eq!(store.delete_matching(&spec).unwrap(), 0);
+    kept.delete_credential().unwrap();
+}
+
+#[test]
+fn test_set_secret_updates_in_place() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("first").unwrap();
+    let store = Store::new().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let attrs = HashMap::from([("label", "set elsewhere")]);
+    assert_eq!(store.update_attributes_matching(&spec, &attrs).unwrap(), 1);
+    entry.set_password("second").unwrap();
+    let mut query = Query::generic_password();
+    query.return_attributes().string(Attr::Service, &name);
+    let found = query.find().unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(
+        found[0].string(Attr::Label).as_deref(),
+        Some("set elsewhere")
+    );
+    assert_eq!(entry.get_password().unwrap(), "second");
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_create_only() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let mods = HashMap::from([("create-only", "true")]);
+    let entry = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    entry.set_password("first").unwrap();
+    let err = entry.set_password("second").unwrap_err();
+    assert_eq!(error_kind(&err), Some(ErrorKind::DuplicateItem));
+    assert_eq!(entry.get_password().unwrap(), "first");
+    // a plain entry for the same item still replaces its secret
+    entry_new(&name, &name).set_password("third").unwrap();
+    assert_eq!(entry.get_password().unwrap(), "third");
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_decode_osstatus() {
+    let kind = decode_osstatus(-25300);
+    assert_eq!(kind, ErrorKind::ItemNotFound);
+    assert_eq!(kind.code(), -25300);
+    assert_eq!(kind.name(), Some("errSecItemNotFound"));
+    assert!(
+        kind.description()
+            .starts_with("errSecItemNotFound (-25300)")
+    );
+    let kind = decode_osstatus(12345);
+    assert_eq!(kind, ErrorKind::Other(12345));
+    assert_eq!(kind.name(), None);
+    assert!(kind.description().starts_with("OSStatus (12345)"));
+    let err = keychain::decode_error(security_framework::base::Error::from_code(-25308));
+    assert_eq!(error_kind(&err), Some(ErrorKind::InteractionNotAllowed));
+    assert_eq!(error_kind(&Error::NoEntry), Some(ErrorKind::ItemNotFound));
+    assert_eq!(error_kind(&Error::NoDefaultStore), None);
+}
+
+#[test]
+fn test_canceled() {
+    let err = keychain::decode_error(security_framework::base::Error::from_code(-128));
+    assert!(matches!(err, Error::NoStorageAccess(_)));
+    assert_eq!(canceled(&err).map(|c| c.by), Some(CanceledBy::User));
+    assert_eq!(error_kind(&err), Some(ErrorKind::UserCanceled));
+    let err = keychain::decode_error(security_framework::base::Error::from_code(-25308));
+    assert_eq!(canceled(&err), None);
+    assert_eq!(
+        AuthenticationCanceled::of_la_error(-4).map(|c| c.by),
+        Some(CanceledBy::System)
+    );
+    assert_eq!(AuthenticationCanceled::of_la_error(-1), None);
+}
+
+#[test]
+fn test_biometry_locked_out() {
+    let err = Error::NoStorageAccess(Box::new(BiometryLockedOut));
+    assert!(biometry_locked_out(&err));
+    assert_eq!(error_kind(&err), Some(ErrorKind::AuthFailed));
+    assert!(err.to_string().contains("passcode"));
+    let err = keychain::decode_error(security_framework::base::Error::from_code(-25293));
+    assert!(!biometry_locked_out(&err));
+    assert!(!biometry_locked_out(&Error::NoEntry));
+}
+
+#[test]
+fn test_keychain_locked() {
+    let err = Error::NoStorageAccess(Box::new(KeychainLocked));
+    assert!(keychain_locked(&err));
+    // code that checks for errSecInteractionNotAllowed keeps working
+    assert_eq!(error_kind(&err), Some(ErrorKind::InteractionNotAllowed));
+    assert!(!passcode_not_set(&err));
+    let err = keychain::decode_error(security_framework::base::Error::from_code(-25308));
+    assert!(!keychain_locked(&err));
+}
+
+#[test]
+fn test_prompt_rate_limited() {
+    let retry_after = std::time::Duration::from_millis(2500);
+    let err = Error::NoStorageAccess(Box::new(PromptRateLimited { retry_after }));
+    assert_eq!(prompt_rate_limited(&err), Some(retry_after));
+    assert!(err.to_string().contains("retry in 2.5 seconds"));
+    assert!(!keychain_locked(&err));
+    assert_eq!(error_kind(&err), None);
+    assert_eq!(prompt_rate_limited(&Error::NoEntry), None);
+}
+
+#[test]
+fn test_main_thread_prompt() {
+    let err = Error::NoStorageAccess(Box::new(MainThreadPrompt));
+    assert!(main_thread_prompt(&err));
+    assert!(err.to_string().contains("background thread"));
+    assert!(canceled(&err).is_none());
+    assert_eq!(error_kind(&err), None);
+    assert!(!main_thread_prompt(&Error::NoEntry));
+}
+
+#[test]
+fn test_passcode_not_set() {
+    let err = Error::NoStorageAccess(Box::new(PasscodeNotSet));
+    assert!(passcode_not_set(&err));
+    assert!(!biometry_locked_out(&err));
+    assert!(err.to_string().contains("set one in Settings"));
+    assert!(!passcode_not_set(&Error::NoEntry));
+}
+
+#[test]
+fn test_user_message() {
+    let kind = ErrorKind::ItemNotFound;
+    assert_eq!(
+        kind.user_message("en"),
+        Some("No saved password was found.")
+    );
+    assert_eq!(kind.user_message("en-US"), kind.user_message("en"));
+    assert_eq!(kind.user_message("fr_CA"), kind.user_message("FR"));
+    assert_ne!(kind.user_message("de"), kind.user_message("en"));
+    assert_eq!(kind.user_message("xx"), kind.user_message("en"));
+    assert_eq!(kind.user_message(""), kind.user_message("en"));
+    assert_eq!(ErrorKind::Param.user_message("en"), None);
+}
+
+#[test]
+fn test_prefetch_configuration() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("prefetched").unwrap();
+    let services = format!("{name}, ,no such service");
+    let config = HashMap::from([("prefetch", services.as_str())]);
+    let store: Arc<CredentialStore> = Store::new_with_configuration(&config).unwrap();
+    let entry2 = store.build(&name, &name, None).unwrap();
+    assert_eq!(entry2.get_password().unwrap(), "prefetched");
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_shared_stores() {
+    let config = HashMap::from([("keychain", "User")]);
+    let store1 = Store::shared(&config).unwrap();
+    let store2 = Store::shared(&config).unwrap();
+    assert!(Arc::ptr_eq(&store1, &store2));
+    let other = HashMap::from([("keychain", "Common")]);
+    let store3 = Store::shared(&other).unwrap();
+    assert!(!Arc::ptr_eq(&store1, &store3));
+    let evicted = Store::evict_shared(&config).unwrap().unwrap();
+    assert!(Arc::ptr_eq(&store1, &evicted));
+    assert!(Store::evict_shared(&config).unwrap().is_none());
+    let store4 = Store::shared(&config).unwrap();
+    assert!(!Arc::ptr_eq(&store1, &store4));
+    Store::evict_all_shared();
+    assert!(Store::evict_shared(&other).unwrap().is_none());
+    let bad = HashMap::from([("keychain", "nowhere")]);
+    assert!(matches!(Store::shared(&bad), Err(Error::Invalid(_, _))));
+    assert!(matches!(
+        Store::evict_shared(&bad),
+        Err(Error::Invalid(_, _))
+    ));
+    // spelling out the defaults (in any case) gets the same store
+    let defaults = Store::shared(&HashMap::new()).unwrap();
+    let spelled = HashMap::from([("keychain", "user"), ("allow-unbounded-search", "true")]);
+    assert!(Arc::ptr_eq(&defaults, &Store::shared(&spelled).unwrap()));
+    assert_eq!(defaults.configuration()["keychain"], "User");
+    // and evicting it by either spelling evicts it for both
+    let evicted = Store::evict_shared(&spelled).unwrap().unwrap();
+    assert!(Arc::ptr_eq(&defaults, &evicted));
+    assert!(Store::evict_shared(&HashMap::new()).unwrap().is_none());
+}
+
+#[test]
+fn test_entry_for() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("from search").unwrap();
+    let found = Entry::search(&HashMap::from([("service", name.as_str())])).unwrap();
+    assert_eq!(found.len(), 1);
+    let cred: &Cred = found[0].as_any().downcast_ref().unwrap();
+    let store = Store::new().unwrap();
+    let rebuilt = store.entry_for(cred.clone()).unwrap();
+    assert_eq!(rebuilt.get_password().unwrap(), "from search");
+    let mut empty = cred.clone();
+    empty.account = String::new();
+    assert!(matches!(store.entry_for(empty), Err(Error::Invalid(_, _))));
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_search_insensitive() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let service = format!("{name}-Café");
+    let entry = entry_new(&service, &name);
+    entry.set_password("loose").unwrap();
+    let lower = format!("{name}-café").to_lowercase();
+    let spec = HashMap::from([("service", lower.as_str())]);
+    assert!(Entry::search(&spec).unwrap().is_empty());
+    let spec = HashMap::from([("service", lower.as_str()), ("case-sensitive", "false")]);
+    assert_eq!(Entry::search(&spec).unwrap().len(), 1);
+    let plain = format!("{name}-cafe").to_lowercase();
+    let spec = HashMap::from([
+        ("service", plain.as_str()),
+        ("case-sensitive", "false"),
+        ("diacritic-sensitive", "false"),
+    ]);
+    let found = Entry::search(&spec).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].get_specifiers().unwrap().0, service);
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_search_prefix_and_contains() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entries = [
+        entry_new(&format!("{name}-mail"), "alice"),
+        entry_new(&format!("{name}-mail"), "bob"),
+        entry_new(&format!("{name}-chat"), "alice"),
+    ];
+    for entry in entries.iter() {
+        entry.set_password("typed").unwrap();
+    }
+    let count = |spec: &[(&str, &str)]| {
+        let spec: HashMap<&str, &str> = spec.iter().cloned().collect();
+        Entry::search(&spec).unwrap().len()
+    };
+    assert_eq!(count(&[("service-prefix", &name)]), 3);
+    let prefix = format!("{name}-m");
+    assert_eq!(count(&[("service-prefix", &prefix)]), 2);
+    assert_eq!(
+        count(&[("service-prefix", &name), ("user-prefix", "al")]),
+        2
+    );
+    assert_eq!(count(&[("service-prefix", &name), ("contains", "ch")]), 1);
+    assert_eq!(count(&[("service-prefix", &name), ("contains", "OB")]), 0);
+    let spec = [
+        ("service-prefix", name.as_str()),
+        ("contains", "OB"),
+        ("case-sensitive", "false"),
+    ];
+    assert_eq!(count(&spec), 1);
+    for entry in entries {
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[test]
+fn test_search_service_under() {
+    let name = generate_random_string();
+    let services = [
+        format!("{name}/app"),
+        format!("{name}/app/component"),
+        format!("{name}/apps"),
+    ];
+    let entries: Vec<Entry> = services.iter().map(|s| entry_new(s, &name)).collect();
+    for entry in entries.iter() {
+        entry.set_password("scoped").unwrap();
+    }
+    let path = format!("{name}/app");
+    let found = Entry::search(&HashMap::from([("service-under", path.as_str())])).unwrap();
+    assert_eq!(found.len(), 2);
+    let path = format!("{name}/");
+    let found = Entry::search(&HashMap::from([("service-under", path.as_str())])).unwrap();
+    assert_eq!(found.len(), 3);
+    let spec = HashMap::from([
+        ("service-under", path.as_str()),
+        ("service", services[2].as_str()),
+    ]);
+    assert_eq!(Entry::search(&spec).unwrap().len(), 1);
+    for entry in entries {
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[test]
+fn test_search_pages() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let users = ["a", "b", "c", "d", "e"];
+    let entries: Vec<Entry> = users.iter().map(|user| entry_new(&name, user)).collect();
+    for entry in entries.iter() {
+        entry.set_password("paged").unwrap();
+    }
+    let page = |offset: &str, limit: &str| -> Vec<String> {
+        let spec = HashMap::from([
+            ("service", name.as_str()),
+            ("offset", offset),
+            ("limit", limit),
+        ]);
+        Entry::search(&spec)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.get_specifiers().unwrap().1)
+            .collect()
+    };
+    assert_eq!(page("0", "2"), ["a", "b"]);
+    assert_eq!(page("2", "2"), ["c", "d"]);
+    assert_eq!(page("4", "2"), ["e"]);
+    assert!(page("5", "2").is_empty());
+    let spec = HashMap::from([("service", name.as_str()), ("limit", "many")]);
+    assert!(matches!(
+        Entry::search(&spec),
+        Err(Error::Invalid(key, _)) if key == "limit"
+    ));
+    for entry in entries {
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[test]
+fn test_search_each() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entries: Vec<Entry> = ["a", "b", "c"]
+        .iter()
+        .map(|user| entry_new(&name, user))
+        .collect();
+    for entry in entries.iter() {
+        entry.set_password("streamed").unwrap();
+    }
+    let store = Store::new().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let mut users = Vec::new();
+    store
+        .search_each(&spec, |entry| {
+            users.push(entry.get_specifiers().unwrap().1);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    users.sort();
+    assert_eq!(users, ["a", "b", "c"]);
+    let mut seen = 0;
+    store
+        .search_each(&spec, |_| {
+            seen += 1;
+            ControlFlow::Break(())
+        })
+        .unwrap();
+    assert_eq!(seen, 1);
+    let spec = HashMap::from([("service", name.as_str()), ("limit", "2")]);
+    let mut paged = Vec::new();
+    store
+        .search_each(&spec, |entry| {
+            paged.push(entry.get_specifiers().unwrap().1);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert_eq!(paged, ["a", "b"]);
+    for entry in entries {
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[test]
+fn test_search_sorted() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entries: Vec<Entry> = ["a", "b", "c"]
+        .iter()
+        .map(|user| entry_new(&name, user))
+        .collect();
+    for entry in entries.iter() {
+        entry.set_password("sorted").unwrap();
+    }
+    // modification dates are kept to the second
+    std::thread::sleep(Duration::from_secs(2));
+    entries[1].set_password("changed").unwrap();
+    let users = |sort: &str| -> Vec<String> {
+        let spec = HashMap::from([("service", name.as_str()), ("sort", sort)]);
+        Entry::search(&spec)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.get_specifiers().unwrap().1)
+            .collect()
+    };
+    assert_eq!(users("modified-desc")[0], "b");
+    assert_eq!(users("modified-asc")[2], "b");
+    let spec = HashMap::from([
+        ("service", name.as_str()),
+        ("sort", "modified-desc"),
+        ("limit", "1"),
+    ]);
+    let first = Entry::search(&spec).unwrap();
+    assert_eq!(first[0].get_specifiers().unwrap().1, "b");
+    let spec = HashMap::from([("service", name.as_str()), ("sort", "newest")]);
+    assert!(matches!(
+        Entry::search(&spec),
+        Err(Error::Invalid(key, _)) if key == "sort"
+    ));
+    for entry in entries {
+        entry.delete_credential().unwrap();
+    }
+}
+
+#[test]
+fn test_app_gated() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let mods = HashMap::from([("app-gated", "true")]);
+    let gated = Entry::new_with_modifiers(&name, &name, &mods).unwrap();
+    // with no gate, everything is denied
+    assert!(matches!(
+        gated.set_password("gated"),
+        Err(Error::NoStorageAccess(_))
+    ));
+    let gate_name = name.clone();
+    set_app_gate(Some(Arc::new(move |request| {
+        request.service == gate_name && request.operation != GateOperation::GetSecret
+    })));
+    gated.set_password("gated").unwrap();
+    assert!(matches!(
+        gated.get_password(),
+        Err(Error::NoStorageAccess(_))
+    ));
+    // the item is marked, so an ungated entry for it is gated too
+    let plain = entry_new(&name, &name);
+    assert!(matches!(
+        plain.get_password(),
+        Err(Error::NoStorageAccess(_))
+    ));
+    let found = Entry::search(&HashMap::from([("service", name.as_str())])).unwrap();
+    let cred: &Cred = found[0].as_any().downcast_ref().unwrap();
+    assert!(cred.app_gated);
+    set_app_gate(Some(Arc::new(|_| true)));
+    assert_eq!(plain.get_password().unwrap(), "gated");
+    // with no gate, items aren't checked for the mark, only gated entries are denied
+    set_app_gate(None);
+    assert_eq!(plain.get_password().unwrap(), "gated");
+    assert!(matches!(
+        gated.get_password(),
+        Err(Error::NoStorageAccess(_))
+    ));
+    set_app_gate(Some(Arc::new(|_| true)));
+    gated.delete_credential().unwrap();
+    set_app_gate(None);
+}
+
+#[cfg(feature = "unstable-change-password")]
+#[link(name = "Security", kind = "framework")]
+unsafe extern "C" {
+    fn SecKeychainLock(keychain: security_framework_sys::base::SecKeychainRef) -> i32;
+    fn SecKeychainDelete(keychain: security_framework_sys::base::SecKeychainRef) -> i32;
+}
+
+#[cfg(feature = "unstable-change-password")]
+#[test]
+fn test_change_password() {
+    use core_foundation::base::TCFType;
+    use security_framework::os::macos::keychain::CreateOptions;
+    // a keychain of our own, so the login keychain's password is left alone
+    let path = std::env::temp_dir().join(format!("{}.keychain-db", generate_random_string()));
+    let mut file = CreateOptions::new()
+        .password("before")
+        .prompt_user(false)
+        .create(&path)
+        .unwrap();
+    assert!(matches!(
+        keychain::change_keychain_password(&file, "wrong", "after"),
+        Err(Error::PlatformFailure(_))
+    ));
+    keychain::change_keychain_password(&file, "before", "after").unwrap();
+    // SAFETY: the keychain is valid
+    assert_eq!(unsafe { SecKeychainLock(file.as_concrete_TypeRef()) }, 0);
+    assert_eq!(file.unlock(Some("before")).unwrap_err().code(), -25293);
+    file.unlock(Some("after")).unwrap();
+    // SAFETY: the keychain is valid
+    assert_eq!(unsafe { SecKeychainDelete(file.as_concrete_TypeRef()) }, 0);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(feature = "secrecy")]
+#[test]
+fn test_secure_accessors() {
+    use secrecy::ExposeSecret;
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(cred.get_secret_secure(), Err(Error::NoEntry)));
+    entry.set_password("wrapped").unwrap();
+    assert_eq!(
+        cred.get_password_secure().unwrap().expose_secret(),
+        "wrapped"
+    );
+    let secret = cred.get_secret_secure().unwrap();
+    assert_eq!(secret.expose_secret(), b"wrapped");
+    assert!(!format!("{secret:?}").contains("wrapped"));
+    entry.set_secret(b"\xffbinary").unwrap();
+    assert!(matches!(
+        cred.get_password_secure(),
+        Err(Error::BadEncoding(bytes)) if bytes == b"\xffbinary"
+    ));
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_default_keychain() {
+    assert_eq!(
+        "default".parse::<keychain::MacKeychainDomain>().unwrap(),
+        keychain::MacKeychainDomain::Default
+    );
+    let login = Store::new().unwrap().resolved_keychain_path().unwrap();
+    assert!(login.is_absolute(), "{login:?}");
+    let config = HashMap::from([("keychain", "Default")]);
+    let store = Store::new_with_configuration(&config).unwrap();
+    let default = store.resolved_keychain_path().unwrap();
+    assert!(default.is_absolute(), "{default:?}");
+}
+
+#[test]
+fn test_validate_configuration() {
+    let config = HashMap::from([("keychain", "User"), ("prefetch", "a,b")]);
+    assert!(validate_configuration(Backend::Keychain, &config).is_empty());
+    let config = HashMap::from([
+        ("keychain", "Nowhere"),
+        ("allow-unbounded-search", "maybe"),
+        ("flavor", "vanilla"),
+        ("debug-redaction", "partial"),
+    ]);
+    let findings = validate_configuration(Backend::Keychain, &config);
+    let mut keys: Vec<&str> = findings
+        .iter()
+        .inspect(|f| assert_eq!(f.severity, Severity::Error, "{f}"))
+        .filter_map(|f| f.key.as_deref())
+        .collect();
+    keys.sort();
+    assert_eq!(
+        keys,
+        [
+            "allow-unbounded-search",
+            "debug-redaction",
+            "flavor",
+            "keychain"
+        ]
+    );
+    let config = HashMap::from([("keychain", "System")]);
+    assert!(matches!(
+        validate_configuration(Backend::Keychain, &config).as_slice(),
+        [Finding { severity: Severity::Warning, key: Some(key), .. }] if key == "keychain"
+    ));
+    // this build has no protected store
+    assert!(matches!(
+        validate_configuration(Backend::Protected, &HashMap::new()).as_slice(),
+        [Finding {
+            severity: Severity::Error,
+            key: None,
+            ..
+        }]
+    ));
+}
+
+#[test]
+fn test_is_available() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let store = get_default_store().unwrap();
+    assert_eq!(
+        apple_store(&store).unwrap().is_available(),
+        Availability::Available
+    );
+    assert!(Availability::of_error(&Error::NoEntry).is_available());
+    let err = Error::NoStorageAccess(Box::new(security_framework::base::Error::from_code(-25308)));
+    assert_eq!(Availability::of_error(&err), Availability::Locked);
+}
+
+#[test]
+fn test_os_version() {
+    SET_STORE.call_once(usually_goes_in_main);
+    // the crate doesn't check versions, because it doesn't run on any older than this
+    let running = os_version::current().unwrap();
+    assert!(running >= (10, 15, 0));
+    let store = get_default_store().unwrap();
+    let ext = apple_store(&store).unwrap();
+    let reported = ext.diagnostics();
+    assert!(
+        reported
+            .iter()
+            .any(|(k, v)| { k == "os-version" && os_version::parse_version(v) == Some(running) })
+    );
+    assert_eq!(os_version::parse_version("14"), Some((14, 0, 0)));
+    assert_eq!(os_version::parse_version("14.2.1\n"), Some((14, 2, 1)));
+    assert_eq!(os_version::parse_version("14.x"), None);
+    assert_eq!(os_version::format_version((10, 15, 7)), "10.15.7");
+}
+
+#[test]
+fn test_apple_store_ext() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let store = get_default_store().unwrap();
+    let ext = apple_store(&store).unwrap();
+    let capabilities = ext.capabilities();
+    assert!(capabilities.search_keys.contains(&"include-invisible"));
+    assert!(capabilities.modifier_keys.contains(&"app-gated"));
+    let info = ext.provider_info();
+    assert_eq!(info.name, "apple-native-keyring-store");
+    assert_eq!(info.backend, Backend::Keychain);
+    assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.capabilities, capabilities);
+    assert!(
+        ext.diagnostics()
+            .iter()
+            .any(|(k, v)| k == "keychain" && v == "User")
+    );
+    let name = generate_random_string();
+    let e1 = entry_new(&name, "user1");
+    e1.set_password("e1").unwrap();
+    let e2 = entry_new(&name, "user2");
+    e2.set_password("e2").unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    assert_eq!(ext.count(&spec).unwrap(), 2);
+    assert_eq!(ext.wipe(&spec).unwrap(), 2);
+    assert_eq!(ext.count(&spec).unwrap(), 0);
+    assert!(matches!(e1.get_password(), Err(Error::NoEntry)));
+}
+
+#[test]
+fn test_low_level_query() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entry1 = entry_new(&name, &name);
+    entry1.set_password("first").unwrap();
+    let entry2 = entry_new(&name, &format!("{name}-2"));
+    entry2.set_password("second").unwrap();
+    let mut query = keychain::Query::new(keychain::MacKeychainDomain::User);
+    query.service(&name);
+    let items = query.find().unwrap();
+    assert_eq!(items.len(), 2);
+    assert!(items.iter().all(|item| item.service == name));
+    assert_eq!(query.entries().unwrap().len(), 2);
+    assert!(matches!(query.secret(), Err(Error::Ambiguous(_))));
+    assert_eq!(query.clone().limit(1).find().unwrap().len(), 1);
+    assert!(matches!(
+        query.clone().limit(0).find(),
+        Err(Error::Invalid(_, _))
+    ));
+    query.account(&name);
+    assert_eq!(query.secret().unwrap(), b"first");
+    entry1.delete_credential().unwrap();
+    entry2.delete_credential().unwrap();
+    assert!(query.find().unwrap().is_empty());
+    assert!(matches!(query.secret(), Err(Error::NoEntry)));
+}
+
+#[test]
+fn test_operation_timeout() {
+    SET_STORE.call_once(usually_goes_in_main);
+    // other tests run alongside this one, so only set a timeout they won't hit
+    timeout::set_operation_timeout(Some(Duration::from_secs(60)));
+    assert_eq!(timeout::operation_timeout(), Some(Duration::from_secs(60)));
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    test_round_trip("timed", &entry, "on a worker");
+    assert_eq!(
+        Entry::search(&HashMap::from([("service", name.as_str())]))
+            .unwrap()
+            .len(),
+        0
+    );
+    timeout::set_operation_timeout(None);
+    let err = Error::NoStorageAccess(Box::new(timeout::Timeout {
+        operation: "get_secret",
+        timeout: Duration::from_secs(1),
+    }));
+    let timed_out = timeout::timed_out(&err).unwrap();
+    assert_eq!(timed_out.operation, "get_secret");
+    assert_eq!(timed_out.to_string(), "get_secret didn't finish within 1s");
+    assert!(timeout::timed_out(&Error::NoEntry).is_none());
+}
+
+#[test]
+fn test_background_operations() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entry = Arc::new(entry_new(&name, &name));
+    let writer = entry.clone();
+    let written = background::submit(move || writer.set_password("in the background"));
+    let reader = entry.clone();
+    let read = background::submit(move || reader.get_password());
+    written.recv().unwrap().unwrap();
+    // operations run in order, so the read sees the write
+    assert_eq!(read.recv().unwrap().unwrap(), "in the background");
+    let panicked = background::submit(|| -> keyring_core::Result<()> { panic!("on purpose") });
+    assert!(matches!(
+        panicked.recv().unwrap(),
+        Err(Error::PlatformFailure(_))
+    ));
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let deleter = entry.clone();
+    background::submit_then(
+        move || deleter.delete_credential(),
+        move |result| sender.send(result.is_ok()).unwrap(),
+    );
+    assert!(receiver.recv().unwrap());
+    assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+}
+
+#[test]
+fn test_snapshot() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let store = Store::new().unwrap();
+    let name = generate_random_string();
+    let entry1 = entry_new(&name, &name);
+    entry1.set_password("unchanged").unwrap();
+    let entry2 = entry_new(&name, &format!("{name}-2"));
+    entry2.set_password("before").unwrap();
+    let mine = |snapshot: &keychain::Snapshot| {
+        snapshot
+            .items
+            .iter()
+            .filter(|item| item.service == name)
+            .count()
+    };
+    let before = store.snapshot().unwrap();
+    assert_eq!(mine(&before), 2);
+    assert!(
+        before
+            .items
+            .iter()
+            .filter(|item| item.service == name)
+            .all(|item| item.checksum.as_ref().is_some_and(|c| c.len() == 64))
+    );
+    entry2.set_password("after").unwrap();
+    entry1.delete_credential().unwrap();
+    let entry3 = entry_new(&name, &format!("{name}-3"));
+    entry3.set_password("new").unwrap();
+    let after = store.snapshot().unwrap();
+    // the snapshot turns prompting off only while it reads
+    assert!(
+        security_framework::os::macos::keychain::SecKeychain::user_interaction_allowed().unwrap()
+    );
+    let diff = keychain::diff(&before, &after);
+    let ours = |items: &[keychain::SnapshotItem]| {
+        items
+            .iter()
+            .filter(|item| item.service == name)
+            .map(|item| item.account.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ours(&diff.added), vec![format!("{name}-3")]);
+    assert_eq!(ours(&diff.removed), vec![name.clone()]);
+    let changed: Vec<_> = diff
+        .changed
+        .iter()
+        .filter(|(old, _)| old.service == name)
+        .collect();
+    assert_eq!(changed.len(), 1);
+    assert_ne!(changed[0].0.checksum, changed[0].1.checksum);
+    assert_eq!(keychain::diff(&after, &after), Default::default());
+    entry2.delete_credential().unwrap();
+    entry3.delete_credential().unwrap();
+}
+
+#[test]
+fn test_unbounded_search() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let guarded: Arc<CredentialStore> =
+        Store::new_with_configuration(&HashMap::from([("allow-unbounded-search", "false")]))
+            .unwrap();
+    assert!(matches!(
+        guarded.search(&HashMap::new()),
+        Err(Error::Invalid(_, _))
+    ));
+    assert!(matches!(
+        guarded.search(&HashMap::from([("include-invisible", "true")])),
+        Err(Error::Invalid(_, _))
+    ));
+    assert!(guarded.search(&HashMap::from([("all", "true")])).is_ok());
+    let name = generate_random_string();
+    let entry = guarded.build(&name, &name, None).unwrap();
+    entry.set_password("bounded").unwrap();
+    let found = guarded
+        .search(&HashMap::from([("service", name.as_str())]))
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_refresher() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("before").unwrap();
+    assert!(matches!(
+        Refresher::start(vec![], Duration::ZERO, |_, _| {}),
+        Err(Error::Invalid(_, _))
+    ));
+    let (tx, rx) = std::sync::mpsc::channel();
+    let refresher = Refresher::start(
+        vec![entry_new(&name, &name)],
+        Duration::from_millis(50),
+        move |_, secret| tx.send(secret.map(|s| s.to_vec())).unwrap(),
+    )
+    .unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(
+        rx.try_recv().is_err(),
+        "The first poll shouldn't report a change"
+    );
+    entry.set_password("after").unwrap();
+    let changed = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(changed.as_deref(), Some("after".as_bytes()));
+    entry.delete_credential().unwrap();
+    let deleted = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(deleted, None);
+    refresher.stop();
+}
+
+#[test]
+fn test_bootstrap() {
+    let name = generate_random_string();
+    let result = keychain::Bootstrap::new(&name, &name)
+        .attempts(2)
+        .retry_delay(Duration::from_millis(10))
+        .read();
+    assert!(
+        matches!(result, Err(keychain::BootstrapError::NotFound)),
+        "Unexpected bootstrap result: {result:?}"
+    );
+    assert!(matches!(
+        keychain::Bootstrap::new("", &name).read(),
+        Err(keychain::BootstrapError::Failed(Error::Invalid(_, _)))
+    ));
+}
+
+#[test]
+#[cfg(feature = "compat")]
+fn test_compat_entry() {
+    use super::compat;
+    let name = generate_random_string();
+    let old = compat::Entry::new_with_target("user", &name, &name).unwrap();
+    old.set_password("compatible").unwrap();
+    let cred: &Cred = old.get_credential().downcast_ref().unwrap();
+    assert_eq!(cred.domain, keychain::MacKeychainDomain::User);
+    let new = Cred::build(keychain::MacKeychainDomain::User, &name, &name).unwrap();
+    assert_eq!(new.get_password().unwrap(), "compatible");
+    assert_eq!(
+        compat::Entry::new(&name, &name)
+            .unwrap()
+            .get_password()
+            .unwrap(),
+        "compatible"
+    );
+    assert!(matches!(
+        compat::Entry::new_with_target("nowhere", &name, &name),
+        Err(Error::Invalid(key, _)) if key == "target"
+    ));
+    assert!(matches!(
+        compat::Entry::new("", &name),
+        Err(Error::Invalid(_, _))
+    ));
+    old.into_inner().delete_credential().unwrap();
+}
+
+#[test]
+fn test_debug_redaction() {
+    let name = generate_random_string();
+    let redacted = entry_new(&name, &name);
+    let output = format!("{redacted:?}");
+    assert!(
+        !output.contains(&name),
+        "Specifiers in debug output: {output}"
+    );
+    let cred: &Cred = redacted.as_any().downcast_ref().unwrap();
+    assert!(!cred.to_string().contains(&name));
+    let only = format!("{:?}", cred.reveal_specifiers_only());
+    assert!(only.contains(&name) && !only.contains("domain"));
+    let store: Arc<CredentialStore> =
+        Store::new_with_configuration(&HashMap::from([("debug-redaction", "reveal-specifiers")]))
+            .unwrap();
+    let revealed = store.build(&name, &name, None).unwrap();
+    assert!(format!("{revealed:?}").contains(&name));
+    assert!(matches!(
+        Store::new_with_configuration(&HashMap::from([("debug-redaction", "some")])),
+        Err(Error::Invalid(_, _))
+    ));
+}
+
+#[test]
+fn test_update_if() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(
+        cred.update_if(b"old", b"new"),
+        Err(Error::NoEntry)
+    ));
+    entry.set_secret(b"old").unwrap();
+    assert!(!cred.update_if(b"stale", b"new").unwrap());
+    assert_eq!(entry.get_secret().unwrap(), b"old");
+    assert!(cred.update_if(b"old", b"new").unwrap());
+    assert_eq!(entry.get_secret().unwrap(), b"new");
+    // a held lease keeps the swap from happening
+    let store = Store::new().unwrap();
+    let lease = store
+        .lock_credential(&name, &name, Duration::from_secs(60))
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        cred.update_if(b"new", b"newer"),
+        Err(Error::NoStorageAccess(_))
+    ));
+    lease.release().unwrap();
+    assert!(cred.update_if(b"new", b"newer").unwrap());
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_large_secret() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let first_chunk = entry_new(&format!("keyring-chunk:{name}"), &format!("{name}:0"));
+    let large: Vec<u8> = (0..3 * keychain::CHUNK_SIZE + 17)
+        .map(|i| i as u8)
+        .collect();
+    entry.set_secret(&large).unwrap();
+    assert_eq!(entry.get_secret().unwrap(), large);
+    assert!(first_chunk.get_secret().is_ok());
+    // the chunks don't show up as entries of their own
+    let store: Arc<CredentialStore> = Store::new().unwrap();
+    let found = store
+        .search(&HashMap::from([("user-prefix", name.as_str())]))
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    // a small secret replaces the chunks
+    entry.set_secret(b"small").unwrap();
+    assert_eq!(entry.get_secret().unwrap(), b"small");
+    assert!(matches!(first_chunk.get_secret(), Err(Error::NoEntry)));
+    // a small secret that starts like an index reads back as it was
+    let lookalike = b"keyring-chunked/1\0\0\0\x05".to_vec();
+    entry.set_secret(&lookalike).unwrap();
+    assert_eq!(entry.get_secret().unwrap(), lookalike);
+    entry.set_secret(&large).unwrap();
+    entry.delete_credential().unwrap();
+    assert!(matches!(entry.get_secret(), Err(Error::NoEntry)));
+    assert!(matches!(first_chunk.get_secret(), Err(Error::NoEntry)));
+}
+
+#[test]
+fn test_rename() {
+    SET_STORE.call_once(usually_goes_in_main);
+    let name = generate_random_string();
+    let new_name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    let cred = entry.as_any().downcast_ref::<Cred>().unwrap();
+    assert!(matches!(
+        cred.rename(&new_name, &new_name),
+        Err(Error::NoEntry)
+    ));
+    assert!(matches!(
+        cred.rename("", &new_name),
+        Err(Error::Invalid(_, _))
+    ));
+    entry.set_secret(b"kept").unwrap();
+    let renamed = cred.rename(&new_name, &new_name).unwrap();
+    assert_eq!(renamed.service, new_name);
+    assert!(matches!(entry.get_secret(), Err(Error::NoEntry)));
+    let moved = entry_new(&new_name, &new_name);
+    assert_eq!(moved.get_secret().unwrap(), b"kept");
+    // renaming onto an existing item fails
+    entry.set_secret(b"other").unwrap();
+    let err = cred.rename(&new_name, &new_name).unwrap_err();
+    assert_eq!(error_kind(&err), Some(ErrorKind::DuplicateItem));
+    entry.delete_credential().unwrap();
+    moved.delete_credential().unwrap();
+}
+
+#[test]
+fn test_lock_credential() {
+    let store = Store::new().unwrap();
+    let name = generate_random_string();
+    let lease = store
+        .lock_credential(&name, &name, Duration::from_secs(60))
+        .unwrap()
+        .expect("Couldn't take an unheld lease");
+    assert!(
+        store
+            .lock_credential(&name, &name, Duration::from_secs(60))
+            .unwrap()
+            .is_none()
+    );
+    let generic: Arc<CredentialStore> = store.clone();
+    let found = generic
+        .search(&HashMap::from([("user", name.as_str())]))
+        .unwrap();
+    assert!(found.is_empty(), "Lease marker found by search: {found:?}");
+    lease.release().unwrap();
+    let short = store
+        .lock_credential(&name, &name, Duration::from_secs(1))
+        .unwrap()
+        .expect("Couldn't take a released lease");
+    std::thread::sleep(Duration::from_secs(2));
+    let taken = store
+        .lock_credential(&name, &name, Duration::from_secs(60))
+        .unwrap()
+        .expect("Couldn't take over an expired lease");
+    // the expired lease can't release the one that took it over
+    short.release().unwrap();
+    assert!(
+        store
+            .lock_credential(&name, &name, Duration::from_secs(60))
+            .unwrap()
+            .is_none()
+    );
+    drop(taken);
+    assert!(store.lock_credential(&name, &name, Duration::ZERO).is_err());
+    store
+        .lock_credential(&name, &name, Duration::from_secs(60))
+        .unwrap()
+        .unwrap()
+        .release()
+        .unwrap();
+}
+
+#[test]
+fn test_partition_ids() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    assert!(matches!(entry.get_attributes(), Err(Error::NoEntry)));
+    entry.set_password("partitioned").unwrap();
+    let attrs = entry.get_attributes().unwrap();
+    let ids = &attrs["partition-ids"];
+    // new items get the partition of the code that made them
+    assert!(!ids.is_empty(), "New item has no partition IDs");
+    let cred: &Cred = entry.as_any().downcast_ref().unwrap();
+    let first = ids.split(',').next().unwrap();
+    // an ID that's already there isn't added, so no password is needed
+    assert!(!cred.add_partition_id(first, "").unwrap());
+    entry.delete_credential().unwrap();
+}
+
+#[test]
+fn test_normalize_acls() {
+    let name = generate_random_string();
+    let entry = entry_new(&name, &name);
+    entry.set_password("acl").unwrap();
+    let store = Store::new().unwrap();
+    let spec = HashMap::from([("service", name.as_str())]);
+    let missing = HashMap::from([("service", "no-such-service"), ("user", "nobody")]);
+    let report = store
+        .normalize_acls(&missing, &keychain::AclPolicy::SelfOnly, "")
+        .unwrap();
+    assert_eq!(report.changed, 0);
+    assert!(report.failures.is_empty());
+    // the test doesn't know the keychain password, so the item isn't changed
+    let policy = keychain::AclPolicy::TrustedApplications(vec!["/usr/bin/security".into()]);
+    let report = store
+        .normalize_acls(&spec, &policy, "not the keychain password")
+        .unwrap();
+    assert_eq!(report.changed, 0);
+    assert_eq!(report.failures.len(), 1, "{report:?}");
+    assert_eq!(report.failures[0].service, name);
+    assert_eq!(entry.get_password().unwrap(), "acl");
+    let bounded =
+        Store::new_with_configuration(&HashMap::from([("allow-unbounded-search", "false")]))
+            .unwrap();
+    assert!(matches!(
+        bounded.normalize_acls(&HashMap::new(), &keychain::AclPolicy::SelfOnly, ""),
+        Err(Error::Invalid(_, _))
+    ));
+    entry.delete_credential().unwrap();
+}
+
 #[test]
 fn test_persistence() {
     let store: Arc<CredentialStore> = Store::new().unwrap();
@@ -0,0 +1,211 @@
+/*!
+
+# Advisory leases
+
+Processes that share a credential sometimes need to agree on which of them
+changes it: when a token is rotated, only one of them should refresh it. Each
+store's `lock_credential` takes a [Lease] on a credential for a given time to
+live, by adding a marker item that only one process at a time can add. The
+lease ends when it's released (or dropped), or when its time runs out; an
+expired lease can be taken over by another process, so one that crashes while
+holding a lease only holds things up until it expires.
+
+Leases are advisory: they don't stop anyone from changing the credential,
+they only coordinate the processes that take them. Expiry is judged by each
+process's clock, which is the same clock for processes on one device.
+Marker items are left out of searches.
+
+Each store's `Cred::update_if`, a compare-and-swap of the secret, takes the
+same lease (briefly, waiting up to [SWAP_WAIT] for it) around reading and
+writing, so it's atomic with respect to other `update_if` calls and to
+holders of `lock_credential` leases. It's not atomic with respect to plain
+`set_secret` calls, which don't take a lease.
+
+ */
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use security_framework::base::Error;
+use security_framework_sys::base::{errSecDuplicateItem, errSecItemNotFound};
+
+use keyring_core::{Error as ErrorCode, Result};
+
+use crate::query::{Attr, Query as RawQuery};
+
+/// The prefix on the service and the generic metadata of lease markers.
+pub(crate) const LEASE_TAG: &str = "keyring-lease:";
+
+/// How a store finds (or, given true, adds) the marker item for a lease.
+pub(crate) struct Marker {
+    pub query: Box<dyn Fn(bool) -> Result<RawQuery> + Send + Sync>,
+    pub decode: fn(Error) -> ErrorCode,
+}
+
+/// A lease on a credential, as taken by a store's `lock_credential`.
+///
+/// See the [module docs](self). Dropping the lease releases it.
+pub struct Lease {
+    marker: Marker,
+    token: String,
+    expires_at: SystemTime,
+    released: bool,
+}
+
+impl std::fmt::Debug for Lease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lease")
+            .field("expires_at", &self.expires_at)
+            .field("released", &self.released)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Lease {
+    /// When the lease runs out, after which another process can take it.
+    pub fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
+
+    /// End the lease.
+    ///
+    /// Releasing a lease that has already been taken over leaves the new
+    /// holder's lease alone.
+    pub fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.delete()
+    }
+
+    fn delete(&self) -> Result<()> {
+        let mut query = (self.marker.query)(false)?;
+        query.data(Attr::Generic, self.token.as_bytes());
+        match query.delete() {
+            Ok(()) => Ok(()),
+            Err(err) if err.code() == errSecItemNotFound => Ok(()),
+            Err(err) => Err((self.marker.decode)(err)),
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if !self.released {
+            if let Err(err) = self.delete() {
+                debug!("Couldn't release a lease: {err:?}");
+            }
+        }
+    }
+}
+
+/// Take a lease for the given time, or return `None` if another unexpired lease
+/// is held.
+pub(crate) fn acquire(marker: Marker, ttl: Duration) -> Result<Option<Lease>> {
+    if ttl.is_zero() {
+        return Err(ErrorCode::Invalid(
+            "ttl".to_string(),
+            "must be greater than zero".to_string(),
+        ));
+    }
+    let now = SystemTime::now();
+    let expires_at = seconds(now + ttl);
+    let nonce = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let token = format!("{LEASE_TAG}{expires_at}:{}-{nonce}", std::process::id());
+    // one try, and one more after clearing away an expired lease
+    for _ in 0..2 {
+        let mut item = (marker.query)(true)?;
+        item.data(Attr::Generic, token.as_bytes()).value(&[]);
+        match item.add() {
+            Ok(()) => {
+                return Ok(Some(Lease {
+                    marker,
+                    token,
+                    expires_at: UNIX_EPOCH + Duration::from_secs(expires_at),
+                    released: false,
+                }));
+            }
+            Err(err) if err.code() == errSecDuplicateItem => {}
+            Err(err) => return Err((marker.decode)(err)),
+        }
+        let mut query = (marker.query)(false)?;
+        query.return_attributes();
+        let Some(held) = query
+            .find()
+            .map_err(marker.decode)?
+            .first()
+            .and_then(|attrs| attrs.data(Attr::Generic))
+        else {
+            // released since we tried to add ours
+            continue;
+        };
+        if expiry(&held).is_some_and(|expiry| expiry > seconds(now)) {
+            return Ok(None);
+        }
+        // only delete the expired lease, not one that another process just took
+        let mut expired = (marker.query)(false)?;
+        expired.data(Attr::Generic, &held);
+        match expired.delete() {
+            Ok(()) => {}
+            Err(err) if err.code() == errSecItemNotFound => {}
+            Err(err) => return Err((marker.decode)(err)),
+        }
+    }
+    Ok(None)
+}
+
+/// How long a compare-and-swap waits for another holder's lease to end.
+pub const SWAP_WAIT: Duration = Duration::from_secs(2);
+
+/// How long a compare-and-swap's own lease lasts, in case it crashes
+/// while holding it.
+const SWAP_TTL: Duration = Duration::from_secs(10);
+
+/// How often a compare-and-swap tries again to take its lease.
+const SWAP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The work of a credential's `update_if`: under a lease, replace the secret
+/// with `new` if `read` gives the expected one, returning whether it did.
+///
+/// This fails with `NoStorageAccess` if someone else holds the lease for
+/// longer than [SWAP_WAIT].
+pub(crate) fn compare_and_swap(
+    marker: impl Fn() -> Marker,
+    read: impl FnOnce() -> Result<Vec<u8>>,
+    write: impl FnOnce() -> Result<()>,
+    expected: &[u8],
+) -> Result<bool> {
+    let deadline = Instant::now() + SWAP_WAIT;
+    let lease = loop {
+        if let Some(lease) = acquire(marker(), SWAP_TTL)? {
+            break lease;
+        }
+        if Instant::now() >= deadline {
+            return Err(ErrorCode::NoStorageAccess(
+                "another process holds a lease on the credential".into(),
+            ));
+        }
+        thread::sleep(SWAP_POLL_INTERVAL);
+    };
+    let swapped = read()? == expected;
+    if swapped {
+        write()?;
+    }
+    lease.release()?;
+    Ok(swapped)
+}
+
+/// The expiry recorded in a marker's generic metadata, if it's well-formed.
+fn expiry(generic: &[u8]) -> Option<u64> {
+    let generic = std::str::from_utf8(generic).ok()?;
+    let (expiry, _) = generic.strip_prefix(LEASE_TAG)?.split_once(':')?;
+    expiry.parse().ok()
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -28,9 +28,39 @@ and both can be enabled.
 
 - `keychain`: Provides access to the "legacy keychain" store. Ignored on iOS.
 - `protected`: Provides access to the "protected data" store. Requires macOS 10.15 or later.
+- `serde`: Makes keychain snapshots (`keychain::Snapshot`) serializable.
+- `secrecy`: Adds `get_secret_secure` and `get_password_secure` to each store's
+  credentials, which return the secret in a zeroize-on-drop `secrecy` wrapper.
+- `compat`: Provides the `compat` module, which builds keychain entries the way
+  keyring 3.x did. Implies `keychain`, and is ignored on iOS.
+- `async`: Provides the `protected::aio` module, whose operations return futures
+  rather than block. Implies `protected`.
+- `unstable-change-password`: Provides `keychain::Store::change_password`, which
+  relies on a Security framework function that isn't public API, so it could stop
+  working in any macOS release. Implies `keychain`.
+- `testkit`: Provides the `testkit` module, with fixtures for tests that run
+  against a real store.
 
 This crate has no default features.
 
+## OS versions
+
+This crate supports macOS 10.15 (_Catalina_) and iOS 13 or later, and
+everything it uses is available there. The stores' diagnostics report the
+version of the running OS.
+
+## Errors
+
+Status codes from the Security framework that don't map to a more specific
+keyring error are reported as `PlatformFailure` errors. The [error] module
+can classify and explain those status codes. A canceled authentication prompt
+is a `NoStorageAccess` error that [error::canceled] recognizes, so an app can
+treat it as the user changing their mind rather than as a failure.
+
+Store operations wait for the Security framework indefinitely unless a
+timeout is set with the [timeout] module. UI apps can keep them off the main
+thread with the [background] module.
+
  */
 
 #[cfg(all(
@@ -46,8 +76,56 @@ pub mod keychain;
 #[cfg(test)]
 mod keychain_test;
 
+#[cfg(all(target_os = "macos", feature = "compat"))]
+pub mod compat;
+
 #[cfg(all(target_os = "ios", not(feature = "protected")))]
 compile_error!("The `protected` feature is required on iOS");
 
 #[cfg(feature = "protected")]
 pub mod protected;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+mod os_version;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+mod query;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+pub mod error;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+pub mod ext;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+pub mod timeout;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+pub mod background;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+pub mod refresh;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+pub mod redact;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+pub mod lease;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+mod chunks;
+
+#[cfg(any(feature = "protected", all(target_os = "macos", feature = "keychain")))]
+pub mod validate;
+
+#[cfg(all(
+    any(test, feature = "testkit"),
+    any(feature = "protected", all(target_os = "macos", feature = "keychain"))
+))]
+pub mod testkit;
+
+#[cfg(all(
+    feature = "secrecy",
+    any(feature = "protected", all(target_os = "macos", feature = "keychain"))
+))]
+mod secure;
@@ -0,0 +1,76 @@
+/*!
+
+# OS version
+
+The stores report the version of the running OS in their diagnostics.
+
+Everything the crate uses from the Security framework is available in macOS
+10.15 and iOS 13, the oldest versions it supports, so no operation checks
+the version first.
+
+ */
+
+use std::ffi::{CStr, c_char, c_int, c_void};
+use std::sync::OnceLock;
+
+use log::debug;
+
+/// The version of the running OS, as `(major, minor, patch)`.
+pub(crate) type Version = (u32, u32, u32);
+
+/// The version of the running OS, if it can be determined.
+pub(crate) fn current() -> Option<Version> {
+    static VERSION: OnceLock<Option<Version>> = OnceLock::new();
+    *VERSION.get_or_init(|| {
+        let version = read_product_version().and_then(|s| parse_version(&s));
+        if version.is_none() {
+            debug!("Couldn't determine the OS version");
+        }
+        version
+    })
+}
+
+/// A version as the OS writes it, such as `14.2.1`.
+pub(crate) fn format_version(version: Version) -> String {
+    format!("{}.{}.{}", version.0, version.1, version.2)
+}
+
+/// A version as the OS writes it, with a missing minor or patch number read as 0.
+pub(crate) fn parse_version(s: &str) -> Option<Version> {
+    let mut parts = s.trim().split('.').map(str::parse::<u32>);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+    Some((major, minor, patch))
+}
+
+unsafe extern "C" {
+    fn sysctlbyname(
+        name: *const c_char,
+        oldp: *mut c_void,
+        oldlenp: *mut usize,
+        newp: *mut c_void,
+        newlen: usize,
+    ) -> c_int;
+}
+
+fn read_product_version() -> Option<String> {
+    let name = c"kern.osproductversion";
+    let mut buffer = [0u8; 32];
+    let mut len = buffer.len();
+    // SAFETY: the buffer and its length are valid for writes, and we pass no new value.
+    let status = unsafe {
+        sysctlbyname(
+            name.as_ptr(),
+            buffer.as_mut_ptr().cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if status != 0 {
+        return None;
+    }
+    let value = CStr::from_bytes_until_nul(&buffer).ok()?;
+    Some(value.to_string_lossy().into_owned())
+}
@@ -7,7 +7,10 @@ _Protected Data_. This module provides a credential store for that service.
 
 To use all the features of this module, your client application must be
 code-signed with a provisioning profile. Since command-line tools cannot be
-code-signed, there's not much point in their using this module.
+code-signed, there's not much point in their using this module. The
+[entitlements] module explains which entitlements a configuration is missing,
+and [Store::health_check] tries out each kind of item a store can hold, for an
+app's diagnostics screen.
 
 There are actually two, distinct protected stores: one local to the
 device, and one that is synchronized with iCloud. If you create a store with
@@ -18,6 +21,10 @@ store is used instead. (Use of the cloud-synchronized store is only available
 to applications that have the iCloud capability enabled in their provisioning
 profile.)
 
+Other devices can change cloud-synchronized credentials at any time; the
+[refresh](crate::refresh) module can watch them for you. A credential can
+be moved from one store to the other with [move_to](Cred::move_to).
+
 For a given service/user pair, this module creates/searches for a generic
 password item whose _account_ attribute holds the user and whose _service_
 attribute holds the service. Because of a quirk in the protected data API,
@@ -40,11 +47,15 @@ store will search across all those access groups for a given entry, so ambiguity
 is possible. To avoid this, an application can create one store for each available
 access group, passing the access group name as the value of the`access-group`
 modifier when creating each store. (This is also how such an application can specify
-which group it wants to use when creating a new credential.)
+which group it wants to use when creating a new credential.) A store can also
+declare, with its `default-group` configuration key, which group it expects new
+items to land in; see [new_with_configuration](Store::new_with_configuration).
 
 If you have retrieved a wrapper entry and want to know the access group of the
 underlying item, you can downcast the wrapper entry to the `Cred` type and look
-at its `access_group` field. For more information about this, see the many Apple
+at its `access_group` field. When an entry turns out to be ambiguous,
+[Duplicates::of_error] gets the credentials from the `Ambiguous` error, so you
+can pick the one in a given access group or delete the extra ones. For more information about this, see the many Apple
 developer docs about sharing access groups among applications. Also look at the
 `tests` example code for the tests of ambiguity.
 
@@ -53,42 +64,126 @@ developer docs about sharing access groups among applications. Also look at the
 Protected data items _in the local store_ can be created with varying levels of
 protection. This module uses a default access policy of "accessible when device
 is unlocked", but entry modifiers can be used to change this. See the docs for
-[build](Store::build) for details.
+[build](Store::build) for details. Because some older OS versions silently ignore
+access-control flags they don't understand, you can use
+[verify_access_control](Cred::verify_access_control) to confirm that an item
+was created with the protection you asked for. Items that require user
+presence can also be given a freshness window, so a recent authentication
+is reused but an older one isn't. When several threads read the same such
+item at once, they share one read, so the user is asked to authenticate once.
+Whether the device has a passcode (and
+which biometry, if any) is reported by [biometry], so an app can fall back to
+a softer policy where user presence can't be required, and an access
+policy's [preflight](AccessPolicy::preflight) says why it can't be used.
+
+The OS never reports an existing item's access policy. If you need to see it
+later (in inventory listings, say), build entries with a `label-template` to
+record the policy in the item's label. To change an item's policy, use
+[update_access_policy](Cred::update_access_policy), which re-creates the item.
+
+Items protected until the device is unlocked can't be read (or written) while
+it's locked; such an operation fails with an error that
+[keychain_locked](crate::error::keychain_locked) recognizes. Rather than retry
+it themselves, clients (such as background token refreshers) can hand it to
+[retry_when_available](Store::retry_when_available).
 
 ## Attributes
 
-This store exposes no attributes.
+Entries report their item's dates, label, comment, description, accessibility,
+synchronizability, and access group from `get_attributes`, and
+[search_with_attributes](Store::search_with_attributes) reports them for every
+item a search finds. The label, comment,
+and description can be set with `update_attributes`, so items show up in the
+Passwords app and Keychain Access with readable names rather than just their
+services, and [update_attributes_matching](Store::update_attributes_matching)
+can set them on many items at once. Apps can also keep a little binary metadata
+of their own (such as a schema version) with an item; see
+[set_metadata](Cred::set_metadata).
+
+## Key items
+
+Cryptographic keys can be kept in the store too, next to the passwords and
+with the same access groups and access policies. See the [keys] module.
+
+## Large secrets
+
+Secrets too big for a keychain item can be kept encrypted with a per-app
+key, in an entry that works like any other. See the [envelope] module.
+
+## Async
+
+With the `async` feature, the `aio` module runs entry operations and searches
+on threads of their own and returns futures for them, so an async client's
+executor isn't blocked while the user authenticates.
+
+## Low-level queries
+
+If you need conditions (such as an item label or a result limit) that
+[search](Store::search) doesn't offer, build a [Query]. It returns the
+raw attributes of the matching items, and can turn them into entries.
+An item can also be reopened without any search from its persistent
+reference; see [persistent_ref](Cred::persistent_ref).
+
+## Hashed specifiers
+
+Item metadata isn't encrypted, and in the cloud-synchronized store it's
+synchronized across the user's devices. If you don't want the services
+and accounts of your items to be visible there, create your store with
+[new_with_hasher](Store::new_with_hasher) and a [SpecifierHasher] of your
+choosing, and each item will be stored under hashed specifiers instead.
 
 ## Search
 
 This store exposes search over both the local and cloud-synchronized stores.
-You can search for credentials by service and/or user (case-sensitive exact match),
-and you can restrict searches to a specific access group.
+You can search for credentials by service and/or user (an exact match, unless
+you ask for case- or diacritic-insensitive matching),
+and you can restrict searches to a specific access group. You can also search for
+several services at once by listing them, separated by commas, and you can
+restrict a search to the app's own App ID group, leaving out shared groups.
+If your services are `/`-separated paths, you can search for all the services
+under a given path. Items can also be given an application-assigned tag (such
+as a UUID that your records use to refer to them) and searched for by it, or by
+the exact value of their generic metadata. A single search can also cover
+both the local and cloud-synchronized stores.
 If you specify neither a service nor a user, then the search will return all
 credentials in the store (or access group), but read on for restrictions.
 
 The OS, by design, does not expose the access policy on existing secrets in the
 store. So the wrapper entries returned from search will always have the default
-access policy, not the policy of the entry that was found.
+access policy, not the policy of the entry that was found. What the OS does
+report is the item's protection class, and a search can tell whether an item
+requires user presence, so each wrapper's `protection` says whether its item
+is device-only, after-first-unlock, or user-presence protected.
 
 Items whose access policy requires user interaction will pop an authentication
 dialog during the search. To avoid this, the default behavior of searches is
 to skip over these entries. You can specify in the search spec that you want
-them not to be skipped, but this is not recommended.
+them not to be skipped, but this is not recommended. If you want to know how many
+entries were skipped, use [search_detailed](Store::search_detailed). To list
+them after the user has authenticated once (in an `LAContext` of your own), use
+[search_in_context](Store::search_in_context). To go through a long list of
+results without collecting them, use [search_each](Store::search_each).
  */
 
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_int;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use log::error;
+use core_foundation::bundle::CFBundle;
+use core_foundation::string::CFString;
+use log::{debug, error, info, warn};
+use objc2_local_authentication::{LABiometryType, LAContext, LAPolicy};
 use security_framework::access_control::{ProtectionMode, SecAccessControl};
 use security_framework::base::Error;
 use security_framework::item;
 use security_framework::passwords::{
     AccessControlOptions, PasswordOptions, delete_generic_password_options, generic_password,
-    set_generic_password_options,
 };
+use security_framework_sys::base::{errSecDuplicateItem, errSecItemNotFound};
 
 use keyring_core::{
     CredentialPersistence, Entry, Error as ErrorCode, Result,
@@ -96,6 +191,33 @@ use keyring_core::{
     attributes::parse_attributes,
 };
 
+use crate::error::{
+    AuthenticationCanceled, BiometryLockedOut, CanceledBy, ErrorKind, KeychainLocked,
+    MainThreadPrompt, PasscodeNotSet, PromptRateLimited, error_kind,
+};
+use crate::ext::{
+    AppleStoreExt, Availability, Backend, Capabilities, ProviderInfo, SharedStores, TextFilters,
+    check_bounded, common_diagnostics, needs_all_results, page, service_is_under, sort_by_date,
+};
+use crate::lease::{self, LEASE_TAG, Lease, Marker};
+use crate::query::{self, Attr, AuthenticationContext, Query as RawQuery, StringMatching};
+use crate::redact::{Masked, Redaction, Specifiers};
+use crate::timeout;
+use crate::validate::{Finding, check_keys};
+
+pub use crate::query::ItemAttributes;
+
+#[cfg(feature = "async")]
+pub mod aio;
+pub mod availability;
+pub mod entitlements;
+pub mod envelope;
+pub mod keys;
+pub use availability::PendingRetry;
+pub use entitlements::{EntitlementReport, diagnostics, entitled_access_groups};
+pub use envelope::EnvelopeCred;
+pub use keys::{KeyItem, KeyKind};
+
 /// Access policies for protected data items.
 ///
 /// These are recognized case-insensitively from their
@@ -110,14 +232,141 @@ pub enum AccessPolicy {
     WhenUnlockedThisDeviceOnly,
     WhenPasscodeSetThisDeviceOnly,
     RequireUserPresence,
+    BiometryAny,
+    BiometryCurrentSet,
+    ApplicationPassword,
 }
 
 impl AccessPolicy {
     fn as_ref(&self) -> &AccessPolicy {
         self
     }
+
+    /// The policy's name, as given to the `access-policy` modifier.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccessPolicy::AfterFirstUnlock => "after-first-unlock",
+            AccessPolicy::AfterFirstUnlockThisDeviceOnly => "after-first-unlock-this-device-only",
+            AccessPolicy::WhenUnlocked => "when-unlocked",
+            AccessPolicy::WhenUnlockedThisDeviceOnly => "when-unlocked-this-device-only",
+            AccessPolicy::WhenPasscodeSetThisDeviceOnly => "when-passcode-set-this-device-only",
+            AccessPolicy::RequireUserPresence => "require-user-presence",
+            AccessPolicy::BiometryAny => "biometry-any",
+            AccessPolicy::BiometryCurrentSet => "biometry-current-set",
+            AccessPolicy::ApplicationPassword => "application-password",
+        }
+    }
+
+    /// The marker a `label-template` puts in an item's label for this policy,
+    /// such as `🔒 require-user-presence`.
+    pub fn label_marker(&self) -> String {
+        format!("{POLICY_MARKER}{}", self.name())
+    }
+
+    /// The policy recorded in a label by its [label_marker](AccessPolicy::label_marker),
+    /// if it has one.
+    ///
+    /// The OS doesn't report the access policy of an existing item, so a
+    /// label is the only place to read it back from.
+    pub fn from_label(label: &str) -> Option<AccessPolicy> {
+        let (_, rest) = label.rsplit_once(POLICY_MARKER)?;
+        rest.split_whitespace().next()?.parse().ok()
+    }
+
+    /// Whether items created with this policy need authentication to be read.
+    fn requires_user_presence(&self) -> bool {
+        matches!(
+            self,
+            AccessPolicy::RequireUserPresence
+                | AccessPolicy::BiometryAny
+                | AccessPolicy::BiometryCurrentSet
+                | AccessPolicy::ApplicationPassword
+        )
+    }
+
+    /// Whether items created with this policy can only be created on a device
+    /// with a passcode.
+    fn requires_passcode(&self) -> bool {
+        matches!(
+            self,
+            AccessPolicy::WhenPasscodeSetThisDeviceOnly
+                | AccessPolicy::RequireUserPresence
+                | AccessPolicy::BiometryAny
+                | AccessPolicy::BiometryCurrentSet
+        )
+    }
+
+    /// The `kSecAttrAccessible` value (as reported in item attributes)
+    /// that items created with this policy should have.
+    fn protection_class(&self) -> &'static str {
+        match self {
+            AccessPolicy::AfterFirstUnlock => "ck",
+            AccessPolicy::AfterFirstUnlockThisDeviceOnly => "cku",
+            AccessPolicy::WhenUnlocked => "ak",
+            AccessPolicy::WhenUnlockedThisDeviceOnly => "aku",
+            AccessPolicy::WhenPasscodeSetThisDeviceOnly => "akpu",
+            AccessPolicy::RequireUserPresence
+            | AccessPolicy::BiometryAny
+            | AccessPolicy::BiometryCurrentSet
+            | AccessPolicy::ApplicationPassword => "ak",
+        }
+    }
+}
+
+/// The Apple constant name for a `kSecAttrAccessible` attribute value.
+fn protection_class_name(value: &str) -> String {
+    match value {
+        "ak" => "kSecAttrAccessibleWhenUnlocked".to_string(),
+        "ck" => "kSecAttrAccessibleAfterFirstUnlock".to_string(),
+        "dk" => "kSecAttrAccessibleAlways".to_string(),
+        "aku" => "kSecAttrAccessibleWhenUnlockedThisDeviceOnly".to_string(),
+        "cku" => "kSecAttrAccessibleAfterFirstUnlockThisDeviceOnly".to_string(),
+        "dku" => "kSecAttrAccessibleAlwaysThisDeviceOnly".to_string(),
+        "akpu" => "kSecAttrAccessibleWhenPasscodeSetThisDeviceOnly".to_string(),
+        "" => "(none)".to_string(),
+        other => format!("unknown ({other})"),
+    }
+}
+
+impl std::fmt::Display for AccessPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+impl std::str::FromStr for AccessPolicy {
+    type Err = ErrorCode;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "after-first-unlock" | "afterfirstunlock" => Ok(AccessPolicy::AfterFirstUnlock),
+            "after-first-unlock-this-device-only" | "afterfirstunlockthisdeviceonly" => {
+                Ok(AccessPolicy::AfterFirstUnlockThisDeviceOnly)
+            }
+            "when-unlocked" | "whenunlocked" | "default" => Ok(AccessPolicy::WhenUnlocked),
+            "when-unlocked-this-device-only" | "whenunlockedthisdeviceonly" => {
+                Ok(AccessPolicy::WhenUnlockedThisDeviceOnly)
+            }
+            "require-user-presence" | "requireuserpresence" => {
+                Ok(AccessPolicy::RequireUserPresence)
+            }
+            "biometry-any" | "biometryany" => Ok(AccessPolicy::BiometryAny),
+            "biometry-current-set" | "biometrycurrentset" => Ok(AccessPolicy::BiometryCurrentSet),
+            "application-password" | "applicationpassword" => Ok(AccessPolicy::ApplicationPassword),
+            "when-passcode-set-this-device-only" | "whenpasscodesetthisdeviceonly" => {
+                Ok(AccessPolicy::WhenPasscodeSetThisDeviceOnly)
+            }
+            _ => Err(ErrorCode::Invalid(
+                "access-policy".to_string(),
+                format!("unknown value: {s}"),
+            )),
+        }
+    }
 }
 
+/// What comes before the policy name in a label's access-policy marker.
+const POLICY_MARKER: &str = "\u{1F512} ";
+
 impl From<&AccessPolicy> for ProtectionMode {
     fn from(value: &AccessPolicy) -> Self {
         match value {
@@ -132,7 +381,43 @@ impl From<&AccessPolicy> for ProtectionMode {
             AccessPolicy::WhenPasscodeSetThisDeviceOnly => {
                 ProtectionMode::AccessibleWhenPasscodeSetThisDeviceOnly
             }
-            AccessPolicy::RequireUserPresence => ProtectionMode::AccessibleWhenUnlocked,
+            AccessPolicy::RequireUserPresence
+            | AccessPolicy::BiometryAny
+            | AccessPolicy::BiometryCurrentSet
+            | AccessPolicy::ApplicationPassword => ProtectionMode::AccessibleWhenUnlocked,
+        }
+    }
+}
+
+/// Whether reading a secret may show the authentication UI.
+///
+/// These are recognized case-insensitively, so `Fail` and `fail` are the same.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuthenticationUi {
+    /// Ask the user to authenticate if the item requires it.
+    #[default]
+    Allow,
+    /// Treat an item that requires authentication as missing, so reading its
+    /// secret fails with a `NoEntry` error.
+    Skip,
+    /// Fail right away, rather than ask the user, if the item requires
+    /// authentication. The error is a `PlatformFailure` whose
+    /// [error_kind](crate::error::error_kind) is `InteractionNotAllowed`.
+    Fail,
+}
+
+impl std::str::FromStr for AuthenticationUi {
+    type Err = ErrorCode;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "allow" => Ok(AuthenticationUi::Allow),
+            "skip" => Ok(AuthenticationUi::Skip),
+            "fail" => Ok(AuthenticationUi::Fail),
+            _ => Err(ErrorCode::Invalid(
+                "authentication-ui".to_string(),
+                format!("unknown value: {s}"),
+            )),
         }
     }
 }
@@ -142,15 +427,358 @@ impl From<&AccessPolicy> for ProtectionMode {
 /// If there is no access group, the credential will be created in a
 /// default group as chosen by the OS per
 /// [these guidelines](https://developer.apple.com/documentation/security/ksecattraccessgroup).
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Cred {
     pub service: String,
     pub account: String,
     pub access_policy: AccessPolicy,
     pub access_group: Option<String>,
     pub cloud_synchronize: bool,
+    /// If set, the account attribute of the item holds this display name,
+    /// and the `account` is kept in the item's generic metadata instead.
+    /// See [build](Store::build).
+    pub display_user: Option<String>,
+    /// If set, [set_secret](CredentialApi::set_secret) first reads the existing
+    /// secret and skips the write if it's unchanged. See [build](Store::build).
+    pub skip_unchanged: bool,
+    /// If set, the service and account the item is actually stored under,
+    /// as produced by the store's [SpecifierHasher].
+    pub stored_specifiers: Option<(String, String)>,
+    /// If set, setting the secret or deleting the credential only logs what it
+    /// would do. See [new_with_configuration](Store::new_with_configuration).
+    pub dry_run: bool,
+    /// If set, a user-presence check within this many seconds satisfies
+    /// the item's user-presence requirement. See [build](Store::build).
+    pub user_presence_within: Option<u32>,
+    /// If set, reading the secret of an item that requires user presence fails,
+    /// rather than prompts, within this many seconds of the last read of the item
+    /// that could have prompted. See [new_with_configuration](Store::new_with_configuration).
+    pub prompt_interval: Option<u32>,
+    /// If set, an authentication prompt shown while reading the secret is
+    /// dismissed if the user hasn't answered it within this many seconds, and
+    /// the read fails with a [Timeout](crate::timeout::Timeout). See [build](Store::build).
+    pub prompt_timeout: Option<u32>,
+    /// If set, reading the secret of an item that requires user presence on
+    /// the main thread fails with a [MainThreadPrompt] error, rather than
+    /// prompts. See [new_with_configuration](Store::new_with_configuration).
+    pub fail_on_main_thread: bool,
+    /// If set, deleting the credential leaves a [Tombstone] behind.
+    /// See [new_with_configuration](Store::new_with_configuration).
+    pub leave_tombstone: bool,
+    /// How much of the credential its `Debug` output shows. See the
+    /// [redact](crate::redact) module.
+    pub redaction: Redaction,
+    /// If set, an application-assigned ID kept in the item's generic metadata,
+    /// by which it can be found. See [build](Store::build).
+    pub tag: Option<String>,
+    /// Whether reading the secret may ask the user to authenticate.
+    /// See [with_authentication_ui](Cred::with_authentication_ui).
+    pub authentication_ui: AuthenticationUi,
+    /// If set, the template for the item's label, whose `{policy}` is replaced
+    /// by the access policy's [label_marker](AccessPolicy::label_marker).
+    /// See [build](Store::build).
+    pub label_template: Option<String>,
+    /// If set, setting the secret fails rather than replace the secret of an
+    /// existing item. See [build](Store::build).
+    pub create_only: bool,
+    /// If set, how the item was protected when this credential was found. Only
+    /// credentials returned by a search or by `get_credential` have it.
+    pub protection: Option<ItemProtection>,
+}
+
+/// The kind of user authentication the device offers, as reported by
+/// [biometry].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Biometry {
+    FaceId,
+    TouchId,
+    OpticId,
+    /// There's no usable biometry (there's no sensor, or nothing is enrolled,
+    /// or it's locked out), but there's a passcode.
+    PasscodeOnly,
+    /// There's no passcode, so items can't require user presence.
+    Unavailable,
+}
+
+impl Biometry {
+    /// Whether biometry can be used.
+    pub fn is_biometric(&self) -> bool {
+        matches!(
+            self,
+            Biometry::FaceId | Biometry::TouchId | Biometry::OpticId
+        )
+    }
+
+    /// The strongest access policy that new items can be created with: one of
+    /// the biometry ones if `biometric` is true and biometry can be used,
+    /// [RequireUserPresence](AccessPolicy::RequireUserPresence) if there's a
+    /// passcode, and the softer default otherwise.
+    pub fn strongest_policy(&self, biometric: bool) -> AccessPolicy {
+        match self {
+            _ if biometric && self.is_biometric() => AccessPolicy::BiometryAny,
+            Biometry::Unavailable => AccessPolicy::default(),
+            _ => AccessPolicy::RequireUserPresence,
+        }
+    }
+}
+
+/// Which kind of user authentication the device offers right now, so an app
+/// can choose between `require-user-presence` (or a biometry policy) and a
+/// softer access policy before creating entries.
+///
+/// This asks LocalAuthentication whether its policies can be evaluated, which
+/// never shows UI. The answer can change (when the user enrolls a face or
+/// removes the passcode, say), so ask again rather than keep it.
+pub fn biometry() -> Biometry {
+    // SAFETY: creating a context and asking whether it can evaluate a policy
+    // have no preconditions, and the biometry type is read after that check.
+    unsafe {
+        let context = LAContext::new();
+        if context
+            .canEvaluatePolicy_error(LAPolicy::DeviceOwnerAuthenticationWithBiometrics)
+            .is_ok()
+        {
+            match context.biometryType() {
+                LABiometryType::FaceID => return Biometry::FaceId,
+                LABiometryType::TouchID => return Biometry::TouchId,
+                LABiometryType::OpticID => return Biometry::OpticId,
+                _ => {}
+            }
+        }
+        match context.canEvaluatePolicy_error(LAPolicy::DeviceOwnerAuthentication) {
+            Ok(()) => Biometry::PasscodeOnly,
+            Err(_) => Biometry::Unavailable,
+        }
+    }
+}
+
+/// Why items can't be created with an access policy right now, as reported by
+/// [preflight](AccessPolicy::preflight).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PolicyUnavailable {
+    /// The device has no passcode, so nothing can require user presence.
+    PasscodeNotSet,
+    /// The device has no biometry sensor (or the app may not use it).
+    BiometryNotAvailable,
+    /// The device has a biometry sensor, but nothing is enrolled.
+    BiometryNotEnrolled,
+    /// Biometry is locked out after too many failed attempts, until the
+    /// user enters the passcode.
+    BiometryLockout,
+    /// Any other reason LocalAuthentication gave, with its code and description.
+    Other(isize, String),
+}
+
+impl std::fmt::Display for PolicyUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyUnavailable::PasscodeNotSet => write!(f, "The device has no passcode"),
+            PolicyUnavailable::BiometryNotAvailable => {
+                write!(f, "Biometry isn't available on the device")
+            }
+            PolicyUnavailable::BiometryNotEnrolled => {
+                write!(f, "No biometry is enrolled on the device")
+            }
+            PolicyUnavailable::BiometryLockout => {
+                write!(f, "Biometry is locked out until the passcode is entered")
+            }
+            PolicyUnavailable::Other(code, description) => {
+                write!(f, "Authentication isn't possible ({code}): {description}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyUnavailable {}
+
+impl From<PolicyUnavailable> for ErrorCode {
+    /// A [NotSupportedByStore](ErrorCode::NotSupportedByStore) error saying why.
+    fn from(reason: PolicyUnavailable) -> Self {
+        ErrorCode::NotSupportedByStore(reason.to_string())
+    }
+}
+
+impl AccessPolicy {
+    /// Check, before creating items with this policy, that the device can
+    /// protect them with it, and say why not if it can't.
+    ///
+    /// This asks LocalAuthentication whether its policy for this access policy
+    /// can be evaluated (with `canEvaluatePolicy`), which never shows UI: the
+    /// biometry policies need enrolled biometry, and `require-user-presence`
+    /// needs a passcode. Other policies (including `application-password`)
+    /// always pass. Without this, such an item can often still be created, and
+    /// the problem only shows when reading it back fails.
+    pub fn preflight(&self) -> std::result::Result<(), PolicyUnavailable> {
+        let policy = match self {
+            AccessPolicy::RequireUserPresence => LAPolicy::DeviceOwnerAuthentication,
+            AccessPolicy::BiometryAny | AccessPolicy::BiometryCurrentSet => {
+                LAPolicy::DeviceOwnerAuthenticationWithBiometrics
+            }
+            _ => return Ok(()),
+        };
+        // SAFETY: creating a context and asking whether it can evaluate a
+        // policy have no preconditions.
+        let result = unsafe { LAContext::new().canEvaluatePolicy_error(policy) };
+        result.map_err(|err| match err.code() {
+            LA_ERROR_PASSCODE_NOT_SET => PolicyUnavailable::PasscodeNotSet,
+            LA_ERROR_BIOMETRY_NOT_AVAILABLE => PolicyUnavailable::BiometryNotAvailable,
+            LA_ERROR_BIOMETRY_NOT_ENROLLED => PolicyUnavailable::BiometryNotEnrolled,
+            LA_ERROR_BIOMETRY_LOCKOUT => PolicyUnavailable::BiometryLockout,
+            code => PolicyUnavailable::Other(code, err.localizedDescription().to_string()),
+        })
+    }
+}
+
+/// The `LAError` codes that [preflight](AccessPolicy::preflight) tells apart.
+const LA_ERROR_PASSCODE_NOT_SET: isize = -5;
+const LA_ERROR_BIOMETRY_NOT_AVAILABLE: isize = -6;
+const LA_ERROR_BIOMETRY_NOT_ENROLLED: isize = -7;
+const LA_ERROR_BIOMETRY_LOCKOUT: isize = -8;
+
+/// How an existing item is protected, as the OS reports it.
+///
+/// Unlike a found credential's [access_policy](Cred::access_policy), which is
+/// always the default (see the module docs), this describes the item itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemProtection {
+    /// The item's protection class, by the name of its `kSecAttrAccessible`
+    /// constant, such as `kSecAttrAccessibleAfterFirstUnlock`.
+    pub accessibility: String,
+    /// Whether the item's access control requires user presence (or biometry,
+    /// or an application password) to read it.
+    pub requires_user_presence: bool,
+}
+
+impl ItemProtection {
+    /// Whether the item stays on this device (its protection class is one of
+    /// the `ThisDeviceOnly` ones).
+    pub fn device_only(&self) -> bool {
+        self.accessibility.ends_with("ThisDeviceOnly")
+    }
+
+    /// The protection of a search result, given whether it requires user presence.
+    fn of_search_result(
+        result: &item::SearchResult,
+        requires_user_presence: bool,
+    ) -> Option<ItemProtection> {
+        let class = result.simplify_dict()?.get("pdmn")?.clone();
+        Some(ItemProtection {
+            accessibility: protection_class_name(&class),
+            requires_user_presence,
+        })
+    }
+}
+
+/// A kind of item probed by [health_check](Store::health_check).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HealthScope {
+    /// An item that stays on the device.
+    Local,
+    /// An item synchronized with iCloud.
+    Cloud,
+    /// An item that stays on the device and requires user presence to read.
+    UserPresence,
 }
 
+/// How one scope fared in a [health_check](Store::health_check).
+///
+/// Each step is [Available](Availability::Available) if it worked, and
+/// otherwise says why it didn't. The steps after a failed write aren't tried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeHealth {
+    pub scope: HealthScope,
+    /// Writing the probe item.
+    pub write: Availability,
+    /// Reading the probe item back.
+    pub read: Option<Availability>,
+    /// Deleting the probe item.
+    pub delete: Option<Availability>,
+}
+
+impl ScopeHealth {
+    /// Whether every step worked.
+    pub fn healthy(&self) -> bool {
+        self.write.is_available()
+            && self.read.as_ref().is_some_and(Availability::is_available)
+            && self.delete.as_ref().is_some_and(Availability::is_available)
+    }
+}
+
+/// What tells apart the items in one scope of a search: their service,
+/// account, and access group.
+type ItemKey = (String, String, Option<String>);
+
+/// The key of a search result, if it has the attributes for one.
+fn item_key(result: &item::SearchResult) -> Option<ItemKey> {
+    let attrs = result.simplify_dict()?;
+    Some((
+        attrs.get("svce")?.clone(),
+        attrs.get("acct")?.clone(),
+        attrs.get("agrp").cloned(),
+    ))
+}
+
+impl std::fmt::Debug for Cred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stored_specifiers = self
+            .stored_specifiers
+            .as_ref()
+            .map(|stored| Masked(stored, self.redaction));
+        f.debug_struct("Cred")
+            .field("service", &Masked(&self.service, self.redaction))
+            .field("account", &Masked(&self.account, self.redaction))
+            .field("access_policy", &self.access_policy)
+            .field("access_group", &self.access_group)
+            .field("cloud_synchronize", &self.cloud_synchronize)
+            .field(
+                "display_user",
+                &self
+                    .display_user
+                    .as_ref()
+                    .map(|u| Masked(u, self.redaction)),
+            )
+            .field("skip_unchanged", &self.skip_unchanged)
+            .field("stored_specifiers", &stored_specifiers)
+            .field("dry_run", &self.dry_run)
+            .field("user_presence_within", &self.user_presence_within)
+            .field("prompt_interval", &self.prompt_interval)
+            .field("prompt_timeout", &self.prompt_timeout)
+            .field("fail_on_main_thread", &self.fail_on_main_thread)
+            .field("leave_tombstone", &self.leave_tombstone)
+            .field("redaction", &self.redaction)
+            .field("tag", &self.tag)
+            .field("authentication_ui", &self.authentication_ui)
+            .field("label_template", &self.label_template)
+            .field("create_only", &self.create_only)
+            .field("protection", &self.protection)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for Cred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.cloud_synchronize {
+            write!(f, "cloud-synchronized protected generic password")
+        } else {
+            write!(f, "protected generic password")
+        }
+    }
+}
+
+/// The prefix on the generic metadata of items created with a display user.
+const DISPLAY_USER_TAG: &str = "keyring-user:";
+
+/// The prefix on the generic metadata of tagged items.
+const TAG_PREFIX: &str = "keyring-tag:";
+
+/// The prefix on the service and the generic metadata of tombstone items.
+const TOMBSTONE_TAG: &str = "keyring-tombstone:";
+
+/// The prefix shared by all the generic metadata this crate writes for itself.
+const RESERVED_METADATA_PREFIX: &str = "keyring-";
+
 impl Cred {
     /// Create an entry representing a protected generic password.
     ///
@@ -164,6 +792,23 @@ impl Cred {
         access_group: Option<String>,
         cloud_synchronize: bool,
     ) -> Result<Entry> {
+        let cred = Self::new(
+            service,
+            user,
+            access_policy,
+            access_group,
+            cloud_synchronize,
+        )?;
+        Ok(Entry::new_with_credential(Arc::new(cred)))
+    }
+
+    fn new(
+        service: &str,
+        user: &str,
+        access_policy: AccessPolicy,
+        access_group: Option<String>,
+        cloud_synchronize: bool,
+    ) -> Result<Self> {
         if service.is_empty() {
             return Err(ErrorCode::Invalid(
                 "service".to_string(),
@@ -176,109 +821,843 @@ impl Cred {
                 "cannot be empty".to_string(),
             ));
         }
-        let cred = Self {
+        Ok(Self {
             service: service.to_string(),
             account: user.to_string(),
             access_policy,
             access_group,
             cloud_synchronize,
-        };
-        Ok(Entry::new_with_credential(Arc::new(cred)))
+            display_user: None,
+            skip_unchanged: false,
+            stored_specifiers: None,
+            dry_run: false,
+            user_presence_within: None,
+            prompt_interval: None,
+            prompt_timeout: None,
+            fail_on_main_thread: false,
+            leave_tombstone: false,
+            redaction: Redaction::Full,
+            tag: None,
+            authentication_ui: AuthenticationUi::Allow,
+            label_template: None,
+            create_only: false,
+            protection: None,
+        })
     }
 
-    fn build_from_search_result(result: &item::SearchResult, cloud_sync: bool) -> Result<Entry> {
-        if let Some(attrs) = result.simplify_dict() {
-            let service = attrs.get("svce").ok_or_else(|| {
-                ErrorCode::Invalid("search result".to_string(), "has no service".to_string())
-            })?;
-            let account = attrs.get("acct").ok_or_else(|| {
-                ErrorCode::Invalid("search result".to_string(), "has no account".to_string())
-            })?;
-            let group = attrs.get("agrp").cloned();
-            Ok(Entry::new_with_credential(Arc::new(Cred {
-                service: service.clone(),
-                account: account.clone(),
-                access_group: group,
-                access_policy: Default::default(),
-                cloud_synchronize: cloud_sync,
-            })))
-        } else {
-            // should never happen
-            Err(ErrorCode::Invalid(
-                "search result".to_string(),
-                "has no attributes".to_string(),
-            ))
+    /// A formatter that shows the credential's service and user, and nothing else.
+    pub fn reveal_specifiers_only(&self) -> Specifiers<'_> {
+        Specifiers {
+            service: &self.service,
+            user: &self.account,
         }
     }
 
-    fn clone_from_search_result(&self, result: &item::SearchResult) -> Self {
+    /// The password of the credential, with any invalid UTF-8 replaced.
+    ///
+    /// `get_password` fails with a [BadEncoding](ErrorCode::BadEncoding) error,
+    /// carrying the raw secret, when the stored data isn't valid UTF-8. That's
+    /// the way to recover the exact bytes; this is for callers (such as those
+    /// reading items written by tools that stored binary data) who would rather
+    /// have a best-effort string, with each invalid sequence shown as U+FFFD.
+    pub fn get_password_lossy(&self) -> Result<String> {
+        let secret = self.get_secret()?;
+        Ok(String::from_utf8_lossy(&secret).into_owned())
+    }
+
+    /// The secret of the credential, in a wrapper that keeps it out of
+    /// `Debug` output and zeroizes it when dropped.
+    #[cfg(feature = "secrecy")]
+    pub fn get_secret_secure(&self) -> Result<secrecy::SecretSlice<u8>> {
+        self.get_secret().map(crate::secure::secure_secret)
+    }
+
+    /// The password of the credential, in a wrapper that keeps it out of
+    /// `Debug` output and zeroizes it when dropped.
+    ///
+    /// As with `get_password`, a secret that isn't UTF-8 is a
+    /// [BadEncoding](ErrorCode::BadEncoding) error carrying the raw bytes.
+    #[cfg(feature = "secrecy")]
+    pub fn get_password_secure(&self) -> Result<secrecy::SecretString> {
+        crate::secure::secure_password(self.get_secret()?)
+    }
+
+    /// A copy of the credential whose secret is read with the given
+    /// authentication UI policy, for a single call such as
+    /// `cred.with_authentication_ui(AuthenticationUi::Fail).get_secret()`.
+    ///
+    /// A background process can use `Fail` to find out that an item needs the
+    /// user without waiting on a prompt nobody will see. Entries can also be
+    /// built with the policy; see [build](Store::build).
+    pub fn with_authentication_ui(&self, ui: AuthenticationUi) -> Cred {
         let mut cred = self.clone();
-        if let Some(attrs) = result.simplify_dict() {
-            if let Some(group) = attrs.get("agrp") {
-                cred.access_group = Some(group.to_string());
-            } else {
-                // should never happen, so warn if it does
-                error!("Search result credential has no access group; using entry's group")
-            }
-        } else {
-            // should never happen, so warn if it does
-            error!("Search result credential has no attributes; using entry's group")
-        }
+        cred.authentication_ui = ui;
         cred
     }
-}
 
-impl CredentialApi for Cred {
-    /// See the keychain-core API docs.
-    fn set_secret(&self, secret: &[u8]) -> Result<()> {
-        let mut options = PasswordOptions::new_generic_password(&self.service, &self.account);
-        options.use_protected_keychain();
-        if let Some(access_group) = &self.access_group {
-            options.set_access_group(access_group);
-        }
-        if self.cloud_synchronize {
-            options.set_access_synchronized(Some(true));
-        } else {
-            match &self.access_policy {
-                AccessPolicy::RequireUserPresence => {
-                    let access_control = SecAccessControl::create_with_protection(
-                        Some(self.access_policy.as_ref().into()),
-                        AccessControlOptions::USER_PRESENCE.bits(),
-                    )
-                    .map_err(decode_error)?;
-                    options.set_access_control(access_control);
-                }
-                other => {
-                    options.set_access_control(
-                        SecAccessControl::create_with_protection(
-                            Some(other.into()),
-                            Default::default(),
-                        )
-                        .map_err(decode_error)?,
-                    );
-                }
-            }
+    /// Get the secret, giving the user the given reason if the OS has to ask
+    /// them to authenticate.
+    ///
+    /// The OS shows the reason in its own authentication UI, in place of its
+    /// generic one, so it should finish a sentence such as "MyApp is trying to".
+    /// For items that don't require user presence, this is just `get_secret`.
+    /// A `require-user-presence-within` window is honored as it is there.
+    pub fn get_secret_with_prompt(&self, prompt: &str) -> Result<Vec<u8>> {
+        if prompt.is_empty() {
+            return Err(ErrorCode::Invalid(
+                "prompt".to_string(),
+                "cannot be empty".to_string(),
+            ));
         }
-        set_generic_password_options(secret, options).map_err(decode_error)?;
-        Ok(())
+        self.check_main_thread()?;
+        let prompt = prompt.to_string();
+        timeout::run("get_secret", self.clone(), move |cred| {
+            let context = AuthenticationContext::with_prompt(&prompt, cred.user_presence_within);
+            cred.read_secret_with_deadline(&context)
+        })
     }
 
-    /// See the keychain-core API docs.
-    fn get_secret(&self) -> Result<Vec<u8>> {
-        let mut options = PasswordOptions::new_generic_password(&self.service, &self.account);
-        options.use_protected_keychain();
-        if let Some(access_group) = &self.access_group {
-            options.set_access_group(access_group);
+    /// Set the secret of a credential with the `ApplicationPassword` access
+    /// policy, supplying the application password so the OS doesn't ask for it.
+    ///
+    /// Creating an item this way encrypts it with the given password, which
+    /// then has to be supplied (or entered by the user) to read the secret. For
+    /// an existing item, the password must be the one it was created with.
+    pub fn set_secret_with_application_password(
+        &self,
+        secret: &[u8],
+        password: &[u8],
+    ) -> Result<()> {
+        let secret = secret.to_vec();
+        let password = password.to_vec();
+        timeout::run("set_secret", self.clone(), move |cred| {
+            cred.write_secret_in(&secret, &application_password_context(&password)?)
+        })
+    }
+
+    /// Get the secret of a credential with the `ApplicationPassword` access
+    /// policy, supplying the application password so the OS doesn't ask for it.
+    ///
+    /// A wrong password fails just as a canceled prompt would.
+    pub fn get_secret_with_application_password(&self, password: &[u8]) -> Result<Vec<u8>> {
+        let password = password.to_vec();
+        timeout::run("get_secret", self.clone(), move |cred| {
+            cred.read_secret_in(&application_password_context(&password)?)
+        })
+    }
+
+    /// Attach application metadata (such as a schema version) to the
+    /// credential's item, replacing any it had.
+    ///
+    /// The metadata is kept in the item's generic attribute, which isn't
+    /// encrypted, so it mustn't be secret. Setting it doesn't read or change the
+    /// secret, and the item must already exist. Items can be searched for by
+    /// their metadata with the `generic` search key. Credentials with a `tag` or
+    /// a `display-user` keep those in the same attribute, so they can't have
+    /// metadata, and metadata can't start with `keyring-`, which marks the
+    /// crate's own uses of the attribute.
+    pub fn set_metadata(&self, metadata: &[u8]) -> Result<()> {
+        if self.tag.is_some() || self.display_user.is_some() {
+            return Err(ErrorCode::Invalid(
+                "metadata".to_string(),
+                "cannot be combined with tag or display-user".to_string(),
+            ));
         }
-        if self.cloud_synchronize {
-            options.set_access_synchronized(Some(true));
+        if metadata.starts_with(RESERVED_METADATA_PREFIX.as_bytes()) {
+            return Err(ErrorCode::Invalid(
+                "metadata".to_string(),
+                format!("cannot start with {RESERVED_METADATA_PREFIX}"),
+            ));
         }
-        generic_password(options).map_err(decode_error)
+        let metadata = metadata.to_vec();
+        timeout::run("set_metadata", self.clone(), move |cred| {
+            cred.write_generic(&metadata)
+        })
     }
 
-    /// See the keychain-core API docs.
-    fn delete_credential(&self) -> Result<()> {
-        let mut options = PasswordOptions::new_generic_password(&self.service, &self.account);
+    /// The application metadata attached to the credential's item by
+    /// [set_metadata](Cred::set_metadata), if it has any.
+    ///
+    /// Like setting it, this doesn't read the secret, so it never prompts.
+    pub fn get_metadata(&self) -> Result<Option<Vec<u8>>> {
+        timeout::run("get_metadata", self.clone(), Cred::read_generic)
+    }
+
+    /// The work of [set_metadata](Cred::set_metadata).
+    fn write_generic(&self, metadata: &[u8]) -> Result<()> {
+        if self.dry_run {
+            if self.count_items()? == 0 {
+                return Err(ErrorCode::NoEntry);
+            }
+            info!(
+                "Dry run: would set the metadata of the item for service {}, user {}",
+                self.service, self.account
+            );
+            return Ok(());
+        }
+        let mut changes = RawQuery::new();
+        changes.data(Attr::Generic, metadata);
+        self.item_query().update(&changes).map_err(decode_error)
+    }
+
+    /// The work of [get_metadata](Cred::get_metadata).
+    fn read_generic(&self) -> Result<Option<Vec<u8>>> {
+        let mut query = self.item_query();
+        query.return_attributes();
+        let found = query.find().map_err(decode_error)?;
+        let attrs = found.first().ok_or(ErrorCode::NoEntry)?;
+        Ok(attrs
+            .data(Attr::Generic)
+            .filter(|data| !data.starts_with(RESERVED_METADATA_PREFIX.as_bytes())))
+    }
+
+    /// Replace the secret only if it's currently the expected one, returning
+    /// whether it was replaced.
+    ///
+    /// This is a compare-and-swap for processes (such as an app and its
+    /// extensions) that rotate a shared token: the one whose `update_if` sees
+    /// the old token replaces it, and the others get `false`. The read and the
+    /// write happen under the credential's [lease](crate::lease), so this is
+    /// atomic with respect to other `update_if` calls and `lock_credential`
+    /// holders in the same access group, though not to plain `set_secret`
+    /// calls. It fails with `NoEntry` if there's no item, and with
+    /// `NoStorageAccess` if another process holds the lease for longer than
+    /// [SWAP_WAIT](crate::lease::SWAP_WAIT). Reading the secret prompts if the
+    /// item requires user presence.
+    pub fn update_if(&self, expected: &[u8], new: &[u8]) -> Result<bool> {
+        let (expected, new) = (expected.to_vec(), new.to_vec());
+        timeout::run("update_if", self.clone(), move |cred| {
+            lease::compare_and_swap(
+                || {
+                    lease_marker(
+                        cred.stored_service().to_string(),
+                        cred.stored_account().to_string(),
+                        cred.access_group.clone(),
+                    )
+                },
+                || cred.read_secret(),
+                || cred.write_secret(&new),
+                &expected,
+            )
+        })
+    }
+
+    /// Move the credential's item to a new service and user, returning the
+    /// credential for its new name.
+    ///
+    /// The item is changed in place, so its secret isn't read (and nobody is
+    /// asked to authenticate), and it keeps its access policy, label, and
+    /// persistent reference. For a credential with a display user, the new
+    /// user replaces the account kept in the item's metadata, and the display
+    /// name is left alone. This fails with `NoEntry` if there's no item, and
+    /// with a duplicate-item `PlatformFailure` if there's already one with
+    /// the new name. Credentials whose specifiers are hashed can't be renamed,
+    /// since that needs the store's [SpecifierHasher].
+    pub fn rename(&self, new_service: &str, new_user: &str) -> Result<Cred> {
+        if self.stored_specifiers.is_some() {
+            return Err(ErrorCode::NotSupportedByStore(
+                "credentials with hashed specifiers can't be renamed".to_string(),
+            ));
+        }
+        for (key, value) in [("service", new_service), ("user", new_user)] {
+            if value.is_empty() {
+                return Err(ErrorCode::Invalid(
+                    key.to_string(),
+                    "cannot be empty".to_string(),
+                ));
+            }
+        }
+        let mut renamed = self.clone();
+        renamed.service = new_service.to_string();
+        renamed.account = new_user.to_string();
+        timeout::run("rename", self.clone(), move |cred| cred.move_item(renamed))
+    }
+
+    /// The work of [rename](Cred::rename).
+    fn move_item(&self, renamed: Cred) -> Result<Cred> {
+        if self.dry_run {
+            self.item_query().find().map_err(decode_error)?;
+            info!(
+                "Dry run: would rename the item for service {}, user {} to service {}, user {}",
+                self.service, self.account, renamed.service, renamed.account
+            );
+            return Ok(renamed);
+        }
+        let mut changes = RawQuery::new();
+        changes.string(Attr::Service, &renamed.service);
+        if renamed.display_user.is_some() {
+            changes.data(
+                Attr::Generic,
+                format!("{DISPLAY_USER_TAG}{}", renamed.account).as_bytes(),
+            );
+        } else {
+            changes.string(Attr::Account, &renamed.account);
+        }
+        self.item_query().update(&changes).map_err(decode_error)?;
+        Ok(renamed)
+    }
+
+    /// Copy the credential's item to another store (such as the
+    /// cloud-synchronized store, for a credential of the local one),
+    /// returning the credential for the copy.
+    ///
+    /// The copy has the same service and user (hashed if the store hashes),
+    /// secret, label, comment, description, and generic metadata, and is in
+    /// the credential's access group or, if it has none, the store's. Its
+    /// settings from a store's configuration (such as `dry-run` and
+    /// `tombstones`) are the destination store's. A credential found by a
+    /// search in a hashed store whose plain service or user wasn't known
+    /// can't be copied to a hashing store, and fails with `Invalid`. It keeps
+    /// the credential's access policy, except in a cloud-synchronized store,
+    /// where it gets the default one. Synchronized items can't require user
+    /// presence or be kept to one device, so copying to a cloud-synchronized
+    /// store fails with `Invalid` for credentials whose access policy does
+    /// either, and for items that turn out to (since a searched-for
+    /// credential's policy may not be its item's). Copying reads the secret,
+    /// and fails with a duplicate-item `PlatformFailure` if the store already
+    /// has the item.
+    pub fn copy_to(&self, store: &Store) -> Result<Cred> {
+        let copy = store.copy_of(self)?;
+        timeout::run("copy_to", self.clone(), move |cred| cred.copy_item(copy))
+    }
+
+    /// Move the credential's item to another store, returning the credential
+    /// for its new item.
+    ///
+    /// This is [copy_to](Cred::copy_to) followed by deleting the original
+    /// (without leaving a tombstone), so that users can opt into (or out of)
+    /// synchronizing a credential after it was made.
+    pub fn move_to(&self, store: &Store) -> Result<Cred> {
+        let copy = store.copy_of(self)?;
+        timeout::run("move_to", self.clone(), move |cred| {
+            let copy = cred.copy_item(copy)?;
+            if cred.dry_run {
+                info!(
+                    "Dry run: would delete the original item for service {}, user {}",
+                    cred.service, cred.account
+                );
+                return Ok(copy);
+            }
+            cred.item_query().delete().map_err(decode_error)?;
+            Ok(copy)
+        })
+    }
+
+    /// The work of [copy_to](Cred::copy_to).
+    fn copy_item(&self, copy: Cred) -> Result<Cred> {
+        let mut query = self.item_query();
+        query.return_attributes();
+        let found = query.find().map_err(decode_error)?;
+        let attrs = found.first().ok_or(ErrorCode::NoEntry)?;
+        let mut source = self.clone();
+        if copy.cloud_synchronize {
+            let accessibility = attrs.string(Attr::Accessible).unwrap_or_default();
+            if protection_class_name(&accessibility).ends_with("ThisDeviceOnly") {
+                return Err(ErrorCode::Invalid(
+                    "accessibility".to_string(),
+                    "the item is kept to this device".to_string(),
+                ));
+            }
+            source.authentication_ui = AuthenticationUi::Fail;
+        }
+        let secret = match source.read_secret() {
+            Err(err)
+                if copy.cloud_synchronize
+                    && error_kind(&err) == Some(ErrorKind::InteractionNotAllowed) =>
+            {
+                return Err(ErrorCode::Invalid(
+                    "access-policy".to_string(),
+                    "the item requires user presence".to_string(),
+                ));
+            }
+            result => result?,
+        };
+        let mut adding = copy.clone();
+        adding.create_only = true;
+        adding.write_secret(&secret)?;
+        if copy.dry_run {
+            return Ok(copy);
+        }
+        let mut changes = RawQuery::new();
+        let mut changed = false;
+        for attr in [Attr::Label, Attr::Comment, Attr::Description] {
+            if attr == Attr::Label && copy.label_template.is_some() {
+                continue;
+            }
+            if let Some(value) = attrs.string(attr) {
+                changes.string(attr, &value);
+                changed = true;
+            }
+        }
+        if copy.tag.is_none() && copy.display_user.is_none() {
+            if let Some(generic) = attrs.data(Attr::Generic) {
+                changes.data(Attr::Generic, &generic);
+                changed = true;
+            }
+        }
+        if changed {
+            copy.item_query().update(&changes).map_err(decode_error)?;
+        }
+        Ok(copy)
+    }
+
+    /// The persistent reference of the credential's item.
+    ///
+    /// This is an opaque handle that stays valid for as long as the item
+    /// exists, even if its service or account is changed, so it can be saved
+    /// (in a database, say) and passed to
+    /// [from_persistent_ref](Cred::from_persistent_ref) later. That finds the
+    /// item directly, rather than searching for it. Getting the reference
+    /// doesn't read the secret, so it never prompts.
+    pub fn persistent_ref(&self) -> Result<Vec<u8>> {
+        timeout::run("persistent_ref", self.clone(), |cred| {
+            cred.item_query()
+                .find_persistent_ref()
+                .map_err(decode_error)
+        })
+    }
+
+    /// The credential for the item with the given persistent reference,
+    /// as given by [persistent_ref](Cred::persistent_ref).
+    ///
+    /// The credential has the item's current service, account, and access
+    /// group, and is configured (dry-run, tombstones, and redaction) by the
+    /// given store, which must see the item: a reference to an item the store
+    /// would not find (such as a cloud-synchronized item, in a local store)
+    /// is a `NoEntry` error. As with search results, the credential has the
+    /// default access policy, and in a store with a [SpecifierHasher], its
+    /// service and account are the hashed ones.
+    pub fn from_persistent_ref(store: &Store, reference: &[u8]) -> Result<Cred> {
+        if reference.is_empty() {
+            return Err(ErrorCode::Invalid(
+                "persistent reference".to_string(),
+                "cannot be empty".to_string(),
+            ));
+        }
+        let reference = reference.to_vec();
+        timeout::run("from_persistent_ref", store.clone(), move |store| {
+            store.cred_for_persistent_ref(&reference)
+        })
+    }
+
+    /// Re-create the credential's item with a new access policy, returning a
+    /// credential with that policy.
+    ///
+    /// Since the OS can't change the access control of an existing item, this
+    /// reads the secret (prompting if the current policy requires it), deletes
+    /// the item, and adds it again with the new policy and a label rendered
+    /// from the credential's `label-template`, if it has one. If the new item
+    /// can't be added, the old one is put back. No tombstone is left behind.
+    /// The `ApplicationPassword` policy can't be switched to this way, since
+    /// it needs the password; set the secret of a new credential instead.
+    pub fn update_access_policy(&self, policy: AccessPolicy) -> Result<Cred> {
+        if self.cloud_synchronize {
+            return Err(ErrorCode::Invalid(
+                "access-policy".to_string(),
+                "cannot be specified in a cloud-synchronized store".to_string(),
+            ));
+        }
+        if policy == AccessPolicy::ApplicationPassword {
+            return Err(ErrorCode::Invalid(
+                "access-policy".to_string(),
+                "an application password must be supplied when the item is created".to_string(),
+            ));
+        }
+        let mut updated = self.clone();
+        updated.access_policy = policy;
+        updated.skip_unchanged = false;
+        if updated.access_policy != AccessPolicy::RequireUserPresence {
+            updated.user_presence_within = None;
+        }
+        timeout::run("update_access_policy", self.clone(), move |cred| {
+            cred.replace_item(updated)
+        })
+    }
+
+    /// The work of [update_access_policy](Cred::update_access_policy).
+    fn replace_item(&self, updated: Cred) -> Result<Cred> {
+        let secret = self.read_secret()?;
+        // fail on an unsupported policy before anything is deleted
+        updated.access_control()?;
+        if self.dry_run {
+            info!(
+                "Dry run: would re-create the item for service {}, user {} with access policy {}",
+                self.service, self.account, updated.access_policy
+            );
+            return Ok(updated);
+        }
+        self.item_query().delete().map_err(decode_error)?;
+        if let Err(err) = updated.write_secret(&secret) {
+            if let Err(restore) = self.write_secret(&secret) {
+                error!(
+                    "Couldn't restore the item for service {}, user {}: {restore}",
+                    self.service, self.account
+                );
+            }
+            return Err(err);
+        }
+        Ok(updated)
+    }
+
+    /// The credential for an item found by a [Query].
+    ///
+    /// Like a search result, it has the default access policy.
+    fn from_item(item: &ItemAttributes) -> Result<Self> {
+        let generic = item.generic.as_deref().map(String::from_utf8_lossy);
+        // items created with a display user keep their real account in the metadata
+        let (account, display_user) = match generic
+            .as_deref()
+            .and_then(|g| g.strip_prefix(DISPLAY_USER_TAG))
+        {
+            Some(user) => (user, Some(item.account.clone())),
+            None => (item.account.as_str(), None),
+        };
+        let mut cred = Self::new(
+            &item.service,
+            account,
+            Default::default(),
+            item.access_group.clone(),
+            item.synchronized,
+        )?;
+        cred.display_user = display_user;
+        cred.tag = generic
+            .as_deref()
+            .and_then(|g| g.strip_prefix(TAG_PREFIX))
+            .map(String::from);
+        Ok(cred)
+    }
+
+    fn from_search_result(result: &item::SearchResult, cloud_sync: bool) -> Result<Self> {
+        if let Some(attrs) = result.simplify_dict() {
+            let service = attrs.get("svce").ok_or_else(|| {
+                ErrorCode::Invalid("search result".to_string(), "has no service".to_string())
+            })?;
+            let account = attrs.get("acct").ok_or_else(|| {
+                ErrorCode::Invalid("search result".to_string(), "has no account".to_string())
+            })?;
+            let group = attrs.get("agrp").cloned();
+            // items created with a display user keep their real account in the metadata
+            let (account, display_user) = match attrs
+                .get("gena")
+                .and_then(|g| g.strip_prefix(DISPLAY_USER_TAG))
+            {
+                Some(user) => (user.to_string(), Some(account.clone())),
+                None => (account.clone(), None),
+            };
+            let tag = attrs
+                .get("gena")
+                .and_then(|g| g.strip_prefix(TAG_PREFIX))
+                .map(String::from);
+            Ok(Cred {
+                service: service.clone(),
+                account,
+                access_group: group,
+                access_policy: Default::default(),
+                cloud_synchronize: cloud_sync,
+                display_user,
+                skip_unchanged: false,
+                stored_specifiers: None,
+                dry_run: false,
+                user_presence_within: None,
+                prompt_interval: None,
+                prompt_timeout: None,
+                fail_on_main_thread: false,
+                leave_tombstone: false,
+                redaction: Redaction::Full,
+                tag,
+                authentication_ui: AuthenticationUi::Allow,
+                label_template: None,
+                create_only: false,
+                protection: None,
+            })
+        } else {
+            // should never happen
+            Err(ErrorCode::Invalid(
+                "search result".to_string(),
+                "has no attributes".to_string(),
+            ))
+        }
+    }
+
+    /// The service attribute of the underlying item.
+    fn stored_service(&self) -> &str {
+        match &self.stored_specifiers {
+            Some((service, _)) => service,
+            None => &self.service,
+        }
+    }
+
+    /// The account of the underlying item.
+    fn stored_account(&self) -> &str {
+        match &self.stored_specifiers {
+            Some((_, account)) => account,
+            None => &self.account,
+        }
+    }
+
+    /// The work of [set_secret](CredentialApi::set_secret), which may run on a worker.
+    fn write_secret(&self, secret: &[u8]) -> Result<()> {
+        if self.dry_run {
+            self.access_control()?;
+            let verb = match self.count_items()? {
+                0 => "create",
+                _ if self.create_only => {
+                    return Err(decode_error(Error::from_code(errSecDuplicateItem)));
+                }
+                _ => "update",
+            };
+            info!(
+                "Dry run: would {verb} the item for service {}, user {}",
+                self.service, self.account
+            );
+            return Ok(());
+        }
+        if self.skip_unchanged {
+            match self.read_secret() {
+                Ok(existing) if existing == secret => return Ok(()),
+                Ok(_) | Err(ErrorCode::NoEntry) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.update_or_add(&self.item_query(), secret)?;
+        self.write_metadata()
+    }
+
+    /// Write the secret in the given authentication context, creating the
+    /// item if need be.
+    fn write_secret_in(&self, secret: &[u8], context: &AuthenticationContext) -> Result<()> {
+        if self.dry_run {
+            return self.write_secret(secret);
+        }
+        let mut query = self.item_query();
+        query.authentication_context(context);
+        self.update_or_add(&query, secret)?;
+        self.write_metadata()
+    }
+
+    /// Change the secret of the item the query finds, or add the item if
+    /// there isn't one.
+    ///
+    /// An existing item is changed in place, so its label, creation date,
+    /// access control, and any other attributes (including ones set by other
+    /// tools) are kept; only a new item gets the credential's access control.
+    ///
+    /// A create-only credential only adds. Otherwise, if another writer adds
+    /// the item between the update finding none and the add, the add is turned
+    /// back into an update (up to [WRITE_ATTEMPTS] times), so concurrent writers
+    /// never see the duplicate-item error: the last write wins.
+    fn update_or_add(&self, query: &RawQuery, secret: &[u8]) -> Result<()> {
+        let mut changes = RawQuery::new();
+        changes.value(secret);
+        if let Some(display_user) = &self.display_user {
+            changes.string(Attr::Account, display_user);
+        }
+        let mut attempt = 1;
+        loop {
+            let updated = if self.create_only {
+                Err(Error::from_code(errSecItemNotFound))
+            } else {
+                query.update(&changes)
+            };
+            match updated {
+                Ok(()) => return Ok(()),
+                Err(err) if err.code() == errSecItemNotFound => {}
+                Err(err) => return Err(decode_error(err)),
+            }
+            let mut item = query.clone();
+            item.value(secret);
+            if let Some(display_user) = &self.display_user {
+                item.string(Attr::Account, display_user);
+            }
+            if let Some(access_control) = self.access_control()? {
+                item.access_control(access_control);
+            }
+            match item.add() {
+                Ok(()) => return Ok(()),
+                Err(err)
+                    if err.code() == errSecDuplicateItem
+                        && !self.create_only
+                        && attempt < WRITE_ATTEMPTS =>
+                {
+                    debug!("Item was added concurrently; updating it (attempt {attempt})");
+                    attempt += 1;
+                }
+                Err(err) => return Err(self.decode_add_error(err)),
+            }
+        }
+    }
+
+    /// Decode an error from adding the credential's item, which (with one of
+    /// several status codes) is how the OS says that the item's access policy
+    /// needs a passcode the device doesn't have.
+    fn decode_add_error(&self, err: Error) -> ErrorCode {
+        if self.access_policy.requires_passcode() && passcode_is_not_set() {
+            return ErrorCode::NoStorageAccess(Box::new(PasscodeNotSet));
+        }
+        decode_error(err)
+    }
+
+    /// Decode an error from reading the credential's secret, which (with
+    /// `errSecAuthFailed`, all the Security framework says about it) is how the
+    /// OS says that biometry is locked out. Only credentials whose items need
+    /// authentication ask LocalAuthentication whether that's why.
+    fn decode_read_error(&self, err: Error) -> ErrorCode {
+        if err.code() == -25293 && self.read_needs_authentication() && biometry_is_locked_out() {
+            return ErrorCode::NoStorageAccess(Box::new(BiometryLockedOut));
+        }
+        decode_error(err)
+    }
+
+    /// Record the credential's tag (in the item's generic metadata) and its
+    /// templated label, if it has them.
+    fn write_metadata(&self) -> Result<()> {
+        if self.tag.is_none() && self.label_template.is_none() {
+            return Ok(());
+        }
+        let mut changes = RawQuery::new();
+        if let Some(tag) = &self.tag {
+            changes.data(Attr::Generic, format!("{TAG_PREFIX}{tag}").as_bytes());
+        }
+        if let Some(template) = &self.label_template {
+            let label = template.replace("{policy}", &self.access_policy.label_marker());
+            changes.string(Attr::Label, &label);
+        }
+        self.item_query().update(&changes).map_err(decode_error)
+    }
+
+    /// The work of [get_secret](CredentialApi::get_secret), with concurrent
+    /// reads of an item that requires user presence coalesced into one.
+    ///
+    /// The first thread to read such an item does the read (and shows the
+    /// prompt), and the threads that ask for the same item while it's at it
+    /// wait for it and get its result, so the user authenticates once. An item
+    /// is known to require user presence if the credential's access policy
+    /// does, or (for a found credential) if its [protection](Cred::protection)
+    /// says so. Reads that can't show the prompt aren't coalesced.
+    fn read_secret_shared(&self) -> Result<Vec<u8>> {
+        if !self.read_may_prompt() {
+            return self.read_secret();
+        }
+        let key = (
+            self.stored_service().to_string(),
+            self.stored_account().to_string(),
+            self.access_group.clone(),
+            self.cloud_synchronize,
+        );
+        let (read, first) = {
+            let mut reads = READS.lock().unwrap_or_else(PoisonError::into_inner);
+            match reads.get(&key) {
+                Some(read) => (read.clone(), false),
+                None => {
+                    self.check_prompt_interval(&key)?;
+                    let read = Arc::new(SharedRead::default());
+                    reads.insert(key.clone(), read.clone());
+                    (read, true)
+                }
+            }
+        };
+        if first {
+            let publish = PublishRead {
+                key: &key,
+                read: &read,
+            };
+            let result = self.read_secret();
+            *read.result.lock().unwrap_or_else(PoisonError::into_inner) =
+                Some(copy_result(&result));
+            drop(publish);
+            return result;
+        }
+        let mut result = read.result.lock().unwrap_or_else(PoisonError::into_inner);
+        while result.is_none() {
+            result = read
+                .done
+                .wait(result)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        copy_result(result.as_ref().unwrap())
+    }
+
+    /// Whether reading the secret may show an authentication prompt.
+    fn read_may_prompt(&self) -> bool {
+        self.read_needs_authentication() && self.authentication_ui == AuthenticationUi::Allow
+    }
+
+    /// Whether the item is known to need authentication to be read.
+    fn read_needs_authentication(&self) -> bool {
+        self.access_policy.requires_user_presence()
+            || self
+                .protection
+                .as_ref()
+                .is_some_and(|p| p.requires_user_presence)
+    }
+
+    /// Warn about (or, with `fail_on_main_thread`, refuse) a read that may
+    /// prompt on the main thread.
+    fn check_main_thread(&self) -> Result<()> {
+        if !self.read_may_prompt() || !on_main_thread() {
+            return Ok(());
+        }
+        if self.fail_on_main_thread {
+            return Err(ErrorCode::NoStorageAccess(Box::new(MainThreadPrompt)));
+        }
+        warn!(
+            "Reading service {}, user {} on the main thread may prompt and block it",
+            self.service, self.account
+        );
+        Ok(())
+    }
+
+    /// Fail if this credential's `prompt_interval` hasn't passed since the
+    /// item was last read, and otherwise note that it's being read now.
+    fn check_prompt_interval(&self, key: &ReadKey) -> Result<()> {
+        let Some(seconds) = self.prompt_interval else {
+            return Ok(());
+        };
+        let interval = Duration::from_secs(seconds.into());
+        let now = Instant::now();
+        let mut prompts = PROMPTS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(elapsed) = prompts.get(key).map(|last| now.duration_since(*last)) {
+            if elapsed < interval {
+                return Err(ErrorCode::NoStorageAccess(Box::new(PromptRateLimited {
+                    retry_after: interval - elapsed,
+                })));
+            }
+        }
+        prompts.insert(key.clone(), now);
+        Ok(())
+    }
+
+    /// The work of [get_secret](CredentialApi::get_secret).
+    fn read_secret(&self) -> Result<Vec<u8>> {
+        match self.authentication_ui {
+            AuthenticationUi::Allow => {}
+            AuthenticationUi::Skip => {
+                let mut query = self.item_query();
+                query.skip_authenticated_items();
+                return query.find_data().map_err(|err| self.decode_read_error(err));
+            }
+            AuthenticationUi::Fail => {
+                let context = AuthenticationContext::non_interactive(self.user_presence_within);
+                return self.read_secret_in(&context);
+            }
+        }
+        if self.prompt_timeout.is_some() {
+            let context = AuthenticationContext::interactive(self.user_presence_within);
+            return self.read_secret_with_deadline(&context);
+        }
+        if let Some(seconds) = self.user_presence_within {
+            let mut query = self.item_query();
+            query.reuse_authentication_within(seconds);
+            return query.find_data().map_err(|err| self.decode_read_error(err));
+        }
+        if self.display_user.is_some() {
+            return self
+                .display_user_query()
+                .find_data()
+                .map_err(|err| self.decode_read_error(err));
+        }
+        let mut options =
+            PasswordOptions::new_generic_password(self.stored_service(), self.stored_account());
         options.use_protected_keychain();
         if let Some(access_group) = &self.access_group {
             options.set_access_group(access_group);
@@ -286,21 +1665,128 @@ impl CredentialApi for Cred {
         if self.cloud_synchronize {
             options.set_access_synchronized(Some(true));
         }
-        delete_generic_password_options(options).map_err(decode_error)?;
+        generic_password(options).map_err(|err| self.decode_read_error(err))
+    }
+
+    /// Read the secret in the given authentication context, which may be
+    /// shared with other reads.
+    fn read_secret_in(&self, context: &AuthenticationContext) -> Result<Vec<u8>> {
+        let mut query = self.item_query();
+        query.authentication_context(context);
+        query.find_data().map_err(|err| self.decode_read_error(err))
+    }
+
+    /// Read the secret in the given authentication context, dismissing any
+    /// prompt the user hasn't answered within the `prompt_timeout`.
+    fn read_secret_with_deadline(&self, context: &AuthenticationContext) -> Result<Vec<u8>> {
+        let Some(seconds) = self.prompt_timeout else {
+            return self.read_secret_in(context);
+        };
+        let timeout = Duration::from_secs(seconds.into());
+        let expired = Arc::new(AtomicBool::new(false));
+        let (finished, waiting) = mpsc::channel::<()>();
+        let invalidator = context.invalidator();
+        let timer = expired.clone();
+        std::thread::Builder::new()
+            .name("keyring-prompt-timeout".to_string())
+            .spawn(move || {
+                if let Err(RecvTimeoutError::Timeout) = waiting.recv_timeout(timeout) {
+                    timer.store(true, Ordering::SeqCst);
+                    invalidator.invalidate();
+                }
+            })
+            .map_err(|err| ErrorCode::PlatformFailure(Box::new(err)))?;
+        let result = self.read_secret_in(context);
+        drop(finished);
+        match result {
+            Err(_) if expired.load(Ordering::SeqCst) => {
+                Err(ErrorCode::NoStorageAccess(Box::new(timeout::Timeout {
+                    operation: "get_secret",
+                    timeout,
+                })))
+            }
+            result => result,
+        }
+    }
+
+    /// The work of [delete_credential](CredentialApi::delete_credential).
+    fn delete_item(&self) -> Result<()> {
+        if self.dry_run {
+            let count = self.count_items()?;
+            if count == 0 {
+                return Err(ErrorCode::NoEntry);
+            }
+            info!(
+                "Dry run: would delete {count} item(s) for service {}, user {}",
+                self.service, self.account
+            );
+            return Ok(());
+        }
+        if self.display_user.is_some() {
+            self.display_user_query().delete().map_err(decode_error)?;
+        } else {
+            let mut options =
+                PasswordOptions::new_generic_password(self.stored_service(), self.stored_account());
+            options.use_protected_keychain();
+            if let Some(access_group) = &self.access_group {
+                options.set_access_group(access_group);
+            }
+            if self.cloud_synchronize {
+                options.set_access_synchronized(Some(true));
+            }
+            delete_generic_password_options(options).map_err(decode_error)?;
+        }
+        if self.leave_tombstone {
+            self.write_tombstone()?;
+        }
         Ok(())
     }
 
-    /// See the keychain-core API docs.
+    /// Record that this credential was deleted, replacing any earlier record.
     ///
-    /// There are two cases:
-    /// 1. If the cred has an access group, then it can't be ambiguous,
-    ///    so we just make sure that it exists before returning None.
-    /// 2. If the cred has no access group, then we do a search to
-    ///    check for ambiguity and, if none, return a wrapper that has
-    ///    the access group attached.
-    fn get_credential(&self) -> Result<Option<Arc<Credential>>> {
-        if let Some(access_group) = &self.access_group {
-            let mut options = PasswordOptions::new_generic_password(&self.service, &self.account);
+    /// Tombstones are kept on this device only, whether or not the credential
+    /// was cloud-synchronized.
+    fn write_tombstone(&self) -> Result<()> {
+        let deleted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let version = bundle_string("CFBundleShortVersionString").unwrap_or_default();
+        let marker = format!("{TOMBSTONE_TAG}{deleted_at}:{version}");
+        let mut query = tombstone_query(self.access_group.as_deref());
+        query
+            .string(
+                Attr::Service,
+                &format!("{TOMBSTONE_TAG}{}", self.stored_service()),
+            )
+            .string(Attr::Account, self.stored_account());
+        let mut changes = RawQuery::new();
+        changes.data(Attr::Generic, marker.as_bytes());
+        match query.update(&changes) {
+            Ok(()) => Ok(()),
+            Err(err) if err.code() == errSecItemNotFound => {
+                let access_control = SecAccessControl::create_with_protection(
+                    Some(ProtectionMode::AccessibleAfterFirstUnlockThisDeviceOnly),
+                    Default::default(),
+                )
+                .map_err(decode_error)?;
+                let mut item = query.clone();
+                item.data(Attr::Generic, marker.as_bytes())
+                    .value(&[])
+                    .access_control(access_control);
+                item.add().map_err(decode_error)
+            }
+            Err(err) => Err(decode_error(err)),
+        }
+    }
+
+    /// The work of [get_credential](CredentialApi::get_credential).
+    fn find_item(&self) -> Result<Option<Arc<Credential>>> {
+        if self.display_user.is_some() {
+            self.get_display_user_credential()
+        } else if let Some(access_group) = &self.access_group {
+            let mut options =
+                PasswordOptions::new_generic_password(self.stored_service(), self.stored_account());
             options.use_protected_keychain();
             options.set_access_group(access_group);
             if self.cloud_synchronize {
@@ -310,111 +1796,2560 @@ impl CredentialApi for Cred {
             Ok(None)
         } else {
             let results = search_items(
-                Some(&self.service),
-                Some(&self.account),
+                Some(self.stored_service()),
+                Some(self.stored_account()),
                 self.access_group.as_deref(),
                 self.cloud_synchronize,
                 false,
             )?;
+            if results.is_empty() {
+                return Err(ErrorCode::NoEntry);
+            }
+            // the items a search that skips protected ones still finds aren't protected
+            let unprotected: HashSet<ItemKey> = search_items(
+                Some(self.stored_service()),
+                Some(self.stored_account()),
+                self.access_group.as_deref(),
+                self.cloud_synchronize,
+                true,
+            )?
+            .iter()
+            .filter_map(item_key)
+            .collect();
+            let found = |result: &item::SearchResult| {
+                let mut cred = self.clone_from_search_result(result);
+                let protected = item_key(result).is_none_or(|key| !unprotected.contains(&key));
+                cred.protection = ItemProtection::of_search_result(result, protected);
+                cred
+            };
             match results.len() {
-                0 => Err(ErrorCode::NoEntry),
-                1 => Ok(Some(Arc::new(self.clone_from_search_result(&results[0])))),
-                _ => {
-                    let entries: Vec<Entry> = results
+                1 => Ok(Some(Arc::new(found(&results[0])))),
+                _ => Err(ErrorCode::Ambiguous(
+                    results
                         .iter()
-                        .map(|r| {
-                            Entry::new_with_credential(Arc::new(self.clone_from_search_result(r)))
-                        })
-                        .collect();
-                    Err(ErrorCode::Ambiguous(entries))
-                }
+                        .map(|r| Entry::new_with_credential(Arc::new(found(r))))
+                        .collect(),
+                )),
             }
         }
     }
 
-    /// See the keychain-core API docs.
-    fn get_specifiers(&self) -> Option<(String, String)> {
-        Some((self.service.clone(), self.account.clone()))
+    /// A query for this credential's item.
+    fn item_query(&self) -> RawQuery {
+        if self.display_user.is_some() {
+            return self.display_user_query();
+        }
+        let mut query = RawQuery::generic_password();
+        query
+            .data_protection()
+            .string(Attr::Service, self.stored_service())
+            .string(Attr::Account, self.stored_account())
+            .boolean(Attr::Synchronizable, self.cloud_synchronize);
+        if let Some(access_group) = &self.access_group {
+            query.string(Attr::AccessGroup, access_group);
+        }
+        query
     }
 
-    /// See the keychain-core API docs.
-    fn as_any(&self) -> &dyn std::any::Any {
-        self
+    /// The work of [get_attributes](CredentialApi::get_attributes).
+    fn read_attributes(&self) -> Result<HashMap<String, String>> {
+        let mut query = self.item_query();
+        query.return_attributes();
+        let found = query.find().map_err(decode_error)?;
+        let attrs = found.first().ok_or(ErrorCode::NoEntry)?;
+        Ok(attribute_map(attrs))
+    }
+
+    /// The work of [update_attributes](CredentialApi::update_attributes).
+    fn write_attributes(&self, changes: &RawQuery) -> Result<()> {
+        if self.dry_run {
+            if self.count_items()? == 0 {
+                return Err(ErrorCode::NoEntry);
+            }
+            info!(
+                "Dry run: would update the attributes of the item for service {}, user {}",
+                self.service, self.account
+            );
+            return Ok(());
+        }
+        self.item_query().update(changes).map_err(decode_error)
+    }
+
+    /// How many items this credential's specifiers match, for a dry run.
+    fn count_items(&self) -> Result<usize> {
+        if self.display_user.is_some() {
+            let mut query = self.display_user_query();
+            query.limit_all().return_attributes();
+            return Ok(query.find().map_err(decode_error)?.len());
+        }
+        Ok(search_items(
+            Some(self.stored_service()),
+            Some(self.stored_account()),
+            self.access_group.as_deref(),
+            self.cloud_synchronize,
+            false,
+        )?
+        .len())
+    }
+
+    fn clone_from_search_result(&self, result: &item::SearchResult) -> Self {
+        let mut cred = self.clone();
+        if let Some(attrs) = result.simplify_dict() {
+            if let Some(group) = attrs.get("agrp") {
+                cred.access_group = Some(group.to_string());
+            } else {
+                // should never happen, so warn if it does
+                error!("Search result credential has no access group; using entry's group")
+            }
+        } else {
+            // should never happen, so warn if it does
+            error!("Search result credential has no attributes; using entry's group")
+        }
+        cred
+    }
+
+    /// The access control for a new item with this credential's access policy.
+    ///
+    /// Cloud-synchronized items can't have an access control.
+    fn access_control(&self) -> Result<Option<SecAccessControl>> {
+        access_control_for(&self.access_policy, self.cloud_synchronize)
+    }
+
+    /// A query for the item of a credential with a display user.
+    ///
+    /// Such items are identified by the account in their metadata,
+    /// not their account attribute.
+    fn display_user_query(&self) -> RawQuery {
+        let mut query = RawQuery::generic_password();
+        query
+            .data_protection()
+            .string(Attr::Service, self.stored_service())
+            .data(
+                Attr::Generic,
+                format!("{DISPLAY_USER_TAG}{}", self.stored_account()).as_bytes(),
+            )
+            .boolean(Attr::Synchronizable, self.cloud_synchronize);
+        if let Some(access_group) = &self.access_group {
+            query.string(Attr::AccessGroup, access_group);
+        }
+        query
+    }
+
+    fn get_display_user_credential(&self) -> Result<Option<Arc<Credential>>> {
+        let mut query = self.display_user_query();
+        query.limit_all().return_attributes();
+        let found = query.find().map_err(decode_error)?;
+        let with_group = |attrs: &query::Attributes| {
+            let mut cred = self.clone();
+            cred.access_group = attrs.string(Attr::AccessGroup);
+            cred
+        };
+        match found.len() {
+            0 => Err(ErrorCode::NoEntry),
+            1 if self.access_group.is_some() => Ok(None),
+            1 => Ok(Some(Arc::new(with_group(&found[0])))),
+            _ => Err(ErrorCode::Ambiguous(
+                found
+                    .iter()
+                    .map(|attrs| Entry::new_with_credential(Arc::new(with_group(attrs))))
+                    .collect(),
+            )),
+        }
+    }
+
+    /// Check that the OS recorded the access control this credential asks for.
+    ///
+    /// This re-queries the underlying item (without fetching its secret, so
+    /// there is no authentication prompt) and compares its protection class
+    /// and its user-presence requirement against the credential's access policy.
+    /// An empty result means they match; otherwise each mismatch is returned.
+    ///
+    /// Cloud-synchronized items are expected to have the OS default protection
+    /// class and no user-presence requirement.
+    ///
+    /// Since wrappers returned from a search always have the default access policy
+    /// (see the module docs), verifying such a wrapper only tells you whether the
+    /// underlying item has the default policy.
+    pub fn verify_access_control(&self) -> Result<Vec<AccessControlMismatch>> {
+        let results = search_items(
+            Some(self.stored_service()),
+            Some(self.stored_account()),
+            self.access_group.as_deref(),
+            self.cloud_synchronize,
+            false,
+        )?;
+        let attrs = match results.len() {
+            0 => return Err(ErrorCode::NoEntry),
+            1 => results[0].simplify_dict().unwrap_or_default(),
+            _ => {
+                let entries: Vec<Entry> = results
+                    .iter()
+                    .map(|r| Entry::new_with_credential(Arc::new(self.clone_from_search_result(r))))
+                    .collect();
+                return Err(ErrorCode::Ambiguous(entries));
+            }
+        };
+        // Items that need authentication are skipped when the UI is suppressed,
+        // so if that search comes up empty the item requires user presence.
+        let requires_presence = search_items(
+            Some(self.stored_service()),
+            Some(self.stored_account()),
+            self.access_group.as_deref(),
+            self.cloud_synchronize,
+            true,
+        )?
+        .is_empty();
+        let (expected_class, expected_presence) = if self.cloud_synchronize {
+            (AccessPolicy::default().protection_class(), false)
+        } else {
+            (
+                self.access_policy.protection_class(),
+                self.access_policy.requires_user_presence(),
+            )
+        };
+        let mut mismatches = Vec::new();
+        let actual_class = attrs.get("pdmn").map(String::as_str).unwrap_or("");
+        if actual_class != expected_class {
+            mismatches.push(AccessControlMismatch {
+                attribute: "protection-class".to_string(),
+                requested: protection_class_name(expected_class),
+                actual: protection_class_name(actual_class),
+            });
+        }
+        if requires_presence != expected_presence {
+            mismatches.push(AccessControlMismatch {
+                attribute: "user-presence".to_string(),
+                requested: expected_presence.to_string(),
+                actual: requires_presence.to_string(),
+            });
+        }
+        Ok(mismatches)
+    }
+}
+
+/// The credentials of an `Ambiguous` error for one of this store's entries:
+/// one for each item with the entry's service and account, each in its own
+/// access group. [find_duplicates](Store::find_duplicates) also reports
+/// items duplicated across sync scopes this way.
+#[derive(Debug, Clone)]
+pub struct Duplicates {
+    pub creds: Vec<Cred>,
+}
+
+impl Duplicates {
+    /// The duplicates in an error, if it's an `Ambiguous` one whose entries
+    /// are all protected-store credentials.
+    pub fn of_error(err: &ErrorCode) -> Option<Duplicates> {
+        let ErrorCode::Ambiguous(entries) = err else {
+            return None;
+        };
+        let creds = entries
+            .iter()
+            .map(|entry| entry.as_any().downcast_ref::<Cred>().cloned())
+            .collect::<Option<Vec<Cred>>>()?;
+        Some(Duplicates { creds })
+    }
+
+    /// The access groups the duplicates are in.
+    pub fn access_groups(&self) -> Vec<&str> {
+        self.creds
+            .iter()
+            .filter_map(|cred| cred.access_group.as_deref())
+            .collect()
+    }
+
+    /// The entry for the duplicate in the given access group, if there is one.
+    ///
+    /// The entry's credential has that access group, so it isn't ambiguous.
+    pub fn in_access_group(&self, access_group: &str) -> Option<Entry> {
+        self.creds
+            .iter()
+            .find(|cred| cred.access_group.as_deref() == Some(access_group))
+            .map(|cred| Entry::new_with_credential(Arc::new(cred.clone())))
+    }
+
+    /// Delete the duplicates, except the one in the `keep` access group (if
+    /// given), and return how many were deleted.
+    ///
+    /// Each is deleted from its own access group, so after keeping one, the
+    /// entry that was ambiguous finds just that one. A duplicate that's
+    /// already gone isn't counted; any other failure stops the deleting.
+    pub fn delete_all_except(&self, keep: Option<&str>) -> Result<usize> {
+        self.delete_unless(|_, cred| keep.is_some() && cred.access_group.as_deref() == keep)
+    }
+
+    /// Keep the most recently modified duplicate and delete the others, and
+    /// return how many were deleted.
+    ///
+    /// This consolidates the duplicates left behind by a migration between
+    /// access groups (or sync scopes) when the copy in use is the one last
+    /// written. Each duplicate's modification date is read from its item.
+    pub fn keep_newest(&self) -> Result<usize> {
+        let modified = |cred: &Cred| {
+            cred.get_attributes()
+                .ok()
+                .and_then(|attrs| attrs.get("modification-date")?.parse::<u64>().ok())
+        };
+        let newest = (0..self.creds.len()).max_by_key(|&index| modified(&self.creds[index]));
+        self.delete_unless(|index, _| Some(index) == newest)
+    }
+
+    /// Delete the duplicates but those `keep` picks.
+    fn delete_unless(&self, keep: impl Fn(usize, &Cred) -> bool) -> Result<usize> {
+        let mut count = 0;
+        for (index, cred) in self.creds.iter().enumerate() {
+            if keep(index, cred) {
+                continue;
+            }
+            match cred.delete_credential() {
+                Ok(()) => count += 1,
+                Err(ErrorCode::NoEntry) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// A difference between the access control requested for an item and
+/// the access control the OS recorded for it.
+///
+/// See [verify_access_control](Cred::verify_access_control).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessControlMismatch {
+    /// Either `protection-class` or `user-presence`.
+    pub attribute: String,
+    pub requested: String,
+    pub actual: String,
+}
+
+impl CredentialApi for Cred {
+    /// See the keychain-core API docs.
+    ///
+    /// An existing item is changed in place: only its secret is replaced, so
+    /// its label, creation date, access control, and any other attributes set
+    /// by other tools are kept. (So this doesn't give an existing item the
+    /// credential's access policy; see
+    /// [update_access_policy](Cred::update_access_policy) for that.)
+    ///
+    /// If the credential was built with `skip-unchanged`, the existing secret
+    /// is read first, and nothing is written if it already matches.
+    fn set_secret(&self, secret: &[u8]) -> Result<()> {
+        let secret = secret.to_vec();
+        timeout::run("set_secret", self.clone(), move |cred| {
+            cred.write_secret(&secret)
+        })
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// If the credential was built with `require-user-presence-within`, a
+    /// recent enough user-presence check is reused rather than prompting again.
+    ///
+    /// A read that may prompt on the main thread is logged as a warning, or
+    /// refused if the store has `fail-on-main-thread`.
+    fn get_secret(&self) -> Result<Vec<u8>> {
+        self.check_main_thread()?;
+        timeout::run("get_secret", self.clone(), Cred::read_secret_shared)
+    }
+
+    /// See the keychain-core API docs.
+    fn delete_credential(&self) -> Result<()> {
+        timeout::run("delete_credential", self.clone(), Cred::delete_item)
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// There are two cases:
+    /// 1. If the cred has an access group, then it can't be ambiguous,
+    ///    so we just make sure that it exists before returning None.
+    /// 2. If the cred has no access group, then we do a search to
+    ///    check for ambiguity and, if none, return a wrapper that has
+    ///    the access group attached.
+    fn get_credential(&self) -> Result<Option<Arc<Credential>>> {
+        timeout::run("get_credential", self.clone(), Cred::find_item)
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// The attributes are the item's `creation-date` and `modification-date`
+    /// (in seconds since the Unix epoch), its `label`, `comment`, and
+    /// `description` (if it has them), its `accessibility` (the name of its
+    /// `kSecAttrAccessible` constant), whether it's `synchronizable`, and its
+    /// `access-group`. They are read without reading the secret, so this never
+    /// prompts.
+    fn get_attributes(&self) -> Result<HashMap<String, String>> {
+        timeout::run("get_attributes", self.clone(), Cred::read_attributes)
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// The attributes that can be set are `label`, `comment`, and `description`,
+    /// which are what the Passwords app and Keychain Access show for the item.
+    /// The secret isn't read or changed. A credential built with a
+    /// `label-template` relabels its item whenever it sets the secret, replacing
+    /// any label set here.
+    fn update_attributes(&self, attrs: &HashMap<&str, &str>) -> Result<()> {
+        // check the keys here, but build the (unsendable) changes on the worker
+        RawQuery::attribute_changes(attrs)?;
+        let attrs: HashMap<String, String> = attrs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        timeout::run("update_attributes", self.clone(), move |cred| {
+            let attrs = attrs
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            cred.write_attributes(&RawQuery::attribute_changes(&attrs)?)
+        })
+    }
+
+    /// See the keychain-core API docs.
+    fn get_specifiers(&self) -> Option<(String, String)> {
+        Some((self.service.clone(), self.account.clone()))
+    }
+
+    /// See the keychain-core API docs.
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    /// See the keychain-core API docs.
+    fn debug_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+const CONFIGURATION_KEYS: &[&str] = &[
+    "access-group",
+    "*cloud-sync",
+    "prefetch",
+    "*dry-run",
+    "default-group",
+    "*tombstones",
+    "*allow-unbounded-search",
+    "debug-redaction",
+    "user-presence-reuse-within",
+    "prompt-interval",
+    "prompt-timeout",
+    "*fail-on-main-thread",
+    "envelope-key",
+    "*envelope-enclave",
+];
+const MODIFIER_KEYS: &[&str] = &[
+    "access-policy",
+    "+access-group",
+    "+display-user",
+    "*skip-unchanged",
+    "require-user-presence-within",
+    "+tag",
+    "authentication-ui",
+    "+label-template",
+    "*envelope",
+    "*create-only",
+    "prompt-timeout",
+];
+const SEARCH_KEYS: &[&str] = &[
+    "service",
+    "account",
+    "access-group",
+    "*show-authentication-ui",
+    "*app-id-only",
+    "service-under",
+    "tag",
+    "generic",
+    "*all",
+    "sync-scope",
+    "limit",
+    "offset",
+    "*case-sensitive",
+    "*diacritic-sensitive",
+    "service-prefix",
+    "account-prefix",
+    "contains",
+    "sort",
+];
+/// The search keys that narrow a search, for stores that reject unbounded searches.
+const RESTRICTING_KEYS: &[&str] = &[
+    "service",
+    "account",
+    "access-group",
+    "service-under",
+    "tag",
+    "generic",
+    "service-prefix",
+    "account-prefix",
+    "contains",
+];
+
+/// The builder for iOS keychain credentials
+#[derive(Clone)]
+pub struct Store {
+    id: String,
+    access_group: Option<String>,
+    cloud_synchronize: bool,
+    hasher: Option<Arc<dyn SpecifierHasher>>,
+    dry_run: bool,
+    default_group: DefaultGroup,
+    tombstones: bool,
+    allow_unbounded_search: bool,
+    redaction: Redaction,
+    user_presence_reuse: Option<u32>,
+    prompt_interval: Option<u32>,
+    prompt_timeout: Option<u32>,
+    fail_on_main_thread: bool,
+    envelope_key: Option<String>,
+    envelope_enclave: bool,
+}
+
+/// How a store chooses the access group for new items.
+/// See [new_with_configuration](Store::new_with_configuration).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DefaultGroup {
+    AppId,
+    FirstEntitlement,
+    Explicit,
+}
+
+impl DefaultGroup {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DefaultGroup::AppId => "app-id",
+            DefaultGroup::FirstEntitlement => "first-entitlement",
+            DefaultGroup::Explicit => "explicit",
+        }
+    }
+}
+
+/// How many times setting a secret tries to update or add its item, when
+/// concurrent writers keep adding or deleting it in between.
+pub const WRITE_ATTEMPTS: u32 = 3;
+
+/// The service and account of the placeholder item used to find the App ID group.
+const APP_ID_PROBE: &str = "keyring-app-id-probe";
+
+/// The service and account of the placeholder item written by
+/// [is_available](Store::is_available).
+const AVAILABILITY_PROBE: &str = "keyring-availability-probe";
+
+/// The service of the probe items written by [health_check](Store::health_check),
+/// whose accounts are their scopes.
+const HEALTH_PROBE: &str = "keyring-health-check";
+
+/// A way of hiding the service and account attributes of stored items.
+///
+/// A store created with [new_with_hasher](Store::new_with_hasher) keeps each
+/// item under the hashed service and account rather than the plain ones, so
+/// the item metadata (which isn't encrypted, and is synchronized to iCloud in
+/// cloud-synchronized stores) doesn't reveal them. An HMAC with a key that only
+/// the app holds is a good choice.
+///
+/// Each method returns its input unchanged by default, so implement just the
+/// ones you want hashed. Hashes must be deterministic and never empty.
+pub trait SpecifierHasher: Send + Sync {
+    /// The service attribute to store an item under.
+    fn hash_service(&self, service: &str) -> String {
+        service.to_string()
+    }
+
+    /// The account attribute to store an item under.
+    fn hash_account(&self, account: &str) -> String {
+        account.to_string()
+    }
+}
+
+/// A record of a deleted credential, as found by [deleted_since](Store::deleted_since).
+///
+/// In a store with a [SpecifierHasher], the service and account are the hashed ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    /// The service of the deleted item.
+    pub service: String,
+    /// The account of the deleted item.
+    pub account: String,
+    pub access_group: Option<String>,
+    /// When the credential was deleted, to the second.
+    pub deleted_at: SystemTime,
+    /// The version of the app that deleted it (its `CFBundleShortVersionString`),
+    /// or empty if the app has no version.
+    pub app_version: String,
+}
+
+impl Tombstone {
+    fn from_attributes(attrs: &query::Attributes) -> Option<Self> {
+        let service = attrs.string(Attr::Service)?;
+        let generic = String::from_utf8(attrs.data(Attr::Generic)?).ok()?;
+        let (seconds, app_version) = generic.strip_prefix(TOMBSTONE_TAG)?.split_once(':')?;
+        Some(Tombstone {
+            service: service.strip_prefix(TOMBSTONE_TAG)?.to_string(),
+            account: attrs.string(Attr::Account).unwrap_or_default(),
+            access_group: attrs.string(Attr::AccessGroup),
+            deleted_at: UNIX_EPOCH + Duration::from_secs(seconds.parse().ok()?),
+            app_version: app_version.to_string(),
+        })
+    }
+}
+
+/// The results of a [search_detailed](Store::search_detailed).
+#[derive(Debug)]
+pub struct SearchOutcome {
+    /// The wrappers for the matching items, as returned by [search](Store::search).
+    pub entries: Vec<Entry>,
+    /// The number of matching items skipped because they require user interaction.
+    pub skipped: usize,
+}
+
+/// One item found by [audit_sync](Store::audit_sync).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncAuditItem {
+    /// The item's service attribute.
+    pub service: String,
+    /// The item's account attribute.
+    pub account: String,
+    pub access_group: Option<String>,
+    /// Whether the item is synchronized with iCloud.
+    pub synchronized: bool,
+    /// The Apple constant name of the item's protection class.
+    pub protection_class: String,
+    /// Whether the item's protection class keeps it on this device
+    /// (and out of backups restored to other devices).
+    pub device_only: bool,
+}
+
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store")
+            .field("vendor", &self.vendor())
+            .field("id", &self.id())
+            .field("access_group", &self.access_group)
+            .field("cloud_synchronize", &self.cloud_synchronize)
+            .field("hashed", &self.hasher.is_some())
+            .field("dry_run", &self.dry_run)
+            .field("default_group", &self.default_group.as_str())
+            .field("tombstones", &self.tombstones)
+            .field("allow_unbounded_search", &self.allow_unbounded_search)
+            .field("redaction", &self.redaction)
+            .field("envelope_key", &self.envelope_key)
+            .field("envelope_enclave", &self.envelope_enclave)
+            .finish()
+    }
+}
+
+impl Store {
+    /// Create a default store, which does *not* synchronize with the cloud.
+    pub fn new() -> Result<Arc<Self>> {
+        Self::configure(&HashMap::new(), None).map(Arc::new)
+    }
+
+    /// Create a configured store.
+    ///
+    /// There are two allowed configuration keys:
+    /// - `cloud-sync` (`true` or `false`), default false. Specifying this key as true
+    ///   will sync all items in the store with iCloud.
+    /// - `access-group`. If non-empty, this store will store all its items in the
+    ///   specified access group. If empty or not specified, as in the default configuration,
+    ///   all items will be stored in the app's default access group. Creating the store
+    ///   fails if the app isn't [entitled](entitled_access_groups) to the access group.
+    ///
+    /// There is also a `prefetch` key, whose value is a comma-separated list of
+    /// services. When it's given, the store looks up the attributes (never the secrets)
+    /// of the items for those services on a background thread, so the OS has them
+    /// ready by the time the app first asks for them. Items whose access policy requires
+    /// user interaction are skipped, and any failures are only logged.
+    ///
+    /// There is also a `dry-run` key (`true` or `false`), default false. In a dry-run
+    /// store, setting a secret, deleting a credential, and the batch operations
+    /// ([update_attributes_matching](Store::update_attributes_matching) and
+    /// [migrate_to_hashed](Store::migrate_to_hashed)) do all their checks, look for
+    /// the affected items, and log (at info level) what they would do, but don't
+    /// change anything. They return what they would have returned, including errors
+    /// such as a missing entitlement or (for deletion) a missing item. Reading
+    /// secrets and searching work as usual.
+    ///
+    /// There is also a `default-group` key, which declares where items go in an
+    /// app whose entitlements list more than one access group:
+    /// - `first-entitlement`: no access group is given, so the OS puts new items
+    ///   in the first group listed in the app's entitlements. An `access-group`
+    ///   can't be specified.
+    /// - `app-id`: new items go in the app's App ID group (its team ID followed
+    ///   by its bundle identifier). An `access-group` can't be specified. Finding
+    ///   the App ID prefix means adding and then deleting a placeholder item, and
+    ///   creating the store fails if the app has no bundle identifier or its first
+    ///   entitlement group has no App ID prefix. A dry-run store writes nothing,
+    ///   so it takes the App ID group from the app's entitlements instead, and
+    ///   creating one fails if they don't list it.
+    /// - `explicit`: new items go in the `access-group`, which must be specified.
+    ///
+    /// If `default-group` isn't specified, it's `explicit` when an `access-group`
+    /// is given and `first-entitlement` otherwise, which is how stores have
+    /// always behaved. Either way, [configuration](Store::configuration) reports it.
+    ///
+    /// There is also a `tombstones` key (`true` or `false`), default false. When it's
+    /// true, deleting a credential leaves a small record behind, saying when (and by
+    /// which version of the app) it was deleted, so that a sync engine can find out
+    /// about deletions later with [deleted_since](Store::deleted_since). Tombstones
+    /// have no secret, never leave the device, and don't show up in searches. If a
+    /// credential's item is deleted but its tombstone can't be written, the deletion
+    /// fails with the error from writing the tombstone.
+    ///
+    /// There is also an `allow-unbounded-search` key (`true` or `false`), default
+    /// true. When it's false, a search spec (or an
+    /// [update_attributes_matching](Store::update_attributes_matching) spec) with
+    /// none of the keys that narrow the search (`service`, `account`, `access-group`,
+    /// `service-under`, `tag`, `generic`, `service-prefix`, `account-prefix`, and
+    /// `contains`) is an error unless it has `all` set to true. This guards
+    /// against a bug passing an empty spec to code that deletes what it finds.
+    ///
+    /// There is also a `debug-redaction` key (`full` or `reveal-specifiers`), default
+    /// `full`. It's the [redaction](crate::redact) policy given to every credential
+    /// the store builds or finds.
+    ///
+    /// There is also a `user-presence-reuse-within` key, a number of seconds from 1
+    /// to 300. Every entry the store builds with the `RequireUserPresence` access
+    /// policy gets it as its `require-user-presence-within` window (unless the entry
+    /// has a window of its own), so an app that reads several such items in a row
+    /// only prompts once. It can't be given to a cloud-synchronized store, whose
+    /// items can't require user presence.
+    ///
+    /// There is also a `prompt-interval` key, a number of seconds from 1 to 3600.
+    /// Once a read of an item that requires user presence could have prompted,
+    /// reading that item again within the interval fails with a
+    /// [PromptRateLimited](crate::error::PromptRateLimited) error, rather than
+    /// prompts, so a bug that reads in a loop can't bury the user in prompts.
+    /// (Threads reading the item at the same time still share one prompt.) Like
+    /// `user-presence-reuse-within`, it can't be given to a cloud-synchronized store.
+    ///
+    /// There is also a `prompt-timeout` key, a number of seconds from 1 to 600,
+    /// which entries the store builds get as their `prompt-timeout` (unless they
+    /// have one of their own). See [build](Store::build). It can't be given to a
+    /// cloud-synchronized store either.
+    ///
+    /// There is also a `fail-on-main-thread` key (`true` or `false`), default
+    /// false. Reading the secret of an item that requires user presence blocks
+    /// the calling thread until the user answers the prompt, which on the main
+    /// thread freezes the app's UI, and can deadlock an app that drives the
+    /// authentication UI from its main run loop. Such a read on the main thread
+    /// is always logged as a warning; in a store with `fail-on-main-thread` it
+    /// fails with a [MainThreadPrompt] error instead. Reads that can't prompt
+    /// (because of their `authentication-ui`) are unaffected.
+    ///
+    /// There is also an `envelope-key` key, the application tag of the store's
+    /// data-encryption key, which entries built with the `envelope` modifier
+    /// encrypt their secrets with, and an `envelope-enclave` key (`true` or
+    /// `false`), default false, which says whether that key is generated in the
+    /// Secure Enclave. The enclave can't be used by a cloud-synchronized store.
+    /// See the [envelope] module.
+    pub fn new_with_configuration(config: &HashMap<&str, &str>) -> Result<Arc<Self>> {
+        Self::configure(config, None).map(Arc::new)
+    }
+
+    /// Create a configured store that hashes item specifiers.
+    ///
+    /// This takes the same configuration as
+    /// [new_with_configuration](Store::new_with_configuration), and stores every
+    /// item under the service and account produced by the given hasher. Entries
+    /// built from the store (and the credentials inside them) keep the plain
+    /// service and user, so the hashing is invisible to clients except in search:
+    /// the search spec is hashed before searching, but the OS only has the hashed
+    /// attributes to report, so results only have a plain service and account
+    /// if the spec provided them. (Each result's
+    /// [stored_specifiers](Cred::stored_specifiers) are always the hashed ones.)
+    ///
+    /// Items that were created without hashing can be moved to their
+    /// hashed specifiers with [migrate_to_hashed](Store::migrate_to_hashed).
+    pub fn new_with_hasher(
+        config: &HashMap<&str, &str>,
+        hasher: Arc<dyn SpecifierHasher>,
+    ) -> Result<Arc<Self>> {
+        Self::configure(config, Some(hasher)).map(Arc::new)
+    }
+
+    fn configure(
+        config: &HashMap<&str, &str>,
+        hasher: Option<Arc<dyn SpecifierHasher>>,
+    ) -> Result<Self> {
+        let config = parse_attributes(CONFIGURATION_KEYS, Some(config))?;
+        let mut store = Self::parse_configuration(&config, hasher)?;
+        if let Some(group) = &store.access_group {
+            entitlements::check_access_group(group)?;
+        }
+        if store.default_group == DefaultGroup::AppId {
+            store.access_group = Some(app_id_group(store.dry_run)?);
+        }
+        if let Some(services) = config.get("prefetch") {
+            let services = services
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| match &store.hasher {
+                    Some(hasher) => hasher.hash_service(s),
+                    None => s.to_string(),
+                })
+                .collect();
+            prefetch(
+                services,
+                store.access_group.clone(),
+                store.cloud_synchronize,
+            )
+        }
+        // errors from items that can't be read while the device is locked say
+        // whether it is, so that's found out once here (and kept up to date by
+        // the OS's notifications) rather than while decoding them
+        availability::check(!store.dry_run);
+        Ok(store)
+    }
+
+    /// The store for a parsed configuration, before anything is looked up:
+    /// a `default-group` `app-id` store has no access group yet, and the
+    /// `access-group` hasn't been checked against the entitlements.
+    fn parse_configuration(
+        config: &HashMap<String, String>,
+        hasher: Option<Arc<dyn SpecifierHasher>>,
+    ) -> Result<Self> {
+        let dry_run = config.get("dry-run").is_some_and(|s| s.eq("true"));
+        let tombstones = config.get("tombstones").is_some_and(|s| s.eq("true"));
+        let allow_unbounded_search = !config
+            .get("allow-unbounded-search")
+            .is_some_and(|s| s.eq("false"));
+        let redaction = match config.get("debug-redaction") {
+            Some(policy) => policy.parse()?,
+            None => Redaction::Full,
+        };
+        let user_presence_reuse = determine_user_presence_reuse(config)?;
+        let prompt_interval = determine_prompt_interval(config)?;
+        let prompt_timeout = determine_prompt_timeout(config)?;
+        let fail_on_main_thread = config
+            .get("fail-on-main-thread")
+            .is_some_and(|s| s.eq("true"));
+        let (envelope_key, envelope_enclave) = determine_envelope_key(config)?;
+        let mut cloud_synchronize = false;
+        let mut access_group = None;
+        if let Some(option) = config.get("cloud-sync") {
+            cloud_synchronize = option.eq("true");
+        }
+        if let Some(option) = config.get("access-group") {
+            if !option.is_empty() {
+                access_group = Some(option.to_string());
+            }
+        }
+        let default_group = determine_default_group(config, access_group.is_some())?;
+        let now = SystemTime::now();
+        let elapsed = if now.lt(&UNIX_EPOCH) {
+            UNIX_EPOCH.duration_since(now).unwrap()
+        } else {
+            now.duration_since(UNIX_EPOCH).unwrap()
+        };
+        let id = format!(
+            "Protected Data Storage, Crate version {}, Instantiated at {}",
+            env!("CARGO_PKG_VERSION"),
+            elapsed.as_secs_f64()
+        );
+        Ok(Store {
+            id,
+            access_group,
+            cloud_synchronize,
+            hasher,
+            dry_run,
+            default_group,
+            tombstones,
+            allow_unbounded_search,
+            redaction,
+            user_presence_reuse,
+            prompt_interval,
+            prompt_timeout,
+            fail_on_main_thread,
+            envelope_key,
+            envelope_enclave,
+        })
+    }
+
+    /// The [configuration](Store::configuration) a store created with the given
+    /// one would have, found without creating it (so nothing is looked up or
+    /// written). A `default-group` `app-id` configuration has no `access-group`,
+    /// since the App ID group isn't found.
+    pub(crate) fn normalized_configuration(
+        config: &HashMap<&str, &str>,
+    ) -> Result<HashMap<String, String>> {
+        let config = parse_attributes(CONFIGURATION_KEYS, Some(config))?;
+        Ok(Self::parse_configuration(&config, None)?.configuration())
+    }
+
+    /// The work of [validate_configuration](crate::validate::validate_configuration)
+    /// for this store.
+    pub(crate) fn validate_configuration(config: &HashMap<&str, &str>) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let config = check_keys(CONFIGURATION_KEYS, config, &mut findings);
+        if let Some(Err(err)) = config
+            .get("debug-redaction")
+            .map(|p| p.parse::<Redaction>())
+        {
+            findings.push(Finding::from_error(err));
+        }
+        if let Err(err) = determine_user_presence_reuse(&config) {
+            findings.push(Finding::from_error(err));
+        }
+        if let Err(err) = determine_prompt_interval(&config) {
+            findings.push(Finding::from_error(err));
+        }
+        if let Err(err) = determine_prompt_timeout(&config) {
+            findings.push(Finding::from_error(err));
+        }
+        if let Err(err) = determine_envelope_key(&config) {
+            findings.push(Finding::from_error(err));
+        }
+        let access_group = config.get("access-group").filter(|g| !g.is_empty());
+        match determine_default_group(&config, access_group.is_some()) {
+            Ok(DefaultGroup::AppId) => {
+                if let Err(err) = app_bundle_identifier("default-group app-id") {
+                    findings.push(Finding::error(Some("default-group"), err.to_string()));
+                }
+            }
+            Ok(_) => {}
+            Err(err) => findings.push(Finding::from_error(err)),
+        }
+        if let Some(group) = access_group {
+            findings.push(Finding::warning(
+                Some("access-group"),
+                format!("the app's keychain-access-groups entitlement must list {group}"),
+            ));
+        }
+        if config.get("cloud-sync").is_some_and(|s| s.eq("true")) {
+            findings.push(Finding::warning(
+                Some("cloud-sync"),
+                "the app's provisioning profile must enable the iCloud capability",
+            ));
+        }
+        findings
+    }
+
+    /// The configuration this store is using.
+    ///
+    /// This has the `access-group`, `user-presence-reuse-within`,
+    /// `prompt-interval`, `prompt-timeout`, and `envelope-key` (if there are
+    /// ones), `envelope-enclave` (if there's an `envelope-key`), `cloud-sync`,
+    /// `dry-run`, `default-group`, `tombstones`, `allow-unbounded-search`,
+    /// `debug-redaction`, and `fail-on-main-thread` in effect, including the
+    /// values of any that weren't specified when the store was created. For an
+    /// `app-id` store, the `access-group` is the App ID group that was found.
+    /// (A `prefetch` is done when the store is created, so it isn't part of
+    /// the configuration.)
+    pub fn configuration(&self) -> HashMap<String, String> {
+        let mut config = HashMap::from([
+            ("cloud-sync".to_string(), self.cloud_synchronize.to_string()),
+            ("dry-run".to_string(), self.dry_run.to_string()),
+            (
+                "default-group".to_string(),
+                self.default_group.as_str().to_string(),
+            ),
+            ("tombstones".to_string(), self.tombstones.to_string()),
+            (
+                "allow-unbounded-search".to_string(),
+                self.allow_unbounded_search.to_string(),
+            ),
+            ("debug-redaction".to_string(), self.redaction.to_string()),
+            (
+                "fail-on-main-thread".to_string(),
+                self.fail_on_main_thread.to_string(),
+            ),
+        ]);
+        if let Some(access_group) = &self.access_group {
+            config.insert("access-group".to_string(), access_group.clone());
+        }
+        if let Some(seconds) = self.user_presence_reuse {
+            config.insert(
+                "user-presence-reuse-within".to_string(),
+                seconds.to_string(),
+            );
+        }
+        if let Some(seconds) = self.prompt_interval {
+            config.insert("prompt-interval".to_string(), seconds.to_string());
+        }
+        if let Some(seconds) = self.prompt_timeout {
+            config.insert("prompt-timeout".to_string(), seconds.to_string());
+        }
+        if let Some(tag) = &self.envelope_key {
+            config.insert("envelope-key".to_string(), tag.clone());
+            config.insert(
+                "envelope-enclave".to_string(),
+                self.envelope_enclave.to_string(),
+            );
+        }
+        config
+    }
+
+    /// Get the process-wide shared store for a configuration.
+    ///
+    /// The first call with a given configuration creates the store, as
+    /// [new_with_configuration](Store::new_with_configuration) would, and later
+    /// calls with the same configuration (in any order, and whether or not
+    /// defaults are spelled out) return that same store. This lets independent
+    /// crates in one binary share a store rather than each creating their own.
+    /// Configurations that differ only in their `prefetch` share a store, since
+    /// it isn't part of the [configuration](Store::configuration). Shared stores
+    /// live until they are evicted with [evict_shared](Store::evict_shared) or
+    /// [evict_all_shared](Store::evict_all_shared).
+    pub fn shared(config: &HashMap<&str, &str>) -> Result<Arc<Self>> {
+        SHARED.get(config)
+    }
+
+    /// Stop sharing the store for a configuration, returning it if there was one.
+    ///
+    /// Anyone still holding the store can keep using it, but the next call
+    /// to [shared](Store::shared) with this configuration creates a new one.
+    /// The configuration is checked (but no store is created for it), so this
+    /// fails if it isn't valid.
+    pub fn evict_shared(config: &HashMap<&str, &str>) -> Result<Option<Arc<Self>>> {
+        SHARED.evict(config)
+    }
+
+    /// Stop sharing all the shared stores.
+    pub fn evict_all_shared() {
+        SHARED.clear();
+    }
+
+    /// The work of [from_persistent_ref](Cred::from_persistent_ref).
+    fn cred_for_persistent_ref(&self, reference: &[u8]) -> Result<Cred> {
+        let mut query = RawQuery::generic_password();
+        query
+            .data_protection()
+            .persistent_ref(reference)
+            .boolean(Attr::Synchronizable, self.cloud_synchronize)
+            .return_attributes();
+        let found = query.find().map_err(decode_error)?;
+        let attrs = found.first().ok_or(ErrorCode::NoEntry)?;
+        let result = attrs.search_result();
+        if is_bookkeeping(&result) {
+            return Err(ErrorCode::NoEntry);
+        }
+        let mut cred = Cred::from_search_result(&result, self.cloud_synchronize)?;
+        if self.hasher.is_some() {
+            cred.stored_specifiers = Some((cred.service.clone(), cred.account.clone()));
+        }
+        self.apply_settings(&mut cred);
+        Ok(cred)
+    }
+
+    /// Give a credential the settings that come from this store's
+    /// configuration rather than from its entry's modifiers.
+    fn apply_settings(&self, cred: &mut Cred) {
+        cred.dry_run = self.dry_run;
+        cred.leave_tombstone = self.tombstones;
+        cred.redaction = self.redaction;
+        cred.prompt_interval = self.prompt_interval;
+        cred.prompt_timeout = self.prompt_timeout;
+        cred.fail_on_main_thread = self.fail_on_main_thread;
+    }
+
+    /// The credential for a copy, in this store, of another store's credential.
+    /// See [copy_to](Cred::copy_to).
+    fn copy_of(&self, cred: &Cred) -> Result<Cred> {
+        let access_policy = if self.cloud_synchronize {
+            match cred.access_policy {
+                AccessPolicy::AfterFirstUnlock | AccessPolicy::WhenUnlocked => {}
+                ref policy => {
+                    return Err(ErrorCode::Invalid(
+                        "access-policy".to_string(),
+                        format!("{policy} cannot be used in a cloud-synchronized store"),
+                    ));
+                }
+            }
+            AccessPolicy::default()
+        } else {
+            cred.access_policy.clone()
+        };
+        let access_group = cred
+            .access_group
+            .clone()
+            .or_else(|| self.access_group.clone());
+        let mut copy = Cred::new(
+            &cred.service,
+            &cred.account,
+            access_policy,
+            access_group,
+            self.cloud_synchronize,
+        )?;
+        copy.display_user = cred.display_user.clone();
+        copy.tag = cred.tag.clone();
+        copy.label_template = cred.label_template.clone();
+        copy.stored_specifiers = self.hashed_for_copy(cred)?;
+        self.apply_settings(&mut copy);
+        // these can also be entry modifiers, which a synchronized item can't have
+        if !self.cloud_synchronize {
+            copy.user_presence_within = cred.user_presence_within;
+            if cred.prompt_timeout.is_some() {
+                copy.prompt_timeout = cred.prompt_timeout;
+            }
+        }
+        Ok(copy)
+    }
+
+    /// The hashed service and account for a copy of a credential, if this
+    /// store hashes.
+    ///
+    /// A credential found by a search in a hashed store whose plain service
+    /// (or account) wasn't known has the hashed one instead, which can't be
+    /// hashed again, so such a credential can't be copied to a hashing store.
+    fn hashed_for_copy(&self, cred: &Cred) -> Result<Option<(String, String)>> {
+        if let (Some(_), Some((service, account))) = (&self.hasher, &cred.stored_specifiers) {
+            let unknown = |plain: &String, stored: &String, hashed: String| {
+                plain == stored && hashed != *plain
+            };
+            if unknown(&cred.service, service, self.hash_service(&cred.service))
+                || unknown(&cred.account, account, self.hash_account(&cred.account))
+            {
+                return Err(ErrorCode::Invalid(
+                    "specifiers".to_string(),
+                    "the credential's plain service or user isn't known, so it can't be \
+                     hashed for this store"
+                        .to_string(),
+                ));
+            }
+        }
+        Ok(self.hashed(&cred.service, &cred.account))
+    }
+
+    /// The hashed service and account for a plain service and account, if this store hashes.
+    fn hashed(&self, service: &str, account: &str) -> Option<(String, String)> {
+        self.hasher
+            .as_ref()
+            .map(|_| (self.hash_service(service), self.hash_account(account)))
+    }
+
+    fn hash_service(&self, service: &str) -> String {
+        match &self.hasher {
+            Some(hasher) => hasher.hash_service(service),
+            None => service.to_string(),
+        }
+    }
+
+    fn hash_account(&self, account: &str) -> String {
+        match &self.hasher {
+            Some(hasher) => hasher.hash_account(account),
+            None => account.to_string(),
+        }
+    }
+
+    /// Move an item created without hashing to its hashed specifiers.
+    ///
+    /// This finds the item stored under the plain service and account and
+    /// changes its service and account attributes to their hashes, leaving its
+    /// secret and access control alone. It returns whether there was an item
+    /// to move. Items created with a display user are not moved.
+    ///
+    /// This fails if the store doesn't hash item specifiers.
+    pub fn migrate_to_hashed(&self, service: &str, account: &str) -> Result<bool> {
+        let Some((hashed_service, hashed_account)) = self.hashed(service, account) else {
+            return Err(ErrorCode::NotSupportedByStore(
+                "migration requires a store created with a hasher".to_string(),
+            ));
+        };
+        let mut query = RawQuery::generic_password();
+        query
+            .data_protection()
+            .string(Attr::Service, service)
+            .string(Attr::Account, account)
+            .boolean(Attr::Synchronizable, self.cloud_synchronize);
+        if let Some(access_group) = &self.access_group {
+            query.string(Attr::AccessGroup, access_group);
+        }
+        if self.dry_run {
+            let mut count_query = query.clone();
+            count_query.limit_all().return_attributes();
+            let found = !count_query.find().map_err(decode_error)?.is_empty();
+            if found {
+                info!("Dry run: would migrate the item for service {service}, user {account}");
+            }
+            return Ok(found);
+        }
+        let mut changes = RawQuery::new();
+        changes
+            .string(Attr::Service, &hashed_service)
+            .string(Attr::Account, &hashed_account);
+        match query.update(&changes) {
+            Ok(()) => Ok(true),
+            Err(err) if err.code() == errSecItemNotFound => Ok(false),
+            Err(err) => Err(decode_error(err)),
+        }
+    }
+
+    /// Report which matching items are synchronized with iCloud and which stay on the device.
+    ///
+    /// Unlike search, this looks at both the local and the cloud-synchronized
+    /// stores, whichever one this store uses. The spec keys are `service`, `account`,
+    /// and `access-group`, as for [search](Store::search) (but a single service only).
+    /// Items that require user interaction are included, since only their attributes
+    /// are read. The reported service and account are the item's attributes, so they
+    /// are hashed in a store that hashes its specifiers. The items the store keeps
+    /// for its own bookkeeping (tombstones, envelope chunks, lease markers, and
+    /// probe items) are left out. As with other searches, a spec that narrows
+    /// nothing needs `all=true` in a store that rejects unbounded searches.
+    pub fn audit_sync(&self, spec: &HashMap<&str, &str>) -> Result<Vec<SyncAuditItem>> {
+        let spec = parse_attributes(&["service", "account", "access-group", "*all"], Some(spec))?;
+        check_bounded(
+            &spec,
+            &["service", "account", "access-group"],
+            self.allow_unbounded_search,
+        )?;
+        let mut query = RawQuery::generic_password();
+        query
+            .data_protection()
+            .any_synchronizable()
+            .limit_all()
+            .return_attributes();
+        if let Some(service) = spec.get("service") {
+            query.string(Attr::Service, &self.hash_service(service));
+        }
+        if let Some(account) = spec.get("account") {
+            query.string(Attr::Account, &self.hash_account(account));
+        }
+        if let Some(access_group) = spec.get("access-group") {
+            query.string(Attr::AccessGroup, access_group);
+        }
+        let items = query.find().map_err(decode_error)?;
+        Ok(items
+            .iter()
+            .filter_map(|attrs| {
+                let service = attrs.string(Attr::Service).unwrap_or_default();
+                if is_bookkeeping_service(&service) {
+                    return None;
+                }
+                let class = attrs.string(Attr::Accessible).unwrap_or_default();
+                Some(SyncAuditItem {
+                    service,
+                    account: attrs.string(Attr::Account).unwrap_or_default(),
+                    access_group: attrs.string(Attr::AccessGroup),
+                    synchronized: attrs.boolean(Attr::Synchronizable).unwrap_or(false),
+                    device_only: is_device_only(&class),
+                    protection_class: protection_class_name(&class),
+                })
+            })
+            .collect())
+    }
+
+    /// Wrap a credential in a new entry.
+    ///
+    /// This is for callers who take the credential from a search result
+    /// (or any other entry), change some of its fields, and want an entry for the
+    /// changed credential without rebuilding it from its service and user.
+    /// The credential is checked just as it would be if it were built, so this
+    /// fails if its service, account, or display user is empty. The settings
+    /// that come from a store's configuration (such as `dry-run`, `tombstones`,
+    /// and `prompt-interval`) are this store's, except that the credential keeps
+    /// its own `prompt_timeout` if it has one, since that can also be an entry
+    /// modifier.
+    pub fn entry_for(&self, cred: Cred) -> Result<Entry> {
+        let mut checked = Cred::new(
+            &cred.service,
+            &cred.account,
+            cred.access_policy,
+            cred.access_group,
+            cred.cloud_synchronize,
+        )?;
+        if cred.display_user.as_ref().is_some_and(String::is_empty) {
+            return Err(ErrorCode::Invalid(
+                "display-user".to_string(),
+                "cannot be empty".to_string(),
+            ));
+        }
+        checked.tag = check_tag(cred.tag, &cred.display_user)?;
+        checked.display_user = cred.display_user;
+        checked.skip_unchanged = cred.skip_unchanged;
+        checked.authentication_ui = cred.authentication_ui;
+        checked.label_template = check_label_template(cred.label_template)?;
+        checked.create_only = cred.create_only;
+        checked.stored_specifiers = cred.stored_specifiers;
+        self.apply_settings(&mut checked);
+        // the prompt timeout can also be an entry modifier
+        if cred.prompt_timeout.is_some() {
+            checked.prompt_timeout = cred.prompt_timeout;
+        }
+        if let Some(seconds) = cred.user_presence_within {
+            checked.user_presence_within =
+                Some(check_user_presence_window(seconds, &checked.access_policy)?);
+        }
+        Ok(Entry::new_with_credential(Arc::new(checked)))
+    }
+
+    /// Take an advisory lease on a credential for the given time, so that
+    /// processes rotating it (such as an agent and its app) can agree on which
+    /// of them does.
+    ///
+    /// This returns `None` if another unexpired lease on the credential is held,
+    /// by this process or any other that shares the store's access group. The
+    /// marker item is kept on this device only, even in a cloud-synchronized
+    /// store. See the [lease](crate::lease) module.
+    pub fn lock_credential(
+        &self,
+        service: &str,
+        user: &str,
+        ttl: Duration,
+    ) -> Result<Option<Lease>> {
+        for (key, value) in [("service", service), ("user", user)] {
+            if value.is_empty() {
+                return Err(ErrorCode::Invalid(
+                    key.to_string(),
+                    "cannot be empty".to_string(),
+                ));
+            }
+        }
+        let service = self.hash_service(service);
+        let account = self.hash_account(user);
+        lease::acquire(
+            lease_marker(service, account, self.access_group.clone()),
+            ttl,
+        )
+    }
+
+    /// The credentials deleted at or after the given time, as recorded by their tombstones.
+    ///
+    /// Tombstones are only written by stores configured with `tombstones`
+    /// (see [new_with_configuration](Store::new_with_configuration)), but this
+    /// finds every tombstone in the store's access group (or in all the app's
+    /// groups, if the store has none), whichever store wrote it. If the same
+    /// credential was deleted more than once, only its latest deletion is reported.
+    pub fn deleted_since(&self, since: SystemTime) -> Result<Vec<Tombstone>> {
+        Ok(self
+            .tombstones()?
+            .into_iter()
+            .filter(|tombstone| tombstone.deleted_at >= since)
+            .collect())
+    }
+
+    /// Delete the tombstones of credentials deleted before the given time,
+    /// returning how many were deleted.
+    ///
+    /// A sync engine can call this once it has passed deletions along.
+    /// In a dry-run store, this only logs what it would delete.
+    pub fn purge_tombstones(&self, before: SystemTime) -> Result<usize> {
+        let old: Vec<Tombstone> = self
+            .tombstones()?
+            .into_iter()
+            .filter(|tombstone| tombstone.deleted_at < before)
+            .collect();
+        if self.dry_run {
+            info!("Dry run: would delete {} tombstone(s)", old.len());
+            return Ok(old.len());
+        }
+        for tombstone in old.iter() {
+            let mut query = tombstone_query(tombstone.access_group.as_deref());
+            query
+                .string(
+                    Attr::Service,
+                    &format!("{TOMBSTONE_TAG}{}", tombstone.service),
+                )
+                .string(Attr::Account, &tombstone.account);
+            query.delete().map_err(decode_error)?;
+        }
+        Ok(old.len())
+    }
+
+    fn tombstones(&self) -> Result<Vec<Tombstone>> {
+        let mut query = tombstone_query(self.access_group.as_deref());
+        query
+            .limit_all()
+            .return_attributes()
+            .skip_authenticated_items();
+        let found = query.find().map_err(decode_error)?;
+        Ok(found
+            .iter()
+            .filter_map(Tombstone::from_attributes)
+            .collect())
+    }
+
+    /// Search the store, reporting how many items were skipped.
+    ///
+    /// This takes the same spec as [search](Store::search) and returns the same
+    /// entries, along with the number of matching items that were left out because
+    /// their access policy requires user interaction. A UI can use this count to
+    /// tell the user that there are protected items they could authenticate to see.
+    ///
+    /// Counting the skipped items takes a second search that doesn't skip them.
+    /// Like the first, it only reads item attributes, never secrets. If the spec
+    /// asks to show the authentication UI, nothing is skipped and the count is zero.
+    pub fn search_detailed(&self, spec: &HashMap<&str, &str>) -> Result<SearchOutcome> {
+        let spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        timeout::run("search", self.clone(), move |store| {
+            store.search_internal(&spec, true)
+        })
+    }
+
+    /// Run an operation once the device's protected data is available, and
+    /// pass its result to `done`.
+    ///
+    /// This is for operations that failed because the device was locked: instead
+    /// of retrying, the caller hands the operation over, and it runs once on the
+    /// [background](crate::background) thread as soon as the OS says the device
+    /// is unlocked (or right away, if it already is). See the
+    /// [availability] module for how that's found out.
+    ///
+    /// The returned handle can [cancel](PendingRetry::cancel) the retry while it
+    /// waits. If a `timeout` is given and the device isn't unlocked within it,
+    /// `done` gets an error that [timed_out](crate::timeout::timed_out)
+    /// recognizes instead; without one, `done` may never be called. Either way,
+    /// `done` isn't called on the calling thread. This returns an error only if
+    /// the thread that times out retries can't be started.
+    pub fn retry_when_available<T, F, C>(
+        &self,
+        op: F,
+        done: C,
+        timeout: Option<Duration>,
+    ) -> Result<PendingRetry>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+        C: FnOnce(Result<T>) + Send + 'static,
+    {
+        availability::retry(op, done, timeout)
+    }
+
+    /// Search the store and read the secret of every item found.
+    ///
+    /// This takes the same spec as [search](Store::search), and returns each
+    /// matching entry along with its secret. As with search, items whose access
+    /// policy requires user interaction are left out unless the spec sets
+    /// `show-authentication-ui` to true. If it does, all of their secrets are
+    /// read in one authentication context, so the user is asked to authenticate
+    /// once rather than once per item (unless the OS insists on asking again,
+    /// as it may for items that require the current biometric set).
+    ///
+    /// Items deleted between the search and the read are left out. Any other
+    /// failure to read a secret, such as the user canceling authentication,
+    /// ends the whole operation with that error.
+    pub fn search_and_get(&self, spec: &HashMap<&str, &str>) -> Result<Vec<(Entry, Vec<u8>)>> {
+        let spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        timeout::run("search_and_get", self.clone(), move |store| {
+            store.get_all_in(&spec, &AuthenticationContext::new())
+        })
+    }
+
+    /// Set the secrets of many credentials in one operation, such as when
+    /// provisioning an app.
+    ///
+    /// Each `(service, user, secret)` is a credential of this store built
+    /// without modifiers, so it gets the store's access group and the default
+    /// access policy. All the secrets are written in one authentication
+    /// context, under one operation timeout. The result for each credential is
+    /// at its place in the returned list, and one that fails (because its
+    /// service is empty, say) doesn't stop the rest from being set.
+    pub fn set_many(&self, items: &[(&str, &str, &[u8])]) -> Result<Vec<Result<()>>> {
+        let items: Vec<(String, String, Vec<u8>)> = items
+            .iter()
+            .map(|(service, user, secret)| (service.to_string(), user.to_string(), secret.to_vec()))
+            .collect();
+        timeout::run("set_many", self.clone(), move |store| {
+            let context = AuthenticationContext::new();
+            Ok(items
+                .iter()
+                .map(|(service, user, secret)| {
+                    store
+                        .plain_cred(service, user)?
+                        .write_secret_in(secret, &context)
+                })
+                .collect())
+        })
+    }
+
+    /// Get the secrets of many credentials in one operation.
+    ///
+    /// This is the reading side of [set_many](Store::set_many): each
+    /// `(service, user)` is a credential of this store built without
+    /// modifiers, and the result for each is at its place in the returned
+    /// list, so a missing item is a `NoEntry` there rather than a failure of
+    /// the whole call. The secrets are read in one authentication context, so
+    /// the user is asked to authenticate once for all the items that need it.
+    pub fn get_many(&self, items: &[(&str, &str)]) -> Result<Vec<Result<Vec<u8>>>> {
+        let items: Vec<(String, String)> = items
+            .iter()
+            .map(|(service, user)| (service.to_string(), user.to_string()))
+            .collect();
+        timeout::run("get_many", self.clone(), move |store| {
+            let context = AuthenticationContext::new();
+            Ok(items
+                .iter()
+                .map(|(service, user)| store.plain_cred(service, user)?.read_secret_in(&context))
+                .collect())
+        })
+    }
+
+    /// A credential of this store built without modifiers.
+    fn plain_cred(&self, service: &str, user: &str) -> Result<Cred> {
+        let mut cred = Cred::new(
+            service,
+            user,
+            AccessPolicy::default(),
+            self.access_group.clone(),
+            self.cloud_synchronize,
+        )?;
+        cred.stored_specifiers = self.hashed(service, user);
+        self.apply_settings(&mut cred);
+        Ok(cred)
+    }
+
+    /// Search the store, including the items that require authentication, and
+    /// return each matching entry along with its secret, read in the given
+    /// LocalAuthentication context.
+    ///
+    /// This is [search_and_get](Store::search_and_get) with
+    /// `show-authentication-ui` set to true, for a "manage my credentials"
+    /// screen: if the caller has already evaluated an access-control policy in
+    /// the context (with `evaluatePolicy`), the items it covers are listed and
+    /// read without asking the user again. Items the context doesn't cover
+    /// prompt as usual. Since the context can't be handed to another thread,
+    /// this runs on the calling thread, without the operation timeout.
+    pub fn search_in_context(
+        &self,
+        spec: &HashMap<&str, &str>,
+        context: &LAContext,
+    ) -> Result<Vec<(Entry, Vec<u8>)>> {
+        let mut spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        spec.insert("show-authentication-ui".to_string(), "true".to_string());
+        self.get_all_in(&spec, &AuthenticationContext::wrap(context))
+    }
+
+    /// The entries matching a parsed search spec, with their secrets read in
+    /// the given context.
+    ///
+    /// Items deleted between the search and the read are left out.
+    fn get_all_in(
+        &self,
+        spec: &HashMap<String, String>,
+        context: &AuthenticationContext,
+    ) -> Result<Vec<(Entry, Vec<u8>)>> {
+        let (found, _) = self.search_attributed(spec, false, Some(context))?;
+        let entries: Vec<Entry> = found.into_iter().map(|(entry, _)| entry).collect();
+        let mut results = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let secret = match entry.as_any().downcast_ref::<Cred>() {
+                Some(cred) => cred.read_secret_in(context),
+                None => entry.get_secret(),
+            };
+            match secret {
+                Ok(secret) => results.push((entry, secret)),
+                Err(ErrorCode::NoEntry) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Search the store, handing each matching entry to `f` as it's decoded
+    /// from the search results.
+    ///
+    /// This is [search](Store::search) for long lists: it takes the same spec
+    /// and finds the same entries, but doesn't gather them into a vector, and
+    /// once `f` returns `ControlFlow::Break` no more are made. Specs that sort
+    /// or page the results (with `sort`, `limit`, or `offset`), and searches
+    /// while an operation timeout is set, are carried out in full first, and
+    /// then the entries are handed over one by one.
+    pub fn search_each(
+        &self,
+        spec: &HashMap<&str, &str>,
+        mut f: impl FnMut(Entry) -> ControlFlow<()>,
+    ) -> Result<()> {
+        let spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        if needs_all_results(&spec) || timeout::operation_timeout().is_some() {
+            let outcome = timeout::run("search", self.clone(), move |store| {
+                store.search_internal(&spec, false)
+            })?;
+            for entry in outcome.entries {
+                if f(entry).is_break() {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        self.each_found(&spec, false, None, &mut |entry, _| f(entry))?;
+        Ok(())
+    }
+
+    /// Search the store, returning each matching entry along with its attributes.
+    ///
+    /// This takes the same spec as [search](Store::search), and each entry comes
+    /// with the attributes its `get_attributes` would return (its dates, label,
+    /// access group, and so on). They're taken from the search's own results, so
+    /// a credential picker can show a whole list without a query per item.
+    /// Like searching, this never reads secrets.
+    pub fn search_with_attributes(
+        &self,
+        spec: &HashMap<&str, &str>,
+    ) -> Result<Vec<(Entry, HashMap<String, String>)>> {
+        let spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        timeout::run("search_with_attributes", self.clone(), move |store| {
+            Ok(store.search_attributed(&spec, false, None)?.0)
+        })
+    }
+
+    /// Change the attributes of every item matching a search spec.
+    ///
+    /// The spec keys are `service`, `account`, and `access-group`, as for
+    /// [search](Store::search). The attribute keys are `label`, `comment`, and
+    /// `description`, and each one given replaces that attribute's value on
+    /// every matching item. Items whose access policy requires user interaction
+    /// are skipped, as they are in search.
+    ///
+    /// The matching items are found first and then changed one at a time, so
+    /// an item another process adds in between isn't changed, and one it
+    /// deletes isn't counted.
+    ///
+    /// Returns the number of items changed. Secrets are never read or changed.
+    pub fn update_attributes_matching(
+        &self,
+        spec: &HashMap<&str, &str>,
+        attrs: &HashMap<&str, &str>,
+    ) -> Result<usize> {
+        let spec = parse_attributes(&["service", "account", "access-group", "*all"], Some(spec))?;
+        check_bounded(
+            &spec,
+            &["service", "account", "access-group"],
+            self.allow_unbounded_search,
+        )?;
+        let changes = RawQuery::attribute_changes(attrs)?;
+        let references = self.matching_refs(&spec)?;
+        if self.dry_run {
+            info!(
+                "Dry run: would update the attributes of {} item(s)",
+                references.len()
+            );
+            return Ok(references.len());
+        }
+        self.count_by_ref(&references, |item| item.update(&changes))
+    }
+
+    /// The persistent references of the items matching a parsed spec of
+    /// `service`, `account`, and `access-group`, leaving out items that
+    /// require user interaction.
+    ///
+    /// The OS only skips such items when finding them: changing or
+    /// deleting items by a query doesn't, so those go by these references.
+    fn matching_refs(&self, spec: &HashMap<String, String>) -> Result<Vec<Vec<u8>>> {
+        let mut query = RawQuery::generic_password();
+        query
+            .data_protection()
+            .skip_authenticated_items()
+            .boolean(Attr::Synchronizable, self.cloud_synchronize)
+            .limit_all()
+            .return_attributes()
+            .return_persistent_refs();
+        if let Some(service) = spec.get("service") {
+            query.string(Attr::Service, &self.hash_service(service));
+        }
+        if let Some(account) = spec.get("account") {
+            query.string(Attr::Account, &self.hash_account(account));
+        }
+        if let Some(access_group) = spec.get("access-group") {
+            query.string(Attr::AccessGroup, access_group);
+        }
+        let found = query.find().map_err(decode_error)?;
+        Ok(found
+            .iter()
+            .filter_map(|attrs| attrs.data(Attr::PersistentRef))
+            .collect())
+    }
+
+    /// Apply an operation to each of the items with the given persistent
+    /// references, returning the number of items it was applied to. Items
+    /// that have gone since they were found aren't counted.
+    fn count_by_ref(
+        &self,
+        references: &[Vec<u8>],
+        op: impl Fn(&RawQuery) -> std::result::Result<(), Error>,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for reference in references {
+            let mut item = RawQuery::generic_password();
+            item.data_protection()
+                .persistent_ref(reference)
+                .boolean(Attr::Synchronizable, self.cloud_synchronize);
+            match op(&item) {
+                Ok(()) => count += 1,
+                Err(err) if err.code() == errSecItemNotFound => {}
+                Err(err) => return Err(decode_error(err)),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Find the credentials that have more than one item: in several access
+    /// groups, or in both the local and the cloud-synchronized store.
+    ///
+    /// This takes the same spec as [search](Store::search), except that both
+    /// sync scopes are always searched, and returns one [Duplicates] for each
+    /// service and account with more than one matching item, in order of
+    /// service and account. Support tools can then narrow each one with
+    /// [in_access_group](Duplicates::in_access_group), or clean it up with
+    /// [keep_newest](Duplicates::keep_newest) or
+    /// [delete_all_except](Duplicates::delete_all_except). Like searching, this
+    /// never reads secrets.
+    pub fn find_duplicates(&self, spec: &HashMap<&str, &str>) -> Result<Vec<Duplicates>> {
+        let mut spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        spec.insert("sync-scope".to_string(), "any".to_string());
+        timeout::run("find_duplicates", self.clone(), move |store| {
+            let mut by_specifiers: HashMap<(String, String), Vec<Cred>> = HashMap::new();
+            for entry in store.search_internal(&spec, false)?.entries {
+                if let Some(cred) = entry.as_any().downcast_ref::<Cred>() {
+                    let stored = (
+                        cred.stored_service().to_string(),
+                        cred.stored_account().to_string(),
+                    );
+                    by_specifiers.entry(stored).or_default().push(cred.clone());
+                }
+            }
+            let mut found: Vec<((String, String), Vec<Cred>)> = by_specifiers
+                .into_iter()
+                .filter(|(_, creds)| creds.len() > 1)
+                .collect();
+            found.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Ok(found
+                .into_iter()
+                .map(|(_, creds)| Duplicates { creds })
+                .collect())
+        })
+    }
+
+    /// Probe whether the store can be used, so an app can degrade gracefully
+    /// before its first credential access fails.
+    ///
+    /// This adds a placeholder item (in the store's access group, and
+    /// synchronized if the store is) and deletes it again, even in a dry-run
+    /// store, since only a write shows whether the app has the entitlements
+    /// and the device is unlocked. It never shows UI. An error from creating
+    /// a store can be classified the same way, with [Availability::of_error].
+    pub fn is_available(&self) -> Availability {
+        match timeout::run("is_available", self.clone(), |store| store.probe()) {
+            Ok(()) => Availability::Available,
+            Err(err) => Availability::of_error(&err),
+        }
+    }
+
+    /// Write, read back, and delete a probe item in each scope the store is
+    /// configured for, and report how each step went, for an app's
+    /// diagnostics screen.
+    ///
+    /// A cloud-synchronized store is probed in the [Cloud](HealthScope::Cloud)
+    /// scope. Any other store is probed in the [Local](HealthScope::Local)
+    /// scope and, since its entries can require user presence, in the
+    /// [UserPresence](HealthScope::UserPresence) scope, whose probe item is
+    /// read back by its attributes only, so the user is never asked to
+    /// authenticate. The probe items are in the store's access group, are
+    /// written even in a dry-run store, and never leave tombstones.
+    pub fn health_check(&self) -> Result<Vec<ScopeHealth>> {
+        timeout::run("health_check", self.clone(), |store| {
+            let scopes = if store.cloud_synchronize {
+                vec![HealthScope::Cloud]
+            } else {
+                vec![HealthScope::Local, HealthScope::UserPresence]
+            };
+            let mut report = Vec::new();
+            for scope in scopes {
+                report.push(store.check_scope(scope)?);
+            }
+            Ok(report)
+        })
+    }
+
+    /// The work of [health_check](Store::health_check) for one scope.
+    fn check_scope(&self, scope: HealthScope) -> Result<ScopeHealth> {
+        let mut probe = self.plain_cred(HEALTH_PROBE, &format!("{scope:?}"))?;
+        probe.stored_specifiers = None;
+        probe.dry_run = false;
+        probe.leave_tombstone = false;
+        if scope == HealthScope::UserPresence {
+            probe.access_policy = AccessPolicy::RequireUserPresence;
+        }
+        let secret = HEALTH_PROBE.as_bytes();
+        let step = |result: Result<()>| match result {
+            Ok(()) => Availability::Available,
+            Err(err) => Availability::of_error(&err),
+        };
+        let write = step(probe.write_secret(secret));
+        if !write.is_available() {
+            return Ok(ScopeHealth {
+                scope,
+                write,
+                read: None,
+                delete: None,
+            });
+        }
+        let read = if scope == HealthScope::UserPresence {
+            probe.read_attributes().map(|_| ())
+        } else {
+            probe.read_secret().and_then(|read| {
+                if read == secret {
+                    return Ok(());
+                }
+                Err(ErrorCode::BadDataFormat(
+                    read,
+                    "the probe item's secret isn't the one written".into(),
+                ))
+            })
+        };
+        // a missing probe item isn't a store that works
+        let read = match read {
+            Err(ErrorCode::NoEntry) => {
+                Availability::Unavailable("the probe item wasn't found".to_string())
+            }
+            read => step(read),
+        };
+        let delete = step(probe.delete_item());
+        Ok(ScopeHealth {
+            scope,
+            write,
+            read: Some(read),
+            delete: Some(delete),
+        })
+    }
+
+    /// The work of [is_available](Store::is_available).
+    fn probe(&self) -> Result<()> {
+        let mut probe = RawQuery::generic_password();
+        probe
+            .data_protection()
+            .string(Attr::Service, AVAILABILITY_PROBE)
+            .string(Attr::Account, AVAILABILITY_PROBE)
+            .boolean(Attr::Synchronizable, self.cloud_synchronize);
+        if let Some(access_group) = &self.access_group {
+            probe.string(Attr::AccessGroup, access_group);
+        }
+        let mut item = probe.clone();
+        item.value(&[]);
+        match item.add() {
+            Ok(()) => {}
+            Err(err) if err.code() == errSecDuplicateItem => {}
+            Err(err) => return Err(decode_error(err)),
+        }
+        probe.delete().map_err(decode_error)
+    }
+
+    /// Delete every credential the store can see, and return how many were
+    /// deleted, for "sign out and erase" flows.
+    ///
+    /// This is one search for all of the store's credentials, with
+    /// `show-authentication-ui` set, so credentials whose access policy
+    /// requires user interaction are found (and the user may be asked to
+    /// authenticate), followed by deleting each one found. It works even in
+    /// stores that reject unbounded searches. Credentials deleted by someone
+    /// else in the meantime aren't counted; any other failure ends the pass
+    /// with that error.
+    ///
+    /// Searches leave out the items the store keeps for its own bookkeeping
+    /// (envelope chunks and lease markers), so those are deleted afterwards,
+    /// ending any leases still held, and aren't counted. Tombstones are left, as they are by any delete.
+    pub fn delete_all(&self) -> Result<usize> {
+        let spec = HashMap::from([
+            ("all".to_string(), "true".to_string()),
+            ("show-authentication-ui".to_string(), "true".to_string()),
+        ]);
+        let count = self.delete_found("delete_all", spec, &[])?;
+        if !self.dry_run {
+            timeout::run("delete_all", self.clone(), Store::delete_bookkeeping)?;
+        }
+        Ok(count)
+    }
+
+    /// Delete the envelope chunks and lease markers the store can see.
+    fn delete_bookkeeping(&self) -> Result<usize> {
+        let mut query = RawQuery::generic_password();
+        query
+            .data_protection()
+            .boolean(Attr::Synchronizable, self.cloud_synchronize)
+            .limit_all()
+            .return_attributes()
+            .return_persistent_refs();
+        if let Some(access_group) = &self.access_group {
+            query.string(Attr::AccessGroup, access_group);
+        }
+        let references: Vec<Vec<u8>> = query
+            .find()
+            .map_err(decode_error)?
+            .iter()
+            .filter(|attrs| {
+                attrs.string(Attr::Service).is_some_and(|service| {
+                    service.starts_with(envelope::ENVELOPE_TAG) || service.starts_with(LEASE_TAG)
+                })
+            })
+            .filter_map(|attrs| attrs.data(Attr::PersistentRef))
+            .collect();
+        self.count_by_ref(&references, RawQuery::delete)
+    }
+
+    /// Delete the credentials whose service starts with a prefix, except those
+    /// whose service is on a keep-list, and return how many were deleted.
+    ///
+    /// This is for logging out: if an app's services all start with, say,
+    /// `com.example.app.`, the user's credentials can be cleared while the
+    /// device-registration secrets named in `keep` stay. Like
+    /// [delete_all](Store::delete_all), it finds credentials that require user
+    /// interaction, too, and a stored service is what's matched, so stores
+    /// that hash their specifiers don't support it.
+    pub fn delete_prefixed(&self, service_prefix: &str, keep: &[&str]) -> Result<usize> {
+        if service_prefix.is_empty() {
+            return Err(ErrorCode::Invalid(
+                "service_prefix".to_string(),
+                "cannot be empty".to_string(),
+            ));
+        }
+        let spec = HashMap::from([
+            ("service-prefix".to_string(), service_prefix.to_string()),
+            ("show-authentication-ui".to_string(), "true".to_string()),
+        ]);
+        self.delete_found("delete_prefixed", spec, keep)
+    }
+
+    /// Delete the credentials a parsed search spec finds, except those whose
+    /// service is in `keep`, and return how many were deleted.
+    fn delete_found(
+        &self,
+        operation: &'static str,
+        spec: HashMap<String, String>,
+        keep: &[&str],
+    ) -> Result<usize> {
+        let keep: Vec<String> = keep.iter().map(|service| service.to_string()).collect();
+        timeout::run(operation, self.clone(), move |store| {
+            let entries = store.search_internal(&spec, false)?.entries;
+            let mut count = 0;
+            for entry in entries {
+                if entry
+                    .get_specifiers()
+                    .is_some_and(|(service, _)| keep.contains(&service))
+                {
+                    continue;
+                }
+                let deleted = match entry.as_any().downcast_ref::<Cred>() {
+                    Some(cred) => cred.delete_item(),
+                    None => entry.delete_credential(),
+                };
+                match deleted {
+                    Ok(()) => count += 1,
+                    Err(ErrorCode::NoEntry) => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(count)
+        })
+    }
+
+    /// Delete every item matching a search spec.
+    ///
+    /// The spec keys are `service`, `account`, and `access-group`, as for
+    /// [update_attributes_matching](Store::update_attributes_matching). Items
+    /// whose access policy requires user interaction are left alone. As there,
+    /// the matching items are found first and then deleted one at a time (by
+    /// persistent reference, without building an entry for each), so an item
+    /// another process adds in between isn't deleted.
+    ///
+    /// A store that leaves tombstones, or that keeps envelope secrets in
+    /// chunk items, has more to do per entry than deleting its item; in those
+    /// stores this is `NotSupportedByStore`, and [wipe](AppleStoreExt::wipe)
+    /// deletes entry by entry instead.
+    ///
+    /// Returns the number of items deleted.
+    pub fn delete_matching(&self, spec: &HashMap<&str, &str>) -> Result<usize> {
+        if self.tombstones || self.envelope_key.is_some() {
+            return Err(ErrorCode::NotSupportedByStore(
+                "deleting matching items at once can't leave tombstones or delete envelope chunks"
+                    .to_string(),
+            ));
+        }
+        let spec = parse_attributes(&["service", "account", "access-group", "*all"], Some(spec))?;
+        check_bounded(
+            &spec,
+            &["service", "account", "access-group"],
+            self.allow_unbounded_search,
+        )?;
+        let references = self.matching_refs(&spec)?;
+        if self.dry_run {
+            info!("Dry run: would delete {} item(s)", references.len());
+            return Ok(references.len());
+        }
+        self.count_by_ref(&references, RawQuery::delete)
+    }
+
+    fn search_internal(
+        &self,
+        spec: &HashMap<String, String>,
+        count_skipped: bool,
+    ) -> Result<SearchOutcome> {
+        let (found, skipped) = self.search_attributed(spec, count_skipped, None)?;
+        Ok(SearchOutcome {
+            entries: found.into_iter().map(|(entry, _)| entry).collect(),
+            skipped,
+        })
+    }
+
+    /// The work of [search_with_attributes](Store::search_with_attributes),
+    /// which also counts the skipped items if asked, and authenticates in the
+    /// given context, if any.
+    fn search_attributed(
+        &self,
+        spec: &HashMap<String, String>,
+        count_skipped: bool,
+        context: Option<&AuthenticationContext>,
+    ) -> Result<(Vec<EntryWithAttributes>, usize)> {
+        let mut entries = Vec::new();
+        let skipped = self.each_found(spec, count_skipped, context, &mut |entry, attrs| {
+            entries.push((entry, attrs));
+            ControlFlow::Continue(())
+        })?;
+        let entries = page(entries, spec, |(entry, _)| entry.get_specifiers())?;
+        Ok((entries, skipped))
+    }
+
+    /// Hand each item matching a parsed search spec, as an entry with its
+    /// attributes, to `f` until it breaks, and return the number of items
+    /// skipped (if asked to count them). This doesn't page the results.
+    fn each_found(
+        &self,
+        spec: &HashMap<String, String>,
+        count_skipped: bool,
+        context: Option<&AuthenticationContext>,
+        f: &mut dyn FnMut(Entry, HashMap<String, String>) -> ControlFlow<()>,
+    ) -> Result<usize> {
+        check_bounded(spec, RESTRICTING_KEYS, self.allow_unbounded_search)?;
+        let service_under = spec.get("service-under");
+        if service_under.is_some() && self.hasher.is_some() {
+            return Err(ErrorCode::NotSupportedByStore(
+                "service-under can't match hashed services".to_string(),
+            ));
+        }
+        let text_filters = TextFilters::of_spec(spec, "account-prefix");
+        if text_filters.is_some() && self.hasher.is_some() {
+            return Err(ErrorCode::NotSupportedByStore(
+                "prefix and substring filters can't match hashed specifiers".to_string(),
+            ));
+        }
+        let matching = StringMatching::of_spec(spec);
+        if matching.is_loose() && self.hasher.is_some() {
+            return Err(ErrorCode::NotSupportedByStore(
+                "hashed specifiers can only be matched exactly".to_string(),
+            ));
+        }
+        let scopes: &[bool] = match spec.get("sync-scope").map(String::as_str) {
+            None | Some("store") => &[self.cloud_synchronize],
+            Some("any") => &[false, true],
+            Some(other) => {
+                return Err(ErrorCode::Invalid(
+                    "sync-scope".to_string(),
+                    format!("must be store or any, not {other}"),
+                ));
+            }
+        };
+        let app_id = if spec.get("app-id-only").is_some_and(|s| s.eq("true")) {
+            Some(app_bundle_identifier("app-id-only")?)
+        } else {
+            None
+        };
+        let show_ui = spec
+            .get("show-authentication-ui")
+            .is_some_and(|s| s.eq("true"));
+        let generic = match (spec.get("tag"), spec.get("generic")) {
+            (Some(_), Some(_)) => {
+                return Err(ErrorCode::Invalid(
+                    "generic".to_string(),
+                    "cannot be specified with tag".to_string(),
+                ));
+            }
+            (Some(tag), None) => Some(format!("{TAG_PREFIX}{tag}").into_bytes()),
+            (None, Some(encoded)) => Some(decode_base64(encoded).ok_or_else(|| {
+                ErrorCode::Invalid("generic".to_string(), "isn't valid base64".to_string())
+            })?),
+            (None, None) => None,
+        };
+        let mut services: Vec<&str> = Vec::new();
+        match spec.get("service") {
+            Some(service) if service.contains(',') => {
+                for service in service.split(',').map(str::trim) {
+                    if service.is_empty() {
+                        return Err(ErrorCode::Invalid(
+                            "service".to_string(),
+                            "a list of services can't have an empty one".to_string(),
+                        ));
+                    }
+                    // a service listed twice is only searched for once
+                    if !services.contains(&service) {
+                        services.push(service);
+                    }
+                }
+            }
+            Some(service) => services.push(service.as_str()),
+            None => {}
+        }
+        // the plain services and account, keyed by what they are stored as
+        let stored_services: HashMap<String, &str> = services
+            .iter()
+            .map(|service| (self.hash_service(service), *service))
+            .collect();
+        let account = spec.get("account");
+        let stored_account = account.map(|account| self.hash_account(account));
+        let mut queries: Vec<Option<String>> = services
+            .iter()
+            .map(|service| Some(self.hash_service(service)))
+            .collect();
+        if !spec.contains_key("service") {
+            queries.push(None);
+        }
+        // each item comes with whether it's cloud-synchronized
+        let find = |suppress_ui: bool| -> Result<Vec<(item::SearchResult, bool)>> {
+            let mut items = Vec::new();
+            for &cloud_sync in scopes {
+                for service in queries.iter() {
+                    let found = search_items_in(
+                        service.as_deref(),
+                        stored_account.as_deref(),
+                        spec.get("access-group").map(String::as_str),
+                        cloud_sync,
+                        suppress_ui,
+                        matching,
+                        context,
+                    )?;
+                    items.extend(found.into_iter().map(|item| (item, cloud_sync)));
+                }
+            }
+            items.retain(|(item, _)| !is_bookkeeping(item));
+            if let Some(app_id) = &app_id {
+                items.retain(|(item, _)| is_app_id_group(item, app_id));
+            }
+            if let Some(generic) = &generic {
+                items.retain(|(item, _)| {
+                    query::Attributes::of_search_result(item)
+                        .and_then(|attrs| attrs.data(Attr::Generic))
+                        .is_some_and(|data| data == *generic)
+                });
+            }
+            if let Some(path) = service_under {
+                items.retain(|(item, _)| {
+                    item.simplify_dict()
+                        .and_then(|attrs| attrs.get("svce").cloned())
+                        .is_some_and(|service| service_is_under(&service, path))
+                });
+            }
+            if let Some(filters) = &text_filters {
+                items.retain(|(item, _)| {
+                    item.simplify_dict().is_some_and(|attrs| {
+                        let get = |key| attrs.get(key).map_or("", String::as_str);
+                        filters.matches(get("svce"), get("acct"))
+                    })
+                });
+            }
+            Ok(items)
+        };
+        let mut items = find(!show_ui)?;
+        // with the UI shown, the items that require user presence are those a
+        // search that skips them doesn't find
+        let unprotected: Option<HashSet<(ItemKey, bool)>> = if show_ui {
+            Some(
+                find(true)?
+                    .iter()
+                    .filter_map(|(item, cloud_sync)| Some((item_key(item)?, *cloud_sync)))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        sort_by_date(&mut items, spec, |(item, _), attr| {
+            query::Attributes::of_search_result(item).and_then(|attrs| attrs.date(attr))
+        })?;
+        for (item, cloud_sync) in items.iter() {
+            let mut cred = Cred::from_search_result(item, *cloud_sync)?;
+            if self.hasher.is_some() {
+                let stored = (cred.service.clone(), cred.account.clone());
+                if let Some(service) = stored_services.get(&cred.service) {
+                    cred.service = service.to_string();
+                }
+                if let (Some(account), Some(stored)) = (account, &stored_account) {
+                    if cred.account == *stored {
+                        cred.account = account.clone();
+                    }
+                }
+                cred.stored_specifiers = Some(stored);
+            }
+            self.apply_settings(&mut cred);
+            let protected = unprotected.as_ref().is_some_and(|unprotected| {
+                item_key(item).is_none_or(|key| !unprotected.contains(&(key, *cloud_sync)))
+            });
+            cred.protection = ItemProtection::of_search_result(item, protected);
+            let attrs = query::Attributes::of_search_result(item)
+                .map(|attrs| attribute_map(&attrs))
+                .unwrap_or_default();
+            if f(Entry::new_with_credential(Arc::new(cred)), attrs).is_break() {
+                break;
+            }
+        }
+        if count_skipped && !show_ui {
+            Ok(find(false)?.len().saturating_sub(items.len()))
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+/// A search result with its item's attributes.
+type EntryWithAttributes = (Entry, HashMap<String, String>);
+
+/// The attributes reported by [get_attributes](CredentialApi::get_attributes)
+/// for an item with the given raw attributes.
+fn attribute_map(attrs: &query::Attributes) -> HashMap<String, String> {
+    let seconds = |attr| {
+        attrs
+            .date(attr)
+            .and_then(|date| date.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs().to_string())
+    };
+    let mut result = HashMap::new();
+    for (key, value) in [
+        ("creation-date", seconds(Attr::CreationDate)),
+        ("modification-date", seconds(Attr::ModificationDate)),
+        ("label", attrs.string(Attr::Label)),
+        ("comment", attrs.string(Attr::Comment)),
+        ("description", attrs.string(Attr::Description)),
+        (
+            "accessibility",
+            attrs
+                .string(Attr::Accessible)
+                .map(|class| protection_class_name(&class)),
+        ),
+        (
+            "synchronizable",
+            Some(
+                attrs
+                    .boolean(Attr::Synchronizable)
+                    .unwrap_or(false)
+                    .to_string(),
+            ),
+        ),
+        ("access-group", attrs.string(Attr::AccessGroup)),
+    ] {
+        if let Some(value) = value {
+            result.insert(key.to_string(), value);
+        }
+    }
+    result
+}
+
+/// The marker for a lease on the item with the given (stored) service and account.
+fn lease_marker(service: String, account: String, access_group: Option<String>) -> Marker {
+    let service = format!("{LEASE_TAG}{service}");
+    Marker {
+        query: Box::new(move |adding| {
+            let mut query = RawQuery::generic_password();
+            query
+                .data_protection()
+                .string(Attr::Service, &service)
+                .string(Attr::Account, &account)
+                .boolean(Attr::Synchronizable, false);
+            if let Some(access_group) = &access_group {
+                query.string(Attr::AccessGroup, access_group);
+            }
+            if adding {
+                let access_control = SecAccessControl::create_with_protection(
+                    Some(ProtectionMode::AccessibleAfterFirstUnlockThisDeviceOnly),
+                    Default::default(),
+                )
+                .map_err(decode_error)?;
+                query.access_control(access_control);
+            }
+            Ok(query)
+        }),
+        decode: decode_error,
+    }
+}
+
+/// A string from the running app's `Info.plist`.
+fn bundle_string(key: &'static str) -> Option<String> {
+    let info = CFBundle::main_bundle().info_dictionary();
+    info.find(CFString::from_static_string(key))
+        .and_then(|value| value.downcast::<CFString>())
+        .map(|value| value.to_string())
+}
+
+/// The bundle identifier of the running app, which the given key needs.
+fn app_bundle_identifier(key: &str) -> Result<String> {
+    bundle_string("CFBundleIdentifier").ok_or_else(|| {
+        ErrorCode::NotSupportedByStore(format!("{key} requires an app bundle with an identifier"))
+    })
+}
+
+/// Whether a search result is an item the store keeps for its own bookkeeping
+/// rather than a credential.
+fn is_bookkeeping(item: &item::SearchResult) -> bool {
+    item.simplify_dict()
+        .and_then(|attrs| attrs.get("svce").cloned())
+        .is_some_and(|service| is_bookkeeping_service(&service))
+}
+
+/// Whether a service is that of an item the store keeps for its own
+/// bookkeeping: a tombstone, a piece of an envelope secret, a lease marker,
+/// the unlock marker, or a probe item.
+fn is_bookkeeping_service(service: &str) -> bool {
+    [TOMBSTONE_TAG, envelope::ENVELOPE_TAG, LEASE_TAG]
+        .iter()
+        .any(|tag| service.starts_with(tag))
+        || availability::is_marker(service)
+        || [APP_ID_PROBE, AVAILABILITY_PROBE, HEALTH_PROBE].contains(&service)
+}
+
+/// Whether items with a protection class (as reported in item attributes)
+/// are kept to this device.
+fn is_device_only(class: &str) -> bool {
+    [
+        AccessPolicy::AfterFirstUnlockThisDeviceOnly,
+        AccessPolicy::WhenUnlockedThisDeviceOnly,
+        AccessPolicy::WhenPasscodeSetThisDeviceOnly,
+    ]
+    .iter()
+    .any(|policy| policy.protection_class() == class)
+}
+
+/// A tag for a credential, which must not also have a display user.
+fn check_tag(tag: Option<String>, display_user: &Option<String>) -> Result<Option<String>> {
+    match tag {
+        Some(_) if display_user.is_some() => Err(ErrorCode::Invalid(
+            "tag".to_string(),
+            "cannot be combined with display-user".to_string(),
+        )),
+        Some(tag) if tag.is_empty() => Err(ErrorCode::Invalid(
+            "tag".to_string(),
+            "cannot be empty".to_string(),
+        )),
+        tag => Ok(tag),
+    }
+}
+
+/// Check that a label template has a place for the access-policy marker.
+fn check_label_template(template: Option<String>) -> Result<Option<String>> {
+    match template {
+        Some(template) if !template.contains("{policy}") => Err(ErrorCode::Invalid(
+            "label-template".to_string(),
+            "must contain {policy}".to_string(),
+        )),
+        template => Ok(template),
+    }
+}
+
+/// Decode standard base64 (with or without padding).
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let digits = encoded.trim_end_matches('=').as_bytes();
+    if digits.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(digits.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for digit in digits {
+        let value = match digit {
+            b'A'..=b'Z' => digit - b'A',
+            b'a'..=b'z' => digit - b'a' + 26,
+            b'0'..=b'9' => digit - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// A query over the local items of an access group (or of all groups), among
+/// which are the tombstones.
+fn tombstone_query(access_group: Option<&str>) -> RawQuery {
+    let mut query = RawQuery::generic_password();
+    query.data_protection().boolean(Attr::Synchronizable, false);
+    if let Some(access_group) = access_group {
+        query.string(Attr::AccessGroup, access_group);
+    }
+    query
+}
+
+/// Whether a search result's access group is the app's own App ID group.
+///
+/// That group is the app's bundle identifier prefixed by its App ID prefix (the
+/// team ID), so shared groups, app groups, and token groups never match.
+fn is_app_id_group(item: &item::SearchResult, bundle_id: &str) -> bool {
+    let Some(group) = item
+        .simplify_dict()
+        .and_then(|attrs| attrs.get("agrp").cloned())
+    else {
+        return false;
+    };
+    match group.split_once('.') {
+        Some((prefix, rest)) => rest == bundle_id && is_app_id_prefix(prefix),
+        None => false,
+    }
+}
+
+/// Whether an access group prefix looks like an App ID prefix (a team ID).
+fn is_app_id_prefix(prefix: &str) -> bool {
+    prefix.len() == 10 && prefix.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// The app's App ID access group.
+///
+/// The OS doesn't say what an app's App ID prefix is, so this adds a placeholder
+/// item without an access group, reads back the group the OS put it in, and
+/// deletes it. The prefix of that group is the App ID prefix, as long as the
+/// app's first entitlement group is one of its keychain access groups.
+///
+/// A dry-run store mustn't write anything, so for one this looks for the App
+/// ID group among the app's [entitled](entitled_access_groups) groups instead.
+fn app_id_group(dry_run: bool) -> Result<String> {
+    let bundle_id = app_bundle_identifier("default-group app-id")?;
+    if dry_run {
+        return entitled_access_groups()
+            .into_iter()
+            .find(|group| {
+                group
+                    .split_once('.')
+                    .is_some_and(|(prefix, rest)| is_app_id_prefix(prefix) && rest == bundle_id)
+            })
+            .ok_or_else(|| {
+                ErrorCode::NotSupportedByStore(
+                    "default-group app-id in a dry-run store requires the App ID group \
+                     in the app's entitlements"
+                        .to_string(),
+                )
+            });
+    }
+    let mut probe = RawQuery::generic_password();
+    probe
+        .data_protection()
+        .string(Attr::Service, APP_ID_PROBE)
+        .string(Attr::Account, APP_ID_PROBE)
+        .boolean(Attr::Synchronizable, false);
+    let mut item = probe.clone();
+    item.value(&[]);
+    match item.add() {
+        Ok(()) => {}
+        Err(err) if err.code() == errSecDuplicateItem => {}
+        Err(err) => return Err(decode_error(err)),
     }
-
-    /// See the keychain-core API docs.
-    fn debug_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
+    let mut query = probe.clone();
+    query.return_attributes();
+    let found = query.find();
+    if let Err(err) = probe.delete() {
+        debug!("Couldn't delete the App ID probe item: {err:?}");
+    }
+    let group = found
+        .map_err(decode_error)?
+        .first()
+        .and_then(|attrs| attrs.string(Attr::AccessGroup));
+    match group.as_deref().and_then(|g| g.split_once('.')) {
+        Some((prefix, _)) if is_app_id_prefix(prefix) => Ok(format!("{prefix}.{bundle_id}")),
+        _ => Err(ErrorCode::NotSupportedByStore(
+            "default-group app-id requires the first entitlement group to have an App ID prefix"
+                .to_string(),
+        )),
     }
 }
 
-/// The builder for iOS keychain credentials
-pub struct Store {
-    id: String,
-    access_group: Option<String>,
-    cloud_synchronize: bool,
+/// An item, by its stored service and account, access group, and
+/// synchronizability.
+type ReadKey = (String, String, Option<String>, bool);
+
+/// A read of an item that other threads are waiting for.
+#[derive(Default)]
+struct SharedRead {
+    result: Mutex<Option<Result<Vec<u8>>>>,
+    done: Condvar,
 }
 
-impl std::fmt::Debug for Store {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Store")
-            .field("vendor", &self.vendor())
-            .field("id", &self.id())
-            .field("access_group", &self.access_group)
-            .field("cloud_synchronize", &self.cloud_synchronize)
-            .finish()
-    }
+/// Ends a shared read when the thread doing it is done with it, whether it
+/// returned or panicked.
+///
+/// The read is taken off the list of those under way, and the threads waiting
+/// for it are woken. If the reading thread panicked before it had a result,
+/// they get a `PlatformFailure` error rather than wait forever.
+struct PublishRead<'a> {
+    key: &'a ReadKey,
+    read: &'a SharedRead,
 }
 
-impl Store {
-    /// Create a default store, which does *not* synchronize with the cloud.
-    pub fn new() -> Result<Arc<Self>> {
-        Ok(Self::new_internal(None, false))
+impl Drop for PublishRead<'_> {
+    fn drop(&mut self) {
+        self.read
+            .result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert_with(|| {
+                Err(ErrorCode::PlatformFailure(
+                    "the thread reading the item panicked".into(),
+                ))
+            });
+        READS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(self.key);
+        self.read.done.notify_all();
     }
+}
 
-    /// Create a configured store.
-    ///
-    /// There are two allowed configuration keys:
-    /// - `cloud-sync` (`true` or `false`), default false. Specifying this key as true
-    ///   will sync all items in the store with iCloud.
-    /// - `access-group`. If non-empty, this store will store all its items in the
-    ///   specified access group. If empty or not specified, as in the default configuration,
-    ///   all items will be stored in the app's default access group.
-    pub fn new_with_configuration(config: &HashMap<&str, &str>) -> Result<Arc<Self>> {
-        let config = parse_attributes(&["access-group", "*cloud-sync"], Some(config))?;
-        let mut cloud_synchronize = false;
-        let mut access_group = None;
-        if let Some(option) = config.get("cloud-sync") {
-            cloud_synchronize = option.eq("true");
-        }
-        if let Some(option) = config.get("access-group") {
-            if !option.is_empty() {
-                access_group = Some(option.to_string());
-            }
+/// The reads of items that require user presence that are under way.
+static READS: LazyLock<Mutex<HashMap<ReadKey, Arc<SharedRead>>>> = LazyLock::new(Default::default);
+
+/// When each item that requires user presence was last read by a credential
+/// with a `prompt_interval`.
+static PROMPTS: LazyLock<Mutex<HashMap<ReadKey, Instant>>> = LazyLock::new(Default::default);
+
+/// A copy of a read's result for a thread that waited for it.
+///
+/// Keyring errors aren't `Clone`, so this rebuilds them. The errors this
+/// crate wraps in them (and the Security framework's status codes) are copied
+/// as they are, so the detectors in the [error](crate::error) module see the
+/// copy as they would the original; any other wrapped error is copied as its
+/// message.
+fn copy_result(result: &Result<Vec<u8>>) -> Result<Vec<u8>> {
+    let err = match result {
+        Ok(secret) => return Ok(secret.clone()),
+        Err(err) => err,
+    };
+    Err(match err {
+        ErrorCode::NoEntry => ErrorCode::NoEntry,
+        ErrorCode::NoStorageAccess(inner) => ErrorCode::NoStorageAccess(copy_platform(inner)),
+        ErrorCode::PlatformFailure(inner) => ErrorCode::PlatformFailure(copy_platform(inner)),
+        ErrorCode::BadEncoding(bytes) => ErrorCode::BadEncoding(bytes.clone()),
+        ErrorCode::BadDataFormat(bytes, inner) => {
+            ErrorCode::BadDataFormat(bytes.clone(), copy_platform(inner))
         }
-        Ok(Self::new_internal(access_group, cloud_synchronize))
-    }
+        ErrorCode::BadStoreFormat(message) => ErrorCode::BadStoreFormat(message.clone()),
+        ErrorCode::TooLong(name, limit) => ErrorCode::TooLong(name.clone(), *limit),
+        ErrorCode::Invalid(name, reason) => ErrorCode::Invalid(name.clone(), reason.clone()),
+        ErrorCode::NoDefaultStore => ErrorCode::NoDefaultStore,
+        ErrorCode::NotSupportedByStore(message) => ErrorCode::NotSupportedByStore(message.clone()),
+        other => ErrorCode::PlatformFailure(other.to_string().into()),
+    })
+}
 
-    fn new_internal(access_group: Option<String>, cloud_synchronize: bool) -> Arc<Self> {
-        let now = SystemTime::now();
-        let elapsed = if now.lt(&UNIX_EPOCH) {
-            UNIX_EPOCH.duration_since(now).unwrap()
-        } else {
-            now.duration_since(UNIX_EPOCH).unwrap()
-        };
-        let id = format!(
-            "Protected Data Storage, Crate version {}, Instantiated at {}",
-            env!("CARGO_PKG_VERSION"),
-            elapsed.as_secs_f64()
-        );
-        Arc::new(Store {
-            id,
-            access_group,
-            cloud_synchronize,
-        })
+/// A copy of the error inside a keyring error. See [copy_result].
+fn copy_platform(inner: &keyring_core::error::PlatformError) -> keyring_core::error::PlatformError {
+    fn copied<T: std::error::Error + Copy + Send + Sync + 'static>(
+        inner: &keyring_core::error::PlatformError,
+    ) -> Option<keyring_core::error::PlatformError> {
+        inner
+            .downcast_ref::<T>()
+            .map(|err| Box::new(*err) as keyring_core::error::PlatformError)
     }
+    if let Some(err) = inner.downcast_ref::<Error>() {
+        return Box::new(Error::from_code(err.code()));
+    }
+    copied::<AuthenticationCanceled>(inner)
+        .or_else(|| copied::<BiometryLockedOut>(inner))
+        .or_else(|| copied::<KeychainLocked>(inner))
+        .or_else(|| copied::<PasscodeNotSet>(inner))
+        .or_else(|| copied::<PromptRateLimited>(inner))
+        .or_else(|| copied::<MainThreadPrompt>(inner))
+        .or_else(|| copied::<timeout::Timeout>(inner))
+        .unwrap_or_else(|| inner.to_string().into())
 }
 
+/// The shared stores.
+static SHARED: SharedStores<Store> = SharedStores::new(
+    Store::new_with_configuration,
+    Store::normalized_configuration,
+);
+
 impl CredentialStoreApi for Store {
     /// See the keychain-core API docs.
     fn vendor(&self) -> String {
@@ -428,7 +4363,7 @@ impl CredentialStoreApi for Store {
 
     /// See the keychain-core API docs.
     ///
-    /// There is only one allowed modifier: `access-policy`, which can be one of
+    /// The `access-policy` modifier can be one of
     /// these (case-insensitive) values (ordered least to most restrictive):
     /// - `AfterFirstUnlock` (or `after-first-unlock`)
     /// - `AfterFirstUnlockThisDeviceOnly` (or `after-first-unlock-this-device-only`)
@@ -436,48 +4371,252 @@ impl CredentialStoreApi for Store {
     /// - `WhenUnlockedThisDeviceOnly` (or `when-unlocked-this-device-only`)
     /// - `WhenPasscodeSetThisDeviceOnly` (or `when-passcode-set-this-device-only`)
     /// - `RequireUserPresence` (or `require-user-presence`)
+    /// - `BiometryAny` (or `biometry-any`)
+    /// - `BiometryCurrentSet` (or `biometry-current-set`)
+    /// - `ApplicationPassword` (or `application-password`)
     ///
     /// These correspond to similarly named values of the `kSecAttrAccessible` attribute,
     /// described in the
     /// [Apple docs](https://developer.apple.com/documentation/security/restricting-keychain-item-accessibility),
-    /// except for `RequireUserPresence` which is like
-    /// `WhenUnlocked` but adds a requirement to do biometric authentication whenever
-    /// the credential is accessed.
+    /// except for the last four, which are like `WhenUnlocked` but add
+    /// a requirement to authenticate whenever the credential is accessed.
+    ///
+    /// `RequireUserPresence` lets the user fall back to the device passcode. The
+    /// two biometry policies don't: they require Touch ID or Face ID. With
+    /// `BiometryAny`, the item stays readable when fingers or faces are enrolled
+    /// or removed; with `BiometryCurrentSet`, any such change makes the item
+    /// permanently unreadable, so its secret has to be set again.
+    ///
+    /// `ApplicationPassword` items are encrypted with a password the app supplies,
+    /// in addition to the device's keys. The app passes it to
+    /// [set_secret_with_application_password](Cred::set_secret_with_application_password)
+    /// and [get_secret_with_application_password](Cred::get_secret_with_application_password);
+    /// the plain `set_secret` and `get_secret` make the OS ask the user for it.
     ///
     /// Note: You cannot specify an access policy in a cloud-synchronized store: the
     /// OS controls this access to manage synchronization.
+    ///
+    /// The `access-group` modifier puts the entry's item in the given access group
+    /// rather than the store's, so one store can keep some entries in a shared
+    /// group and others in the app's own. As with the store's `access-group`, the
+    /// app must have the group in its entitlements, or building the entry fails.
+    /// It can't be given in a store whose `default-group` is `app-id`, since that
+    /// store's items all go in the App ID group. A `first-entitlement` store only
+    /// leaves the group of entries that don't name one to the OS, so its entries
+    /// can.
+    ///
+    /// The `display-user` modifier, if specified, is what the item's account attribute
+    /// holds, so it's what system UIs such as the Passwords app show as the username.
+    /// The entry's user is kept in the item's generic metadata instead, and the entry
+    /// is found by that. Since the OS only keeps one item per service and account,
+    /// two entries for the same service can't share a display user.
+    ///
+    /// The `skip-unchanged` modifier (value true or false, default false) makes
+    /// setting a secret first read the existing one and skip the write if they
+    /// match, so an unchanged secret doesn't update the item's modification date
+    /// (and, for cloud-synchronized items, trigger a sync). It's opt-in because
+    /// reading the secret of an item that requires user presence will prompt.
+    ///
+    /// The `require-user-presence-within` modifier is a number of seconds, from 1
+    /// to 300. It implies the `RequireUserPresence` access policy (so it can't be
+    /// combined with any other policy, or used in a cloud-synchronized store), and
+    /// lets reading the secret go ahead without a prompt if the user unlocked the
+    /// device with biometrics within that many seconds. Otherwise the user is asked
+    /// to authenticate again, which is what flows such as payments need. The OS
+    /// doesn't reuse authentications older than five minutes, hence the limit.
+    ///
+    /// The `tag` modifier is an application-assigned ID (such as a UUID) that
+    /// setting the secret stores in the item's generic metadata, so the item can be
+    /// found by searching for the tag, whatever its service and user. It can't be
+    /// combined with `display-user`, which keeps the user in the generic metadata.
+    ///
+    /// The `authentication-ui` modifier (`allow`, `skip`, or `fail`, default
+    /// `allow`) says what reading the secret does if the item requires the user to
+    /// authenticate: ask them, treat the item as missing, or fail right away. See
+    /// [AuthenticationUi].
+    ///
+    /// The `label-template` modifier is a label for the item that contains
+    /// `{policy}`, such as `MyApp token {policy}`. Setting the secret gives the item
+    /// that label, with `{policy}` replaced by the access policy's marker (for
+    /// example `MyApp token 🔒 require-user-presence`), so listings of the items
+    /// show their policies, and [from_label](AccessPolicy::from_label) can read a
+    /// policy back. [update_access_policy](Cred::update_access_policy) keeps
+    /// the label up to date.
+    ///
+    /// The `envelope` modifier (value true or false, default false) makes the
+    /// entry encrypt its secret with the store's data-encryption key, and split
+    /// the ciphertext over several items if it's large. The store must have an
+    /// `envelope-key`, and the modifier can't be combined with `skip-unchanged`,
+    /// since no two encryptions of a secret are the same. See the [envelope]
+    /// module.
+    ///
+    /// The `create-only` modifier (value true or false, default false) makes
+    /// setting the secret fail if the item already exists, rather than replace
+    /// its secret, for workflows in which overwriting someone else's credential
+    /// would be destructive. The failure is a `PlatformFailure` whose
+    /// [error_kind](crate::error::error_kind) is
+    /// [DuplicateItem](crate::error::ErrorKind::DuplicateItem). It can't be
+    /// combined with `skip-unchanged`.
+    ///
+    /// The `prompt-timeout` modifier is a number of seconds, from 1 to 600, that
+    /// the user has to answer an authentication prompt shown while reading the
+    /// secret (by `get_secret` or [get_secret_with_prompt](Cred::get_secret_with_prompt)).
+    /// If they haven't answered by then, the prompt is dismissed and the read
+    /// fails with a `NoStorageAccess` error holding a
+    /// [Timeout](crate::timeout::Timeout). It overrides the store's
+    /// `prompt-timeout`, and can't be used in a cloud-synchronized store.
     fn build(
         &self,
         service: &str,
         user: &str,
         modifiers: Option<&HashMap<&str, &str>>,
     ) -> Result<Entry> {
-        let mods = parse_attributes(&["access-policy"], modifiers)?;
-        if self.cloud_synchronize && mods.contains_key("access-policy") {
-            return Err(ErrorCode::Invalid(
-                "access-policy".to_string(),
-                "cannot be specified in a cloud-synchronized store".to_string(),
-            ));
+        let mods = parse_attributes(MODIFIER_KEYS, modifiers)?;
+        for key in [
+            "access-policy",
+            "require-user-presence-within",
+            "prompt-timeout",
+        ] {
+            if self.cloud_synchronize && mods.contains_key(key) {
+                return Err(ErrorCode::Invalid(
+                    key.to_string(),
+                    "cannot be specified in a cloud-synchronized store".to_string(),
+                ));
+            }
         }
-        Cred::build(
+        let user_presence_within = match mods.get("require-user-presence-within") {
+            Some(seconds) => Some(seconds.parse::<u32>().map_err(|_| {
+                ErrorCode::Invalid(
+                    "require-user-presence-within".to_string(),
+                    format!("not a number of seconds: {seconds}"),
+                )
+            })?),
+            None => None,
+        };
+        let access_policy = match user_presence_within {
+            Some(_) if !mods.contains_key("access-policy") => AccessPolicy::RequireUserPresence,
+            _ => determine_access_policy(&mods)?,
+        };
+        let access_group = match mods.get("access-group") {
+            Some(_) if self.default_group == DefaultGroup::AppId => {
+                return Err(ErrorCode::Invalid(
+                    "access-group".to_string(),
+                    "cannot be specified in a store with default-group app-id".to_string(),
+                ));
+            }
+            Some(access_group) => {
+                entitlements::check_access_group(access_group)?;
+                Some(access_group.clone())
+            }
+            None => self.access_group.clone(),
+        };
+        let mut cred = Cred::new(
             service,
             user,
-            determine_access_policy(&mods)?,
-            self.access_group.clone(),
+            access_policy,
+            access_group,
             self.cloud_synchronize,
-        )
+        )?;
+        if let Some(seconds) = user_presence_within {
+            cred.user_presence_within =
+                Some(check_user_presence_window(seconds, &cred.access_policy)?);
+        } else if cred.access_policy == AccessPolicy::RequireUserPresence {
+            cred.user_presence_within = self.user_presence_reuse;
+        }
+        cred.display_user = mods.get("display-user").cloned();
+        cred.tag = check_tag(mods.get("tag").cloned(), &cred.display_user)?;
+        cred.skip_unchanged = mods.get("skip-unchanged").is_some_and(|s| s.eq("true"));
+        if let Some(ui) = mods.get("authentication-ui") {
+            cred.authentication_ui = ui.parse()?;
+        }
+        cred.label_template = check_label_template(mods.get("label-template").cloned())?;
+        cred.create_only = mods.get("create-only").is_some_and(|s| s.eq("true"));
+        self.apply_settings(&mut cred);
+        if let Some(seconds) = mods.get("prompt-timeout") {
+            cred.prompt_timeout = Some(parse_prompt_timeout(seconds)?);
+        }
+        if cred.create_only && cred.skip_unchanged {
+            return Err(ErrorCode::Invalid(
+                "create-only".to_string(),
+                "cannot be combined with skip-unchanged".to_string(),
+            ));
+        }
+        cred.stored_specifiers = self.hashed(service, user);
+        if mods.get("envelope").is_some_and(|s| s.eq("true")) {
+            if cred.skip_unchanged {
+                return Err(ErrorCode::Invalid(
+                    "envelope".to_string(),
+                    "cannot be combined with skip-unchanged".to_string(),
+                ));
+            }
+            let envelope = self.envelope_cred(cred, "envelope")?;
+            return Ok(Entry::new_with_credential(Arc::new(envelope)));
+        }
+        Ok(Entry::new_with_credential(Arc::new(cred)))
     }
 
     /// See the keychain-core API docs.
     ///
     /// The primary spec keys are `service`, `account`, and `access-group`, which
     /// restrict the search to items which match (case-sensitive) the given values.
-    /// Without any restrictions, every generic password item in the store is returned.
+    /// The `case-sensitive` and `diacritic-sensitive` keys (value true or false,
+    /// default true) make the service and account match ignoring case or accents,
+    /// except in stores that hash their specifiers (which can't).
+    ///
+    /// The `service-prefix` and `account-prefix` keys restrict the search to
+    /// items whose service or account starts with the given text, and the
+    /// `contains` key to those whose service or account contains it, so a
+    /// picker can narrow its list as the user types. They're checked against
+    /// each item found (case-insensitively if `case-sensitive` is false), and
+    /// aren't supported in stores that hash their specifiers.
+    /// Without any restrictions, every generic password item in the store is returned
+    /// (unless the store doesn't allow unbounded searches, in which case the spec must
+    /// have `all` set to true; see [new_with_configuration](Store::new_with_configuration)).
+    ///
+    /// The `service` key can also be a comma-separated list of services, in which
+    /// case items for any of the listed services are returned, each once even if
+    /// its service is listed more than once. A list with an empty service in it
+    /// (such as `a,,b` or `,`) is an error. (So a single service whose name
+    /// contains a comma can't be searched for.)
     ///
     /// There is a `show-authentication-ui` key (value true or false, default false)
     /// which can be used to prevent the default behavior of skipping
     /// any items whose access policy requires user interaction.
     ///
+    /// There is a `service-under` key whose value is a `/`-separated path such as
+    /// `org/app`. It restricts the search to items whose service is that path or
+    /// is below it (such as `org/app/component`). It's applied to the results of
+    /// the search, so it can be combined with the other keys, but it isn't
+    /// supported in stores that hash their specifiers.
+    ///
+    /// There is an `app-id-only` key (value true or false, default false) which
+    /// restricts the search to items in the app's own App ID access group
+    /// (its team ID followed by its bundle identifier), leaving out items in
+    /// shared keychain groups, app groups, and token groups. This fails if the
+    /// process has no bundle identifier.
+    ///
+    /// There is a `tag` key, which restricts the search to items given that tag
+    /// with the `tag` modifier (see [build](CredentialStoreApi::build)), and a
+    /// `generic` key, whose value is base64-encoded bytes, which restricts it to
+    /// items whose generic metadata is exactly those bytes. (So items made by
+    /// other tools can be found by their generic metadata, too.) At most one of
+    /// them can be given.
+    ///
+    /// There is a `sync-scope` key (value `store` or `any`, default `store`).
+    /// With `any`, both the local and the cloud-synchronized stores are
+    /// searched, whichever this store is, so a UI can show one merged list;
+    /// each entry's [Cred] tells which store its item is in by its
+    /// `cloud_synchronize` field (as does the `synchronizable` attribute).
+    ///
+    /// There are `limit` and `offset` keys (numbers of entries) for showing
+    /// the results a page at a time: with either, the results are ordered by
+    /// service and account, the first `offset` are skipped, and at most
+    /// `limit` of the rest are returned.
+    ///
+    /// For a "recently used" list, give `sort` as `modified-desc` (newest
+    /// first) or `modified-asc`; `created-desc` and `created-asc` go by when
+    /// the items were added. The page is then taken in that order.
+    ///
     /// Because the OS hides the access policy information
     /// of existing items, every wrapper returned from a search has a
     /// default access policy which may or may not match that of the item
@@ -485,31 +4624,11 @@ impl CredentialStoreApi for Store {
     /// delete the underlying item and re-create it from the wrapper
     /// by setting its password.
     fn search(&self, spec: &HashMap<&str, &str>) -> Result<Vec<Entry>> {
-        let spec = parse_attributes(
-            &[
-                "service",
-                "account",
-                "access-group",
-                "*show-authentication-ui",
-            ],
-            Some(spec),
-        )?;
-        let cloud_sync = self.cloud_synchronize;
-        let show_ui = spec
-            .get("show-authentication-ui")
-            .is_some_and(|s| s.eq("true"));
-        let items = search_items(
-            spec.get("service").map(String::as_str),
-            spec.get("account").map(String::as_str),
-            spec.get("access-group").map(String::as_str),
-            cloud_sync,
-            !show_ui,
-        )?;
-        let mut results = Vec::new();
-        for item in items.iter() {
-            results.push(Cred::build_from_search_result(item, cloud_sync)?)
-        }
-        Ok(results)
+        let spec = parse_attributes(SEARCH_KEYS, Some(spec))?;
+        let outcome = timeout::run("search", self.clone(), move |store| {
+            store.search_internal(&spec, false)
+        })?;
+        Ok(outcome.entries)
     }
 
     /// See the keychain-core API docs.
@@ -528,6 +4647,197 @@ impl CredentialStoreApi for Store {
     }
 }
 
+impl AppleStoreExt for Store {
+    fn count(&self, spec: &HashMap<&str, &str>) -> Result<usize> {
+        Ok(self.search(spec)?.len())
+    }
+
+    fn wipe(&self, spec: &HashMap<&str, &str>) -> Result<usize> {
+        let entries = self.search(spec)?;
+        for entry in entries.iter() {
+            entry.delete_credential()?;
+        }
+        Ok(entries.len())
+    }
+
+    fn update_attributes_matching(
+        &self,
+        spec: &HashMap<&str, &str>,
+        attrs: &HashMap<&str, &str>,
+    ) -> Result<usize> {
+        Store::update_attributes_matching(self, spec, attrs)
+    }
+
+    fn delete_matching(&self, spec: &HashMap<&str, &str>) -> Result<usize> {
+        Store::delete_matching(self, spec)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::from_keys(CONFIGURATION_KEYS, MODIFIER_KEYS, SEARCH_KEYS)
+    }
+
+    fn provider_info(&self) -> ProviderInfo {
+        ProviderInfo::new(Backend::Protected, self.capabilities())
+    }
+
+    fn diagnostics(&self) -> Vec<(String, String)> {
+        let mut diagnostics = common_diagnostics(self.vendor(), self.id());
+        diagnostics.push((
+            "access-group".to_string(),
+            self.access_group.clone().unwrap_or_default(),
+        ));
+        diagnostics.push(("cloud-sync".to_string(), self.cloud_synchronize.to_string()));
+        diagnostics.push(("hashed".to_string(), self.hasher.is_some().to_string()));
+        diagnostics.push(("dry-run".to_string(), self.dry_run.to_string()));
+        diagnostics.push((
+            "default-group".to_string(),
+            self.default_group.as_str().to_string(),
+        ));
+        diagnostics
+    }
+
+    fn is_available(&self) -> Availability {
+        Store::is_available(self)
+    }
+}
+
+/// A low-level query over the generic password items in the protected data store.
+///
+/// This is for clients who need more control than [search](Store::search)
+/// gives them. Each setter adds a condition on matching items; with none, every
+/// generic password item the app can see (local or cloud-synchronized, in any of
+/// its access groups) matches. The query is run with [find](Query::find),
+/// [entries](Query::entries), or [secret](Query::secret), and errors are
+/// reported just as the store reports them.
+///
+/// A query matches the attributes the items are actually stored under, so in
+/// a store with a [SpecifierHasher] it must be given (and will report) the
+/// hashed service and account.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    service: Option<String>,
+    account: Option<String>,
+    access_group: Option<String>,
+    label: Option<String>,
+    synchronized: Option<bool>,
+    limit: Option<u32>,
+    show_authentication_ui: bool,
+}
+
+impl Query {
+    /// A query that matches every item.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Match items with this service.
+    pub fn service(&mut self, service: &str) -> &mut Self {
+        self.service = Some(service.to_string());
+        self
+    }
+
+    /// Match items with this account.
+    pub fn account(&mut self, account: &str) -> &mut Self {
+        self.account = Some(account.to_string());
+        self
+    }
+
+    /// Match items in this access group.
+    pub fn access_group(&mut self, access_group: &str) -> &mut Self {
+        self.access_group = Some(access_group.to_string());
+        self
+    }
+
+    /// Match items with this label.
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Match only items that are (or aren't) synchronized with iCloud.
+    pub fn synchronized(&mut self, synchronized: bool) -> &mut Self {
+        self.synchronized = Some(synchronized);
+        self
+    }
+
+    /// Match at most this many items, which must be at least 1.
+    pub fn limit(&mut self, limit: u32) -> &mut Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Match items whose access policy requires user interaction, prompting the
+    /// user when they are read. As in [search](Store::search), they are skipped
+    /// by default.
+    pub fn show_authentication_ui(&mut self, show: bool) -> &mut Self {
+        self.show_authentication_ui = show;
+        self
+    }
+
+    /// The attributes of the matching items.
+    ///
+    /// Finding no items is not an error; it returns an empty list.
+    pub fn find(&self) -> Result<Vec<ItemAttributes>> {
+        let mut query = RawQuery::generic_password();
+        query.data_protection().return_attributes();
+        match self.limit {
+            Some(0) => {
+                return Err(ErrorCode::Invalid(
+                    "limit".to_string(),
+                    "must be at least 1".to_string(),
+                ));
+            }
+            Some(limit) => query.limit(limit),
+            None => query.limit_all(),
+        };
+        for (attr, value) in [
+            (Attr::Service, &self.service),
+            (Attr::Account, &self.account),
+            (Attr::AccessGroup, &self.access_group),
+            (Attr::Label, &self.label),
+        ] {
+            if let Some(value) = value {
+                query.string(attr, value);
+            }
+        }
+        match self.synchronized {
+            Some(synchronized) => query.boolean(Attr::Synchronizable, synchronized),
+            None => query.any_synchronizable(),
+        };
+        if !self.show_authentication_ui {
+            query.skip_authenticated_items();
+        }
+        let found = query.find().map_err(decode_error)?;
+        Ok(found.iter().map(query::Attributes::item).collect())
+    }
+
+    /// Wrappers for the matching items, as [search](Store::search) would return.
+    ///
+    /// Items with an empty service or account are left out, since they
+    /// can't be wrapped.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        Ok(self
+            .find()?
+            .iter()
+            .filter_map(|item| Cred::from_item(item).ok())
+            .map(|cred| Entry::new_with_credential(Arc::new(cred)))
+            .collect())
+    }
+
+    /// The secret of the one matching item.
+    ///
+    /// This fails with `NoEntry` if no item matches, and with `Ambiguous`
+    /// if more than one does.
+    pub fn secret(&self) -> Result<Vec<u8>> {
+        let mut entries = self.entries()?;
+        match entries.len() {
+            0 => Err(ErrorCode::NoEntry),
+            1 => entries.remove(0).get_secret(),
+            _ => Err(ErrorCode::Ambiguous(entries)),
+        }
+    }
+}
+
 fn search_items(
     service: Option<&str>,
     account: Option<&str>,
@@ -535,27 +4845,52 @@ fn search_items(
     cloud_sync: bool,
     suppress_ui: bool,
 ) -> Result<Vec<item::SearchResult>> {
-    let mut options = item::ItemSearchOptions::new();
-    options
-        .class(item::ItemClass::generic_password())
-        .load_attributes(true)
-        .limit(item::Limit::All)
-        .skip_authenticated_items(suppress_ui);
+    search_items_in(
+        service,
+        account,
+        access_group,
+        cloud_sync,
+        suppress_ui,
+        StringMatching::default(),
+        None,
+    )
+}
+
+/// Search for items, matching their services and accounts as given, and
+/// authenticating (for items that need it) in the given context, if any.
+fn search_items_in(
+    service: Option<&str>,
+    account: Option<&str>,
+    access_group: Option<&str>,
+    cloud_sync: bool,
+    suppress_ui: bool,
+    matching: StringMatching,
+    context: Option<&AuthenticationContext>,
+) -> Result<Vec<item::SearchResult>> {
+    let mut query = RawQuery::generic_password();
+    query
+        .data_protection()
+        .limit_all()
+        .return_attributes()
+        .boolean(Attr::Synchronizable, cloud_sync)
+        .matching(matching);
+    if suppress_ui {
+        query.skip_authenticated_items();
+    }
     if let Some(service) = service {
-        options.service(service);
+        query.string(Attr::Service, service);
     }
     if let Some(account) = account {
-        options.account(account);
+        query.string(Attr::Account, account);
     }
     if let Some(access_group) = access_group {
-        options.access_group(access_group);
-    }
-    options.cloud_sync(Some(cloud_sync));
-    #[cfg(target_os = "macos")]
-    options.ignore_legacy_keychains();
-    let result = options.search();
-    match result {
-        Ok(results) => Ok(results),
+        query.string(Attr::AccessGroup, access_group);
+    }
+    if let Some(context) = context {
+        query.authentication_context(context);
+    }
+    match query.find() {
+        Ok(found) => Ok(found.iter().map(query::Attributes::search_result).collect()),
         Err(err) => match decode_error(err) {
             ErrorCode::NoEntry => Ok(Vec::new()),
             other => Err(other),
@@ -563,31 +4898,204 @@ fn search_items(
     }
 }
 
-fn determine_access_policy(mods: &HashMap<String, String>) -> Result<AccessPolicy> {
-    if let Some(policy) = mods.get("access-policy") {
-        match policy.to_ascii_lowercase().as_str() {
-            "after-first-unlock" | "afterfirstunlock" => Ok(AccessPolicy::AfterFirstUnlock),
-            "after-first-unlock-this-device-only" | "afterfirstunlockthisdeviceonly" => {
-                Ok(AccessPolicy::AfterFirstUnlock)
-            }
-            "when-unlocked" | "whenunlocked" | "default" => Ok(AccessPolicy::WhenUnlocked),
-            "when-unlocked-this-device-only" | "whenunlockedthisdeviceonly" => {
-                Ok(AccessPolicy::WhenUnlocked)
-            }
-            "require-user-presence" | "requireuserpresence" => {
-                Ok(AccessPolicy::RequireUserPresence)
-            }
-            "when-passcode-set-this-device-only" | "whenpasscodesetthisdeviceonly" => {
-                Ok(AccessPolicy::WhenPasscodeSetThisDeviceOnly)
+/// Look up the attributes of the given services' items on a background thread.
+fn prefetch(services: Vec<String>, access_group: Option<String>, cloud_sync: bool) {
+    if services.is_empty() {
+        return;
+    }
+    let spawned = std::thread::Builder::new()
+        .name("keyring-prefetch".to_string())
+        .spawn(move || {
+            for service in services {
+                match search_items(
+                    Some(&service),
+                    None,
+                    access_group.as_deref(),
+                    cloud_sync,
+                    true,
+                ) {
+                    Ok(items) => debug!("Prefetched {} items for {service}", items.len()),
+                    Err(err) => debug!("Couldn't prefetch items for {service}: {err:?}"),
+                }
             }
-            _ => Err(ErrorCode::Invalid(
-                "access-policy".to_string(),
-                format!("unknown value: {policy}"),
-            )),
+        });
+    if let Err(err) = spawned {
+        debug!("Couldn't start the prefetch thread: {err:?}");
+    }
+}
+
+/// The default group given by a configuration, or an error if it conflicts
+/// with whether the configuration has an access group.
+fn determine_default_group(
+    config: &HashMap<String, String>,
+    has_access_group: bool,
+) -> Result<DefaultGroup> {
+    match config.get("default-group").map(String::as_str) {
+        None if has_access_group => Ok(DefaultGroup::Explicit),
+        None => Ok(DefaultGroup::FirstEntitlement),
+        Some("explicit") if has_access_group => Ok(DefaultGroup::Explicit),
+        Some("explicit") => Err(ErrorCode::Invalid(
+            "default-group".to_string(),
+            "explicit requires an access-group".to_string(),
+        )),
+        Some(other @ ("app-id" | "first-entitlement")) if has_access_group => {
+            Err(ErrorCode::Invalid(
+                "access-group".to_string(),
+                format!("cannot be specified with default-group {other}"),
+            ))
+        }
+        Some("app-id") => Ok(DefaultGroup::AppId),
+        Some("first-entitlement") => Ok(DefaultGroup::FirstEntitlement),
+        Some(other) => Err(ErrorCode::Invalid(
+            "default-group".to_string(),
+            format!("unknown value: {other}"),
+        )),
+    }
+}
+
+fn determine_access_policy(mods: &HashMap<String, String>) -> Result<AccessPolicy> {
+    match mods.get("access-policy") {
+        Some(policy) => policy.parse(),
+        None => Ok(AccessPolicy::default()),
+    }
+}
+
+/// An authentication context that supplies an application password.
+fn application_password_context(password: &[u8]) -> Result<AuthenticationContext> {
+    if password.is_empty() {
+        return Err(ErrorCode::Invalid(
+            "application password".to_string(),
+            "cannot be empty".to_string(),
+        ));
+    }
+    AuthenticationContext::with_application_password(password).ok_or_else(|| {
+        ErrorCode::PlatformFailure("The OS didn't accept the application password".into())
+    })
+}
+
+/// The `envelope-key` tag and `envelope-enclave` setting of a parsed store
+/// configuration.
+fn determine_envelope_key(config: &HashMap<String, String>) -> Result<(Option<String>, bool)> {
+    let enclave = config.get("envelope-enclave").is_some_and(|s| s.eq("true"));
+    let key = config
+        .get("envelope-key")
+        .filter(|tag| !tag.is_empty())
+        .cloned();
+    if enclave && key.is_none() {
+        return Err(ErrorCode::Invalid(
+            "envelope-enclave".to_string(),
+            "needs an envelope-key".to_string(),
+        ));
+    }
+    if enclave && config.get("cloud-sync").is_some_and(|s| s.eq("true")) {
+        return Err(ErrorCode::Invalid(
+            "envelope-enclave".to_string(),
+            "cannot be used by a cloud-synchronized store".to_string(),
+        ));
+    }
+    Ok((key, enclave))
+}
+
+/// The `user-presence-reuse-within` window of a parsed store configuration.
+fn determine_user_presence_reuse(config: &HashMap<String, String>) -> Result<Option<u32>> {
+    let Some(seconds) = config.get("user-presence-reuse-within") else {
+        return Ok(None);
+    };
+    let invalid =
+        |msg: &str| ErrorCode::Invalid("user-presence-reuse-within".to_string(), msg.to_string());
+    if config.get("cloud-sync").is_some_and(|s| s.eq("true")) {
+        return Err(invalid("cannot be specified in a cloud-synchronized store"));
+    }
+    match seconds.parse::<u32>() {
+        Ok(seconds) if (1..=300).contains(&seconds) => Ok(Some(seconds)),
+        _ => Err(invalid("must be from 1 to 300 seconds")),
+    }
+}
+
+fn determine_prompt_interval(config: &HashMap<String, String>) -> Result<Option<u32>> {
+    let Some(seconds) = config.get("prompt-interval") else {
+        return Ok(None);
+    };
+    let invalid = |msg: &str| ErrorCode::Invalid("prompt-interval".to_string(), msg.to_string());
+    if config.get("cloud-sync").is_some_and(|s| s.eq("true")) {
+        return Err(invalid("cannot be specified in a cloud-synchronized store"));
+    }
+    match seconds.parse::<u32>() {
+        Ok(seconds) if (1..=3600).contains(&seconds) => Ok(Some(seconds)),
+        _ => Err(invalid("must be from 1 to 3600 seconds")),
+    }
+}
+
+fn determine_prompt_timeout(config: &HashMap<String, String>) -> Result<Option<u32>> {
+    let Some(seconds) = config.get("prompt-timeout") else {
+        return Ok(None);
+    };
+    if config.get("cloud-sync").is_some_and(|s| s.eq("true")) {
+        return Err(ErrorCode::Invalid(
+            "prompt-timeout".to_string(),
+            "cannot be specified in a cloud-synchronized store".to_string(),
+        ));
+    }
+    parse_prompt_timeout(seconds).map(Some)
+}
+
+/// Parse the `prompt-timeout` of a store or entry.
+fn parse_prompt_timeout(seconds: &str) -> Result<u32> {
+    match seconds.parse::<u32>() {
+        Ok(seconds) if (1..=600).contains(&seconds) => Ok(seconds),
+        _ => Err(ErrorCode::Invalid(
+            "prompt-timeout".to_string(),
+            "must be from 1 to 600 seconds".to_string(),
+        )),
+    }
+}
+
+/// The access control for items with an access policy, or `None` for
+/// cloud-synchronized items, which can't have one.
+fn access_control_for(
+    policy: &AccessPolicy,
+    cloud_synchronize: bool,
+) -> Result<Option<SecAccessControl>> {
+    if cloud_synchronize {
+        return Ok(None);
+    }
+    let access_control = match policy {
+        AccessPolicy::RequireUserPresence => SecAccessControl::create_with_protection(
+            Some(policy.as_ref().into()),
+            AccessControlOptions::USER_PRESENCE.bits(),
+        ),
+        AccessPolicy::BiometryAny | AccessPolicy::BiometryCurrentSet => {
+            let flags = if *policy == AccessPolicy::BiometryAny {
+                AccessControlOptions::BIOMETRY_ANY
+            } else {
+                AccessControlOptions::BIOMETRY_CURRENT_SET
+            };
+            SecAccessControl::create_with_protection(Some(policy.as_ref().into()), flags.bits())
         }
-    } else {
-        Ok(AccessPolicy::default())
+        AccessPolicy::ApplicationPassword => SecAccessControl::create_with_protection(
+            Some(policy.as_ref().into()),
+            AccessControlOptions::APPLICATION_PASSWORD.bits(),
+        ),
+        other => SecAccessControl::create_with_protection(Some(other.into()), Default::default()),
+    };
+    Ok(Some(access_control.map_err(decode_error)?))
+}
+
+/// Check a `require-user-presence-within` window against the access policy it's used with.
+fn check_user_presence_window(seconds: u32, access_policy: &AccessPolicy) -> Result<u32> {
+    if *access_policy != AccessPolicy::RequireUserPresence {
+        return Err(ErrorCode::Invalid(
+            "require-user-presence-within".to_string(),
+            "requires the RequireUserPresence access policy".to_string(),
+        ));
     }
+    if !(1..=300).contains(&seconds) {
+        return Err(ErrorCode::Invalid(
+            "require-user-presence-within".to_string(),
+            "must be from 1 to 300 seconds".to_string(),
+        ));
+    }
+    Ok(seconds)
 }
 
 /// Map an iOS API error to a crate error with appropriate annotation
@@ -596,6 +5104,12 @@ fn determine_access_policy(mods: &HashMap<String, String>) -> Result<AccessPolic
 /// [this reference](https://opensource.apple.com/source/libsecurity_keychain/libsecurity_keychain-78/lib/SecBase.h.auto.html)
 fn decode_error(err: Error) -> ErrorCode {
     match err.code() {
+        -128 => AuthenticationCanceled::error(CanceledBy::User), // errSecUserCanceled
+        // errSecInteractionNotAllowed, which is also how an item that requires
+        // authentication fails when authentication UI isn't allowed
+        -25308 if availability::known_unavailable() => {
+            ErrorCode::NoStorageAccess(Box::new(KeychainLocked))
+        }
         -25291 => ErrorCode::NoStorageAccess(Box::new(err)), // errSecNotAvailable
         -25292 => ErrorCode::NoStorageAccess(Box::new(err)), // errSecReadOnly
         -25300 => ErrorCode::NoEntry,                        // errSecItemNotFound
@@ -603,3 +5117,23 @@ fn decode_error(err: Error) -> ErrorCode {
         _ => ErrorCode::PlatformFailure(Box::new(err)),
     }
 }
+
+/// Whether LocalAuthentication says the device has no passcode.
+fn passcode_is_not_set() -> bool {
+    AccessPolicy::RequireUserPresence.preflight() == Err(PolicyUnavailable::PasscodeNotSet)
+}
+
+unsafe extern "C" {
+    fn pthread_main_np() -> c_int;
+}
+
+/// Whether the calling thread is the process's main thread.
+fn on_main_thread() -> bool {
+    // SAFETY: this only looks at the calling thread.
+    unsafe { pthread_main_np() != 0 }
+}
+
+/// Whether LocalAuthentication says biometry is locked out right now.
+fn biometry_is_locked_out() -> bool {
+    AccessPolicy::BiometryAny.preflight() == Err(PolicyUnavailable::BiometryLockout)
+}
@@ -0,0 +1,181 @@
+/*!
+
+# Async operations
+
+Reading an item that requires user presence blocks the calling thread until
+the user has authenticated, which can take seconds, and any store operation
+can block if the security agent is slow. An async client can't afford that on
+an executor thread. With the `async` feature, this module's operations return
+futures instead: the Security framework calls run on a thread of their own,
+which wakes the future when they finish.
+
+An [AsyncEntry] wraps an [Entry] of any store, and [search] searches a
+protected [Store]. The futures don't need any particular executor, and
+dropping one doesn't cancel the operation it's waiting for, which (like an
+operation that [times out](crate::timeout)) may still complete.
+
+ */
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use keyring_core::api::CredentialStoreApi;
+use keyring_core::{Entry, Error as ErrorCode, Result};
+
+use super::Store;
+
+/// A store operation running on a thread of its own.
+///
+/// It resolves to the operation's result.
+#[must_use = "futures do nothing unless polled, though the operation runs anyway"]
+pub struct Operation<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+struct State<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T: Send + 'static> Operation<T> {
+    /// Start running an operation.
+    fn spawn(f: impl FnOnce() -> Result<T> + Send + 'static) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            result: None,
+            waker: None,
+        }));
+        let shared = state.clone();
+        let spawned = thread::Builder::new()
+            .name("keyring-async".to_string())
+            .spawn(move || {
+                let result = f();
+                let mut state = shared.lock().unwrap_or_else(PoisonError::into_inner);
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+        if let Err(err) = spawned {
+            state.lock().unwrap_or_else(PoisonError::into_inner).result =
+                Some(Err(ErrorCode::PlatformFailure(Box::new(err))));
+        }
+        Operation { state }
+    }
+}
+
+impl<T> Future for Operation<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Operation<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        f.debug_struct("Operation")
+            .field("finished", &state.result.is_some())
+            .finish()
+    }
+}
+
+/// An entry whose operations return futures.
+///
+/// Clones share the entry.
+#[derive(Debug, Clone)]
+pub struct AsyncEntry {
+    entry: Arc<Entry>,
+}
+
+impl From<Entry> for AsyncEntry {
+    fn from(entry: Entry) -> Self {
+        AsyncEntry::new(entry)
+    }
+}
+
+impl AsyncEntry {
+    /// Wrap an entry.
+    pub fn new(entry: Entry) -> Self {
+        AsyncEntry {
+            entry: Arc::new(entry),
+        }
+    }
+
+    /// The wrapped entry, for the operations that don't block.
+    pub fn entry(&self) -> &Entry {
+        &self.entry
+    }
+
+    /// Run an operation on the entry.
+    fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Entry) -> Result<T> + Send + 'static,
+    ) -> Operation<T> {
+        let entry = self.entry.clone();
+        Operation::spawn(move || f(&entry))
+    }
+
+    /// See [Entry::get_secret].
+    pub fn get_secret(&self) -> Operation<Vec<u8>> {
+        self.run(Entry::get_secret)
+    }
+
+    /// See [Entry::get_password].
+    pub fn get_password(&self) -> Operation<String> {
+        self.run(Entry::get_password)
+    }
+
+    /// See [Entry::set_secret].
+    pub fn set_secret(&self, secret: &[u8]) -> Operation<()> {
+        let secret = secret.to_vec();
+        self.run(move |entry| entry.set_secret(&secret))
+    }
+
+    /// See [Entry::set_password].
+    pub fn set_password(&self, password: &str) -> Operation<()> {
+        let password = password.to_string();
+        self.run(move |entry| entry.set_password(&password))
+    }
+
+    /// See [Entry::delete_credential].
+    pub fn delete_credential(&self) -> Operation<()> {
+        self.run(Entry::delete_credential)
+    }
+
+    /// See [Entry::get_credential].
+    pub fn get_credential(&self) -> Operation<AsyncEntry> {
+        self.run(|entry| entry.get_credential().map(AsyncEntry::new))
+    }
+}
+
+/// Search a store, as [search](Store::search) does.
+///
+/// Searches skip items that require user presence unless the spec asks for
+/// them, but even so a search of a large store can take a while.
+pub fn search(store: &Arc<Store>, spec: &HashMap<&str, &str>) -> Operation<Vec<AsyncEntry>> {
+    let store = store.clone();
+    let spec: HashMap<String, String> = spec
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Operation::spawn(move || {
+        let spec = spec
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let entries = store.search(&spec)?;
+        Ok(entries.into_iter().map(AsyncEntry::new).collect())
+    })
+}
@@ -0,0 +1,324 @@
+/*!
+
+# Protected data availability
+
+Items protected until the device is unlocked can't be read or written while
+it's locked. This module keeps track of whether they can be, so the store can
+run the operations handed to [retry_when_available](super::Store::retry_when_available)
+as soon as they can succeed.
+
+The state is found by looking up a marker item that is only readable while the
+device is unlocked (and is created, while it is, the first time it's needed).
+After that, the OS says when the state changes: on iOS, UIKit posts
+`UIApplicationProtectedDataDidBecomeAvailable` and
+`UIApplicationProtectedDataWillBecomeUnavailable` to the app's notification
+center, and on macOS, the system posts the distributed `com.apple.screenIsUnlocked`
+notification. The notifications are observed through Core Foundation by name,
+so this crate doesn't have to link UIKit. Both are delivered on the main thread,
+so a process whose main run loop isn't running (a command-line tool, say)
+never hears about an unlock, and its retries wait until they time out.
+
+All the waiting retries share one thread, which only wakes when a retry's
+timeout expires; the retries run, one at a time, on the
+[background](crate::background) thread.
+
+ */
+
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, Once, PoisonError};
+use std::time::{Duration, Instant};
+
+use core_foundation::base::TCFType;
+use core_foundation::string::{CFString, CFStringRef};
+use log::debug;
+use security_framework_sys::base::{errSecDuplicateItem, errSecItemNotFound};
+
+use keyring_core::{Error as ErrorCode, Result};
+
+use super::AccessPolicy;
+use crate::background;
+use crate::query::{Attr, Query as RawQuery};
+use crate::timeout::Timeout;
+
+/// The service and account of the marker item.
+const UNLOCK_MARKER: &str = "keyring-unlock-marker";
+
+/// `errSecInteractionNotAllowed`, which is how an item fails while the device is locked.
+const INTERACTION_NOT_ALLOWED: i32 = -25308;
+
+const UNKNOWN: u8 = 0;
+const AVAILABLE: u8 = 1;
+const UNAVAILABLE: u8 = 2;
+
+/// Whether protected data is available, as last found or announced.
+static STATE: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+/// Whether the service is the marker's, so searches can leave it out.
+pub(super) fn is_marker(service: &str) -> bool {
+    service == UNLOCK_MARKER
+}
+
+/// Whether protected data was unavailable when last found, or announced to be.
+///
+/// This doesn't look anything up, so errors can be decoded with it.
+pub(super) fn known_unavailable() -> bool {
+    STATE.load(Ordering::Acquire) == UNAVAILABLE
+}
+
+/// Find out whether protected data is available, by looking up the marker.
+///
+/// The marker is created if it's missing and `create` is set. This returns
+/// `None` if the state can't be found out (the marker is missing and can't be
+/// created, say), and otherwise records it.
+pub(super) fn check(create: bool) -> Option<bool> {
+    observe();
+    let available = probe(create)?;
+    STATE.store(
+        if available { AVAILABLE } else { UNAVAILABLE },
+        Ordering::Release,
+    );
+    Some(available)
+}
+
+fn probe(create: bool) -> Option<bool> {
+    let mut marker = RawQuery::generic_password();
+    marker
+        .data_protection()
+        .string(Attr::Service, UNLOCK_MARKER)
+        .string(Attr::Account, UNLOCK_MARKER)
+        .boolean(Attr::Synchronizable, false);
+    let mut query = marker.clone();
+    query.return_attributes();
+    match query.find() {
+        Ok(_) => return Some(true),
+        Err(err) if err.code() == INTERACTION_NOT_ALLOWED => return Some(false),
+        Err(err) if err.code() == errSecItemNotFound && create => {}
+        Err(err) => {
+            debug!("Couldn't look up the unlock marker: {err:?}");
+            return None;
+        }
+    }
+    let mut item = marker;
+    item.string(
+        Attr::Accessible,
+        AccessPolicy::WhenUnlocked.protection_class(),
+    )
+    .value(&[]);
+    match item.add() {
+        Ok(()) => Some(true),
+        Err(err) if err.code() == errSecDuplicateItem => Some(true),
+        Err(err) if err.code() == INTERACTION_NOT_ALLOWED => Some(false),
+        Err(err) => {
+            debug!("Couldn't create the unlock marker: {err:?}");
+            None
+        }
+    }
+}
+
+/// A retry waiting for protected data to become available.
+struct Waiting {
+    id: u64,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    /// Runs the operation (with `None`) or reports that it timed out.
+    finish: Box<dyn FnOnce(Option<Timeout>) + Send>,
+}
+
+static WAITING: Mutex<Vec<Waiting>> = Mutex::new(Vec::new());
+
+/// Wakes the timeout thread when a retry with a deadline is added.
+static DEADLINES: Condvar = Condvar::new();
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A retry handed to [retry_when_available](super::Store::retry_when_available)
+/// that may not have run yet.
+#[derive(Debug)]
+#[must_use = "dropping the handle doesn't cancel the retry"]
+pub struct PendingRetry {
+    id: u64,
+}
+
+impl PendingRetry {
+    /// Cancel the retry if it's still waiting, in which case its `done` is never
+    /// called. Returns whether it was still waiting.
+    pub fn cancel(&self) -> bool {
+        let mut waiting = WAITING.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = waiting.len();
+        waiting.retain(|w| w.id != self.id);
+        waiting.len() < before
+    }
+}
+
+/// Run an operation once protected data is available, giving up after the
+/// timeout (if any).
+pub(super) fn retry<T, F, C>(op: F, done: C, timeout: Option<Duration>) -> Result<PendingRetry>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+    C: FnOnce(Result<T>) + Send + 'static,
+{
+    if timeout.is_some() {
+        start_timeouts()?;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    WAITING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Waiting {
+            id,
+            timeout,
+            // a timeout too long to represent never expires
+            deadline: timeout.and_then(|timeout| Instant::now().checked_add(timeout)),
+            finish: Box::new(move |expired| match expired {
+                None => background::submit_then(op, done),
+                Some(expired) => done(Err(ErrorCode::NoStorageAccess(Box::new(expired)))),
+            }),
+        });
+    DEADLINES.notify_all();
+    // it's checked after the retry is waiting, so an unlock in between isn't missed;
+    // if the state can't be found out, the operation gets to try
+    if check(true) != Some(false) {
+        run_waiting();
+    }
+    Ok(PendingRetry { id })
+}
+
+/// Hand every waiting retry to the background thread.
+fn run_waiting() {
+    let waiting = std::mem::take(&mut *WAITING.lock().unwrap_or_else(PoisonError::into_inner));
+    for retry in waiting {
+        (retry.finish)(None);
+    }
+}
+
+/// Start the thread that times out waiting retries, if it isn't running.
+fn start_timeouts() -> Result<()> {
+    static STARTED: Mutex<bool> = Mutex::new(false);
+    let mut started = STARTED.lock().unwrap_or_else(PoisonError::into_inner);
+    if *started {
+        return Ok(());
+    }
+    std::thread::Builder::new()
+        .name("keyring-retry".to_string())
+        .spawn(expire_timeouts)
+        .map_err(|err| ErrorCode::PlatformFailure(Box::new(err)))?;
+    *started = true;
+    Ok(())
+}
+
+fn expire_timeouts() {
+    let mut waiting = WAITING.lock().unwrap_or_else(PoisonError::into_inner);
+    loop {
+        let now = Instant::now();
+        let (expired, still): (Vec<Waiting>, Vec<Waiting>) = std::mem::take(&mut *waiting)
+            .into_iter()
+            .partition(|w| w.deadline.is_some_and(|deadline| deadline <= now));
+        *waiting = still;
+        if !expired.is_empty() {
+            drop(waiting);
+            for retry in expired {
+                (retry.finish)(Some(Timeout {
+                    operation: "retry_when_available",
+                    timeout: retry.timeout.unwrap_or_default(),
+                }));
+            }
+            waiting = WAITING.lock().unwrap_or_else(PoisonError::into_inner);
+            continue;
+        }
+        waiting = match waiting.iter().filter_map(|w| w.deadline).min() {
+            Some(deadline) => {
+                DEADLINES
+                    .wait_timeout(waiting, deadline.saturating_duration_since(now))
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            }
+            None => DEADLINES
+                .wait(waiting)
+                .unwrap_or_else(PoisonError::into_inner),
+        };
+    }
+}
+
+#[cfg(target_os = "ios")]
+const BECAME_AVAILABLE: &str = "UIApplicationProtectedDataDidBecomeAvailable";
+#[cfg(target_os = "ios")]
+const BECOMING_UNAVAILABLE: &str = "UIApplicationProtectedDataWillBecomeUnavailable";
+#[cfg(target_os = "macos")]
+const BECAME_AVAILABLE: &str = "com.apple.screenIsUnlocked";
+
+#[cfg(target_os = "ios")]
+const OBSERVED: &[&str] = &[BECAME_AVAILABLE, BECOMING_UNAVAILABLE];
+#[cfg(target_os = "macos")]
+const OBSERVED: &[&str] = &[BECAME_AVAILABLE];
+
+/// Start observing the OS's notifications, if that hasn't been done.
+fn observe() {
+    static OBSERVING: Once = Once::new();
+    OBSERVING.call_once(|| {
+        #[cfg(target_os = "ios")]
+        // SAFETY: the local center always exists.
+        let center = unsafe { CFNotificationCenterGetLocalCenter() };
+        #[cfg(target_os = "macos")]
+        // SAFETY: the distributed center always exists.
+        let center = unsafe { CFNotificationCenterGetDistributedCenter() };
+        let observer = std::ptr::from_ref(&STATE).cast::<c_void>();
+        for name in OBSERVED {
+            let name = CFString::from_static_string(name);
+            // SAFETY: the center and name are valid, the observer is a static that
+            // outlives the observation, and the callback has the signature CF expects.
+            unsafe {
+                CFNotificationCenterAddObserver(
+                    center,
+                    observer,
+                    notified,
+                    name.as_concrete_TypeRef(),
+                    std::ptr::null(),
+                    DELIVER_IMMEDIATELY,
+                )
+            };
+        }
+    });
+}
+
+extern "C" fn notified(
+    _center: *mut c_void,
+    _observer: *mut c_void,
+    name: CFStringRef,
+    _object: *const c_void,
+    _user_info: *const c_void,
+) {
+    // SAFETY: CF passes the name of the notification, which it owns.
+    let name = unsafe { CFString::wrap_under_get_rule(name) }.to_string();
+    debug!("Notified of {name}");
+    if name == BECAME_AVAILABLE {
+        STATE.store(AVAILABLE, Ordering::Release);
+        // this runs on the main thread, so the retries only get handed over
+        run_waiting();
+    } else {
+        STATE.store(UNAVAILABLE, Ordering::Release);
+    }
+}
+
+/// `CFNotificationSuspensionBehaviorDeliverImmediately`
+const DELIVER_IMMEDIATELY: isize = 4;
+
+type NotificationCallback =
+    extern "C" fn(*mut c_void, *mut c_void, CFStringRef, *const c_void, *const c_void);
+
+#[link(name = "CoreFoundation", kind = "framework")]
+unsafe extern "C" {
+    #[cfg(target_os = "ios")]
+    fn CFNotificationCenterGetLocalCenter() -> *mut c_void;
+    #[cfg(target_os = "macos")]
+    fn CFNotificationCenterGetDistributedCenter() -> *mut c_void;
+    fn CFNotificationCenterAddObserver(
+        center: *mut c_void,
+        observer: *const c_void,
+        callback: NotificationCallback,
+        name: CFStringRef,
+        object: *const c_void,
+        suspension_behavior: isize,
+    );
+}
@@ -0,0 +1,306 @@
+/*!
+
+# Entitlement diagnostics
+
+The protected data store only works for a process signed with the right
+entitlements, and when one is missing the OS just says that "A required
+entitlement isn't present". [diagnostics] reads the entitlements the running
+process is actually signed with (provisioning profile and all) and reports
+which of them a store configuration needs but doesn't have.
+
+[entitled_access_groups] lists the access groups the process can keep items
+in, and creating a store with an `access-group` that isn't one of them fails
+right away, rather than at the first write.
+
+It can only tell whether an entitlement is there, not whether its value is
+one the OS will accept for the app's team: that's decided when an item is
+written. So a report without findings makes a configuration likely to work,
+not certain to.
+
+ */
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+
+use core_foundation::array::CFArray;
+use core_foundation::base::{CFAllocatorRef, CFType, CFTypeRef, TCFType, kCFAllocatorDefault};
+use core_foundation::boolean::CFBoolean;
+use core_foundation::error::CFErrorRef;
+use core_foundation::string::{CFString, CFStringRef};
+
+use super::CONFIGURATION_KEYS;
+use keyring_core::{Error as ErrorCode, Result};
+
+use crate::validate::{Finding, check_keys};
+
+type SecTaskRef = *const c_void;
+
+#[link(name = "Security", kind = "framework")]
+unsafe extern "C" {
+    fn SecTaskCreateFromSelf(allocator: CFAllocatorRef) -> SecTaskRef;
+    fn SecTaskCopyValueForEntitlement(
+        task: SecTaskRef,
+        entitlement: CFStringRef,
+        error: *mut CFErrorRef,
+    ) -> CFTypeRef;
+    fn SecTaskCopySigningIdentifier(task: SecTaskRef, error: *mut CFErrorRef) -> CFStringRef;
+    fn SecTaskGetCodeSignStatus(task: SecTaskRef) -> u32;
+}
+
+/// The code-signing status flag for a valid signature.
+const CS_VALID: u32 = 0x1;
+
+/// The access group of items kept by tokens (such as smart cards), which
+/// every process can read.
+pub const TOKEN_GROUP: &str = "com.apple.token";
+
+/// What [diagnostics] found out about the running process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntitlementReport {
+    /// Whether the process has a valid code signature.
+    pub code_signed: bool,
+    /// The identifier the process is signed with, if it's signed.
+    pub signing_identifier: Option<String>,
+    /// The application identifier (App ID prefix and bundle ID) the process is
+    /// entitled to, whose access group is the default for new items.
+    pub application_identifier: Option<String>,
+    /// The groups listed by the process's `keychain-access-groups` entitlement.
+    pub keychain_access_groups: Vec<String>,
+    /// All the access groups the process is entitled to, as listed by
+    /// [entitled_access_groups].
+    pub access_groups: Vec<String>,
+    /// Whether the process has an iCloud entitlement.
+    pub icloud: bool,
+    /// What the configuration needs that the process doesn't have, along with
+    /// any problems with the configuration itself.
+    pub findings: Vec<Finding>,
+}
+
+impl EntitlementReport {
+    /// Whether the process is entitled to the given access group.
+    ///
+    /// An entitlement ending in `*` covers every group that starts with the
+    /// rest of it.
+    pub fn allows_access_group(&self, group: &str) -> bool {
+        allows(&self.access_groups, group)
+    }
+}
+
+/// The access groups the running process is entitled to keep items in.
+///
+/// These are, in order:
+/// - the App ID group, from the `application-identifier` entitlement;
+/// - the groups listed by the `keychain-access-groups` entitlement;
+/// - the app groups listed by the `com.apple.security.application-groups`
+///   entitlement;
+/// - the [token group](TOKEN_GROUP).
+///
+/// A group may end with `*`, standing for every group that starts with the
+/// rest of it. A process with no entitlements gets an empty list, not just the
+/// token group, since it can't keep items anywhere.
+pub fn entitled_access_groups() -> Vec<String> {
+    Task::current()
+        .map(|task| task.access_groups())
+        .unwrap_or_default()
+}
+
+/// Check that the running process is entitled to the given access group.
+///
+/// This is used when a store is created. A process whose entitlements can't be
+/// read passes the check, and the OS reports the problem when it's used.
+pub(super) fn check_access_group(group: &str) -> Result<()> {
+    let groups = entitled_access_groups();
+    if groups.is_empty() || allows(&groups, group) {
+        return Ok(());
+    }
+    Err(ErrorCode::Invalid(
+        "access-group".to_string(),
+        format!(
+            "the process isn't entitled to access group {group}; its groups are: {}",
+            groups.join(", ")
+        ),
+    ))
+}
+
+/// Whether the given group is one of (or is matched by a wildcard in) the groups.
+fn allows(groups: &[String], group: &str) -> bool {
+    groups
+        .iter()
+        .any(|allowed| match allowed.strip_suffix('*') {
+            Some(prefix) => group.starts_with(prefix),
+            None => allowed == group,
+        })
+}
+
+/// Inspect the running process's code signature and entitlements, and
+/// report what it's missing for a protected store with the given configuration.
+///
+/// The findings are errors for entitlements the configuration needs and the
+/// process doesn't have, such as the access group named by `access-group`
+/// or, for `cloud-sync`, an iCloud entitlement. Problems with the
+/// configuration are reported as
+/// [validate_configuration](crate::validate::validate_configuration) would.
+pub fn diagnostics(config: &HashMap<&str, &str>) -> EntitlementReport {
+    let mut findings = Vec::new();
+    let config = check_keys(CONFIGURATION_KEYS, config, &mut findings);
+    let task = Task::current();
+    let application_identifier = task.as_ref().and_then(Task::application_identifier);
+    let keychain_access_groups = task
+        .as_ref()
+        .map(|task| task.strings_entitlement("keychain-access-groups"))
+        .unwrap_or_default();
+    let access_groups = task.as_ref().map(Task::access_groups).unwrap_or_default();
+    let icloud = task.as_ref().is_some_and(|task| {
+        [
+            "com.apple.developer.icloud-services",
+            "com.apple.developer.icloud-container-identifiers",
+            "com.apple.developer.ubiquity-kvstore-identifier",
+        ]
+        .iter()
+        .any(|name| task.has_entitlement(name))
+    });
+    let mut report = EntitlementReport {
+        code_signed: task.as_ref().is_some_and(Task::code_signed),
+        signing_identifier: task.as_ref().and_then(Task::signing_identifier),
+        application_identifier,
+        keychain_access_groups,
+        access_groups,
+        icloud,
+        findings,
+    };
+    if !report.code_signed {
+        report.findings.push(Finding::error(
+            None,
+            "the process isn't validly code-signed, so it has no entitlements",
+        ));
+    }
+    if report.access_groups.is_empty() {
+        report.findings.push(Finding::error(
+            None,
+            "the process has neither an application-identifier nor a \
+             keychain-access-groups entitlement, so it has no access group to keep items in",
+        ));
+    }
+    if let Some(group) = config.get("access-group").filter(|g| !g.is_empty()) {
+        if !report.allows_access_group(group) {
+            report.findings.push(Finding::error(
+                Some("access-group"),
+                format!("the process isn't entitled to access group {group}"),
+            ));
+        }
+    }
+    if config.get("default-group").is_some_and(|g| g.eq("app-id"))
+        && report.application_identifier.is_none()
+    {
+        report.findings.push(Finding::error(
+            Some("default-group"),
+            "the process has no application-identifier entitlement",
+        ));
+    }
+    if config.get("cloud-sync").is_some_and(|s| s.eq("true")) && !report.icloud {
+        report.findings.push(Finding::error(
+            Some("cloud-sync"),
+            "the process has no iCloud entitlement; enable the iCloud capability \
+             in its provisioning profile",
+        ));
+    }
+    report
+}
+
+/// The running process, as the Security framework sees it.
+struct Task(CFType);
+
+impl Task {
+    fn current() -> Option<Task> {
+        // SAFETY: the default allocator is always valid.
+        let task = unsafe { SecTaskCreateFromSelf(kCFAllocatorDefault) };
+        if task.is_null() {
+            return None;
+        }
+        // SAFETY: the task was just created, so we own it.
+        Some(Task(unsafe { CFType::wrap_under_create_rule(task) }))
+    }
+
+    fn application_identifier(&self) -> Option<String> {
+        self.string_entitlement("application-identifier")
+            .or_else(|| self.string_entitlement("com.apple.application-identifier"))
+    }
+
+    /// The work of [entitled_access_groups].
+    fn access_groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = self.application_identifier().into_iter().collect();
+        for group in self
+            .strings_entitlement("keychain-access-groups")
+            .into_iter()
+            .chain(self.strings_entitlement("com.apple.security.application-groups"))
+        {
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+        if !groups.is_empty() {
+            groups.push(TOKEN_GROUP.to_string());
+        }
+        groups
+    }
+
+    fn code_signed(&self) -> bool {
+        // SAFETY: the task is valid.
+        unsafe { SecTaskGetCodeSignStatus(self.0.as_CFTypeRef()) & CS_VALID != 0 }
+    }
+
+    fn signing_identifier(&self) -> Option<String> {
+        // SAFETY: the task is valid, and a null error pointer is allowed.
+        let identifier =
+            unsafe { SecTaskCopySigningIdentifier(self.0.as_CFTypeRef(), std::ptr::null_mut()) };
+        if identifier.is_null() {
+            return None;
+        }
+        // SAFETY: the identifier was copied for us, so we own it.
+        Some(unsafe { CFString::wrap_under_create_rule(identifier) }.to_string())
+    }
+
+    /// The value of an entitlement, if the process has it.
+    fn entitlement(&self, name: &str) -> Option<CFType> {
+        let name = CFString::new(name);
+        // SAFETY: the task and name are valid, and a null error pointer is allowed.
+        let value = unsafe {
+            SecTaskCopyValueForEntitlement(
+                self.0.as_CFTypeRef(),
+                name.as_concrete_TypeRef(),
+                std::ptr::null_mut(),
+            )
+        };
+        if value.is_null() {
+            return None;
+        }
+        // SAFETY: the value was copied for us, so we own it.
+        Some(unsafe { CFType::wrap_under_create_rule(value) })
+    }
+
+    fn has_entitlement(&self, name: &str) -> bool {
+        match self.entitlement(name) {
+            Some(value) => value.downcast::<CFBoolean>().is_none_or(bool::from),
+            None => false,
+        }
+    }
+
+    fn string_entitlement(&self, name: &str) -> Option<String> {
+        Some(self.entitlement(name)?.downcast::<CFString>()?.to_string())
+    }
+
+    fn strings_entitlement(&self, name: &str) -> Vec<String> {
+        let Some(array) = self
+            .entitlement(name)
+            .and_then(|value| value.downcast::<CFArray>())
+        else {
+            return Vec::new();
+        };
+        array
+            .iter()
+            // SAFETY: the array holds CF objects, which it keeps alive.
+            .map(|value| unsafe { CFType::wrap_under_get_rule(*value) })
+            .filter_map(|value| value.downcast::<CFString>().map(|s| s.to_string()))
+            .collect()
+    }
+}
@@ -0,0 +1,296 @@
+/*!
+
+# Envelope encryption
+
+Keychain items are meant for small secrets: large ones make every read and
+write slow, and sync poorly. In a store configured with an `envelope-key`,
+an entry built with the `envelope` modifier encrypts its secret in the app
+and keeps only the ciphertext in the store. It's still an ordinary [Entry]:
+`set_secret`, `get_secret`, and `delete_credential` take care of the rest.
+
+The secret is encrypted with ECIES (an ephemeral key agreement, X9.63 key
+derivation with SHA-256, and AES-GCM) to a per-app P-256 key, the
+_data-encryption key_. That's the [key item](super::keys) tagged with the
+store's `envelope-key`, and it's generated the first time an envelope entry's
+secret is set. If the store's `envelope-enclave` is true, it's generated in
+the Secure Enclave, so it can't leave the device (or be synchronized, so such
+a store can't be cloud-synchronized).
+
+Small ciphertexts are kept in the entry's item. Larger ones are split into
+chunks of [CHUNK_SIZE] bytes, each kept in an item of its own, which searches
+leave out, laid out the way the keychain store keeps its large secrets. The
+chunks get the entry's access policy, so the ciphertext of a secret that
+requires user presence can't be read without the user authenticating,
+whatever size it is. (The key gets the default policy, since it's shared by
+every envelope entry of the store: on its own, it decrypts nothing.) Reading
+or writing such a secret reads or writes all of its items in one
+authentication context, so the user is asked once, not once per chunk.
+
+Entries found by searching are plain credentials, whose secrets are envelope
+headers; [Store::envelope_entry] turns one back into an envelope entry.
+
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::info;
+use security_framework::key::{Algorithm, GenerateKeyOptions, KeyType, SecKey, Token};
+
+use keyring_core::api::{Credential, CredentialApi};
+use keyring_core::{Entry, Error as ErrorCode, Result};
+
+use super::{AuthenticationUi, Cred, KeyItem, Store};
+use crate::chunks::{self, Layout};
+use crate::query::AuthenticationContext;
+use crate::timeout;
+
+pub use crate::chunks::CHUNK_SIZE;
+
+/// The prefix on the service of chunk items.
+pub(super) const ENVELOPE_TAG: &str = "keyring-envelope:";
+
+/// How envelope ciphertexts are chunked: the entry's item holds an envelope
+/// header, which is a chunk index.
+const LAYOUT: Layout = Layout {
+    magic: b"keyring-envelope/1",
+    tag: ENVELOPE_TAG,
+};
+
+/// The encryption used for envelope secrets.
+const ALGORITHM: Algorithm = Algorithm::ECIESEncryptionCofactorVariableIVX963SHA256AESGCM;
+
+/// An entry whose secret is encrypted with the store's data-encryption key.
+///
+/// See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct EnvelopeCred {
+    /// The credential whose item holds the envelope header.
+    pub cred: Cred,
+    /// The data-encryption key.
+    pub key: KeyItem,
+    /// Whether a data-encryption key that has to be generated is generated
+    /// in the Secure Enclave.
+    pub enclave: bool,
+}
+
+impl Store {
+    /// An envelope entry for a credential of this store, such as one found by
+    /// searching.
+    ///
+    /// This fails if the store has no `envelope-key`.
+    pub fn envelope_entry(&self, cred: &Cred) -> Result<Entry> {
+        let envelope = self.envelope_cred(cred.clone(), "envelope_entry")?;
+        Ok(Entry::new_with_credential(Arc::new(envelope)))
+    }
+
+    /// The envelope credential for the given (header) credential.
+    pub(super) fn envelope_cred(&self, cred: Cred, key: &str) -> Result<EnvelopeCred> {
+        let Some(tag) = &self.envelope_key else {
+            return Err(ErrorCode::Invalid(
+                key.to_string(),
+                "the store has no envelope-key".to_string(),
+            ));
+        };
+        Ok(EnvelopeCred {
+            cred,
+            key: self.key_item(tag.as_bytes(), None)?,
+            enclave: self.envelope_enclave,
+        })
+    }
+}
+
+impl EnvelopeCred {
+    /// The credential for the chunk item with the given index.
+    fn chunk(&self, index: u32) -> Cred {
+        let mut chunk = self.cred.clone();
+        (chunk.service, chunk.account) = LAYOUT.chunk_specifiers(
+            self.cred.stored_service(),
+            self.cred.stored_account(),
+            index,
+        );
+        chunk.stored_specifiers = None;
+        chunk.display_user = None;
+        chunk.tag = None;
+        chunk.label_template = None;
+        chunk.skip_unchanged = false;
+        chunk.leave_tombstone = false;
+        chunk
+    }
+
+    /// The authentication context shared by the items of an operation, if
+    /// they require user presence and the user may be asked for it.
+    fn shared_context(&self) -> Option<AuthenticationContext> {
+        (self.cred.access_policy.requires_user_presence()
+            && self.cred.authentication_ui == AuthenticationUi::Allow)
+            .then(|| AuthenticationContext::interactive(self.cred.user_presence_within))
+    }
+
+    /// Write the secret of one of the envelope's items.
+    fn write_item(
+        cred: &Cred,
+        secret: &[u8],
+        context: Option<&AuthenticationContext>,
+    ) -> Result<()> {
+        match context {
+            Some(context) => cred.write_secret_in(secret, context),
+            None => cred.write_secret(secret),
+        }
+    }
+
+    /// Read the secret of one of the envelope's items.
+    fn read_item(cred: &Cred, context: Option<&AuthenticationContext>) -> Result<Vec<u8>> {
+        match context {
+            Some(context) => cred.read_secret_in(context),
+            None => cred.read_secret(),
+        }
+    }
+
+    /// The data-encryption key, generated (and stored) if there isn't one.
+    fn find_or_generate_key(&self) -> Result<SecKey> {
+        self.key.find_or_add(|| {
+            let mut options = GenerateKeyOptions::default();
+            options
+                .set_key_type(KeyType::ec_sec_prime_random())
+                .set_size_in_bits(256);
+            if self.enclave {
+                options.set_token(Token::SecureEnclave);
+            }
+            SecKey::new(&options).map_err(|err| {
+                ErrorCode::PlatformFailure(
+                    format!("can't generate the data-encryption key: {err}").into(),
+                )
+            })
+        })
+    }
+
+    /// The work of [set_secret](CredentialApi::set_secret).
+    fn write_envelope(&self, secret: &[u8]) -> Result<()> {
+        let key = self.find_or_generate_key()?;
+        let public_key = key.public_key().ok_or_else(|| {
+            ErrorCode::PlatformFailure("the data-encryption key has no public key".into())
+        })?;
+        let ciphertext = public_key
+            .encrypt_data(ALGORITHM, secret)
+            .map_err(|err| ErrorCode::PlatformFailure(format!("can't encrypt: {err}").into()))?;
+        let (header, chunks) = LAYOUT.split(&ciphertext);
+        let count = chunks.len() as u32;
+        let context = self.shared_context();
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            Self::write_item(&self.chunk(index as u32), chunk, context.as_ref())?;
+        }
+        Self::write_item(&self.cred, &header, context.as_ref())?;
+        if self.cred.dry_run {
+            info!(
+                "Dry run: would keep {count} chunk(s) for service {}, user {}",
+                self.cred.service, self.cred.account
+            );
+            return Ok(());
+        }
+        self.delete_chunks_from(count)
+    }
+
+    /// The work of [get_secret](CredentialApi::get_secret).
+    fn read_envelope(&self) -> Result<Vec<u8>> {
+        let context = self.shared_context();
+        let header = Self::read_item(&self.cred, context.as_ref())?;
+        let ciphertext = LAYOUT.join(header, |index| {
+            Self::read_item(&self.chunk(index), context.as_ref())
+        })?;
+        let key = self.key.find_key()?;
+        key.decrypt_data(ALGORITHM, &ciphertext).map_err(|err| {
+            ErrorCode::BadDataFormat(ciphertext, format!("can't decrypt: {err}").into())
+        })
+    }
+
+    /// The work of [delete_credential](CredentialApi::delete_credential).
+    fn delete_envelope(&self) -> Result<()> {
+        self.cred.delete_item()?;
+        if self.cred.dry_run {
+            return Ok(());
+        }
+        self.delete_chunks_from(0)
+    }
+
+    /// Delete the chunk items from the given index on.
+    fn delete_chunks_from(&self, first: u32) -> Result<()> {
+        chunks::delete_from(first, |index| self.chunk(index).delete_item())
+    }
+
+    /// The work of [get_credential](CredentialApi::get_credential).
+    fn find_envelope(&self) -> Result<Option<Arc<Credential>>> {
+        let Some(wrapper) = self.cred.find_item()? else {
+            return Ok(None);
+        };
+        let Some(cred) = wrapper.as_any().downcast_ref::<Cred>() else {
+            return Ok(Some(wrapper));
+        };
+        let mut envelope = self.clone();
+        envelope.cred = cred.clone();
+        Ok(Some(Arc::new(envelope)))
+    }
+}
+
+impl CredentialApi for EnvelopeCred {
+    /// See the keychain-core API docs.
+    ///
+    /// The secret is encrypted, and the chunks it needs are written before the
+    /// header that lists them. Chunks left over from a longer secret are deleted.
+    fn set_secret(&self, secret: &[u8]) -> Result<()> {
+        let secret = secret.to_vec();
+        timeout::run("set_secret", self.clone(), move |envelope| {
+            envelope.write_envelope(&secret)
+        })
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// Ciphertext that can't be decrypted (because the data-encryption key
+    /// was replaced, say) is a `BadDataFormat` error holding the ciphertext.
+    fn get_secret(&self) -> Result<Vec<u8>> {
+        timeout::run("get_secret", self.clone(), EnvelopeCred::read_envelope)
+    }
+
+    /// See the keychain-core API docs.
+    fn delete_credential(&self) -> Result<()> {
+        timeout::run(
+            "delete_credential",
+            self.clone(),
+            EnvelopeCred::delete_envelope,
+        )
+    }
+
+    /// See the keychain-core API docs.
+    fn get_credential(&self) -> Result<Option<Arc<Credential>>> {
+        timeout::run("get_credential", self.clone(), EnvelopeCred::find_envelope)
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// These are the attributes of the item holding the envelope header.
+    fn get_attributes(&self) -> Result<HashMap<String, String>> {
+        self.cred.get_attributes()
+    }
+
+    /// See the keychain-core API docs.
+    ///
+    /// These are set on the item holding the envelope header.
+    fn update_attributes(&self, attrs: &HashMap<&str, &str>) -> Result<()> {
+        self.cred.update_attributes(attrs)
+    }
+
+    /// See the keychain-core API docs.
+    fn get_specifiers(&self) -> Option<(String, String)> {
+        self.cred.get_specifiers()
+    }
+
+    /// See the keychain-core API docs.
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    /// See the keychain-core API docs.
+    fn debug_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
@@ -0,0 +1,289 @@
+/*!
+
+# Key items
+
+Besides passwords, the protected data store holds cryptographic keys. A
+[KeyItem] is a key identified by its _application tag_, an app-chosen byte
+string (such as `com.example.app.signing`), kept in the same access groups
+and with the same access policies as the store's passwords. Get one from
+[Store::key_item](super::Store::key_item).
+
+Keys are stored and returned as the security-framework crate's `SecKey`, so
+they can be used for signing and encryption without their bytes ever
+leaving the keychain. Keys can also be imported from (and exported to) their
+external representation: PKCS #1 for RSA keys, and ANSI X9.63 for elliptic
+curve keys. Keys the OS won't export (such as keys generated in the Secure
+Enclave) can still be stored and used.
+
+An item holds one key at a time. The OS tells keys apart by their
+_application label_ (normally a hash of the public key), so a stored key's
+application label is set to its tag, which makes storing a second key under
+the same tag replace the first rather than add another item.
+
+ */
+
+use std::collections::HashMap;
+
+use core_foundation::base::TCFType;
+use core_foundation::data::CFData;
+use core_foundation::dictionary::CFDictionary;
+use core_foundation::error::CFErrorRef;
+use core_foundation::string::CFString;
+use log::info;
+use security_framework::key::SecKey;
+use security_framework_sys::base::errSecDuplicateItem;
+use security_framework_sys::item::{
+    kSecAttrKeyClass, kSecAttrKeyClassPrivate, kSecAttrKeyClassPublic, kSecAttrKeyType,
+    kSecAttrKeyTypeECSECPrimeRandom, kSecAttrKeyTypeRSA,
+};
+use security_framework_sys::key::SecKeyCreateWithData;
+
+use keyring_core::{Error as ErrorCode, Result};
+
+use super::{AccessPolicy, Store, access_control_for, decode_error, determine_access_policy};
+use crate::query::{Attr, Query as RawQuery};
+use crate::timeout;
+
+/// The kinds of key that can be imported from an external representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    RsaPrivate,
+    RsaPublic,
+    EcPrivate,
+    EcPublic,
+}
+
+/// A cryptographic key item, identified by its application tag.
+///
+/// See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct KeyItem {
+    /// The application tag that identifies the item.
+    pub tag: Vec<u8>,
+    /// If set, the access group the item is in.
+    pub access_group: Option<String>,
+    /// The access policy given to the item when the key is stored.
+    pub access_policy: AccessPolicy,
+    pub cloud_synchronize: bool,
+    /// If set, the label given to the item when the key is stored.
+    pub label: Option<String>,
+    dry_run: bool,
+}
+
+/// The modifiers allowed by [key_item](Store::key_item).
+const KEY_MODIFIER_KEYS: &[&str] = &["access-policy", "+access-group", "+label"];
+
+impl Store {
+    /// The key item with the given application tag.
+    ///
+    /// The item is in the store's access group (and is cloud-synchronized if
+    /// the store is) unless the modifiers say otherwise. The modifiers are
+    /// `access-policy` and `access-group`, which work as they do for
+    /// [build](Store::build), and `label`, which is shown for the item in
+    /// Keychain Access. Creating a key item doesn't put anything in the store.
+    pub fn key_item(&self, tag: &[u8], modifiers: Option<&HashMap<&str, &str>>) -> Result<KeyItem> {
+        if tag.is_empty() {
+            return Err(ErrorCode::Invalid(
+                "tag".to_string(),
+                "cannot be empty".to_string(),
+            ));
+        }
+        let mods = keyring_core::attributes::parse_attributes(KEY_MODIFIER_KEYS, modifiers)?;
+        if self.cloud_synchronize && mods.contains_key("access-policy") {
+            return Err(ErrorCode::Invalid(
+                "access-policy".to_string(),
+                "cannot be specified in a cloud-synchronized store".to_string(),
+            ));
+        }
+        Ok(KeyItem {
+            tag: tag.to_vec(),
+            access_group: mods
+                .get("access-group")
+                .cloned()
+                .or_else(|| self.access_group.clone()),
+            access_policy: determine_access_policy(&mods)?,
+            cloud_synchronize: self.cloud_synchronize,
+            label: mods.get("label").cloned(),
+            dry_run: self.dry_run,
+        })
+    }
+}
+
+impl KeyItem {
+    /// Store a key in the item, replacing any key it held.
+    pub fn store(&self, key: &SecKey) -> Result<()> {
+        let key = key.clone();
+        timeout::run("store_key", self.clone(), move |item| item.add_key(&key))
+    }
+
+    /// Import a key from its external representation and store it in the item,
+    /// returning the key.
+    pub fn import(&self, kind: KeyKind, data: &[u8]) -> Result<SecKey> {
+        let key = key_from_data(kind, data)?;
+        self.store(&key)?;
+        Ok(key)
+    }
+
+    /// The key held by the item.
+    ///
+    /// Keys whose access policy requires user presence are returned without
+    /// prompting; the user is asked to authenticate when the key is used.
+    pub fn get(&self) -> Result<SecKey> {
+        timeout::run("get_key", self.clone(), KeyItem::find_key)
+    }
+
+    /// The external representation of the key held by the item.
+    ///
+    /// This fails with `NotSupportedByStore` for keys the OS won't export.
+    pub fn export(&self) -> Result<Vec<u8>> {
+        match self.get()?.external_representation() {
+            Some(data) => Ok(data.bytes().to_vec()),
+            None => Err(ErrorCode::NotSupportedByStore(
+                "the key can't be exported".to_string(),
+            )),
+        }
+    }
+
+    /// Delete the item.
+    pub fn delete(&self) -> Result<()> {
+        timeout::run("delete_key", self.clone(), |item| {
+            if item.dry_run {
+                item.find_key()?;
+                info!("Dry run: would delete the key item {:?}", item.tag_string());
+                return Ok(());
+            }
+            item.query().delete().map_err(decode_error)
+        })
+    }
+
+    /// A query for the item.
+    fn query(&self) -> RawQuery {
+        let mut query = RawQuery::key();
+        query
+            .data_protection()
+            .data(Attr::ApplicationTag, &self.tag)
+            .boolean(Attr::Synchronizable, self.cloud_synchronize);
+        if let Some(access_group) = &self.access_group {
+            query.string(Attr::AccessGroup, access_group);
+        }
+        query
+    }
+
+    /// The work of [get](KeyItem::get).
+    pub(super) fn find_key(&self) -> Result<SecKey> {
+        let found = self.query().find_ref().map_err(decode_error)?;
+        found
+            .downcast::<SecKey>()
+            .ok_or_else(|| ErrorCode::PlatformFailure("the key item didn't hold a key".into()))
+    }
+
+    /// The key held by the item, storing the one made by `make` if it holds none.
+    ///
+    /// Unlike [store](KeyItem::store), this never replaces a key: if another
+    /// process stores one first, that's the one returned.
+    pub(super) fn find_or_add(&self, make: impl FnOnce() -> Result<SecKey>) -> Result<SecKey> {
+        match self.find_key() {
+            Err(ErrorCode::NoEntry) => {}
+            found => return found,
+        }
+        let key = make()?;
+        let item = self.new_item(&key)?;
+        if self.dry_run {
+            info!(
+                "Dry run: would store a new key in the key item {:?}",
+                self.tag_string()
+            );
+            return Ok(key);
+        }
+        match item.add() {
+            Ok(()) => Ok(key),
+            Err(err) if err.code() == errSecDuplicateItem => self.find_key(),
+            Err(err) => Err(decode_error(err)),
+        }
+    }
+
+    /// The query that adds the given key as the item.
+    fn new_item(&self, key: &SecKey) -> Result<RawQuery> {
+        let mut item = self.query();
+        item.value_ref(key).data(Attr::ApplicationLabel, &self.tag);
+        if let Some(label) = &self.label {
+            item.string(Attr::Label, label);
+        }
+        if let Some(access_control) =
+            access_control_for(&self.access_policy, self.cloud_synchronize)?
+        {
+            item.access_control(access_control);
+        }
+        Ok(item)
+    }
+
+    /// The work of [store](KeyItem::store).
+    fn add_key(&self, key: &SecKey) -> Result<()> {
+        let item = self.new_item(key)?;
+        if self.dry_run {
+            info!(
+                "Dry run: would store a key in the key item {:?}",
+                self.tag_string()
+            );
+            return Ok(());
+        }
+        match item.add() {
+            Err(err) if err.code() == errSecDuplicateItem => {
+                self.query().delete().map_err(decode_error)?;
+                item.add().map_err(decode_error)
+            }
+            result => result.map_err(decode_error),
+        }
+    }
+
+    /// The tag, for logging.
+    fn tag_string(&self) -> String {
+        String::from_utf8_lossy(&self.tag).into_owned()
+    }
+}
+
+/// A key made from its external representation.
+fn key_from_data(kind: KeyKind, data: &[u8]) -> Result<SecKey> {
+    // SAFETY: these are immutable constants exported by the Security framework.
+    let (key_type, key_class) = unsafe {
+        match kind {
+            KeyKind::RsaPrivate => (kSecAttrKeyTypeRSA, kSecAttrKeyClassPrivate),
+            KeyKind::RsaPublic => (kSecAttrKeyTypeRSA, kSecAttrKeyClassPublic),
+            KeyKind::EcPrivate => (kSecAttrKeyTypeECSECPrimeRandom, kSecAttrKeyClassPrivate),
+            KeyKind::EcPublic => (kSecAttrKeyTypeECSECPrimeRandom, kSecAttrKeyClassPublic),
+        }
+    };
+    // SAFETY: as above, and the values are constants too.
+    let attributes = unsafe {
+        CFDictionary::from_CFType_pairs(&[
+            (
+                CFString::wrap_under_get_rule(kSecAttrKeyType),
+                CFString::wrap_under_get_rule(key_type),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrKeyClass),
+                CFString::wrap_under_get_rule(key_class),
+            ),
+        ])
+    };
+    let data = CFData::from_buffer(data);
+    let mut error: CFErrorRef = std::ptr::null_mut();
+    // SAFETY: the data and attributes are valid, and the error is a valid place to write.
+    let key = unsafe {
+        SecKeyCreateWithData(
+            data.as_concrete_TypeRef(),
+            attributes.as_concrete_TypeRef(),
+            &mut error,
+        )
+    };
+    if key.is_null() {
+        // SAFETY: a null key comes with an error, which we own.
+        let error = unsafe { core_foundation::error::CFError::wrap_under_create_rule(error) };
+        return Err(ErrorCode::Invalid(
+            "key data".to_string(),
+            error.description().to_string(),
+        ));
+    }
+    // SAFETY: the key was just created, so we own it.
+    Ok(unsafe { SecKey::wrap_under_create_rule(key) })
+}
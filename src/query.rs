@@ -0,0 +1,780 @@
+/*!
+
+# Raw item queries
+
+The security-framework crate's search and password APIs only cover
+some of the item attributes and query options this crate needs. This module
+builds `SecItem` query dictionaries directly, so the stores can use the
+attributes and options those APIs leave out. It is deliberately small: each
+store still uses the higher-level APIs wherever they suffice.
+
+Errors are returned as Security framework errors; each store maps
+them with its own `decode_error`.
+
+ */
+
+use std::collections::HashMap;
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use core_foundation::array::{CFArray, CFArrayRef};
+use core_foundation::base::{CFType, CFTypeRef, TCFType};
+use core_foundation::boolean::CFBoolean;
+use core_foundation::data::CFData;
+use core_foundation::date::CFDate;
+use core_foundation::dictionary::CFDictionary;
+use core_foundation::number::CFNumber;
+use core_foundation::string::{CFString, CFStringRef};
+use security_framework::base::Error;
+#[cfg(all(target_os = "macos", feature = "keychain"))]
+use security_framework::os::macos::keychain::SecKeychain;
+use security_framework_sys::base::errSecItemNotFound;
+use security_framework_sys::item::*;
+use security_framework_sys::keychain_item::{
+    SecItemAdd, SecItemCopyMatching, SecItemDelete, SecItemUpdate,
+};
+
+#[cfg(feature = "protected")]
+use objc2_foundation::{NSData, NSString};
+#[cfg(feature = "protected")]
+use objc2_local_authentication::{LAContext, LACredentialType};
+#[cfg(feature = "protected")]
+use security_framework::access_control::SecAccessControl;
+#[cfg(feature = "protected")]
+use security_framework::item::SearchResult;
+
+use keyring_core::{Error as ErrorCode, attributes::parse_attributes};
+
+#[link(name = "Security", kind = "framework")]
+unsafe extern "C" {
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    static kSecAttrIsInvisible: CFStringRef;
+    static kSecAttrGeneric: CFStringRef;
+    #[cfg(feature = "protected")]
+    static kSecAttrAccessible: CFStringRef;
+    static kSecAttrCreationDate: CFStringRef;
+    static kSecAttrModificationDate: CFStringRef;
+    #[cfg(feature = "protected")]
+    static kSecAttrApplicationTag: CFStringRef;
+    #[cfg(feature = "protected")]
+    static kSecAttrApplicationLabel: CFStringRef;
+    #[cfg(feature = "protected")]
+    static kSecValuePersistentRef: CFStringRef;
+    static kSecMatchDiacriticInsensitive: CFStringRef;
+}
+
+/// The seconds from the Unix epoch to the Core Foundation epoch (2001-01-01).
+const CF_EPOCH_OFFSET: f64 = 978_307_200.0;
+
+/// The item attributes this crate reads or writes directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Attr {
+    Service,
+    Account,
+    AccessGroup,
+    Generic,
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    IsInvisible,
+    Synchronizable,
+    Label,
+    Comment,
+    Description,
+    #[cfg(feature = "protected")]
+    Accessible,
+    CreationDate,
+    ModificationDate,
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    Server,
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    Protocol,
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    Port,
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    Path,
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    SecurityDomain,
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    AuthenticationType,
+    #[cfg(feature = "protected")]
+    ApplicationTag,
+    #[cfg(feature = "protected")]
+    ApplicationLabel,
+    /// Not an attribute, but reported with them when asked for.
+    #[cfg(feature = "protected")]
+    PersistentRef,
+}
+
+impl Attr {
+    fn key(self) -> CFString {
+        // SAFETY: these are immutable constants exported by the Security framework.
+        unsafe {
+            CFString::wrap_under_get_rule(match self {
+                Attr::Service => kSecAttrService,
+                Attr::Account => kSecAttrAccount,
+                Attr::AccessGroup => kSecAttrAccessGroup,
+                Attr::Generic => kSecAttrGeneric,
+                #[cfg(all(target_os = "macos", feature = "keychain"))]
+                Attr::IsInvisible => kSecAttrIsInvisible,
+                Attr::Synchronizable => kSecAttrSynchronizable,
+                Attr::Label => kSecAttrLabel,
+                Attr::Comment => kSecAttrComment,
+                Attr::Description => kSecAttrDescription,
+                #[cfg(feature = "protected")]
+                Attr::Accessible => kSecAttrAccessible,
+                Attr::CreationDate => kSecAttrCreationDate,
+                Attr::ModificationDate => kSecAttrModificationDate,
+                #[cfg(all(target_os = "macos", feature = "keychain"))]
+                Attr::Server => kSecAttrServer,
+                #[cfg(all(target_os = "macos", feature = "keychain"))]
+                Attr::Protocol => kSecAttrProtocol,
+                #[cfg(all(target_os = "macos", feature = "keychain"))]
+                Attr::Port => kSecAttrPort,
+                #[cfg(all(target_os = "macos", feature = "keychain"))]
+                Attr::Path => kSecAttrPath,
+                #[cfg(all(target_os = "macos", feature = "keychain"))]
+                Attr::SecurityDomain => kSecAttrSecurityDomain,
+                #[cfg(all(target_os = "macos", feature = "keychain"))]
+                Attr::AuthenticationType => kSecAttrAuthenticationType,
+                #[cfg(feature = "protected")]
+                Attr::ApplicationTag => kSecAttrApplicationTag,
+                #[cfg(feature = "protected")]
+                Attr::ApplicationLabel => kSecAttrApplicationLabel,
+                #[cfg(feature = "protected")]
+                Attr::PersistentRef => kSecValuePersistentRef,
+            })
+        }
+    }
+}
+
+/// How a search compares the strings it matches (such as services and
+/// accounts) with those of the items.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct StringMatching {
+    pub case_insensitive: bool,
+    pub diacritic_insensitive: bool,
+}
+
+impl StringMatching {
+    /// The matching asked for by a parsed search spec's `case-sensitive` and
+    /// `diacritic-sensitive` keys, which default to true.
+    pub fn of_spec(spec: &HashMap<String, String>) -> Self {
+        StringMatching {
+            case_insensitive: spec.get("case-sensitive").is_some_and(|s| s.eq("false")),
+            diacritic_insensitive: spec
+                .get("diacritic-sensitive")
+                .is_some_and(|s| s.eq("false")),
+        }
+    }
+
+    /// Whether this is anything but exact matching.
+    #[cfg(feature = "protected")]
+    pub fn is_loose(&self) -> bool {
+        self.case_insensitive || self.diacritic_insensitive
+    }
+}
+
+/// A LocalAuthentication context that can be shared by several queries.
+#[cfg(feature = "protected")]
+#[derive(Clone)]
+pub(crate) struct AuthenticationContext(CFType);
+
+#[cfg(feature = "protected")]
+impl AuthenticationContext {
+    /// A fresh context, in which the user hasn't yet authenticated.
+    pub fn new() -> Self {
+        // SAFETY: creating a context has no preconditions.
+        let context = unsafe { LAContext::new() };
+        Self::wrap(&context)
+    }
+
+    /// A context that supplies the given application password, so items that
+    /// require one can be read and written without the OS asking for it.
+    ///
+    /// Returns `None` if the OS won't take the password.
+    pub fn with_application_password(password: &[u8]) -> Option<Self> {
+        let password = NSData::with_bytes(password);
+        // SAFETY: creating a context has no preconditions, and setting its
+        // credential copies the data.
+        unsafe {
+            let context = LAContext::new();
+            context
+                .setCredential_type(Some(&password), LACredentialType::ApplicationPassword)
+                .then(|| Self::wrap(&context))
+        }
+    }
+
+    /// A context whose authentication prompt gives the user the given reason,
+    /// and which (like [reuse_authentication_within](Query::reuse_authentication_within))
+    /// may reuse a recent device-unlock authentication.
+    pub fn with_prompt(prompt: &str, reuse_within: Option<u32>) -> Self {
+        let prompt = NSString::from_str(prompt);
+        // SAFETY: creating a context has no preconditions, setting its reason
+        // copies the string, and setting its reuse duration only records the value.
+        unsafe {
+            let context = LAContext::new();
+            context.setLocalizedReason(&prompt);
+            if let Some(seconds) = reuse_within {
+                context.setTouchIDAuthenticationAllowableReuseDuration(seconds as f64);
+            }
+            Self::wrap(&context)
+        }
+    }
+
+    /// A context in which the user is asked to authenticate as usual, unless
+    /// (like [reuse_authentication_within](Query::reuse_authentication_within))
+    /// a recent device-unlock authentication can be reused.
+    pub fn interactive(reuse_within: Option<u32>) -> Self {
+        // SAFETY: creating a context has no preconditions, and setting its
+        // reuse duration only records the value.
+        unsafe {
+            let context = LAContext::new();
+            if let Some(seconds) = reuse_within {
+                context.setTouchIDAuthenticationAllowableReuseDuration(seconds as f64);
+            }
+            Self::wrap(&context)
+        }
+    }
+
+    /// A context that fails, rather than asks the user, when an item requires
+    /// authentication, unless a recent enough device-unlock authentication
+    /// can be reused.
+    pub fn non_interactive(reuse_within: Option<u32>) -> Self {
+        // SAFETY: creating a context has no preconditions, and setting its
+        // properties only records the values.
+        unsafe {
+            let context = LAContext::new();
+            context.setInteractionNotAllowed(true);
+            if let Some(seconds) = reuse_within {
+                context.setTouchIDAuthenticationAllowableReuseDuration(seconds as f64);
+            }
+            Self::wrap(&context)
+        }
+    }
+
+    /// The given LAContext, including any authentication done in it already.
+    pub fn wrap(context: &LAContext) -> Self {
+        // SAFETY: an LAContext is an Objective-C object, which Core Foundation
+        // can retain and release.
+        let context =
+            unsafe { CFType::wrap_under_get_rule(context as *const LAContext as CFTypeRef) };
+        AuthenticationContext(context)
+    }
+
+    /// A handle that can invalidate this context from another thread.
+    pub fn invalidator(&self) -> Invalidator {
+        Invalidator(self.0.clone())
+    }
+}
+
+/// A handle on an [AuthenticationContext] that can only invalidate it.
+#[cfg(feature = "protected")]
+pub(crate) struct Invalidator(CFType);
+
+// SAFETY: LocalAuthentication lets a context be invalidated from any thread,
+// and Core Foundation retains and releases objects atomically.
+#[cfg(feature = "protected")]
+unsafe impl Send for Invalidator {}
+
+#[cfg(feature = "protected")]
+impl Invalidator {
+    /// Dismiss any authentication prompt shown for the context, failing the
+    /// query waiting for it, and make every later use of the context fail.
+    pub fn invalidate(&self) {
+        // SAFETY: the object is the LAContext the handle was made from.
+        unsafe { (*(self.0.as_CFTypeRef() as *const LAContext)).invalidate() }
+    }
+}
+
+/// A `SecItem` query (or set of attributes to update) under construction.
+#[derive(Clone)]
+pub(crate) struct Query {
+    pairs: Vec<(CFString, CFType)>,
+}
+
+impl Query {
+    /// An empty query, used to hold the attributes changed by an update.
+    pub fn new() -> Self {
+        Query { pairs: Vec::new() }
+    }
+
+    /// The attribute changes described by a client's attribute map.
+    ///
+    /// The allowed keys are `label`, `comment`, and `description`, and
+    /// at least one of them must be given.
+    pub fn attribute_changes(attrs: &HashMap<&str, &str>) -> keyring_core::Result<Self> {
+        let attrs = parse_attributes(&["label", "comment", "description"], Some(attrs))?;
+        if attrs.is_empty() {
+            return Err(ErrorCode::Invalid(
+                "attributes".to_string(),
+                "at least one of label, comment, or description must be given".to_string(),
+            ));
+        }
+        let mut changes = Query::new();
+        for (key, attr) in [
+            ("label", Attr::Label),
+            ("comment", Attr::Comment),
+            ("description", Attr::Description),
+        ] {
+            if let Some(value) = attrs.get(key) {
+                changes.string(attr, value);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// A query over generic password items.
+    pub fn generic_password() -> Self {
+        let mut query = Query::new();
+        // SAFETY: these are immutable constants exported by the Security framework.
+        unsafe {
+            query.push(
+                kSecClass,
+                CFString::wrap_under_get_rule(kSecClassGenericPassword),
+            );
+        }
+        query
+    }
+
+    /// A query over internet password items.
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    pub fn internet_password() -> Self {
+        let mut query = Query::new();
+        // SAFETY: these are immutable constants exported by the Security framework.
+        unsafe {
+            query.push(
+                kSecClass,
+                CFString::wrap_under_get_rule(kSecClassInternetPassword),
+            );
+        }
+        query
+    }
+
+    /// A query over cryptographic key items.
+    #[cfg(feature = "protected")]
+    pub fn key() -> Self {
+        let mut query = Query::new();
+        // SAFETY: these are immutable constants exported by the Security framework.
+        unsafe { query.push(kSecClass, CFString::wrap_under_get_rule(kSecClassKey)) };
+        query
+    }
+
+    /// Restrict the query to the given legacy keychains.
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    pub fn keychains(&mut self, keychains: &[SecKeychain]) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecMatchSearchList, CFArray::from_CFTypes(keychains)) };
+        self
+    }
+
+    /// Add the item to the given legacy keychain.
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    pub fn use_keychain(&mut self, keychain: &SecKeychain) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecUseKeychain, keychain.clone()) };
+        self
+    }
+
+    /// Use the data protection keychain rather than the legacy keychains.
+    #[cfg(feature = "protected")]
+    pub fn data_protection(&mut self) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecUseDataProtectionKeychain, CFBoolean::true_value()) };
+        self
+    }
+
+    /// Let a recent device-unlock authentication satisfy the item's user-presence
+    /// requirement, if it happened within the given number of seconds.
+    ///
+    /// The OS caps the window at five minutes.
+    #[cfg(feature = "protected")]
+    pub fn reuse_authentication_within(&mut self, seconds: u32) -> &mut Self {
+        // SAFETY: creating a context has no preconditions, and setting its
+        // reuse duration only records the value.
+        let context = unsafe {
+            let context = LAContext::new();
+            context.setTouchIDAuthenticationAllowableReuseDuration(seconds as f64);
+            context
+        };
+        self.authentication_context(&AuthenticationContext::wrap(&context))
+    }
+
+    /// Authenticate the user (if the item requires it) in the given context.
+    ///
+    /// Queries that share a context share the user's authentication, so
+    /// fetching several protected items with one context prompts at most once.
+    #[cfg(feature = "protected")]
+    pub fn authentication_context(&mut self, context: &AuthenticationContext) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecUseAuthenticationContext, context.0.clone()) };
+        self
+    }
+
+    /// Compare the query's strings with the items' as given.
+    pub fn matching(&mut self, matching: StringMatching) -> &mut Self {
+        // SAFETY: these are immutable constants exported by the Security framework.
+        unsafe {
+            if matching.case_insensitive {
+                self.push(kSecMatchCaseInsensitive, CFBoolean::true_value());
+            }
+            if matching.diacritic_insensitive {
+                self.push(kSecMatchDiacriticInsensitive, CFBoolean::true_value());
+            }
+        }
+        self
+    }
+
+    /// Match items whether or not they are synchronized with iCloud.
+    #[cfg(feature = "protected")]
+    pub fn any_synchronizable(&mut self) -> &mut Self {
+        // SAFETY: these are immutable constants exported by the Security framework.
+        unsafe {
+            self.push(
+                kSecAttrSynchronizable,
+                CFString::wrap_under_get_rule(kSecAttrSynchronizableAny),
+            );
+        }
+        self
+    }
+
+    /// Match at most the given number of items.
+    pub fn limit(&mut self, limit: u32) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecMatchLimit, CFNumber::from(i64::from(limit))) };
+        self
+    }
+
+    /// Match all items rather than just the first.
+    pub fn limit_all(&mut self) -> &mut Self {
+        // SAFETY: these are immutable constants exported by the Security framework.
+        unsafe {
+            self.push(
+                kSecMatchLimit,
+                CFString::wrap_under_get_rule(kSecMatchLimitAll),
+            );
+        }
+        self
+    }
+
+    /// Return the attributes of matching items.
+    pub fn return_attributes(&mut self) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecReturnAttributes, CFBoolean::true_value()) };
+        self
+    }
+
+    /// Return the data (secret) of the matching item.
+    pub fn return_data(&mut self) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecReturnData, CFBoolean::true_value()) };
+        self
+    }
+
+    /// Return the persistent references of matching items, along with any
+    /// attributes asked for.
+    #[cfg(feature = "protected")]
+    pub fn return_persistent_refs(&mut self) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecReturnPersistentRef, CFBoolean::true_value()) };
+        self
+    }
+
+    /// Skip items that need user authentication rather than prompting for them.
+    #[cfg(feature = "protected")]
+    pub fn skip_authenticated_items(&mut self) -> &mut Self {
+        // SAFETY: these are immutable constants exported by the Security framework.
+        unsafe {
+            self.push(
+                kSecUseAuthenticationUI,
+                CFString::wrap_under_get_rule(kSecUseAuthenticationUISkip),
+            );
+        }
+        self
+    }
+
+    /// Set the data (secret) of an item being added or updated.
+    pub fn value(&mut self, secret: &[u8]) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecValueData, CFData::from_buffer(secret)) };
+        self
+    }
+
+    /// Set the object (such as a key) that an item being added holds.
+    #[cfg(feature = "protected")]
+    pub fn value_ref<T: TCFType>(&mut self, object: &T) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecValueRef, object.as_CFType()) };
+        self
+    }
+
+    /// Match the item with the given persistent reference.
+    #[cfg(feature = "protected")]
+    pub fn persistent_ref(&mut self, reference: &[u8]) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecValuePersistentRef, CFData::from_buffer(reference)) };
+        self
+    }
+
+    /// Set the access control of an item being added.
+    #[cfg(feature = "protected")]
+    pub fn access_control(&mut self, access_control: SecAccessControl) -> &mut Self {
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { self.push(kSecAttrAccessControl, access_control) };
+        self
+    }
+
+    /// Match (or set) a string-valued attribute.
+    pub fn string(&mut self, attr: Attr, value: &str) -> &mut Self {
+        self.pairs
+            .push((attr.key(), CFString::new(value).into_CFType()));
+        self
+    }
+
+    /// Match (or set) a boolean-valued attribute.
+    pub fn boolean(&mut self, attr: Attr, value: bool) -> &mut Self {
+        self.pairs
+            .push((attr.key(), CFBoolean::from(value).into_CFType()));
+        self
+    }
+
+    /// Match (or set) a number-valued attribute.
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    pub fn number(&mut self, attr: Attr, value: i64) -> &mut Self {
+        self.pairs
+            .push((attr.key(), CFNumber::from(value).into_CFType()));
+        self
+    }
+
+    /// Match (or set) a data-valued attribute.
+    pub fn data(&mut self, attr: Attr, value: &[u8]) -> &mut Self {
+        self.pairs
+            .push((attr.key(), CFData::from_buffer(value).into_CFType()));
+        self
+    }
+
+    /// Find the attributes of all the items matching the query.
+    ///
+    /// The query should ask for attributes to be returned.
+    /// Finding no items is not an error; it returns an empty list.
+    pub fn find(&self) -> Result<Vec<Attributes>, Error> {
+        let Some(result) = self.copy_matching()? else {
+            return Ok(Vec::new());
+        };
+        let mut items = Vec::new();
+        if result.type_of() == CFArray::<CFType>::type_id() {
+            // SAFETY: we just checked that the result is an array.
+            let array: CFArray<CFType> =
+                unsafe { CFArray::wrap_under_get_rule(result.as_CFTypeRef() as CFArrayRef) };
+            for item in array.iter() {
+                if let Some(dict) = item.downcast::<CFDictionary>() {
+                    items.push(Attributes(dict));
+                }
+            }
+        } else if let Some(dict) = result.downcast::<CFDictionary>() {
+            items.push(Attributes(dict));
+        }
+        Ok(items)
+    }
+
+    /// Fetch the data (secret) of the single item matching the query.
+    ///
+    /// Unlike [find](Query::find), finding no item is an `errSecItemNotFound` error.
+    pub fn find_data(&self) -> Result<Vec<u8>, Error> {
+        let mut query = self.clone();
+        query.return_data();
+        match query.copy_matching()?.and_then(|r| r.downcast::<CFData>()) {
+            Some(data) => Ok(data.bytes().to_vec()),
+            None => Err(Error::from_code(errSecItemNotFound)),
+        }
+    }
+
+    /// Fetch the object (such as a key) held by the single item matching the query.
+    ///
+    /// Like [find_data](Query::find_data), finding no item is an `errSecItemNotFound` error.
+    #[cfg(feature = "protected")]
+    pub fn find_ref(&self) -> Result<CFType, Error> {
+        let mut query = self.clone();
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { query.push(kSecReturnRef, CFBoolean::true_value()) };
+        query
+            .copy_matching()?
+            .ok_or_else(|| Error::from_code(errSecItemNotFound))
+    }
+
+    /// Fetch the persistent reference of the single item matching the query.
+    ///
+    /// Like [find_data](Query::find_data), finding no item is an `errSecItemNotFound` error.
+    #[cfg(feature = "protected")]
+    pub fn find_persistent_ref(&self) -> Result<Vec<u8>, Error> {
+        let mut query = self.clone();
+        // SAFETY: this is an immutable constant exported by the Security framework.
+        unsafe { query.push(kSecReturnPersistentRef, CFBoolean::true_value()) };
+        match query.copy_matching()?.and_then(|r| r.downcast::<CFData>()) {
+            Some(data) => Ok(data.bytes().to_vec()),
+            None => Err(Error::from_code(errSecItemNotFound)),
+        }
+    }
+
+    /// Add an item with the query's attributes.
+    pub fn add(&self) -> Result<(), Error> {
+        let query = self.to_dictionary();
+        // SAFETY: the query is a valid dictionary and we don't ask for a result.
+        cvt(unsafe { SecItemAdd(query.as_concrete_TypeRef(), ptr::null_mut()) })
+    }
+
+    /// Change the given attributes of all items matching the query.
+    pub fn update(&self, changes: &Query) -> Result<(), Error> {
+        let query = self.to_dictionary();
+        let changes = changes.to_dictionary();
+        // SAFETY: both the query and the changes are valid dictionaries.
+        cvt(unsafe { SecItemUpdate(query.as_concrete_TypeRef(), changes.as_concrete_TypeRef()) })
+    }
+
+    /// Delete all items matching the query.
+    pub fn delete(&self) -> Result<(), Error> {
+        let query = self.to_dictionary();
+        // SAFETY: the query is a valid dictionary.
+        cvt(unsafe { SecItemDelete(query.as_concrete_TypeRef()) })
+    }
+
+    fn copy_matching(&self) -> Result<Option<CFType>, Error> {
+        let query = self.to_dictionary();
+        let mut result: CFTypeRef = ptr::null();
+        // SAFETY: the query is a valid dictionary and the result pointer is valid for writes.
+        let status = unsafe { SecItemCopyMatching(query.as_concrete_TypeRef(), &mut result) };
+        if status == errSecItemNotFound {
+            return Ok(None);
+        }
+        cvt(status)?;
+        if result.is_null() {
+            return Ok(None);
+        }
+        // SAFETY: the result follows the create rule, so we take ownership of it.
+        Ok(Some(unsafe { CFType::wrap_under_create_rule(result) }))
+    }
+
+    fn to_dictionary(&self) -> CFDictionary<CFString, CFType> {
+        CFDictionary::from_CFType_pairs(&self.pairs)
+    }
+
+    /// # Safety
+    ///
+    /// The key must be one of the `kSec*` constants exported by the Security framework.
+    unsafe fn push(&mut self, key: CFStringRef, value: impl TCFType) {
+        // SAFETY: the caller guarantees the key is a valid constant.
+        let key = unsafe { CFString::wrap_under_get_rule(key) };
+        self.pairs.push((key, value.into_CFType()));
+    }
+}
+
+fn cvt(status: i32) -> Result<(), Error> {
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(Error::from_code(status))
+    }
+}
+
+/// The attributes of an item found by a query.
+pub(crate) struct Attributes(CFDictionary);
+
+/// The attributes of a generic password item, as returned by a store's `Query`.
+///
+/// Attributes the item doesn't have are empty (or `None`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ItemAttributes {
+    /// The service attribute.
+    pub service: String,
+    /// The account attribute.
+    pub account: String,
+    /// The access group, which only protected data items have.
+    pub access_group: Option<String>,
+    pub label: Option<String>,
+    pub comment: Option<String>,
+    pub description: Option<String>,
+    /// The generic metadata, which the stores use to mark some items.
+    pub generic: Option<Vec<u8>>,
+    /// Whether the item is synchronized with iCloud.
+    pub synchronized: bool,
+}
+
+impl Attributes {
+    /// The attributes in a search result, if it has them.
+    #[cfg(feature = "protected")]
+    pub fn of_search_result(result: &SearchResult) -> Option<Self> {
+        match result {
+            SearchResult::Dict(dict) => Some(Attributes(dict.clone())),
+            _ => None,
+        }
+    }
+
+    /// The attributes as a search result, such as the security-framework crate's
+    /// search returns.
+    #[cfg(feature = "protected")]
+    pub fn search_result(&self) -> SearchResult {
+        SearchResult::Dict(self.0.clone())
+    }
+
+    /// The attributes a store's `Query` reports.
+    pub fn item(&self) -> ItemAttributes {
+        ItemAttributes {
+            service: self.string(Attr::Service).unwrap_or_default(),
+            account: self.string(Attr::Account).unwrap_or_default(),
+            access_group: self.string(Attr::AccessGroup),
+            label: self.string(Attr::Label),
+            comment: self.string(Attr::Comment),
+            description: self.string(Attr::Description),
+            generic: self.data(Attr::Generic),
+            synchronized: self.boolean(Attr::Synchronizable).unwrap_or_default(),
+        }
+    }
+
+    fn value(&self, attr: Attr) -> Option<CFType> {
+        let key = attr.key();
+        let value = self.0.find(key.as_CFTypeRef())?;
+        // SAFETY: values in an attribute dictionary are CF objects.
+        Some(unsafe { CFType::wrap_under_get_rule(*value) })
+    }
+
+    /// A string-valued attribute.
+    pub fn string(&self, attr: Attr) -> Option<String> {
+        self.value(attr)?
+            .downcast::<CFString>()
+            .map(|s| s.to_string())
+    }
+
+    /// A number-valued attribute.
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    pub fn number(&self, attr: Attr) -> Option<i64> {
+        self.value(attr)?.downcast::<CFNumber>()?.to_i64()
+    }
+
+    /// A data-valued attribute.
+    pub fn data(&self, attr: Attr) -> Option<Vec<u8>> {
+        self.value(attr)?
+            .downcast::<CFData>()
+            .map(|d| d.bytes().to_vec())
+    }
+
+    /// A date-valued attribute.
+    pub fn date(&self, attr: Attr) -> Option<SystemTime> {
+        let date = self.value(attr)?.downcast::<CFDate>()?;
+        let seconds = date.abs_time() + CF_EPOCH_OFFSET;
+        if seconds < 0.0 {
+            return None;
+        }
+        Some(UNIX_EPOCH + Duration::from_secs_f64(seconds))
+    }
+
+    /// A boolean-valued attribute, which the OS may report as a number.
+    pub fn boolean(&self, attr: Attr) -> Option<bool> {
+        let value = self.value(attr)?;
+        if let Some(b) = value.downcast::<CFBoolean>() {
+            Some(b.into())
+        } else {
+            value
+                .downcast::<CFNumber>()
+                .and_then(|n| n.to_i64())
+                .map(|n| n != 0)
+        }
+    }
+}
@@ -0,0 +1,90 @@
+/*!
+
+# Redaction in debug output
+
+Credentials get printed in logs, and their service and user often identify
+a person or an account, so the `Debug` output of both stores' credentials
+hides them. (Secrets are never part of a credential, so they can't appear.)
+
+A store can be configured to reveal the specifiers with the
+`debug-redaction` key: `full` (the default) or `reveal-specifiers`. The
+policy is applied to every credential the store builds or finds, and it's
+what `debug_fmt` (and so the `Debug` output of an entry) follows. The
+`Display` output of a credential never shows its specifiers, whatever the
+policy; to log just the specifiers of one credential, use its
+`reveal_specifiers_only` formatter.
+
+ */
+
+use std::fmt::{Debug, Display, Formatter};
+
+use keyring_core::{Error as ErrorCode, Result};
+
+/// How much of a credential its `Debug` output shows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Redaction {
+    /// The service and user (and anything derived from them) are hidden.
+    #[default]
+    Full,
+    /// Every field is shown.
+    RevealSpecifiers,
+}
+
+impl std::str::FromStr for Redaction {
+    type Err = ErrorCode;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(Redaction::Full),
+            "reveal-specifiers" => Ok(Redaction::RevealSpecifiers),
+            _ => Err(ErrorCode::Invalid(
+                "debug-redaction".to_string(),
+                format!("'{s}' is not full or reveal-specifiers"),
+            )),
+        }
+    }
+}
+
+impl Display for Redaction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Redaction::Full => f.write_str("full"),
+            Redaction::RevealSpecifiers => f.write_str("reveal-specifiers"),
+        }
+    }
+}
+
+/// The specifiers of a credential, and nothing else about it.
+///
+/// This is what `reveal_specifiers_only` returns.
+pub struct Specifiers<'a> {
+    pub(crate) service: &'a str,
+    pub(crate) user: &'a str,
+}
+
+impl Debug for Specifiers<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cred")
+            .field("service", &self.service)
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Display for Specifiers<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "service {:?}, user {:?}", self.service, self.user)
+    }
+}
+
+/// A field whose `Debug` output is hidden unless the policy reveals specifiers.
+pub(crate) struct Masked<'a, T: Debug>(pub &'a T, pub Redaction);
+
+impl<T: Debug> Debug for Masked<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.1 {
+            Redaction::Full => f.write_str("<redacted>"),
+            Redaction::RevealSpecifiers => self.0.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+/*!
+
+# Background refresh
+
+Cloud-synchronized credentials can be changed by the user's other devices at
+any time, so processes that share them tend to poll. A [Refresher] does that
+polling on a background thread: it re-reads a set of entries every interval,
+and calls back when an entry's secret differs from what the previous poll
+saw (including when the credential was deleted or reappears).
+
+Polls that fail are retried with exponential backoff, up to
+[MAX_BACKOFF_FACTOR] times the interval, and polling goes back to the normal
+interval as soon as a poll succeeds. A poll that finds the device locked
+(`errSecInteractionNotAllowed`) stops there, without reading the remaining
+entries, and backs off in the same way. Failures are logged at debug level
+rather than reported through the callback.
+
+The refresher never keeps secrets: it remembers a digest of each one, which
+is only meaningful within the process.
+
+ */
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::debug;
+
+use keyring_core::{Entry, Error as ErrorCode, Result};
+
+use crate::error::{ErrorKind, error_kind};
+
+/// The longest a failing refresher waits between polls, as a multiple of its interval.
+pub const MAX_BACKOFF_FACTOR: u32 = 16;
+
+/// A background poller for a set of entries. See the [module docs](self).
+///
+/// Polling stops when the refresher is stopped or dropped.
+#[derive(Debug)]
+pub struct Refresher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Refresher {
+    /// Start polling the given entries every `interval`.
+    ///
+    /// The first poll happens right away and only records what it finds. After
+    /// that, `on_change` is called with the entry and its new secret (or `None`
+    /// if the credential is gone) whenever a poll finds a different secret.
+    ///
+    /// Polling reads secrets, so entries whose access policy requires user
+    /// presence will prompt on every poll; don't refresh them.
+    pub fn start<F>(entries: Vec<Entry>, interval: Duration, on_change: F) -> Result<Self>
+    where
+        F: Fn(&Entry, Option<&[u8]>) + Send + 'static,
+    {
+        if interval.is_zero() {
+            return Err(ErrorCode::Invalid(
+                "interval".to_string(),
+                "must be greater than zero".to_string(),
+            ));
+        }
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("keyring-refresh".to_string())
+            .spawn(move || {
+                let mut seen: Vec<Option<Option<u64>>> = vec![None; entries.len()];
+                let mut delay = interval;
+                loop {
+                    delay = if poll(&entries, &mut seen, &on_change) {
+                        interval
+                    } else {
+                        (delay * 2).min(interval * MAX_BACKOFF_FACTOR)
+                    };
+                    match stopped.recv_timeout(delay) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        _ => break,
+                    }
+                }
+            })
+            .map_err(|err| ErrorCode::PlatformFailure(Box::new(err)))?;
+        Ok(Refresher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop polling, waiting for a poll in progress to finish.
+    pub fn stop(mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                debug!("The refresh thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        // dropping the sender wakes the thread, which then exits on its own
+        self.stop.take();
+    }
+}
+
+/// Read each entry once, calling back for the ones that changed.
+///
+/// Returns whether every entry could be read.
+fn poll<F>(entries: &[Entry], seen: &mut [Option<Option<u64>>], on_change: &F) -> bool
+where
+    F: Fn(&Entry, Option<&[u8]>),
+{
+    let mut ok = true;
+    for (entry, seen) in entries.iter().zip(seen.iter_mut()) {
+        let secret = match entry.get_secret() {
+            Ok(secret) => Some(secret),
+            Err(ErrorCode::NoEntry) => None,
+            Err(err) if error_kind(&err) == Some(ErrorKind::InteractionNotAllowed) => {
+                debug!("Refresh found the device locked; backing off");
+                return false;
+            }
+            Err(err) => {
+                debug!("Refresh couldn't read {entry:?}: {err:?}");
+                ok = false;
+                continue;
+            }
+        };
+        let digest = secret.as_deref().map(digest);
+        if seen.is_some_and(|previous| previous != digest) {
+            on_change(entry, secret.as_deref());
+        }
+        *seen = Some(digest);
+    }
+    ok
+}
+
+fn digest(secret: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    secret.hash(&mut hasher);
+    hasher.finish()
+}
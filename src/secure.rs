@@ -0,0 +1,37 @@
+/*!
+
+# Secrets in secrecy wrappers
+
+With the `secrecy` feature, each store's credentials have `get_secret_secure`
+and `get_password_secure` accessors that return their secret wrapped in the
+[secrecy](https://docs.rs/secrecy) crate's types, which don't show the secret
+in `Debug` output and zeroize it when they're dropped. These helpers do the
+wrapping, taking care that no unzeroized copy of the secret is left behind.
+
+ */
+
+use secrecy::zeroize::Zeroize;
+use secrecy::{SecretSlice, SecretString};
+
+use keyring_core::{Error as ErrorCode, Result};
+
+/// Wrap a secret, zeroizing the buffer it came in.
+pub(crate) fn secure_secret(mut secret: Vec<u8>) -> SecretSlice<u8> {
+    // copying into an exactly-sized buffer means boxing it can't reallocate
+    let wrapped = SecretSlice::from(secret.as_slice().to_vec());
+    secret.zeroize();
+    wrapped
+}
+
+/// Wrap a secret as a password, zeroizing the buffer it came in.
+///
+/// A secret that isn't UTF-8 is a [BadEncoding](ErrorCode::BadEncoding) error,
+/// as it is from `get_password`, so the caller gets the raw bytes back.
+pub(crate) fn secure_password(mut secret: Vec<u8>) -> Result<SecretString> {
+    let wrapped = match std::str::from_utf8(&secret) {
+        Ok(password) => SecretString::from(password.to_string()),
+        Err(_) => return Err(ErrorCode::BadEncoding(secret)),
+    };
+    secret.zeroize();
+    Ok(wrapped)
+}
@@ -0,0 +1,126 @@
+/*!
+
+# Test fixtures
+
+Integration tests that run against a real store (on a CI device, say) leave
+items behind whenever a test crashes before cleaning up after itself. A
+[ScopedNamespace] gives a run a service prefix of its own, such as
+`keyring-test/1a2b3c4d-5e6f`, and deletes every item under that prefix when
+it's dropped. Since the drop happens while a panic unwinds, too, a test that
+fails still cleans up.
+
+Services in the namespace are `/`-separated paths below the prefix, and the
+cleanup is a `service-under` search, so it doesn't work with protected stores
+that hash their specifiers.
+
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::error;
+
+use keyring_core::{CredentialStore, Entry, Error as ErrorCode, Result};
+
+use crate::ext::{Backend, apple_store};
+
+/// A per-run service prefix whose items are deleted when it's dropped.
+///
+/// See the [module docs](self).
+pub struct ScopedNamespace {
+    store: Arc<CredentialStore>,
+    prefix: String,
+}
+
+impl std::fmt::Debug for ScopedNamespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopedNamespace")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ScopedNamespace {
+    /// A namespace in the given store, below the given base path.
+    ///
+    /// The namespace's prefix is the base followed by a run ID that's unique to
+    /// this process and this call. The store must be one of this crate's.
+    pub fn new(store: &Arc<CredentialStore>, base: &str) -> Result<Self> {
+        if apple_store(store).is_none() {
+            return Err(ErrorCode::NotSupportedByStore(
+                "scoped namespaces need an Apple native store".to_string(),
+            ));
+        }
+        let base = base.trim_matches('/');
+        if base.is_empty() {
+            return Err(ErrorCode::Invalid(
+                "base".to_string(),
+                "must not be empty".to_string(),
+            ));
+        }
+        Ok(ScopedNamespace {
+            store: store.clone(),
+            prefix: format!("{base}/{}", run_id()),
+        })
+    }
+
+    /// The prefix every service in the namespace starts with.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The namespace's service with the given name.
+    pub fn service(&self, name: &str) -> String {
+        format!("{}/{name}", self.prefix)
+    }
+
+    /// An entry in the namespace, for the service with the given name.
+    pub fn entry(&self, name: &str, user: &str) -> Result<Entry> {
+        self.entry_with_modifiers(name, user, &HashMap::new())
+    }
+
+    /// An entry in the namespace built with the given modifiers.
+    pub fn entry_with_modifiers(
+        &self,
+        name: &str,
+        user: &str,
+        modifiers: &HashMap<&str, &str>,
+    ) -> Result<Entry> {
+        self.store.build(&self.service(name), user, Some(modifiers))
+    }
+
+    /// Delete every item in the namespace, returning how many were deleted.
+    ///
+    /// Dropping the namespace does this too, logging any failure.
+    pub fn cleanup(&self) -> Result<usize> {
+        let Some(store) = apple_store(&self.store) else {
+            return Ok(0);
+        };
+        let mut spec = HashMap::from([("service-under", self.prefix.as_str())]);
+        if store.provider_info().backend == Backend::Protected {
+            spec.insert("show-authentication-ui", "true");
+        }
+        store.wipe(&spec)
+    }
+}
+
+impl Drop for ScopedNamespace {
+    fn drop(&mut self) {
+        if let Err(err) = self.cleanup() {
+            error!("Couldn't clean up namespace {}: {err}", self.prefix);
+        }
+    }
+}
+
+/// An ID made from the time, the process ID, and a per-process counter.
+fn run_id() -> String {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{nanos:x}-{:x}-{count}", std::process::id())
+}
@@ -0,0 +1,107 @@
+/*!
+
+# Operation timeouts
+
+Calls into the Security framework normally return promptly, but they can
+hang, for example when the security agent that services them is stuck. By
+default the stores wait for them indefinitely. Calling [set_operation_timeout]
+sets a process-wide limit instead: each credential operation (setting,
+getting, or deleting a secret, and getting a credential) and each search then
+runs on a worker thread, and if it hasn't finished when the timeout expires
+the caller gets a `NoStorageAccess` error holding a [Timeout].
+
+An abandoned operation keeps running on its worker thread, and may still
+complete, so after a timeout the item may or may not have been changed.
+The timeout also covers any time spent waiting for the user to authenticate,
+so it should be generous enough for that. For a deadline on the prompt itself,
+give the protected store or entry a `prompt-timeout`: a prompt the user
+doesn't answer in time is dismissed, and the read fails with a [Timeout] too.
+
+ */
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use keyring_core::{Error as ErrorCode, Result};
+
+static OPERATION_TIMEOUT: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// Set (or, with `None`, remove) the timeout for store operations in this process.
+pub fn set_operation_timeout(timeout: Option<Duration>) {
+    *OPERATION_TIMEOUT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = timeout;
+}
+
+/// The timeout for store operations in this process, if there is one.
+pub fn operation_timeout() -> Option<Duration> {
+    *OPERATION_TIMEOUT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The error inside a `NoStorageAccess` error from an operation that timed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    /// The operation, such as `get_secret` or `search`.
+    pub operation: &'static str,
+    /// The timeout that expired.
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} didn't finish within {:?}",
+            self.operation, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// The [Timeout] behind a keyring error, if it's from an operation that timed out.
+pub fn timed_out(err: &ErrorCode) -> Option<&Timeout> {
+    match err {
+        ErrorCode::NoStorageAccess(inner) => inner.downcast_ref(),
+        _ => None,
+    }
+}
+
+/// Run an operation on a target, giving up on it if the operation timeout expires.
+///
+/// Without a timeout, the operation runs on the calling thread.
+pub(crate) fn run<C, T>(
+    operation: &'static str,
+    target: C,
+    f: impl FnOnce(&C) -> Result<T> + Send + 'static,
+) -> Result<T>
+where
+    C: Send + 'static,
+    T: Send + 'static,
+{
+    let Some(timeout) = operation_timeout() else {
+        return f(&target);
+    };
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("keyring-operation".to_string())
+        .spawn(move || {
+            // the caller may have given up, in which case nobody wants the result
+            let _ = sender.send(f(&target));
+        })
+        .map_err(|err| ErrorCode::PlatformFailure(Box::new(err)))?;
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(ErrorCode::NoStorageAccess(Box::new(Timeout {
+            operation,
+            timeout,
+        }))),
+        Err(RecvTimeoutError::Disconnected) => Err(ErrorCode::PlatformFailure(
+            format!("{operation} panicked").into(),
+        )),
+    }
+}
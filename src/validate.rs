@@ -0,0 +1,116 @@
+/*!
+
+# Configuration validation
+
+Apps that take store configurations from users (in config files, say) want to
+know what's wrong with one before they try it. [validate_configuration] checks
+a configuration map for either store without creating the store, and reports
+everything it finds rather than stopping at the first problem.
+
+Errors are problems that would make creating the store fail: unknown keys,
+malformed values, combinations of keys the store rejects, and stores or
+features this build or OS doesn't have. Warnings are prerequisites (such as
+entitlements) that can't be checked without writing an item, so a
+configuration with only warnings may still work.
+
+ */
+
+use std::collections::HashMap;
+
+use keyring_core::{Error as ErrorCode, attributes::parse_attributes};
+
+use crate::ext::Backend;
+
+/// How serious a [Finding] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    /// Creating a store with this configuration would fail.
+    Error,
+    /// The configuration depends on something that can't be checked here.
+    Warning,
+}
+
+/// One problem found by [validate_configuration].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The configuration key the finding is about, if it's about just one.
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl Finding {
+    pub(crate) fn error(key: Option<&str>, message: impl Into<String>) -> Self {
+        Finding {
+            severity: Severity::Error,
+            key: key.map(String::from),
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn warning(key: Option<&str>, message: impl Into<String>) -> Self {
+        Finding {
+            severity: Severity::Warning,
+            key: key.map(String::from),
+            message: message.into(),
+        }
+    }
+
+    /// The finding for an error a store would have returned.
+    pub(crate) fn from_error(err: ErrorCode) -> Self {
+        match err {
+            ErrorCode::Invalid(key, message) => Finding::error(Some(&key), message),
+            other => Finding::error(None, other.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match &self.key {
+            Some(key) => write!(f, "{severity}: {key}: {}", self.message),
+            None => write!(f, "{severity}: {}", self.message),
+        }
+    }
+}
+
+/// Check a configuration for the given store, without creating the store.
+///
+/// An empty result means the store would accept the configuration.
+pub fn validate_configuration(kind: Backend, config: &HashMap<&str, &str>) -> Vec<Finding> {
+    match kind {
+        #[cfg(all(target_os = "macos", feature = "keychain"))]
+        Backend::Keychain => crate::keychain::Store::validate_configuration(config),
+        #[cfg(feature = "protected")]
+        Backend::Protected => crate::protected::Store::validate_configuration(config),
+        #[allow(unreachable_patterns)]
+        other => vec![Finding::error(
+            None,
+            format!("the {other} store isn't available in this build"),
+        )],
+    }
+}
+
+/// The well-formed entries of a configuration, with a finding for each of the others.
+///
+/// The keys are marked as they are for `parse_attributes`, which checks each entry.
+pub(crate) fn check_keys(
+    keys: &[&str],
+    config: &HashMap<&str, &str>,
+    findings: &mut Vec<Finding>,
+) -> HashMap<String, String> {
+    let mut entries: Vec<_> = config.iter().collect();
+    entries.sort();
+    let mut valid = HashMap::new();
+    for (key, value) in entries {
+        match parse_attributes(keys, Some(&HashMap::from([(*key, *value)]))) {
+            Ok(parsed) => valid.extend(parsed),
+            Err(err) => findings.push(Finding::from_error(err)),
+        }
+    }
+    valid
+}